@@ -6,7 +6,7 @@ use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
     io::{BufReader, Cursor, Read, Seek, SeekFrom, Write},
     path::Path,
@@ -16,6 +16,7 @@ use strum::{Display, EnumIter};
 use tracing::{debug, info, trace};
 
 pub mod qcow;
+pub mod reader;
 
 trait ReadSeek: Read + Seek {}
 impl ReadSeek for BufReader<File> {}
@@ -340,8 +341,8 @@ pub enum HeaderEncryptionType {
 }
 
 /// Metadata about an element within this image.
-#[derive(BinRead, BinWrite, Debug, Eq, PartialEq)]
-#[brw(big)]
+#[derive(BinRead, BinWrite, Debug, Eq, PartialEq, Clone)]
+#[brw(big, import(version: u8))]
 pub struct ElementHeader {
     /// Length of the os field in bytes
     pub os_length: u8,
@@ -356,6 +357,12 @@ pub struct ElementHeader {
     /// Element name
     #[br(count = name_length)]
     pub name: Vec<u8>,
+
+    /// `content_id` of the goldboot image this element was built on top of,
+    /// or zeros if it was built from scratch. Absent before version 3.
+    #[br(if(version >= 3))]
+    #[bw(if(version >= 3))]
+    pub parent: [u8; 32],
 }
 
 impl ElementHeader {
@@ -367,14 +374,44 @@ impl ElementHeader {
         String::from_utf8_lossy(&self.os).into_owned()
     }
 
+    /// Hex-encoded `content_id` of the parent image, if there is one.
+    pub fn parent_hex(&self) -> Option<String> {
+        if self.parent == [0u8; 32] {
+            None
+        } else {
+            Some(hex::encode(self.parent))
+        }
+    }
+
     pub fn new(os: &str, name: &str) -> Result<ElementHeader> {
         Ok(ElementHeader {
             os_length: u8::try_from(os.len()).context("OS string too long")?,
             os: os.as_bytes().to_vec(),
             name_length: u8::try_from(name.len()).context("Name string too long")?,
             name: name.as_bytes().to_vec(),
+            parent: [0u8; 32],
         })
     }
+
+    /// Record the `content_id` of the image this element was built on.
+    pub fn with_parent(mut self, content_id: [u8; 32]) -> Self {
+        self.parent = content_id;
+        self
+    }
+}
+
+/// The newest image format version. Version 2 images (which lack
+/// [`ElementHeader::parent`]) can still be read.
+pub const FORMAT_VERSION: u8 = 3;
+
+/// The format version to write an image with `elements` in. Images without a
+/// parent stay at version 2 so readers that predate version 3 accept them.
+pub fn format_version(elements: &[ElementHeader]) -> u8 {
+    if elements.iter().any(|element| element.parent != [0u8; 32]) {
+        FORMAT_VERSION
+    } else {
+        2
+    }
 }
 
 /// Contains metadata which is always plaintext. Anything potentially useful to
@@ -384,7 +421,7 @@ impl ElementHeader {
 #[brw(magic = b"\xc0\x1d\xb0\x01", big)]
 pub struct PrimaryHeader {
     /// Format version
-    #[br(assert(version == 2))]
+    #[br(assert(version == 2 || version == FORMAT_VERSION))]
     pub version: u8,
 
     /// Total size of all blocks combined in bytes
@@ -399,7 +436,8 @@ pub struct PrimaryHeader {
     /// Number of elements in this image
     pub element_count: u8,
 
-    #[br(count = element_count)]
+    #[br(count = element_count, args { inner: (version,) })]
+    #[bw(args(*version))]
     pub elements: Vec<ElementHeader>,
 
     /// System architecture
@@ -597,6 +635,12 @@ pub const MANIFEST_FLAG_HEADERS_ENCRYPTED: u8 = 0x01;
 /// Parsed manifest in raw byte form: each section is the bytes as they
 /// appear on disk (encrypted if the source `.gb` is encrypted). Decrypted
 /// metadata is obtained via [`parse_manifest`].
+///
+/// A server without the password can't locate the protected header or the
+/// digest table of an encrypted image (their bounds live in the encrypted
+/// directory), so it sends a *partial* manifest with those two sections
+/// empty. Clients decrypt the directory with [`parse_manifest_directory`]
+/// and fetch the missing sections by byte range.
 pub struct ManifestBlob {
     pub headers_encrypted: bool,
     pub primary_bytes: Vec<u8>,
@@ -633,6 +677,12 @@ impl ManifestBlob {
         })
     }
 
+    /// Whether the protected header and digest table are missing (see the
+    /// type docs).
+    pub fn is_partial(&self) -> bool {
+        self.headers_encrypted && self.protected_bytes.is_empty()
+    }
+
     /// Serialise the manifest blob to bytes.
     pub fn write_to(&self) -> Vec<u8> {
         let mut out = Vec::new();
@@ -670,20 +720,19 @@ fn write_length_prefixed(out: &mut Vec<u8>, data: &[u8]) {
     out.extend_from_slice(data);
 }
 
-/// Parse a manifest blob into the four typed metadata structures, decrypting
-/// each section if the source image is encrypted. Returns the cluster region
-/// start offset (= end of protected header bytes in the original `.gb` file).
-pub fn parse_manifest(
+/// Parse the primary header and directory out of a manifest blob, decrypting
+/// the directory if the source image is encrypted. This is all a client
+/// needs to complete a partial manifest: the directory holds the offsets
+/// and sizes of the remaining sections.
+pub fn parse_manifest_directory(
     blob: &ManifestBlob,
     password: Option<String>,
-) -> Result<(PrimaryHeader, ProtectedHeader, Directory, DigestTable, u64)> {
+) -> Result<(PrimaryHeader, Directory)> {
     let primary: PrimaryHeader = Cursor::new(&blob.primary_bytes).read_be()?;
-
-    let header_cipher = new_key(password.unwrap_or_default());
     let directory: Directory = match primary.encryption_type {
         HeaderEncryptionType::None => Cursor::new(&blob.directory_bytes).read_be()?,
         HeaderEncryptionType::Aes256 => {
-            let plain = header_cipher
+            let plain = new_key(password.unwrap_or_default())
                 .decrypt(
                     Nonce::from_slice(&primary.directory_nonce),
                     blob.directory_bytes.as_slice(),
@@ -692,7 +741,22 @@ pub fn parse_manifest(
             Cursor::new(plain).read_be()?
         }
     };
+    Ok((primary, directory))
+}
+
+/// Parse a manifest blob into the four typed metadata structures, decrypting
+/// each section if the source image is encrypted. Returns the cluster region
+/// start offset (= end of protected header bytes in the original `.gb` file).
+pub fn parse_manifest(
+    blob: &ManifestBlob,
+    password: Option<String>,
+) -> Result<(PrimaryHeader, ProtectedHeader, Directory, DigestTable, u64)> {
+    if blob.is_partial() {
+        bail!("manifest is missing the protected header and digest table");
+    }
+    let (primary, directory) = parse_manifest_directory(blob, password.clone())?;
 
+    let header_cipher = new_key(password.unwrap_or_default());
     let protected: ProtectedHeader = match primary.encryption_type {
         HeaderEncryptionType::None => Cursor::new(&blob.protected_bytes).read_be()?,
         HeaderEncryptionType::Aes256 => {
@@ -879,18 +943,38 @@ impl ImageHandle {
     /// (still encrypted if the source is encrypted). Used by the registry
     /// server to answer `/manifest` requests without ever needing the
     /// image password.
+    ///
+    /// For an encrypted image whose directory hasn't been loaded, the
+    /// result is a partial manifest (see [`ManifestBlob`]).
     pub fn read_manifest_blob(&self) -> Result<ManifestBlob> {
-        let directory = self
-            .directory
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("directory not loaded"))?;
         let primary_len = self.primary_header_len()?;
+        let headers_encrypted = matches!(
+            self.primary_header.encryption_type,
+            HeaderEncryptionType::Aes256
+        );
 
         let mut file = File::open(&self.path)?;
         let mut primary_bytes = vec![0u8; primary_len as usize];
         file.seek(SeekFrom::Start(0))?;
         file.read_exact(&mut primary_bytes)?;
 
+        let mut directory_bytes = vec![0u8; self.primary_header.directory_size as usize];
+        file.seek(SeekFrom::Start(self.primary_header.directory_offset))?;
+        file.read_exact(&mut directory_bytes)?;
+
+        let Some(directory) = self.directory.as_ref() else {
+            if !headers_encrypted {
+                bail!("directory not loaded");
+            }
+            return Ok(ManifestBlob {
+                headers_encrypted,
+                primary_bytes,
+                protected_bytes: Vec::new(),
+                directory_bytes,
+                digest_table_bytes: Vec::new(),
+            });
+        };
+
         let mut protected_bytes = vec![0u8; directory.protected_size as usize];
         file.seek(SeekFrom::Start(primary_len))?;
         file.read_exact(&mut protected_bytes)?;
@@ -899,15 +983,8 @@ impl ImageHandle {
         file.seek(SeekFrom::Start(directory.digest_table_offset))?;
         file.read_exact(&mut digest_table_bytes)?;
 
-        let mut directory_bytes = vec![0u8; self.primary_header.directory_size as usize];
-        file.seek(SeekFrom::Start(self.primary_header.directory_offset))?;
-        file.read_exact(&mut directory_bytes)?;
-
         Ok(ManifestBlob {
-            headers_encrypted: matches!(
-                self.primary_header.encryption_type,
-                HeaderEncryptionType::Aes256
-            ),
+            headers_encrypted,
             primary_bytes,
             protected_bytes,
             directory_bytes,
@@ -1298,6 +1375,7 @@ impl ImageHandle {
     /// therefore in any later whole-file integrity checks, but are
     /// deliberately not part of `content_id` — that is computed over the
     /// cluster region only.
+    #[allow(clippy::too_many_arguments)]
     pub fn from_qcow<F: Fn(u64, u64)>(
         name: &str,
         tag: &str,
@@ -1305,6 +1383,7 @@ impl ImageHandle {
         source: &Qcow3,
         dest: impl AsRef<Path>,
         password: Option<String>,
+        options: &ConvertOptions,
         progress: F,
     ) -> Result<ImageHandle> {
         info!(qcow = ?source, "Converting qcow image to goldboot image");
@@ -1315,23 +1394,48 @@ impl ImageHandle {
         let mut dest_file = File::create(&dest)?;
         let mut source_file = File::open(&source.path)?;
 
+        let timestamp = match options.timestamp {
+            Some(timestamp) => timestamp,
+            None => match source_date_epoch()? {
+                Some(timestamp) => timestamp,
+                None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            },
+        };
+
         // Prepare cipher and RNG if the image header should be encrypted
         let header_cipher = new_key(password.clone().unwrap_or("".to_string()));
-        let mut rng = rand::rng();
+        // Derived keys and nonces also depend on the disk's contents, so
+        // converting a different disk with the same options can't reuse a
+        // nonce under the same key
+        let (mut entropy, cluster_digests) = match options.nonce_key {
+            Some(key) => {
+                let mut table_digest = Sha256::new();
+                let mut cluster_digests = Vec::new();
+                let mut seen = HashSet::new();
+                for (block_offset, digest) in block_digests(source)? {
+                    table_digest.update(block_offset.to_be_bytes());
+                    table_digest.update(digest);
+                    if seen.insert(digest) {
+                        cluster_digests.push(digest);
+                    }
+                }
+                (
+                    Entropy::Derived {
+                        key,
+                        timestamp,
+                        content: table_digest.finalize().into(),
+                    },
+                    cluster_digests,
+                )
+            }
+            None => (Entropy::Random(rand::rng()), Vec::new()),
+        };
 
         // Prepare directory
         let mut directory = Directory {
-            protected_nonce: {
-                let mut b = [0u8; 12];
-                rng.fill_bytes(&mut b);
-                b
-            },
+            protected_nonce: entropy.bytes("protected", 0, &[]),
             protected_size: 0,
-            digest_table_nonce: {
-                let mut b = [0u8; 12];
-                rng.fill_bytes(&mut b);
-                b
-            },
+            digest_table_nonce: entropy.bytes("digest table", 0, &[]),
             digest_table_offset: 0,
             digest_table_size: 0,
         };
@@ -1342,17 +1446,13 @@ impl ImageHandle {
         // Prepare primary header (content_id starts as zeros and is patched
         // in after the cluster region is written).
         let mut primary_header = PrimaryHeader {
-            version: 2,
+            version: format_version(&metadata),
             arch: ImageArch::Amd64,   // TODO
             size: source.header.size, // TODO this is aligned to the cluster size?
-            directory_nonce: {
-                let mut b = [0u8; 12];
-                rng.fill_bytes(&mut b);
-                b
-            },
+            directory_nonce: entropy.bytes("directory", 0, &[]),
             directory_offset: 0,
             directory_size: 0,
-            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            timestamp,
             encryption_type: if password.is_some() {
                 HeaderEncryptionType::Aes256
             } else {
@@ -1378,17 +1478,14 @@ impl ImageHandle {
             } else {
                 ClusterEncryptionType::None
             },
-            cluster_key: {
-                let mut b = [0u8; 32];
-                rng.fill_bytes(&mut b);
-                b
-            },
+            cluster_key: entropy.bytes("cluster key", 0, &[]),
             nonce_table: if password.is_some() {
-                (0..cluster_count)
-                    .map(|_| {
-                        let mut b = [0u8; 12];
-                        rng.fill_bytes(&mut b);
-                        b
+                // Each nonce also covers the plaintext of the unique cluster
+                // it encrypts
+                (0..cluster_count as usize)
+                    .map(|i| {
+                        let digest = cluster_digests.get(i).map_or(&[][..], |d| &d[..]);
+                        entropy.bytes("cluster", i as u64, digest)
                     })
                     .collect()
             } else {
@@ -1600,6 +1697,107 @@ impl ImageHandle {
     }
 }
 
+/// Options for [`ImageHandle::from_qcow`] that make its output reproducible.
+/// By default the header timestamp is the current time and the cluster key
+/// and nonces are random, so two conversions of the same disk never produce
+/// the same file (or, when encrypted, the same `content_id`).
+#[derive(Clone, Debug, Default)]
+pub struct ConvertOptions {
+    /// Header timestamp in seconds since the epoch. Defaults to
+    /// `SOURCE_DATE_EPOCH` if it is set, or else the current time.
+    pub timestamp: Option<u64>,
+
+    /// Derive the cluster key and every nonce from this key, the timestamp
+    /// and the disk's contents instead of drawing them at random. Nonces also
+    /// cover the plaintext of their cluster, so the same key never encrypts
+    /// different data under the same nonce.
+    pub nonce_key: Option<[u8; 32]>,
+}
+
+/// The build timestamp from `SOURCE_DATE_EPOCH`, if it is set.
+/// See <https://reproducible-builds.org/specs/source-date-epoch/>.
+pub fn source_date_epoch() -> Result<Option<u64>> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) if !value.trim().is_empty() => Ok(Some(
+            value
+                .trim()
+                .parse()
+                .context("SOURCE_DATE_EPOCH is not a number of seconds")?,
+        )),
+        _ => Ok(None),
+    }
+}
+
+/// The digest of every allocated block of a qcow2 with its offset, in the
+/// order [`ImageHandle::from_qcow`] converts them.
+fn block_digests(source: &Qcow3) -> Result<Vec<(u64, [u8; 32])>> {
+    let mut source_file = File::open(&source.path)?;
+    let mut digests = Vec::new();
+    let mut block_offset: u64 = 0;
+
+    for l1_entry in &source.l1_table {
+        if let Some(l2_table) = l1_entry.read_l2(&mut source_file, source.header.cluster_bits) {
+            for l2_entry in l2_table {
+                if let Some(mut contents) = l2_entry.read_contents(
+                    &mut source_file,
+                    source.header.cluster_size(),
+                    source.header.compression_type,
+                )? {
+                    if block_offset + source.header.cluster_size() > source.header.size {
+                        contents.truncate((source.header.size - block_offset) as usize);
+                    }
+                    digests.push((
+                        block_offset,
+                        Sha256::new().chain_update(&contents).finalize().into(),
+                    ));
+                }
+                block_offset += source.header.cluster_size();
+            }
+        } else {
+            block_offset += source.header.cluster_size() * source.header.l2_entries_per_cluster();
+        }
+    }
+    Ok(digests)
+}
+
+/// Where the cluster key and nonces of a new image come from.
+enum Entropy {
+    Random(rand::rngs::ThreadRng),
+    /// `content` is a digest of the image's digest table.
+    Derived {
+        key: [u8; 32],
+        timestamp: u64,
+        content: [u8; 32],
+    },
+}
+
+impl Entropy {
+    /// Bytes for the given purpose, e.g. the nonce of cluster `index`.
+    /// Derived bytes also depend on `data`, e.g. the cluster's digest.
+    fn bytes<const N: usize>(&mut self, purpose: &str, index: u64, data: &[u8]) -> [u8; N] {
+        let mut b = [0u8; N];
+        match self {
+            Entropy::Random(rng) => rng.fill_bytes(&mut b),
+            Entropy::Derived {
+                key,
+                timestamp,
+                content,
+            } => {
+                let digest = Sha256::new()
+                    .chain_update(key)
+                    .chain_update(timestamp.to_be_bytes())
+                    .chain_update(content)
+                    .chain_update(purpose)
+                    .chain_update(index.to_be_bytes())
+                    .chain_update(data)
+                    .finalize();
+                b.copy_from_slice(&digest[..N]);
+            }
+        }
+        b
+    }
+}
+
 // ────────────────────────────────────────────────────────────────────────────
 // Test helpers
 // ────────────────────────────────────────────────────────────────────────────
@@ -1694,7 +1892,7 @@ pub(crate) mod test_support {
         // Build a placeholder primary header so we can compute its serialised
         // length (the cluster region starts immediately after).
         let mut primary_header = PrimaryHeader {
-            version: 2,
+            version: FORMAT_VERSION,
             size: total_size,
             timestamp: 0,
             encryption_type: HeaderEncryptionType::Aes256,
@@ -1896,6 +2094,44 @@ mod tests {
         Ok(())
     }
 
+    /// A partial manifest of an encrypted image can be completed with the
+    /// password by reading the sections the decrypted directory points at.
+    #[test]
+    fn partial_manifest_round_trip() -> Result<()> {
+        let dir = tempdir()?;
+        let img_path = dir.path().join("image.gb");
+        let blocks = vec![vec![0x44u8; 4096], vec![0x55u8; 4096]];
+        build_synthetic_image(&img_path, &blocks, 4096, "pw")?;
+
+        // What a registry without the password would serve
+        let mut blob = ImageHandle::open(&img_path)?.read_manifest_blob()?;
+        assert!(blob.is_partial());
+        assert!(parse_manifest(&blob, Some("pw".to_string())).is_err());
+        assert!(parse_manifest_directory(&blob, Some("wrong".to_string())).is_err());
+
+        let (_, directory) = parse_manifest_directory(&blob, Some("pw".to_string()))?;
+        let file = std::fs::read(&img_path)?;
+        let protected_start = blob.primary_bytes.len();
+        blob.protected_bytes =
+            file[protected_start..protected_start + directory.protected_size as usize].to_vec();
+        let digest_start = directory.digest_table_offset as usize;
+        blob.digest_table_bytes =
+            file[digest_start..digest_start + directory.digest_table_size as usize].to_vec();
+
+        let mut handle = ImageHandle::open(&img_path)?;
+        handle.load(Some("pw".to_string()))?;
+        let complete = handle.read_manifest_blob()?;
+        assert_eq!(blob.protected_bytes, complete.protected_bytes);
+        assert_eq!(blob.digest_table_bytes, complete.digest_table_bytes);
+
+        let (_, protected, _, digest, cluster_start) =
+            parse_manifest(&blob, Some("pw".to_string()))?;
+        assert_eq!(protected.block_size, 4096);
+        assert_eq!(digest.digest_count, 2);
+        assert_eq!(cluster_start, handle.cluster_region_bounds()?.0);
+        Ok(())
+    }
+
     /// stream_write must fail cleanly on a bogus oversized cluster size
     /// (cap protects against allocation DoS from a malicious server).
     #[test]
@@ -2022,6 +2258,67 @@ mod tests {
         assert!(r.validate().is_err());
     }
 
+    /// Version 2 headers have no element parent and still parse.
+    #[test]
+    fn read_version_2_primary_header() -> Result<()> {
+        let header = PrimaryHeader {
+            version: FORMAT_VERSION,
+            size: 4096,
+            timestamp: 0,
+            encryption_type: HeaderEncryptionType::None,
+            element_count: 1,
+            elements: vec![ElementHeader::new("Nix", "Nix")?.with_parent([7u8; 32])],
+            arch: ImageArch::Amd64,
+            name_length: 4,
+            name: b"test".to_vec(),
+            tag_length: 2,
+            tag: b"v1".to_vec(),
+            content_id: [0u8; 32],
+            directory_nonce: [0u8; 12],
+            directory_offset: 0,
+            directory_size: 0,
+        };
+        let mut bytes = Cursor::new(Vec::new());
+        header.write(&mut bytes)?;
+        let bytes = bytes.into_inner();
+
+        let parsed = PrimaryHeader::read_from_bytes(&bytes)?;
+        assert_eq!(
+            parsed.elements[0].parent_hex(),
+            Some(hex::encode([7u8; 32]))
+        );
+
+        // Downgrade: drop the parent that follows the element's name
+        let parent_start = 4 + 1 + 8 + 8 + 1 + 1 + (1 + 3) + (1 + 3);
+        let mut v2 = bytes.clone();
+        v2[4] = 2;
+        v2.drain(parent_start..parent_start + 32);
+
+        let parsed = PrimaryHeader::read_from_bytes(&v2)?;
+        assert_eq!(parsed.elements[0].os(), "Nix");
+        assert_eq!(parsed.elements[0].parent_hex(), None);
+        assert_eq!(parsed.name_str(), "test");
+
+        // Writing the parsed header back yields the same version 2 bytes
+        let mut rewritten = Cursor::new(Vec::new());
+        parsed.write(&mut rewritten)?;
+        assert_eq!(rewritten.into_inner(), v2);
+        Ok(())
+    }
+
+    /// Images are only written as version 3 when an element has a parent.
+    #[test]
+    fn format_version_depends_on_parents() -> Result<()> {
+        let scratch = ElementHeader::new("Nix", "Nix")?;
+        assert_eq!(format_version(&[scratch.clone()]), 2);
+        assert_eq!(
+            format_version(&[scratch.clone(), scratch.with_parent([7u8; 32])]),
+            FORMAT_VERSION
+        );
+        assert_eq!(format_version(&[]), 2);
+        Ok(())
+    }
+
     /// `PrimaryHeader.content_id` (populated during build) must match the
     /// independently-recomputed SHA256 over the cluster region bytes. This
     /// is the invariant the registry server relies on: it can trust the
@@ -2085,4 +2382,69 @@ mod tests {
         );
         Ok(())
     }
+
+    /// With a nonce key and a fixed timestamp, converting the same qcow
+    /// twice gives byte-identical encrypted images.
+    #[test]
+    fn from_qcow_is_reproducible_with_nonce_key() -> Result<()> {
+        let dir = tempdir()?;
+        let qcow = Qcow3::open("test/small.qcow2")?;
+        let convert = |name: &str, options: &ConvertOptions| {
+            let path = dir.path().join(name);
+            ImageHandle::from_qcow(
+                "test",
+                "v1",
+                vec![],
+                &qcow,
+                &path,
+                Some("pw".to_string()),
+                options,
+                |_, _| {},
+            )?;
+            Ok::<_, anyhow::Error>(std::fs::read(path)?)
+        };
+
+        let options = ConvertOptions {
+            timestamp: Some(1_700_000_000),
+            nonce_key: Some([7u8; 32]),
+        };
+        assert_eq!(convert("a.gb", &options)?, convert("b.gb", &options)?);
+
+        let random = ConvertOptions {
+            timestamp: Some(1_700_000_000),
+            nonce_key: None,
+        };
+        assert_ne!(convert("c.gb", &random)?, convert("d.gb", &random)?);
+        Ok(())
+    }
+
+    /// Different disks converted with the same nonce key and timestamp get
+    /// different cluster keys and nonces.
+    #[test]
+    fn nonce_key_does_not_reuse_nonces_across_disks() -> Result<()> {
+        let dir = tempdir()?;
+        let options = ConvertOptions {
+            timestamp: Some(1_700_000_000),
+            nonce_key: Some([7u8; 32]),
+        };
+        let convert = |qcow: &str| {
+            let handle = ImageHandle::from_qcow(
+                "test",
+                "v1",
+                vec![],
+                &Qcow3::open(qcow)?,
+                dir.path().join("image.gb"),
+                Some("pw".to_string()),
+                &options,
+                |_, _| {},
+            )?;
+            Ok::<_, anyhow::Error>(handle.protected_header.unwrap())
+        };
+
+        let small = convert("test/small.qcow2")?;
+        let sparse = convert("test/sparse.qcow2")?;
+        assert_ne!(small.cluster_key, sparse.cluster_key);
+        assert_ne!(small.nonce_table[0], sparse.nonce_table[0]);
+        Ok(())
+    }
 }
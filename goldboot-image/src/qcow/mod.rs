@@ -118,6 +118,30 @@ impl Qcow3 {
         self.snapshots.iter().find(|s| s.name == name)
     }
 
+    /// Take an offline snapshot (disk only, no VM state) with the given name.
+    /// The qcow must not be attached to a running VM.
+    pub fn create_snapshot(&self, name: &str) -> Result<()> {
+        self.qemu_img_snapshot("-c", name)
+    }
+
+    /// Delete the snapshot with the given name.
+    pub fn delete_snapshot(&self, name: &str) -> Result<()> {
+        self.qemu_img_snapshot("-d", name)
+    }
+
+    fn qemu_img_snapshot(&self, op: &str, name: &str) -> Result<()> {
+        debug!(path = %self.path, op, name, "Running qemu-img snapshot");
+        let status = Command::new("qemu-img")
+            .args(["snapshot", op, name, &self.path])
+            .stdout(Stdio::null())
+            .status()?;
+
+        if !status.success() {
+            bail!("qemu-img snapshot {op} {name} failed");
+        }
+        Ok(())
+    }
+
     /// Revert the image to the snapshot with the given name.
     ///
     /// This updates the active L1 table pointer and size in the image header to
@@ -270,6 +294,21 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn create_and_delete_snapshot() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("disk.qcow2");
+        Qcow3::create(&path, 1024 * 1024)?.create_snapshot("install")?;
+
+        let qcow = Qcow3::open(&path)?;
+        let snapshot = qcow.snapshot_by_name("install").unwrap();
+        assert!(!snapshot.has_vm_state());
+
+        qcow.delete_snapshot("install")?;
+        assert!(Qcow3::open(&path)?.snapshots.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn count_clusters_zlib_compressed() -> Result<()> {
         // compressed_zlib.qcow2 has 64 allocated compressed clusters (full 4 MiB)
@@ -96,6 +96,12 @@ pub struct SnapshotTime {
 }
 
 impl Snapshot {
+    /// Whether the snapshot saved the running VM (a live snapshot) rather than
+    /// just the disk.
+    pub fn has_vm_state(&self) -> bool {
+        self.extra_data.vm_state_size > 0 || self.vm_state_size > 0
+    }
+
     fn read_l1(&self, reader: &mut (impl Read + Seek)) -> Option<Vec<L1Entry>> {
         use binrw::BinReaderExt;
         reader.seek(SeekFrom::Start(self.l1_table_offset)).ok()?;
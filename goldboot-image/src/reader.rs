@@ -0,0 +1,203 @@
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce, aead::Aead};
+use anyhow::{Result, bail};
+use binrw::BinReaderExt;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+};
+
+use crate::{
+    Cluster, ClusterCompressionType, ClusterEncryptionType, ImageHandle, ProtectedHeader,
+    build_cluster_ordinal_map,
+};
+
+/// A streaming reader over the virtual disk contents of a loaded
+/// [`ImageHandle`].
+///
+/// Blocks without a cluster are read as all-zeros. The most recently decoded
+/// block is cached, so sequential reads decode each cluster once.
+pub struct ImageReader<'a> {
+    image: &'a ImageHandle,
+    protected_header: &'a ProtectedHeader,
+    file: BufReader<File>,
+    cipher: Aes256Gcm,
+
+    /// Digest table entry (cluster offset) for each populated block index
+    blocks: HashMap<u64, u64>,
+
+    /// Nonce table index for each cluster offset
+    cluster_ordinal: HashMap<u64, usize>,
+
+    cached: Option<(u64, Vec<u8>)>,
+    pos: u64,
+}
+
+impl ImageHandle {
+    /// Open a reader over the image's virtual disk. The image must be
+    /// [loaded](Self::load) first.
+    pub fn reader(&self) -> Result<ImageReader<'_>> {
+        let (Some(protected_header), Some(digest_table)) =
+            (self.protected_header.as_ref(), self.digest_table.as_ref())
+        else {
+            bail!("Image not loaded");
+        };
+
+        let block_size = protected_header.block_size as u64;
+        Ok(ImageReader {
+            image: self,
+            protected_header,
+            file: BufReader::new(File::open(&self.path)?),
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&protected_header.cluster_key)),
+            blocks: digest_table
+                .digest_table
+                .iter()
+                .map(|entry| (entry.block_offset / block_size, entry.cluster_offset))
+                .collect(),
+            cluster_ordinal: build_cluster_ordinal_map(&digest_table.digest_table),
+            cached: None,
+            pos: 0,
+        })
+    }
+}
+
+impl ImageReader<'_> {
+    /// The size of the virtual disk in bytes.
+    pub fn size(&self) -> u64 {
+        self.image.primary_header.size
+    }
+
+    /// Whether the block containing `offset` has a cluster. Unpopulated
+    /// blocks are zero, so copies can skip them to stay sparse.
+    pub fn is_populated(&self, offset: u64) -> bool {
+        self.blocks
+            .contains_key(&(offset / self.protected_header.block_size as u64))
+    }
+
+    fn decode_block(&mut self, block: u64, cluster_offset: u64) -> Result<()> {
+        if self.cached.as_ref().is_some_and(|(b, _)| *b == block) {
+            return Ok(());
+        }
+
+        self.file.seek(SeekFrom::Start(cluster_offset))?;
+        let mut cluster: Cluster = self.file.read_be()?;
+
+        cluster.data = match self.protected_header.cluster_encryption {
+            ClusterEncryptionType::None => cluster.data,
+            ClusterEncryptionType::Aes256 => {
+                let nonce_idx = *self
+                    .cluster_ordinal
+                    .get(&cluster_offset)
+                    .ok_or_else(|| anyhow::anyhow!("missing cluster ordinal"))?;
+                self.cipher
+                    .decrypt(
+                        Nonce::from_slice(&self.protected_header.nonce_table[nonce_idx]),
+                        cluster.data.as_ref(),
+                    )
+                    .map_err(|e| anyhow::anyhow!("decryption failed: {e}"))?
+            }
+        };
+
+        cluster.data = match self.protected_header.cluster_compression {
+            ClusterCompressionType::None => cluster.data,
+            ClusterCompressionType::Zstd => zstd::decode_all(std::io::Cursor::new(&cluster.data))?,
+        };
+
+        self.cached = Some((block, cluster.data));
+        Ok(())
+    }
+}
+
+impl Read for ImageReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.size();
+        if self.pos >= size {
+            return Ok(0);
+        }
+
+        let block_size = self.protected_header.block_size as u64;
+        let block = self.pos / block_size;
+        let offset_in_block = (self.pos % block_size) as usize;
+
+        let available = (block_size as usize - offset_in_block)
+            .min((size - self.pos) as usize)
+            .min(buf.len());
+
+        match self.blocks.get(&block).copied() {
+            Some(cluster_offset) => {
+                self.decode_block(block, cluster_offset)
+                    .map_err(std::io::Error::other)?;
+                let data = &self.cached.as_ref().unwrap().1;
+
+                // The last block of a disk may be stored short
+                let end = (offset_in_block + available).min(data.len());
+                let copied = end.saturating_sub(offset_in_block);
+                buf[..copied].copy_from_slice(&data[offset_in_block..end]);
+                buf[copied..available].fill(0);
+            }
+            None => buf[..available].fill(0),
+        }
+
+        self.pos += available as u64;
+        Ok(available)
+    }
+}
+
+impl Seek for ImageReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let size = self.size() as i64;
+        let new_pos = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::End(n) => size + n,
+            SeekFrom::Current(n) => self.pos as i64 + n,
+        };
+        if new_pos < 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before start",
+            ));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::build_synthetic_image;
+    use tempfile::tempdir;
+
+    #[test]
+    fn read_encrypted_image() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("image.gb");
+
+        let block_size = 4096;
+        let blocks = vec![
+            vec![0x11u8; block_size],
+            vec![0x22u8; block_size],
+            vec![0x11u8; block_size],
+        ];
+        build_synthetic_image(&path, &blocks, block_size as u32, "pw")?;
+
+        let mut image = ImageHandle::open(&path)?;
+        assert!(image.reader().is_err());
+        image.load(Some("pw".to_string()))?;
+
+        let mut reader = image.reader()?;
+        assert_eq!(reader.size(), 3 * block_size as u64);
+        assert!(reader.is_populated(2 * block_size as u64));
+
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents)?;
+        assert_eq!(contents, blocks.concat());
+
+        // Reads that straddle a block boundary
+        let mut buf = [0u8; 8];
+        reader.seek(SeekFrom::Start(block_size as u64 - 4))?;
+        reader.read_exact(&mut buf)?;
+        assert_eq!(buf, [0x11, 0x11, 0x11, 0x11, 0x22, 0x22, 0x22, 0x22]);
+        Ok(())
+    }
+}
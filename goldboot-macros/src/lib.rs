@@ -155,7 +155,8 @@ pub fn Os(args: TokenStream, input: TokenStream) -> TokenStream {
         _ => false,
     };
 
-    // Check whether the struct has named fields called `pre_steps`/`post_steps`
+    // Check whether the struct has named fields called `pre_steps`/`post_steps`/
+    // `offline_steps`/`base_image`
     let has_named_field = |name: &str| match &input.data {
         syn::Data::Struct(data) => match &data.fields {
             syn::Fields::Named(fields) => fields
@@ -168,6 +169,8 @@ pub fn Os(args: TokenStream, input: TokenStream) -> TokenStream {
     };
     let has_pre_steps_field = has_named_field("pre_steps");
     let has_post_steps_field = has_named_field("post_steps");
+    let has_offline_steps_field = has_named_field("offline_steps");
+    let has_base_image_field = has_named_field("base_image");
 
     let arch_impls: Vec<TokenStream2> = os_args
         .architectures
@@ -228,6 +231,55 @@ pub fn Os(args: TokenStream, input: TokenStream) -> TokenStream {
         quote! {}
     };
 
+    let offline_steps_impl = if has_offline_steps_field {
+        quote! {
+            fn offline_steps(&self) -> &[crate::builder::offline::OfflineStep] {
+                &self.offline_steps
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let base_image_impl = if has_base_image_field {
+        quote! {
+            fn base_image(&self) -> Option<&crate::builder::options::base_image::BaseImage> {
+                Some(&self.base_image)
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    // Post-steps and offline steps only affect the checkpoints taken after the
+    // install, so they are left out of the install's cache key
+    let clear_post_steps = if has_post_steps_field {
+        quote! { install.post_steps.clear(); }
+    } else {
+        quote! {}
+    };
+    let clear_offline_steps = if has_offline_steps_field {
+        quote! { install.offline_steps.clear(); }
+    } else {
+        quote! {}
+    };
+    let serialize_install_ron_impl = if has_post_steps_field || has_offline_steps_field {
+        quote! {
+            fn serialize_install_ron(&self) -> anyhow::Result<String> {
+                let mut install = self.clone();
+                #clear_post_steps
+                #clear_offline_steps
+                Ok(ron::ser::to_string(&install)?)
+            }
+        }
+    } else {
+        quote! {
+            fn serialize_install_ron(&self) -> anyhow::Result<String> {
+                Ok(ron::ser::to_string(self)?)
+            }
+        }
+    };
+
     let os_alloy_impl = if os_args.alloy {
         quote! {
             fn os_alloy(&self) -> bool {
@@ -258,11 +310,17 @@ pub fn Os(args: TokenStream, input: TokenStream) -> TokenStream {
 
             #post_steps_impl
 
+            #offline_steps_impl
+
+            #base_image_impl
+
             #os_alloy_impl
 
             fn serialize_ron(&self, config: &ron::ser::PrettyConfig) -> anyhow::Result<String> {
                 Ok(ron::ser::to_string_pretty(self, config.clone())?)
             }
+
+            #serialize_install_ron_impl
         }
 
         inventory::submit! {
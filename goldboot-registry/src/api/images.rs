@@ -1,70 +1,70 @@
 //! Image endpoints: list, manifest, clusters (range-supported), push.
 
-use crate::{cmd::start::ServerConfig, storage::Storage};
+use crate::{
+    cmd::start::ServerConfig,
+    index::Index,
+    metrics::Metrics,
+    mirror::{Mirror, UpstreamNotFound},
+    quota::{Quota, QuotaExceeded},
+    storage::Storage,
+};
 use anyhow::Result;
 use axum::{
     Json,
     body::Body,
-    extract::{Path, Request},
+    extract::{Path, Query, Request},
     http::{HeaderMap, HeaderValue, StatusCode, header},
     response::{IntoResponse, Response},
 };
 use futures_util::TryStreamExt;
-use goldboot::registry::protocol::{ImageListResponse, MANIFEST_CONTENT_TYPE, RegistryImageEntry};
+use goldboot::registry::protocol::{
+    ErrorResponse, ImageListResponse, ImageQuery, MANIFEST_CONTENT_TYPE,
+};
 use goldboot_image::ImageHandle;
 use std::sync::Arc;
 use tokio::io::AsyncReadExt;
 use tokio_util::io::ReaderStream;
 use tracing::{info, warn};
 
-/// `GET /v1/images`
+/// `GET /v1/images` — lists images matching the optional [`ImageQuery`]
+/// parameters.
 pub async fn list(
     storage: axum::extract::Extension<Arc<Storage>>,
+    index: axum::extract::Extension<Arc<Index>>,
+    Query(query): Query<ImageQuery>,
 ) -> Result<Json<ImageListResponse>, StatusCode> {
     let storage = storage.0.clone();
-    let images = tokio::task::spawn_blocking(move || -> Result<Vec<RegistryImageEntry>> {
-        let mut out = Vec::new();
-        for (name, tag) in storage.list()? {
-            let path = storage.image_path(&name, &tag)?;
-            match ImageHandle::open(&path) {
-                Ok(h) => out.push(RegistryImageEntry {
-                    name,
-                    tag,
-                    size: h.primary_header.size,
-                    file_size: h.file_size,
-                    arch: h.primary_header.arch,
-                    timestamp: h.primary_header.timestamp,
-                    // `h.id` is the content_id hex string (cluster-region SHA256).
-                    id: h.id,
-                }),
-                Err(e) => warn!(error = ?e, path = %path.display(), "skipping unreadable image"),
-            }
-        }
-        Ok(out)
-    })
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-    .map_err(|e| {
-        warn!(error = ?e, "list failed");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-    Ok(Json(ImageListResponse { images }))
+    let index = index.0.clone();
+    let entries = tokio::task::spawn_blocking(move || index.entries(&storage))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            warn!(error = ?e, "list failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(query.apply(entries)))
 }
 
 /// `GET /v1/images/:name/tags/:tag/manifest`
 pub async fn manifest(
     storage: axum::extract::Extension<Arc<Storage>>,
+    mirror: axum::extract::Extension<Option<Arc<Mirror>>>,
+    index: axum::extract::Extension<Arc<Index>>,
+    quota: axum::extract::Extension<Arc<Quota>>,
     Path((name, tag)): Path<(String, String)>,
 ) -> Result<Response, StatusCode> {
     let storage = storage.0.clone();
+    let mirror = mirror.0.clone();
+    let index = index.0.clone();
+    let quota = quota.0.clone();
     let blob_bytes = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
-        let path = storage.image_path(&name, &tag)?;
-        let mut handle = ImageHandle::open(&path)?;
-        if handle.directory.is_none() {
-            handle.load(None).map_err(|_| {
-                anyhow::anyhow!("encrypted images are not yet supported by the registry")
-            })?;
+        if let Some(mirror) = &mirror {
+            mirror.ensure_local(&storage, &index, &quota, &name, &tag)?;
         }
+        let path = storage.image_path(&name, &tag)?;
+        // Encrypted images stay locked, which yields a partial manifest the
+        // client completes through the blob endpoint
+        let handle = ImageHandle::open(&path)?;
         let blob = handle.read_manifest_blob()?;
         Ok(blob.write_to())
     })
@@ -72,7 +72,7 @@ pub async fn manifest(
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
     .map_err(|e| {
         warn!(error = ?e, "manifest failed");
-        StatusCode::INTERNAL_SERVER_ERROR
+        lookup_status(&e, StatusCode::INTERNAL_SERVER_ERROR)
     })?;
 
     Ok((
@@ -86,15 +86,26 @@ pub async fn manifest(
 }
 
 /// `GET /v1/images/:name/tags/:tag/clusters` — streams the cluster region.
-/// Supports `Range:` for resume.
+/// Supports `Range:` for resume. Unavailable for encrypted images, whose
+/// cluster region can't be located without the password.
 pub async fn clusters(
     storage: axum::extract::Extension<Arc<Storage>>,
+    mirror: axum::extract::Extension<Option<Arc<Mirror>>>,
+    index: axum::extract::Extension<Arc<Index>>,
+    quota: axum::extract::Extension<Arc<Quota>>,
+    metrics: axum::extract::Extension<Arc<Metrics>>,
     Path((name, tag)): Path<(String, String)>,
     headers: HeaderMap,
 ) -> Result<Response, StatusCode> {
     let storage = storage.0.clone();
+    let mirror = mirror.0.clone();
+    let index = index.0.clone();
+    let quota = quota.0.clone();
     let (file_path, cluster_start, cluster_end) =
         tokio::task::spawn_blocking(move || -> Result<(std::path::PathBuf, u64, u64)> {
+            if let Some(mirror) = &mirror {
+                mirror.ensure_local(&storage, &index, &quota, &name, &tag)?;
+            }
             let path = storage.image_path(&name, &tag)?;
             let mut handle = ImageHandle::open(&path)?;
             if handle.directory.is_none() {
@@ -107,17 +118,64 @@ pub async fn clusters(
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
         .map_err(|e| {
             warn!(error = ?e, "clusters lookup failed");
-            StatusCode::NOT_FOUND
+            lookup_status(&e, StatusCode::NOT_FOUND)
+        })?;
+
+    serve_range(&file_path, cluster_start, cluster_end, &headers, &metrics).await
+}
+
+/// `GET /v1/images/:name/tags/:tag/blob` — streams the raw `.gb` file.
+/// Supports `Range:`. Clients of encrypted images use this to fetch the
+/// sections a partial manifest leaves out, and the cluster region.
+pub async fn blob(
+    storage: axum::extract::Extension<Arc<Storage>>,
+    mirror: axum::extract::Extension<Option<Arc<Mirror>>>,
+    index: axum::extract::Extension<Arc<Index>>,
+    quota: axum::extract::Extension<Arc<Quota>>,
+    metrics: axum::extract::Extension<Arc<Metrics>>,
+    Path((name, tag)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let storage = storage.0.clone();
+    let mirror = mirror.0.clone();
+    let index = index.0.clone();
+    let quota = quota.0.clone();
+    let (file_path, file_len) =
+        tokio::task::spawn_blocking(move || -> Result<(std::path::PathBuf, u64)> {
+            if let Some(mirror) = &mirror {
+                mirror.ensure_local(&storage, &index, &quota, &name, &tag)?;
+            }
+            let path = storage.image_path(&name, &tag)?;
+            let len = std::fs::metadata(&path)?.len();
+            Ok((path, len))
+        })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            warn!(error = ?e, "blob lookup failed");
+            lookup_status(&e, StatusCode::NOT_FOUND)
         })?;
 
-    let total_len = cluster_end - cluster_start;
+    serve_range(&file_path, 0, file_len, &headers, &metrics).await
+}
+
+/// Stream `[region_start, region_end)` of `file_path`, honouring a `Range:`
+/// header relative to the start of the region.
+async fn serve_range(
+    file_path: &std::path::Path,
+    region_start: u64,
+    region_end: u64,
+    headers: &HeaderMap,
+    metrics: &Arc<Metrics>,
+) -> Result<Response, StatusCode> {
+    let total_len = region_end - region_start;
     let (range_start, range_end_inclusive) =
-        parse_range_header(&headers, total_len).map_err(|_| StatusCode::RANGE_NOT_SATISFIABLE)?;
+        parse_range_header(headers, total_len).map_err(|_| StatusCode::RANGE_NOT_SATISFIABLE)?;
 
-    let absolute_start = cluster_start + range_start;
+    let absolute_start = region_start + range_start;
     let absolute_len = range_end_inclusive - range_start + 1;
 
-    let mut file = tokio::fs::File::open(&file_path)
+    let mut file = tokio::fs::File::open(file_path)
         .await
         .map_err(|_| StatusCode::NOT_FOUND)?;
     use tokio::io::AsyncSeekExt;
@@ -125,7 +183,13 @@ pub async fn clusters(
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let limited = file.take(absolute_len);
-    let stream = ReaderStream::new(limited);
+    let guard = metrics.stream_started();
+    let metrics = metrics.clone();
+    let stream = ReaderStream::new(limited).inspect_ok(move |chunk| {
+        // The guard lives as long as the stream, however the client leaves
+        let _guard = &guard;
+        metrics.add_cluster_bytes(chunk.len() as u64);
+    });
     let body = Body::from_stream(stream.map_err(std::io::Error::from));
 
     let mut resp = Response::new(body);
@@ -184,10 +248,14 @@ fn parse_range_header(headers: &HeaderMap, total_len: u64) -> Result<(u64, u64)>
 /// by the global RequestBodyLimitLayer applied during router construction.
 pub async fn push(
     storage: axum::extract::Extension<Arc<Storage>>,
+    index: axum::extract::Extension<Arc<Index>>,
+    quota: axum::extract::Extension<Arc<Quota>>,
     server_config: axum::extract::Extension<ServerConfig>,
+    metrics: axum::extract::Extension<Arc<Metrics>>,
     Path((name, tag)): Path<(String, String)>,
     req: Request,
-) -> Result<StatusCode, StatusCode> {
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    let start = std::time::Instant::now();
     let cap = server_config.0.max_upload_size;
     let body = req.into_body();
     let body_stream = body.into_data_stream();
@@ -196,6 +264,8 @@ pub async fn push(
 
     // Hop to a blocking task to write the file with the sync API.
     let storage_clone = storage.0.clone();
+    let index = index.0.clone();
+    let quota = quota.0.clone();
     let name_c = name.clone();
     let tag_c = tag.clone();
     let result = tokio::task::spawn_blocking(move || -> Result<u64> {
@@ -223,13 +293,25 @@ pub async fn push(
             anyhow::bail!("URL tag '{tag_c}' does not match image header tag '{header_tag}'");
         }
 
+        let _guard = quota.push_lock.lock().unwrap();
+        if !quota.is_unlimited() {
+            let entries = index.entries(&storage_clone)?;
+            quota.check(&entries, &name_c, &tag_c, buf.len() as u64)?;
+        }
+
         storage_clone.put(&name_c, &tag_c, Cursor::new(buf), None)
     })
     .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .map_err(|_| {
+        error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "push task failed".to_string(),
+        )
+    })?;
 
     match result {
         Ok(written) => {
+            metrics.observe_push(written, start.elapsed().as_secs_f64());
             info!(
                 event = "push.ok",
                 image = %name,
@@ -240,7 +322,122 @@ pub async fn push(
         }
         Err(e) => {
             warn!(error = ?e, "push failed");
-            Err(StatusCode::BAD_REQUEST)
+            let status = if e.is::<QuotaExceeded>() {
+                StatusCode::INSUFFICIENT_STORAGE
+            } else {
+                StatusCode::BAD_REQUEST
+            };
+            Err(error_response(status, format!("{e:#}")))
         }
     }
 }
+
+/// Status for a failed image lookup: a miss on the mirror's upstream is a
+/// 404 and a mirrored image that doesn't fit the quota a 507, like a push.
+fn lookup_status(e: &anyhow::Error, fallback: StatusCode) -> StatusCode {
+    if e.is::<UpstreamNotFound>() {
+        StatusCode::NOT_FOUND
+    } else if e.is::<QuotaExceeded>() {
+        StatusCode::INSUFFICIENT_STORAGE
+    } else {
+        fallback
+    }
+}
+
+fn error_response(status: StatusCode, message: String) -> (StatusCode, Json<ErrorResponse>) {
+    (status, Json(ErrorResponse { message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{build_image, build_image_with, spawn_registry};
+    use goldboot::registry::{Client, protocol::ImageQuery};
+    use tempfile::tempdir;
+
+    #[test]
+    fn list_applies_query_parameters() {
+        let dir = tempdir().unwrap();
+        let address = spawn_registry(dir.path(), None);
+        let client = Client::new(&address, None).unwrap();
+
+        let staging = tempdir().unwrap();
+        for (name, tag) in [("debian", "v1"), ("debian", "v2"), ("alpine", "v1")] {
+            let path = staging.path().join(format!("{name}-{tag}.gb"));
+            build_image(&path, name, tag, &[vec![0x11u8; 4096]]);
+            let len = std::fs::metadata(&path).unwrap().len();
+            client
+                .push_image(name, tag, std::fs::File::open(&path).unwrap(), len)
+                .unwrap();
+        }
+
+        let result = client
+            .search_images(&ImageQuery {
+                name_prefix: Some("deb".into()),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(result.total, 2);
+        assert_eq!(result.images.len(), 1);
+        assert_eq!(result.images[0].name, "debian");
+
+        let result = client
+            .search_images(&ImageQuery {
+                os: Some("test".into()),
+                since: Some(1_800_000_000),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(result.total, 0);
+
+        // No parameters still lists everything
+        assert_eq!(client.list_images().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn stream_write_encrypted_image() {
+        let dir = tempdir().unwrap();
+        let address = spawn_registry(dir.path(), None);
+        let client = Client::new(&address, None).unwrap();
+
+        let staging = tempdir().unwrap();
+        let image = staging.path().join("image.gb");
+        let blocks = vec![vec![0x5au8; 4096], vec![0xa5u8; 4096], vec![0x01u8; 4096]];
+        build_image_with(&image, "secret", "v1", &blocks, Some("hunter2"));
+        let len = std::fs::metadata(&image).unwrap().len();
+        client
+            .push_image("secret", "v1", std::fs::File::open(&image).unwrap(), len)
+            .unwrap();
+
+        let dest = staging.path().join("disk.raw");
+        std::fs::File::create(&dest).unwrap();
+
+        let err = client
+            .stream_write_to_dest("secret", "v1", None, &dest, |_, _| {})
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("password is required"),
+            "{err:#}"
+        );
+        assert!(
+            client
+                .stream_write_to_dest("secret", "v1", Some("wrong".into()), &dest, |_, _| {})
+                .is_err()
+        );
+
+        let (_, protected, digest) = client
+            .stream_write_to_dest("secret", "v1", Some("hunter2".into()), &dest, |_, _| {})
+            .unwrap();
+        assert_eq!(protected.cluster_count, 3);
+        assert_eq!(digest.digest_count, 3);
+        assert_eq!(std::fs::read(&dest).unwrap(), blocks.concat());
+
+        // Pulling needs no password; the file comes back verbatim
+        let pulled = staging.path().join("pulled.gb");
+        client.pull_to_file("secret", "v1", &pulled).unwrap();
+        assert_eq!(
+            std::fs::read(&pulled).unwrap(),
+            std::fs::read(&image).unwrap()
+        );
+    }
+}
@@ -0,0 +1,40 @@
+//! Prometheus scrape endpoint.
+
+use crate::{index::Index, metrics::Metrics, storage::Storage};
+use axum::{
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use std::{collections::BTreeMap, sync::Arc};
+use tracing::warn;
+
+/// `GET /metrics` — only routed when the server runs with `--metrics`.
+pub async fn scrape(
+    storage: axum::extract::Extension<Arc<Storage>>,
+    index: axum::extract::Extension<Arc<Index>>,
+    metrics: axum::extract::Extension<Arc<Metrics>>,
+) -> Result<Response, StatusCode> {
+    let storage = storage.0.clone();
+    let index = index.0.clone();
+    let entries = tokio::task::spawn_blocking(move || index.entries(&storage))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            warn!(error = ?e, "metrics storage scan failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut usage: BTreeMap<String, u64> = BTreeMap::new();
+    for entry in entries {
+        *usage.entry(entry.name).or_default() += entry.file_size;
+    }
+
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        )],
+        metrics.render(&usage),
+    )
+        .into_response())
+}
@@ -1 +1,3 @@
 pub mod images;
+pub mod metrics;
+pub mod usage;
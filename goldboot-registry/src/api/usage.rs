@@ -0,0 +1,26 @@
+//! Storage usage endpoint.
+
+use crate::{index::Index, quota::Quota, storage::Storage};
+use axum::{Json, http::StatusCode};
+use goldboot::registry::protocol::UsageResponse;
+use std::sync::Arc;
+use tracing::warn;
+
+/// `GET /v1/usage` — bytes and tags stored per image name, plus the
+/// configured quota limits.
+pub async fn usage(
+    storage: axum::extract::Extension<Arc<Storage>>,
+    index: axum::extract::Extension<Arc<Index>>,
+    quota: axum::extract::Extension<Arc<Quota>>,
+) -> Result<Json<UsageResponse>, StatusCode> {
+    let storage = storage.0.clone();
+    let index = index.0.clone();
+    let entries = tokio::task::spawn_blocking(move || index.entries(&storage))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            warn!(error = ?e, "usage failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(quota.usage(&entries)))
+}
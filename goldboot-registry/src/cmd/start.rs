@@ -4,14 +4,23 @@
 //! authentication. Operators are expected to put nginx (or another reverse
 //! proxy) in front of it to handle both. See the project README.
 
-use crate::{api, storage::Storage};
+use crate::{
+    api,
+    index::Index,
+    metrics::{self, Metrics},
+    mirror::Mirror,
+    quota::Quota,
+    replicate::Replicator,
+    storage::Storage,
+};
 use anyhow::{Context, Result, bail};
 use axum::{
-    Router,
+    Router, middleware,
     routing::{get, put},
 };
 use clap::Args;
-use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use goldboot::registry::protocol::QuotaLimits;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 use tower_http::{
     limit::RequestBodyLimitLayer, set_header::SetResponseHeaderLayer, trace::TraceLayer,
 };
@@ -19,6 +28,8 @@ use tracing::info;
 
 pub const DEFAULT_MAX_UPLOAD: u64 = 32 * 1024 * 1024 * 1024;
 
+pub const DEFAULT_REPLICATE_INTERVAL: u64 = 15 * 60;
+
 #[derive(Args, Debug)]
 pub struct StartArgs {
     /// Address to bind. Should usually be loopback when running behind a
@@ -33,25 +44,79 @@ pub struct StartArgs {
     /// Maximum upload size in bytes (default 32 GiB).
     #[clap(long, default_value_t = DEFAULT_MAX_UPLOAD)]
     pub max_upload_size: u64,
+
+    /// Run as a pull-through mirror of this upstream registry. Images that
+    /// aren't stored locally are fetched from the upstream on first request
+    /// and cached in the data directory.
+    #[clap(long)]
+    pub upstream: Option<String>,
+
+    /// HTTP Basic Auth username for the upstream registry
+    #[clap(long, requires = "upstream_password")]
+    pub upstream_username: Option<String>,
+
+    /// HTTP Basic Auth password for the upstream registry
+    #[clap(long, requires = "upstream_username")]
+    pub upstream_password: Option<String>,
+
+    /// Peer registry to push local images to on a schedule. May be given
+    /// more than once.
+    #[clap(long)]
+    pub replicate_to: Vec<String>,
+
+    /// Seconds between replication passes
+    #[clap(long, default_value_t = DEFAULT_REPLICATE_INTERVAL)]
+    pub replicate_interval: u64,
+
+    /// HTTP Basic Auth username for the replication peers
+    #[clap(long, requires = "peer_password")]
+    pub peer_username: Option<String>,
+
+    /// HTTP Basic Auth password for the replication peers
+    #[clap(long, requires = "peer_username")]
+    pub peer_password: Option<String>,
+
+    /// Expose Prometheus metrics on `GET /metrics`
+    #[clap(long, num_args = 0)]
+    pub metrics: bool,
+
+    /// Reject pushes that would take the total stored bytes over this limit
+    #[clap(long)]
+    pub max_total_bytes: Option<u64>,
+
+    /// Reject pushes that would give an image name more tags than this
+    #[clap(long)]
+    pub max_tags_per_name: Option<u64>,
+
+    /// Reject pushes that would take an image name's stored bytes (across
+    /// all of its tags) over this limit
+    #[clap(long)]
+    pub max_bytes_per_name: Option<u64>,
 }
 
 #[derive(Clone)]
 pub struct ServerConfig {
     pub max_upload_size: u64,
+
+    /// Whether `GET /metrics` is served.
+    pub metrics: bool,
+
+    /// Limits enforced on pushes.
+    pub quota: QuotaLimits,
 }
 
-pub async fn run(args: StartArgs) -> Result<()> {
-    let bind: SocketAddr = args
-        .bind
-        .parse()
-        .with_context(|| format!("invalid bind address '{}'", args.bind))?;
-    let storage = Arc::new(Storage::new(args.data_dir)?);
-    let server_config = ServerConfig {
-        max_upload_size: args.max_upload_size,
-    };
-    let max_upload = args.max_upload_size as usize;
+/// Build the HTTP API. `mirror` enables pull-through mode for the manifest
+/// and cluster endpoints.
+pub fn router(
+    storage: Arc<Storage>,
+    server_config: ServerConfig,
+    mirror: Option<Arc<Mirror>>,
+) -> Router {
+    let max_upload = server_config.max_upload_size as usize;
+    let metrics = Arc::new(Metrics::default());
+    let quota = Arc::new(Quota::new(server_config.quota.clone()));
 
-    let app: Router = Router::new()
+    let mut app = Router::new()
         .route("/v1/images", get(api::images::list))
         .route(
             "/v1/images/{name}/tags/{tag}/manifest",
@@ -61,15 +126,66 @@ pub async fn run(args: StartArgs) -> Result<()> {
             "/v1/images/{name}/tags/{tag}/clusters",
             get(api::images::clusters),
         )
+        .route("/v1/images/{name}/tags/{tag}/blob", get(api::images::blob))
         .route("/v1/images/{name}/tags/{tag}", put(api::images::push))
-        .layer(SetResponseHeaderLayer::overriding(
-            axum::http::header::SERVER,
-            axum::http::HeaderValue::from_static("goldboot-registry"),
+        .route("/v1/usage", get(api::usage::usage));
+    if server_config.metrics {
+        app = app.route("/metrics", get(api::metrics::scrape));
+    }
+
+    app.route_layer(middleware::from_fn_with_state(
+        metrics.clone(),
+        metrics::track,
+    ))
+    .layer(SetResponseHeaderLayer::overriding(
+        axum::http::header::SERVER,
+        axum::http::HeaderValue::from_static("goldboot-registry"),
+    ))
+    .layer(RequestBodyLimitLayer::new(max_upload))
+    .layer(TraceLayer::new_for_http())
+    .layer(axum::Extension(storage))
+    .layer(axum::Extension(Arc::new(Index::default())))
+    .layer(axum::Extension(metrics))
+    .layer(axum::Extension(quota))
+    .layer(axum::Extension(server_config))
+    .layer(axum::Extension(mirror))
+}
+
+pub async fn run(args: StartArgs) -> Result<()> {
+    let bind: SocketAddr = args
+        .bind
+        .parse()
+        .with_context(|| format!("invalid bind address '{}'", args.bind))?;
+    let storage = Arc::new(Storage::new(args.data_dir)?);
+    let server_config = ServerConfig {
+        max_upload_size: args.max_upload_size,
+        metrics: args.metrics,
+        quota: QuotaLimits {
+            max_total_bytes: args.max_total_bytes,
+            max_tags_per_name: args.max_tags_per_name,
+            max_bytes_per_name: args.max_bytes_per_name,
+        },
+    };
+
+    let mirror = args.upstream.map(|upstream| {
+        info!(upstream = %upstream, "Mirroring upstream registry");
+        Arc::new(Mirror::new(
+            upstream,
+            args.upstream_username.zip(args.upstream_password),
         ))
-        .layer(RequestBodyLimitLayer::new(max_upload))
-        .layer(TraceLayer::new_for_http())
-        .layer(axum::Extension(storage.clone()))
-        .layer(axum::Extension(server_config));
+    });
+
+    if !args.replicate_to.is_empty() {
+        let replicator = Replicator {
+            peers: args.replicate_to,
+            auth: args.peer_username.zip(args.peer_password),
+            interval: Duration::from_secs(args.replicate_interval.max(1)),
+        };
+        info!(peers = ?replicator.peers, interval = ?replicator.interval, "Replicating to peers");
+        tokio::spawn(replicator.run(storage.clone()));
+    }
+
+    let app = router(storage, server_config, mirror);
 
     info!(
         addr = %bind,
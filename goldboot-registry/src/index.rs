@@ -0,0 +1,125 @@
+//! Cached image metadata for the listing endpoint.
+//!
+//! Opening every `.gb` on each `GET /v1/images` gets slow as the data
+//! directory grows, so the parsed metadata for each name/tag pair is kept in
+//! memory and only re-read when the file's length or modification time
+//! changes. This keeps the cache correct no matter how an image arrived
+//! (push, mirror fetch or a file copied in by hand).
+
+use crate::storage::Storage;
+use anyhow::Result;
+use goldboot::registry::protocol::RegistryImageEntry;
+use goldboot_image::ImageHandle;
+use std::{collections::HashMap, sync::Mutex, time::SystemTime};
+use tracing::warn;
+
+#[derive(Default)]
+pub struct Index {
+    entries: Mutex<HashMap<(String, String), Cached>>,
+}
+
+struct Cached {
+    len: u64,
+    modified: Option<SystemTime>,
+    entry: RegistryImageEntry,
+}
+
+impl Index {
+    /// Every readable image in `storage`, in listing order. Stale or new
+    /// entries are refreshed and removed images are dropped from the cache.
+    /// Blocks, so call it from `spawn_blocking`.
+    pub fn entries(&self, storage: &Storage) -> Result<Vec<RegistryImageEntry>> {
+        let listing = storage.list()?;
+        let mut cache = self.entries.lock().unwrap();
+        cache.retain(|key, _| listing.binary_search(key).is_ok());
+
+        let mut out = Vec::with_capacity(listing.len());
+        for (name, tag) in listing {
+            let path = storage.image_path(&name, &tag)?;
+            let Ok(metadata) = std::fs::metadata(&path) else {
+                // Deleted since the directory was listed
+                continue;
+            };
+            let len = metadata.len();
+            let modified = metadata.modified().ok();
+
+            let key = (name, tag);
+            if let Some(cached) = cache.get(&key) {
+                if cached.len == len && cached.modified == modified {
+                    out.push(cached.entry.clone());
+                    continue;
+                }
+            }
+
+            match ImageHandle::open(&path) {
+                Ok(h) => {
+                    let entry = RegistryImageEntry {
+                        name: key.0.clone(),
+                        tag: key.1.clone(),
+                        size: h.primary_header.size,
+                        file_size: h.file_size,
+                        arch: h.primary_header.arch,
+                        timestamp: h.primary_header.timestamp,
+                        // `h.id` is the content_id hex string (cluster-region SHA256).
+                        id: h.id,
+                        os: h.primary_header.elements.iter().map(|e| e.os()).collect(),
+                    };
+                    out.push(entry.clone());
+                    cache.insert(
+                        key,
+                        Cached {
+                            len,
+                            modified,
+                            entry,
+                        },
+                    );
+                }
+                Err(e) => {
+                    warn!(error = ?e, path = %path.display(), "skipping unreadable image");
+                    cache.remove(&key);
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::build_image;
+    use tempfile::tempdir;
+
+    #[test]
+    fn refreshes_changed_and_removed_images() {
+        let dir = tempdir().unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let staging = tempdir().unwrap();
+        let index = Index::default();
+
+        let push = |tag: &str, blocks: &[Vec<u8>]| {
+            let path = staging.path().join(format!("{tag}.gb"));
+            build_image(&path, "alpine", tag, blocks);
+            storage
+                .put("alpine", tag, std::fs::File::open(&path).unwrap(), None)
+                .unwrap();
+        };
+
+        push("v1", &[vec![1u8; 4096]]);
+        push("v2", &[vec![2u8; 4096]]);
+        let first = index.entries(&storage).unwrap();
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0].os, vec!["Test".to_string()]);
+
+        // Overwriting a tag with different content is picked up
+        push("v1", &[vec![3u8; 4096], vec![4u8; 4096]]);
+        let second = index.entries(&storage).unwrap();
+        assert_ne!(second[0].id, first[0].id);
+        assert_eq!(second[0].size, 8192);
+
+        std::fs::remove_file(storage.image_path("alpine", "v2").unwrap()).unwrap();
+        let third = index.entries(&storage).unwrap();
+        assert_eq!(third.len(), 1);
+        assert_eq!(index.entries.lock().unwrap().len(), 1);
+    }
+}
@@ -2,7 +2,14 @@ use clap::Parser;
 
 mod api;
 mod cmd;
+mod index;
+mod metrics;
+mod mirror;
+mod quota;
+mod replicate;
 mod storage;
+#[cfg(test)]
+mod test_support;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -19,6 +26,54 @@ struct CommandLine {
     /// Maximum upload size in bytes (default 32 GiB).
     #[clap(long, default_value_t = cmd::start::DEFAULT_MAX_UPLOAD)]
     pub max_upload_size: u64,
+
+    /// Run as a pull-through mirror of this upstream registry. Images that
+    /// aren't stored locally are fetched from the upstream on first request
+    /// and cached in the data directory.
+    #[clap(long)]
+    pub upstream: Option<String>,
+
+    /// HTTP Basic Auth username for the upstream registry
+    #[clap(long, requires = "upstream_password")]
+    pub upstream_username: Option<String>,
+
+    /// HTTP Basic Auth password for the upstream registry
+    #[clap(long, requires = "upstream_username")]
+    pub upstream_password: Option<String>,
+
+    /// Peer registry to push local images to on a schedule. May be given
+    /// more than once.
+    #[clap(long)]
+    pub replicate_to: Vec<String>,
+
+    /// Seconds between replication passes
+    #[clap(long, default_value_t = cmd::start::DEFAULT_REPLICATE_INTERVAL)]
+    pub replicate_interval: u64,
+
+    /// HTTP Basic Auth username for the replication peers
+    #[clap(long, requires = "peer_password")]
+    pub peer_username: Option<String>,
+
+    /// HTTP Basic Auth password for the replication peers
+    #[clap(long, requires = "peer_username")]
+    pub peer_password: Option<String>,
+
+    /// Expose Prometheus metrics on `GET /metrics`
+    #[clap(long, num_args = 0)]
+    pub metrics: bool,
+
+    /// Reject pushes that would take the total stored bytes over this limit
+    #[clap(long)]
+    pub max_total_bytes: Option<u64>,
+
+    /// Reject pushes that would give an image name more tags than this
+    #[clap(long)]
+    pub max_tags_per_name: Option<u64>,
+
+    /// Reject pushes that would take an image name's stored bytes (across
+    /// all of its tags) over this limit
+    #[clap(long)]
+    pub max_bytes_per_name: Option<u64>,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -36,6 +91,17 @@ fn main() -> anyhow::Result<()> {
         bind: cli.bind,
         data_dir: cli.data_dir,
         max_upload_size: cli.max_upload_size,
+        upstream: cli.upstream,
+        upstream_username: cli.upstream_username,
+        upstream_password: cli.upstream_password,
+        replicate_to: cli.replicate_to,
+        replicate_interval: cli.replicate_interval,
+        peer_username: cli.peer_username,
+        peer_password: cli.peer_password,
+        metrics: cli.metrics,
+        max_total_bytes: cli.max_total_bytes,
+        max_tags_per_name: cli.max_tags_per_name,
+        max_bytes_per_name: cli.max_bytes_per_name,
     }))?;
 
     Ok(())
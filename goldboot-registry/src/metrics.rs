@@ -0,0 +1,358 @@
+//! Prometheus metrics.
+//!
+//! Counters are always collected (they're cheap) but only exposed on
+//! `GET /metrics` when the server is started with `--metrics`. The text
+//! exposition format is simple enough that it's rendered by hand here rather
+//! than pulling in a metrics crate.
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+/// Upper bounds (seconds) for request latency buckets.
+const LATENCY_BOUNDS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+/// Upper bounds (bytes) for push size buckets.
+const PUSH_SIZE_BOUNDS: &[f64] = &[
+    1048576.0,
+    16777216.0,
+    134217728.0,
+    1073741824.0,
+    4294967296.0,
+    17179869184.0,
+    68719476736.0,
+];
+
+/// Upper bounds (seconds) for push duration buckets.
+const PUSH_DURATION_BOUNDS: &[f64] = &[1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0];
+
+#[derive(Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Cumulative count per bound, like the exposition format.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter_mut()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let (bucket_labels, own_labels) = if labels.is_empty() {
+            (String::new(), String::new())
+        } else {
+            (format!("{labels},"), format!("{{{labels}}}"))
+        };
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{bucket_labels}le=\"{bound}\"}} {bucket}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{bucket_labels}le=\"+Inf\"}} {}",
+            self.count
+        );
+        let _ = writeln!(out, "{name}_sum{own_labels} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{own_labels} {}", self.count);
+    }
+}
+
+struct Inner {
+    /// Request count by (route, method, status).
+    requests: BTreeMap<(String, String, u16), u64>,
+    /// Request latency by (route, method).
+    latency: BTreeMap<(String, String), Histogram>,
+    push_size: Histogram,
+    push_duration: Histogram,
+}
+
+pub struct Metrics {
+    inner: Mutex<Inner>,
+    cluster_bytes: AtomicU64,
+    active_streams: AtomicI64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                requests: BTreeMap::new(),
+                latency: BTreeMap::new(),
+                push_size: Histogram::new(PUSH_SIZE_BOUNDS),
+                push_duration: Histogram::new(PUSH_DURATION_BOUNDS),
+            }),
+            cluster_bytes: AtomicU64::new(0),
+            active_streams: AtomicI64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    fn observe_request(&self, route: &str, method: &str, status: u16, seconds: f64) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .requests
+            .entry((route.to_string(), method.to_string(), status))
+            .or_default() += 1;
+        inner
+            .latency
+            .entry((route.to_string(), method.to_string()))
+            .or_insert_with(|| Histogram::new(LATENCY_BOUNDS))
+            .observe(seconds);
+    }
+
+    /// Record a successful push of `bytes` that took `seconds`.
+    pub fn observe_push(&self, bytes: u64, seconds: f64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.push_size.observe(bytes as f64);
+        inner.push_duration.observe(seconds);
+    }
+
+    /// Count bytes streamed from the clusters or blob endpoint.
+    pub fn add_cluster_bytes(&self, bytes: u64) {
+        self.cluster_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Mark a clusters/blob stream as active until the returned guard is
+    /// dropped.
+    pub fn stream_started(self: &Arc<Self>) -> StreamGuard {
+        self.active_streams.fetch_add(1, Ordering::Relaxed);
+        StreamGuard(self.clone())
+    }
+
+    /// Render everything in the Prometheus text format. `storage` is the
+    /// on-disk size of each image name.
+    pub fn render(&self, storage: &BTreeMap<String, u64>) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP goldboot_registry_http_requests_total HTTP requests handled.\n");
+        out.push_str("# TYPE goldboot_registry_http_requests_total counter\n");
+        for ((route, method, status), count) in &inner.requests {
+            let _ = writeln!(
+                out,
+                "goldboot_registry_http_requests_total{{route=\"{}\",method=\"{method}\",status=\"{status}\"}} {count}",
+                escape(route)
+            );
+        }
+
+        out.push_str(
+            "# HELP goldboot_registry_http_request_duration_seconds HTTP request latency.\n",
+        );
+        out.push_str("# TYPE goldboot_registry_http_request_duration_seconds histogram\n");
+        for ((route, method), histogram) in &inner.latency {
+            histogram.render(
+                &mut out,
+                "goldboot_registry_http_request_duration_seconds",
+                &format!("route=\"{}\",method=\"{method}\"", escape(route)),
+            );
+        }
+
+        out.push_str("# HELP goldboot_registry_cluster_bytes_served_total Bytes streamed from the clusters and blob endpoints.\n");
+        out.push_str("# TYPE goldboot_registry_cluster_bytes_served_total counter\n");
+        let _ = writeln!(
+            out,
+            "goldboot_registry_cluster_bytes_served_total {}",
+            self.cluster_bytes.load(Ordering::Relaxed)
+        );
+
+        out.push_str(
+            "# HELP goldboot_registry_active_streams Cluster streams currently in progress.\n",
+        );
+        out.push_str("# TYPE goldboot_registry_active_streams gauge\n");
+        let _ = writeln!(
+            out,
+            "goldboot_registry_active_streams {}",
+            self.active_streams.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP goldboot_registry_push_size_bytes Size of accepted pushes.\n");
+        out.push_str("# TYPE goldboot_registry_push_size_bytes histogram\n");
+        inner
+            .push_size
+            .render(&mut out, "goldboot_registry_push_size_bytes", "");
+
+        out.push_str(
+            "# HELP goldboot_registry_push_duration_seconds Duration of accepted pushes.\n",
+        );
+        out.push_str("# TYPE goldboot_registry_push_duration_seconds histogram\n");
+        inner
+            .push_duration
+            .render(&mut out, "goldboot_registry_push_duration_seconds", "");
+
+        out.push_str(
+            "# HELP goldboot_registry_storage_bytes On-disk size of all tags of an image name.\n",
+        );
+        out.push_str("# TYPE goldboot_registry_storage_bytes gauge\n");
+        for (name, bytes) in storage {
+            let _ = writeln!(
+                out,
+                "goldboot_registry_storage_bytes{{name=\"{}\"}} {bytes}",
+                escape(name)
+            );
+        }
+
+        out
+    }
+}
+
+/// Decrements the active stream gauge when dropped.
+pub struct StreamGuard(Arc<Metrics>);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.active_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Escape a label value for the text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Middleware recording the count and latency of every routed request,
+/// labelled by route template rather than concrete path so image names
+/// don't explode the label cardinality.
+pub async fn track(State(metrics): State<Arc<Metrics>>, req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = req.method().to_string();
+
+    let start = Instant::now();
+    let response = next.run(req).await;
+    metrics.observe_request(
+        &route,
+        &method,
+        response.status().as_u16(),
+        start.elapsed().as_secs_f64(),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{build_image, spawn_registry};
+    use goldboot::registry::Client;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn scrape(address: &str) -> String {
+        reqwest::blocking::get(format!("{address}/metrics"))
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .text()
+            .unwrap()
+    }
+
+    /// Value of the first sample whose line starts with `prefix`.
+    fn sample(body: &str, prefix: &str) -> Option<f64> {
+        body.lines()
+            .find(|l| l.starts_with(prefix))
+            .and_then(|l| l.rsplit(' ').next())
+            .and_then(|v| v.parse().ok())
+    }
+
+    #[test]
+    fn scrape_after_push_and_pull() {
+        let dir = tempdir().unwrap();
+        let address = spawn_registry(dir.path(), None);
+        let client = Client::new(&address, None).unwrap();
+
+        let staging = tempdir().unwrap();
+        let image = staging.path().join("image.gb");
+        build_image(
+            &image,
+            "alpine",
+            "v1",
+            &[vec![0x33u8; 4096], vec![0x44u8; 4096]],
+        );
+        let len = std::fs::metadata(&image).unwrap().len();
+        client
+            .push_image("alpine", "v1", std::fs::File::open(&image).unwrap(), len)
+            .unwrap();
+        client
+            .pull_to_file("alpine", "v1", &staging.path().join("pulled.gb"))
+            .unwrap();
+
+        let body = scrape(&address);
+        assert_eq!(
+            sample(
+                &body,
+                "goldboot_registry_http_requests_total{route=\"/v1/images/{name}/tags/{tag}\",method=\"PUT\",status=\"201\"}"
+            ),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample(
+                &body,
+                "goldboot_registry_http_requests_total{route=\"/v1/images/{name}/tags/{tag}/clusters\",method=\"GET\",status=\"200\"}"
+            ),
+            Some(1.0)
+        );
+        assert!(sample(&body, "goldboot_registry_cluster_bytes_served_total").unwrap() > 0.0);
+        assert_eq!(
+            sample(&body, "goldboot_registry_push_size_bytes_count"),
+            Some(1.0)
+        );
+        assert_eq!(
+            sample(&body, "goldboot_registry_push_size_bytes_sum"),
+            Some(len as f64)
+        );
+        assert_eq!(
+            sample(&body, "goldboot_registry_storage_bytes{name=\"alpine\"}"),
+            Some(len as f64)
+        );
+
+        // The stream guard drops once the server finishes sending, which can
+        // trail the client by a moment
+        let mut active = None;
+        for _ in 0..50 {
+            active = sample(&scrape(&address), "goldboot_registry_active_streams ");
+            if active == Some(0.0) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(active, Some(0.0));
+    }
+}
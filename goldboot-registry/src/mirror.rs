@@ -0,0 +1,298 @@
+//! Pull-through mirror mode.
+//!
+//! When an upstream registry is configured, requests for a name/tag pair
+//! that isn't stored locally are satisfied by pulling the image from the
+//! upstream with the regular [`Client`], caching it in [`Storage`] and
+//! serving it from there on. Cached images are never refreshed: tags are
+//! expected to be immutable once pushed. Fetched images count against the
+//! storage quota exactly like pushed ones.
+
+use crate::{index::Index, quota::Quota, storage::Storage};
+use anyhow::{Result, bail};
+use goldboot::registry::{Client, protocol::ImageQuery};
+use goldboot_image::ImageHandle;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+
+/// The upstream doesn't have the requested name/tag either.
+#[derive(Debug)]
+pub struct UpstreamNotFound(pub String);
+
+impl fmt::Display for UpstreamNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UpstreamNotFound {}
+
+type InFlight = Mutex<HashMap<(String, String), Arc<Mutex<()>>>>;
+
+/// Drops a name/tag's entry from the in-flight map once its last waiter is
+/// done, whether or not the fetch succeeded.
+struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+    key: (String, String),
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // Waiters clone the lock while holding the map, so the map's own
+        // reference plus ours means nobody else is queued
+        if in_flight
+            .get(&self.key)
+            .is_some_and(|lock| Arc::strong_count(lock) <= 2)
+        {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+pub struct Mirror {
+    /// Address of the upstream registry (anything `registry_root` accepts).
+    upstream: String,
+
+    /// Optional Basic Auth credentials for the upstream.
+    auth: Option<(String, String)>,
+
+    /// One lock per name/tag so concurrent requests for the same missing
+    /// image trigger a single upstream fetch.
+    in_flight: InFlight,
+}
+
+impl Mirror {
+    pub fn new(upstream: String, auth: Option<(String, String)>) -> Self {
+        Self {
+            upstream,
+            auth,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn upstream(&self) -> &str {
+        &self.upstream
+    }
+
+    /// Make sure `name`/`tag` exists in `storage`, fetching it from the
+    /// upstream first when it doesn't. Blocks, so call it from
+    /// `spawn_blocking`.
+    pub fn ensure_local(
+        &self,
+        storage: &Storage,
+        index: &Index,
+        quota: &Quota,
+        name: &str,
+        tag: &str,
+    ) -> Result<()> {
+        if storage.exists(name, tag)? {
+            return Ok(());
+        }
+
+        let key = (name.to_string(), tag.to_string());
+        let lock = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let _in_flight = InFlightGuard {
+            in_flight: &self.in_flight,
+            key,
+        };
+        let _guard = lock.lock().unwrap();
+
+        // Another request may have finished the fetch while we waited
+        if !storage.exists(name, tag)? {
+            self.fetch(storage, index, quota, name, tag)?;
+        }
+        Ok(())
+    }
+
+    fn fetch(
+        &self,
+        storage: &Storage,
+        index: &Index,
+        quota: &Quota,
+        name: &str,
+        tag: &str,
+    ) -> Result<()> {
+        let client = Client::new(&self.upstream, self.auth.clone())?;
+
+        // Look the image up first so a missing one is reported as such and
+        // the quota is checked before anything is downloaded
+        let Some(entry) = client
+            .search_images(&ImageQuery {
+                name_prefix: Some(name.to_string()),
+                ..Default::default()
+            })?
+            .images
+            .into_iter()
+            .find(|entry| entry.name == name && entry.tag == tag)
+        else {
+            return Err(UpstreamNotFound(format!("'{name}:{tag}' not found upstream")).into());
+        };
+        if !quota.is_unlimited() {
+            let _guard = quota.push_lock.lock().unwrap();
+            quota.check(&index.entries(storage)?, name, tag, entry.file_size)?;
+        }
+
+        info!(upstream = %self.upstream, image = %name, tag = %tag, "Fetching image from upstream");
+
+        let staged = storage.staging_path();
+        let result = client
+            .pull_to_file(name, tag, &staged)
+            .and_then(|_| verify_identity(&staged, name, tag))
+            .and_then(|_| {
+                // The upstream listing may be stale, so check again with the
+                // real size before the image becomes visible
+                let _guard = quota.push_lock.lock().unwrap();
+                if !quota.is_unlimited() {
+                    let size = std::fs::metadata(&staged)?.len();
+                    quota.check(&index.entries(storage)?, name, tag, size)?;
+                }
+                storage.adopt(name, tag, &staged)
+            });
+
+        match result {
+            Ok(bytes) => {
+                info!(
+                    event = "mirror.ok",
+                    image = %name,
+                    tag = %tag,
+                    bytes
+                );
+                Ok(())
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(&staged);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Refuse to cache an upstream image whose header disagrees with the
+/// requested name/tag, exactly like a mismatched push is refused.
+fn verify_identity(path: &std::path::Path, name: &str, tag: &str) -> Result<()> {
+    let handle = ImageHandle::open(path)?;
+    let header_name = handle.primary_header.name_str();
+    let header_tag = handle.primary_header.tag_str();
+    if header_name != name || header_tag != tag {
+        bail!("upstream served '{header_name}:{header_tag}' when asked for '{name}:{tag}'");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cmd::start::{DEFAULT_MAX_UPLOAD, ServerConfig},
+        test_support::{build_image, spawn_registry, spawn_registry_with},
+    };
+    use goldboot::registry::protocol::QuotaLimits;
+    use tempfile::tempdir;
+
+    /// Push a small `name:tag` image to the registry at `address`.
+    fn push(address: &str, staging: &std::path::Path, name: &str, tag: &str) -> Vec<u8> {
+        let image = staging.join(format!("{name}-{tag}.gb"));
+        build_image(&image, name, tag, &[vec![0x5Au8; 4096], vec![0xA5u8; 4096]]);
+        let len = std::fs::metadata(&image).unwrap().len();
+        Client::new(address, None)
+            .unwrap()
+            .push_image(name, tag, std::fs::File::open(&image).unwrap(), len)
+            .unwrap();
+        std::fs::read(&image).unwrap()
+    }
+
+    #[test]
+    fn fetches_unknown_images_from_upstream() {
+        let upstream_dir = tempdir().unwrap();
+        let upstream = spawn_registry(upstream_dir.path(), None);
+
+        let staging = tempdir().unwrap();
+        let image = push(&upstream, staging.path(), "alpine", "v1");
+
+        let mirror_dir = tempdir().unwrap();
+        let mirror = spawn_registry(mirror_dir.path(), Some(upstream.clone()));
+
+        // Nothing is cached before the first request
+        let client = Client::new(&mirror, None).unwrap();
+        assert!(client.list_images().unwrap().is_empty());
+
+        let pulled = staging.path().join("pulled.gb");
+        client.pull_to_file("alpine", "v1", &pulled).unwrap();
+        assert_eq!(std::fs::read(&pulled).unwrap(), image);
+
+        // Now served from the local cache
+        let images = client.list_images().unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].name, "alpine");
+        assert!(mirror_dir.path().join("alpine/v1.gb").is_file());
+
+        // Unknown upstream images are reported as missing
+        let err = client
+            .fetch_manifest("alpine", "missing", None)
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("404"), "{err:#}");
+    }
+
+    #[test]
+    fn forgets_failed_fetches() {
+        let upstream_dir = tempdir().unwrap();
+        let upstream = spawn_registry(upstream_dir.path(), None);
+
+        let mirror_dir = tempdir().unwrap();
+        let storage = Storage::new(mirror_dir.path()).unwrap();
+        let mirror = Mirror::new(upstream, None);
+
+        for _ in 0..3 {
+            let err = mirror
+                .ensure_local(
+                    &storage,
+                    &Index::default(),
+                    &Quota::default(),
+                    "alpine",
+                    "v1",
+                )
+                .unwrap_err();
+            assert!(err.is::<UpstreamNotFound>(), "{err:#}");
+        }
+        assert!(mirror.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn mirrored_images_count_against_the_quota() {
+        let upstream_dir = tempdir().unwrap();
+        let upstream = spawn_registry(upstream_dir.path(), None);
+        let staging = tempdir().unwrap();
+        push(&upstream, staging.path(), "alpine", "v1");
+
+        let mirror_dir = tempdir().unwrap();
+        let mirror = spawn_registry_with(
+            mirror_dir.path(),
+            Some(upstream),
+            ServerConfig {
+                max_upload_size: DEFAULT_MAX_UPLOAD,
+                metrics: false,
+                quota: QuotaLimits {
+                    max_total_bytes: Some(1),
+                    ..Default::default()
+                },
+            },
+        );
+
+        let err = Client::new(&mirror, None)
+            .unwrap()
+            .fetch_manifest("alpine", "v1", None)
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("507"), "{err:#}");
+        assert!(!mirror_dir.path().join("alpine/v1.gb").exists());
+    }
+}
@@ -0,0 +1,221 @@
+//! Storage quotas enforced on push.
+//!
+//! Usage is derived from the [`Index`](crate::index::Index) listing, so it
+//! always reflects what's actually on disk. Overwriting an existing tag only
+//! counts the difference between the old and new file.
+
+use goldboot::registry::protocol::{NameUsage, QuotaLimits, RegistryImageEntry, UsageResponse};
+use std::{collections::BTreeMap, fmt, sync::Mutex};
+
+/// A push that would take the registry over one of its limits. The message
+/// is returned to the client verbatim.
+#[derive(Debug)]
+pub struct QuotaExceeded(pub String);
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+#[derive(Default)]
+pub struct Quota {
+    pub limits: QuotaLimits,
+
+    /// Held from the quota check until the pushed file is in place, so
+    /// concurrent pushes can't both squeeze under the same limit.
+    pub push_lock: Mutex<()>,
+}
+
+impl Quota {
+    pub fn new(limits: QuotaLimits) -> Self {
+        Self {
+            limits,
+            push_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.limits == QuotaLimits::default()
+    }
+
+    /// Check whether storing `incoming` bytes as `name`/`tag` keeps usage
+    /// within every limit, given the currently stored `entries`.
+    pub fn check(
+        &self,
+        entries: &[RegistryImageEntry],
+        name: &str,
+        tag: &str,
+        incoming: u64,
+    ) -> Result<(), QuotaExceeded> {
+        let others = || entries.iter().filter(|e| !(e.name == name && e.tag == tag));
+
+        if let Some(max) = self.limits.max_total_bytes {
+            let total = others().map(|e| e.file_size).sum::<u64>() + incoming;
+            if total > max {
+                return Err(QuotaExceeded(format!(
+                    "quota exceeded: registry would store {total} bytes, the limit is {max} bytes"
+                )));
+            }
+        }
+
+        if let Some(max) = self.limits.max_bytes_per_name {
+            let total = others()
+                .filter(|e| e.name == name)
+                .map(|e| e.file_size)
+                .sum::<u64>()
+                + incoming;
+            if total > max {
+                return Err(QuotaExceeded(format!(
+                    "quota exceeded: image '{name}' would use {total} bytes, the limit per name is {max} bytes"
+                )));
+            }
+        }
+
+        if let Some(max) = self.limits.max_tags_per_name {
+            let tags = others().filter(|e| e.name == name).count() as u64 + 1;
+            if tags > max {
+                return Err(QuotaExceeded(format!(
+                    "quota exceeded: image '{name}' would have {tags} tags, the limit per name is {max}; delete old tags first"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Summarize `entries` for `GET /v1/usage`.
+    pub fn usage(&self, entries: &[RegistryImageEntry]) -> UsageResponse {
+        let mut names: BTreeMap<&str, NameUsage> = BTreeMap::new();
+        for entry in entries {
+            let usage = names.entry(&entry.name).or_insert_with(|| NameUsage {
+                name: entry.name.clone(),
+                tags: 0,
+                bytes: 0,
+            });
+            usage.tags += 1;
+            usage.bytes += entry.file_size;
+        }
+
+        UsageResponse {
+            total_bytes: entries.iter().map(|e| e.file_size).sum(),
+            limits: self.limits.clone(),
+            names: names.into_values().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cmd::start::{DEFAULT_MAX_UPLOAD, ServerConfig},
+        test_support::{build_image, spawn_registry_with},
+    };
+    use goldboot::registry::Client;
+    use goldboot_image::ImageArch;
+    use tempfile::tempdir;
+
+    fn entry(name: &str, tag: &str, file_size: u64) -> RegistryImageEntry {
+        RegistryImageEntry {
+            name: name.to_string(),
+            tag: tag.to_string(),
+            size: 0,
+            file_size,
+            arch: ImageArch::Amd64,
+            timestamp: 0,
+            id: String::new(),
+            os: vec![],
+        }
+    }
+
+    #[test]
+    fn enforces_each_limit() {
+        let entries = vec![
+            entry("ci", "1", 100),
+            entry("ci", "2", 100),
+            entry("base", "v1", 300),
+        ];
+
+        let quota = Quota::new(QuotaLimits {
+            max_total_bytes: Some(600),
+            ..Default::default()
+        });
+        assert!(quota.check(&entries, "ci", "3", 100).is_ok());
+        assert!(quota.check(&entries, "ci", "3", 101).is_err());
+        // Overwrites only count the new file
+        assert!(quota.check(&entries, "base", "v1", 400).is_ok());
+
+        let quota = Quota::new(QuotaLimits {
+            max_bytes_per_name: Some(250),
+            ..Default::default()
+        });
+        assert!(quota.check(&entries, "ci", "3", 50).is_ok());
+        assert!(quota.check(&entries, "ci", "3", 51).is_err());
+
+        let quota = Quota::new(QuotaLimits {
+            max_tags_per_name: Some(2),
+            ..Default::default()
+        });
+        let err = quota.check(&entries, "ci", "3", 1).unwrap_err();
+        assert!(err.to_string().contains("'ci' would have 3 tags"));
+        assert!(quota.check(&entries, "ci", "2", 1).is_ok());
+        assert!(quota.check(&entries, "base", "v2", 1).is_ok());
+    }
+
+    #[test]
+    fn usage_groups_by_name() {
+        let entries = vec![
+            entry("ci", "1", 100),
+            entry("ci", "2", 50),
+            entry("base", "v1", 300),
+        ];
+        let usage = Quota::default().usage(&entries);
+        assert_eq!(usage.total_bytes, 450);
+        assert_eq!(usage.names.len(), 2);
+        assert_eq!(usage.names[0].name, "base");
+        assert_eq!(usage.names[1].tags, 2);
+        assert_eq!(usage.names[1].bytes, 150);
+    }
+
+    #[test]
+    fn rejects_pushes_over_quota() {
+        let dir = tempdir().unwrap();
+        let address = spawn_registry_with(
+            dir.path(),
+            None,
+            ServerConfig {
+                max_upload_size: DEFAULT_MAX_UPLOAD,
+                metrics: false,
+                quota: QuotaLimits {
+                    max_tags_per_name: Some(2),
+                    ..Default::default()
+                },
+            },
+        );
+        let client = Client::new(&address, None).unwrap();
+
+        let staging = tempdir().unwrap();
+        let push = |tag: &str| {
+            let path = staging.path().join(format!("{tag}.gb"));
+            build_image(&path, "nightly", tag, &[vec![0x77u8; 4096]]);
+            let len = std::fs::metadata(&path).unwrap().len();
+            client.push_image("nightly", tag, std::fs::File::open(&path).unwrap(), len)
+        };
+
+        push("1").unwrap();
+        push("2").unwrap();
+        let err = push("3").unwrap_err();
+        assert!(format!("{err:#}").contains("would have 3 tags"), "{err:#}");
+        // Re-pushing an existing tag doesn't add one
+        push("2").unwrap();
+
+        let usage = client.usage().unwrap();
+        assert_eq!(usage.limits.max_tags_per_name, Some(2));
+        assert_eq!(usage.names.len(), 1);
+        assert_eq!(usage.names[0].tags, 2);
+        assert_eq!(usage.total_bytes, usage.names[0].bytes);
+    }
+}
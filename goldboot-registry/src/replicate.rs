@@ -0,0 +1,158 @@
+//! Scheduled push replication to peer registries.
+//!
+//! On every tick, each peer's image listing is compared against the local
+//! data directory and any name/tag pair the peer is missing (or holds with a
+//! different content ID) is pushed with the regular [`Client`]. Replication
+//! only ever adds or overwrites; it never deletes from a peer.
+
+use crate::storage::Storage;
+use anyhow::Result;
+use goldboot::registry::Client;
+use goldboot_image::ImageHandle;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+pub struct Replicator {
+    /// Addresses of the peer registries to push to.
+    pub peers: Vec<String>,
+
+    /// Optional Basic Auth credentials shared by all peers.
+    pub auth: Option<(String, String)>,
+
+    /// Time between replication passes.
+    pub interval: Duration,
+}
+
+impl Replicator {
+    /// Replicate forever, one pass per `interval`. The first pass runs
+    /// immediately.
+    pub async fn run(self, storage: Arc<Storage>) {
+        let this = Arc::new(self);
+        let mut ticker = tokio::time::interval(this.interval);
+        loop {
+            ticker.tick().await;
+            let this = this.clone();
+            let storage = storage.clone();
+            match tokio::task::spawn_blocking(move || this.replicate_once(&storage)).await {
+                Ok(pushed) => {
+                    if pushed > 0 {
+                        info!(event = "replicate.ok", pushed, "Replication pass finished");
+                    }
+                }
+                Err(e) => warn!(error = ?e, "replication pass panicked"),
+            }
+        }
+    }
+
+    /// Run a single replication pass against every peer. Failures are
+    /// logged per peer (and per image) so one unreachable peer doesn't stall
+    /// the others. Returns the number of images pushed.
+    pub fn replicate_once(&self, storage: &Storage) -> usize {
+        let local = match local_images(storage) {
+            Ok(local) => local,
+            Err(e) => {
+                warn!(error = ?e, "failed to list local images for replication");
+                return 0;
+            }
+        };
+
+        let mut pushed = 0;
+        for peer in &self.peers {
+            match self.replicate_to(storage, peer, &local) {
+                Ok(n) => pushed += n,
+                Err(e) => warn!(peer = %peer, error = ?e, "replication to peer failed"),
+            }
+        }
+        pushed
+    }
+
+    fn replicate_to(
+        &self,
+        storage: &Storage,
+        peer: &str,
+        local: &[(String, String, String)],
+    ) -> Result<usize> {
+        let client = Client::new(peer, self.auth.clone())?;
+        let remote: HashMap<(String, String), String> = client
+            .list_images()?
+            .into_iter()
+            .map(|e| ((e.name, e.tag), e.id))
+            .collect();
+
+        let mut pushed = 0;
+        for (name, tag, id) in local {
+            if remote.get(&(name.clone(), tag.clone())) == Some(id) {
+                continue;
+            }
+
+            let (file, len) = storage.open(name, tag)?;
+            match client.push_image(name, tag, file, len) {
+                Ok(()) => {
+                    info!(
+                        event = "replicate.push",
+                        peer = %peer,
+                        image = %name,
+                        tag = %tag,
+                        bytes = len
+                    );
+                    pushed += 1;
+                }
+                Err(e) => {
+                    warn!(peer = %peer, image = %name, tag = %tag, error = ?e, "push to peer failed")
+                }
+            }
+        }
+        Ok(pushed)
+    }
+}
+
+/// Every locally stored image as `(name, tag, content_id)`.
+fn local_images(storage: &Storage) -> Result<Vec<(String, String, String)>> {
+    let mut out = Vec::new();
+    for (name, tag) in storage.list()? {
+        let path = storage.image_path(&name, &tag)?;
+        match ImageHandle::open(&path) {
+            Ok(h) => out.push((name, tag, h.id)),
+            Err(e) => warn!(error = ?e, path = %path.display(), "skipping unreadable image"),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{build_image, spawn_registry};
+    use tempfile::tempdir;
+
+    #[test]
+    fn pushes_missing_images_to_peers() {
+        let source_dir = tempdir().unwrap();
+        let source = Storage::new(source_dir.path()).unwrap();
+        let staging = tempdir().unwrap();
+        for tag in ["v1", "v2"] {
+            let path = staging.path().join(format!("{tag}.gb"));
+            build_image(&path, "debian", tag, &[vec![0x42u8; 4096]]);
+            source
+                .put("debian", tag, std::fs::File::open(&path).unwrap(), None)
+                .unwrap();
+        }
+
+        let peer_dir = tempdir().unwrap();
+        let peer = spawn_registry(peer_dir.path(), None);
+
+        let replicator = Replicator {
+            peers: vec![peer.clone()],
+            auth: None,
+            interval: Duration::from_secs(60),
+        };
+        assert_eq!(replicator.replicate_once(&source), 2);
+
+        let images = Client::new(&peer, None).unwrap().list_images().unwrap();
+        let tags: Vec<&str> = images.iter().map(|e| e.tag.as_str()).collect();
+        assert_eq!(tags, vec!["v1", "v2"]);
+
+        // A second pass has nothing left to do
+        assert_eq!(replicator.replicate_once(&source), 0);
+    }
+}
@@ -7,6 +7,7 @@
 
 use anyhow::{Context, Result};
 use goldboot_image::validate_ref_segment as validate_component;
+use rand::RngExt;
 use std::{
     fs,
     io::{Read, Write},
@@ -38,6 +39,37 @@ impl Storage {
         Ok(self.data_dir.join(name).join(format!("{tag}.gb")))
     }
 
+    /// Whether an image is currently stored under `name`/`tag`.
+    pub fn exists(&self, name: &str, tag: &str) -> Result<bool> {
+        Ok(self.image_path(name, tag)?.is_file())
+    }
+
+    /// Return a fresh path inside the data directory for staging a
+    /// download before it is moved into place with [`Storage::adopt`].
+    /// Staging files live at the top level, which [`Storage::list`] never
+    /// descends into.
+    pub fn staging_path(&self) -> PathBuf {
+        let id: String = rand::rng()
+            .sample_iter(&rand::distr::Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect();
+        self.data_dir.join(format!(".staging-{id}.gb"))
+    }
+
+    /// Atomically move an already-complete image file (typically from
+    /// [`Storage::staging_path`]) into place. Returns its size in bytes.
+    pub fn adopt(&self, name: &str, tag: &str, staged: &Path) -> Result<u64> {
+        let final_path = self.image_path(name, tag)?;
+        if let Some(parent) = final_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let len = fs::metadata(staged)?.len();
+        fs::rename(staged, &final_path)
+            .with_context(|| format!("move {} into place", staged.display()))?;
+        Ok(len)
+    }
+
     /// Open an existing image for reading. Returns the file handle and
     /// its on-disk length.
     pub fn open(&self, name: &str, tag: &str) -> Result<(fs::File, u64)> {
@@ -166,4 +198,21 @@ mod tests {
         let listing = s.list().unwrap();
         assert_eq!(listing, vec![("img".into(), "tag".into())]);
     }
+
+    #[test]
+    fn adopt_moves_staged_file_into_place() {
+        let dir = tempdir().unwrap();
+        let s = Storage::new(dir.path()).unwrap();
+        let staged = s.staging_path();
+        fs::write(&staged, b"mirrored").unwrap();
+        assert!(!s.exists("img", "tag").unwrap());
+
+        assert_eq!(s.adopt("img", "tag", &staged).unwrap(), 8);
+        assert!(s.exists("img", "tag").unwrap());
+        assert!(!staged.exists());
+
+        // Staging files never show up as hosted images
+        fs::write(s.staging_path(), b"partial").unwrap();
+        assert_eq!(s.list().unwrap(), vec![("img".into(), "tag".into())]);
+    }
 }
@@ -0,0 +1,220 @@
+//! Helpers for tests that exercise the registry end-to-end: building small
+//! synthetic images without qemu-img and running in-process servers on
+//! free ports.
+
+use crate::{
+    cmd::start::{DEFAULT_MAX_UPLOAD, ServerConfig, router},
+    mirror::Mirror,
+    storage::Storage,
+};
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce, aead::Aead};
+use binrw::BinWrite;
+use goldboot_image::{
+    Cluster, ClusterCompressionType, ClusterEncryptionType, DigestTable, DigestTableEntry,
+    Directory, ElementHeader, FORMAT_VERSION, HeaderEncryptionType, ImageArch, PrimaryHeader,
+    ProtectedHeader,
+};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::{
+    io::{Cursor, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Arc,
+};
+
+/// Write an unencrypted, zstd-compressed `.gb` named `name:tag` holding the
+/// given blocks (all of the same size). Duplicate blocks are not
+/// deduplicated; every block gets its own cluster.
+pub fn build_image(path: &Path, name: &str, tag: &str, blocks: &[Vec<u8>]) {
+    build_image_with(path, name, tag, blocks, None);
+}
+
+/// Like [`build_image`], optionally encrypting the headers and clusters with
+/// `password`.
+pub fn build_image_with(
+    path: &Path,
+    name: &str,
+    tag: &str,
+    blocks: &[Vec<u8>],
+    password: Option<&str>,
+) {
+    let nonce = || {
+        let mut b = [0u8; 12];
+        rand::rng().fill_bytes(&mut b);
+        b
+    };
+
+    let header_cipher = password.map(|password| {
+        let key: [u8; 32] = Sha256::digest(password.as_bytes()).into();
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+    });
+    let seal = |nonce: &[u8; 12], plain: Vec<u8>| match &header_cipher {
+        Some(cipher) => cipher
+            .encrypt(Nonce::from_slice(nonce), plain.as_slice())
+            .unwrap(),
+        None => plain,
+    };
+
+    let block_size = blocks[0].len() as u32;
+    let mut primary = PrimaryHeader {
+        version: FORMAT_VERSION,
+        size: blocks.iter().map(|b| b.len() as u64).sum(),
+        timestamp: 1_700_000_000,
+        encryption_type: if password.is_some() {
+            HeaderEncryptionType::Aes256
+        } else {
+            HeaderEncryptionType::None
+        },
+        element_count: 1,
+        elements: vec![ElementHeader::new("Test", "test").unwrap()],
+        arch: ImageArch::Amd64,
+        name_length: name.len() as u8,
+        name: name.as_bytes().to_vec(),
+        tag_length: tag.len() as u8,
+        tag: tag.as_bytes().to_vec(),
+        content_id: [0u8; 32],
+        directory_nonce: if password.is_some() {
+            nonce()
+        } else {
+            [0u8; 12]
+        },
+        directory_offset: 0,
+        directory_size: 0,
+    };
+    let protected = if password.is_some() {
+        let mut cluster_key = [0u8; 32];
+        rand::rng().fill_bytes(&mut cluster_key);
+        ProtectedHeader {
+            block_size,
+            cluster_count: blocks.len() as u32,
+            cluster_compression: ClusterCompressionType::Zstd,
+            cluster_encryption: ClusterEncryptionType::Aes256,
+            nonce_table: blocks.iter().map(|_| nonce()).collect(),
+            cluster_key,
+        }
+    } else {
+        ProtectedHeader {
+            block_size,
+            cluster_count: blocks.len() as u32,
+            cluster_compression: ClusterCompressionType::Zstd,
+            cluster_encryption: ClusterEncryptionType::None,
+            nonce_table: vec![],
+            cluster_key: [0u8; 32],
+        }
+    };
+    let cluster_cipher =
+        password.map(|_| Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&protected.cluster_key)));
+
+    let (protected_nonce, digest_table_nonce) = if password.is_some() {
+        (nonce(), nonce())
+    } else {
+        ([0u8; 12], [0u8; 12])
+    };
+
+    let mut out = Cursor::new(Vec::new());
+    primary.write_be(&mut out).unwrap();
+    let mut plain = Cursor::new(Vec::new());
+    protected.write_be(&mut plain).unwrap();
+    let protected_bytes = seal(&protected_nonce, plain.into_inner());
+    out.write_all(&protected_bytes).unwrap();
+
+    let mut digest_table = DigestTable {
+        digest_count: blocks.len() as u32,
+        digest_table: vec![],
+    };
+    let mut content = Sha256::new();
+    for (i, block) in blocks.iter().enumerate() {
+        let cluster_offset = out.position();
+        let mut data = zstd::encode_all(block.as_slice(), 0).unwrap();
+        if let Some(cipher) = &cluster_cipher {
+            data = cipher
+                .encrypt(
+                    Nonce::from_slice(&protected.nonce_table[i]),
+                    data.as_slice(),
+                )
+                .unwrap();
+        }
+        let cluster = Cluster {
+            size: data.len() as u32,
+            data,
+        };
+        cluster.write_be(&mut out).unwrap();
+        content.update(cluster.size.to_be_bytes());
+        content.update(&cluster.data);
+        digest_table.digest_table.push(DigestTableEntry {
+            cluster_offset,
+            block_offset: i as u64 * block_size as u64,
+            digest: Sha256::digest(block).into(),
+        });
+    }
+    primary.content_id = content.finalize().into();
+
+    let digest_table_offset = out.position();
+    let mut plain = Cursor::new(Vec::new());
+    digest_table.write_be(&mut plain).unwrap();
+    let digest_table_bytes = seal(&digest_table_nonce, plain.into_inner());
+    out.write_all(&digest_table_bytes).unwrap();
+    let directory = Directory {
+        protected_nonce,
+        protected_size: protected_bytes.len() as u32,
+        digest_table_nonce,
+        digest_table_offset,
+        digest_table_size: digest_table_bytes.len() as u32,
+    };
+
+    primary.directory_offset = out.position();
+    let mut plain = Cursor::new(Vec::new());
+    directory.write_be(&mut plain).unwrap();
+    let directory_bytes = seal(&primary.directory_nonce, plain.into_inner());
+    out.write_all(&directory_bytes).unwrap();
+    primary.directory_size = directory_bytes.len() as u32;
+
+    out.seek(SeekFrom::Start(0)).unwrap();
+    primary.write_be(&mut out).unwrap();
+
+    std::fs::File::create(path)
+        .unwrap()
+        .write_all(&out.into_inner())
+        .unwrap();
+}
+
+/// Serve a registry over `data_dir` on a free loopback port from a
+/// background thread, optionally mirroring `upstream`. Returns its address
+/// in the form the client expects (`http://127.0.0.1:<port>`).
+pub fn spawn_registry(data_dir: &Path, upstream: Option<String>) -> String {
+    spawn_registry_with(
+        data_dir,
+        upstream,
+        ServerConfig {
+            max_upload_size: DEFAULT_MAX_UPLOAD,
+            metrics: true,
+            quota: Default::default(),
+        },
+    )
+}
+
+/// Like [`spawn_registry`], with an explicit server configuration.
+pub fn spawn_registry_with(
+    data_dir: &Path,
+    upstream: Option<String>,
+    server_config: ServerConfig,
+) -> String {
+    let storage = Arc::new(Storage::new(data_dir).unwrap());
+    let mirror = upstream.map(|upstream| Arc::new(Mirror::new(upstream, None)));
+    let app = router(storage, server_config, mirror);
+
+    let port = portpicker::pick_unused_port().expect("no free port");
+    let listener = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+    listener.set_nonblocking(true).unwrap();
+
+    std::thread::spawn(move || {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                axum::serve(listener, app).await.unwrap();
+            });
+    });
+
+    format!("http://127.0.0.1:{port}")
+}
@@ -0,0 +1,242 @@
+//! A minimal SSH server that goldboot copies into a VM during an image build
+//! so that it can run commands in the guest before a real SSH server is
+//! installed.
+//!
+//! ```sh
+//! goldboot-sshd <port> <host key> <authorized public key>
+//! ```
+//!
+//! Only the one authorized key may log in, and the only supported requests are
+//! `exec` (with `env`) and the `sftp` subsystem. Commands run through `sh -c`
+//! (`cmd /C` on Windows) and their stdin, stdout, stderr and exit status are
+//! connected to the channel.
+//!
+//! It has to be built for the guest, e.g. with
+//! `cargo build --release -p goldboot-sshd --target x86_64-unknown-linux-musl`.
+
+use anyhow::{Context, Result, bail};
+use russh::{
+    Channel, ChannelId, CryptoVec,
+    keys::PublicKey,
+    server::{self, Auth, Msg, Server as _, Session},
+};
+use std::{collections::HashMap, net::SocketAddr, process::Stdio, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    process::{ChildStdin, Command},
+};
+
+mod sftp;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, port, host_key, authorized_key] = args.as_slice() else {
+        bail!("Usage: goldboot-sshd <port> <host key> <authorized public key>");
+    };
+
+    let host_key = russh::keys::load_secret_key(host_key, None).context("loading host key")?;
+    let authorized_key = PublicKey::from_openssh(
+        std::fs::read_to_string(authorized_key)
+            .context("reading authorized key")?
+            .trim(),
+    )?;
+
+    let config = server::Config {
+        keys: vec![host_key],
+        auth_rejection_time: Duration::from_secs(1),
+        ..Default::default()
+    };
+
+    Server { authorized_key }
+        .run_on_address(Arc::new(config), ("0.0.0.0", port.parse::<u16>()?))
+        .await?;
+    Ok(())
+}
+
+#[derive(Clone)]
+struct Server {
+    authorized_key: PublicKey,
+}
+
+impl server::Server for Server {
+    type Handler = Handler;
+
+    fn new_client(&mut self, _: Option<SocketAddr>) -> Handler {
+        Handler {
+            authorized_key: self.authorized_key.clone(),
+            channels: HashMap::new(),
+            env: HashMap::new(),
+            stdin: HashMap::new(),
+        }
+    }
+}
+
+struct Handler {
+    authorized_key: PublicKey,
+
+    /// Channels that may still request the sftp subsystem
+    channels: HashMap<ChannelId, Channel<Msg>>,
+
+    /// Environment requested for each channel's command
+    env: HashMap<ChannelId, Vec<(String, String)>>,
+
+    /// Stdin of each channel's running command
+    stdin: HashMap<ChannelId, ChildStdin>,
+}
+
+impl server::Handler for Handler {
+    type Error = anyhow::Error;
+
+    async fn auth_publickey(&mut self, _: &str, key: &PublicKey) -> Result<Auth> {
+        Ok(if key.key_data() == self.authorized_key.key_data() {
+            Auth::Accept
+        } else {
+            Auth::reject()
+        })
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _: &mut Session,
+    ) -> Result<bool> {
+        self.channels.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<()> {
+        match (name, self.channels.remove(&channel)) {
+            ("sftp", Some(stream)) => {
+                session.channel_success(channel)?;
+                tokio::spawn(russh_sftp::server::run(
+                    stream.into_stream(),
+                    sftp::Sftp::default(),
+                ));
+            }
+            _ => session.channel_failure(channel)?,
+        }
+        Ok(())
+    }
+
+    async fn env_request(
+        &mut self,
+        channel: ChannelId,
+        name: &str,
+        value: &str,
+        _: &mut Session,
+    ) -> Result<()> {
+        self.env
+            .entry(channel)
+            .or_default()
+            .push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<()> {
+        let command = String::from_utf8_lossy(data).into_owned();
+
+        // The channel's messages go to the handler from now on, and an unread
+        // channel would eventually stall the session
+        self.channels.remove(&channel);
+
+        let mut child = shell(&command)
+            .envs(self.env.remove(&channel).unwrap_or_default())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(stdin) = child.stdin.take() {
+            self.stdin.insert(channel, stdin);
+        }
+        session.channel_success(channel)?;
+
+        let handle = session.handle();
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        tokio::spawn(async move {
+            tokio::join!(
+                forward(&handle, channel, None, stdout),
+                forward(&handle, channel, Some(1), stderr),
+            );
+
+            let code = match child.wait().await {
+                Ok(status) => status.code().unwrap_or(255) as u32,
+                Err(_) => 255,
+            };
+            let _ = handle.exit_status_request(channel, code).await;
+            let _ = handle.eof(channel).await;
+            let _ = handle.close(channel).await;
+        });
+        Ok(())
+    }
+
+    async fn data(&mut self, channel: ChannelId, data: &[u8], _: &mut Session) -> Result<()> {
+        if let Some(stdin) = self.stdin.get_mut(&channel) {
+            stdin.write_all(data).await?;
+        }
+        Ok(())
+    }
+
+    async fn channel_eof(&mut self, channel: ChannelId, _: &mut Session) -> Result<()> {
+        // Dropping stdin closes it
+        self.stdin.remove(&channel);
+        Ok(())
+    }
+
+    async fn channel_close(&mut self, channel: ChannelId, _: &mut Session) -> Result<()> {
+        self.channels.remove(&channel);
+        self.env.remove(&channel);
+        self.stdin.remove(&channel);
+        Ok(())
+    }
+}
+
+/// Copy a command's output to the channel, as extended data if `ext` is set.
+async fn forward(
+    handle: &server::Handle,
+    channel: ChannelId,
+    ext: Option<u32>,
+    output: Option<impl AsyncRead + Unpin>,
+) {
+    let Some(mut output) = output else {
+        return;
+    };
+
+    let mut buf = vec![0u8; 32 * 1024];
+    while let Ok(n) = output.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+        let data = CryptoVec::from_slice(&buf[..n]);
+        let sent = match ext {
+            Some(ext) => handle.extended_data(channel, ext, data).await,
+            None => handle.data(channel, data).await,
+        };
+        if sent.is_err() {
+            break;
+        }
+    }
+}
+
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    }
+}
@@ -0,0 +1,138 @@
+//! The `sftp` subsystem, which goldboot uses to copy directories into the
+//! guest. Only what's needed to create files and directories and set their
+//! permissions is supported.
+
+use russh_sftp::protocol::{
+    Attrs, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode, Version,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Seek, SeekFrom, Write},
+};
+
+#[derive(Default)]
+pub struct Sftp {
+    /// Open files by handle
+    files: HashMap<String, fs::File>,
+    next_handle: u64,
+}
+
+fn ok(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: "Ok".to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+fn status(error: std::io::Error) -> StatusCode {
+    match error.kind() {
+        std::io::ErrorKind::NotFound => StatusCode::NoSuchFile,
+        std::io::ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+        _ => StatusCode::Failure,
+    }
+}
+
+impl russh_sftp::server::Handler for Sftp {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn init(&mut self, _: u32, _: HashMap<String, String>) -> Result<Version, Self::Error> {
+        Ok(Version::new())
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        flags: OpenFlags,
+        _: FileAttributes,
+    ) -> Result<Handle, Self::Error> {
+        let file = fs::OpenOptions::new()
+            .read(flags.contains(OpenFlags::READ))
+            .write(flags.contains(OpenFlags::WRITE))
+            .append(flags.contains(OpenFlags::APPEND))
+            .create(flags.contains(OpenFlags::CREATE))
+            .truncate(flags.contains(OpenFlags::TRUNCATE))
+            .open(filename)
+            .map_err(status)?;
+
+        self.next_handle += 1;
+        let handle = self.next_handle.to_string();
+        self.files.insert(handle.clone(), file);
+        Ok(Handle { id, handle })
+    }
+
+    async fn write(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<Status, Self::Error> {
+        let file = self.files.get_mut(&handle).ok_or(StatusCode::Failure)?;
+        file.seek(SeekFrom::Start(offset)).map_err(status)?;
+        file.write_all(&data).map_err(status)?;
+        Ok(ok(id))
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        self.files.remove(&handle);
+        Ok(ok(id))
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        _: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        fs::create_dir(path).map_err(status)?;
+        Ok(ok(id))
+    }
+
+    async fn setstat(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        #[cfg(unix)]
+        if let Some(mode) = attrs.permissions {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777)).map_err(status)?;
+        }
+        #[cfg(not(unix))]
+        let _ = (path, attrs);
+        Ok(ok(id))
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let metadata = fs::metadata(path).map_err(status)?;
+        Ok(Attrs {
+            id,
+            attrs: FileAttributes::from(&metadata),
+        })
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let metadata = fs::symlink_metadata(path).map_err(status)?;
+        Ok(Attrs {
+            id,
+            attrs: FileAttributes::from(&metadata),
+        })
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        let path = fs::canonicalize(path).map_err(status)?;
+        Ok(Name {
+            id,
+            files: vec![File::dummy(path.to_string_lossy())],
+        })
+    }
+}
@@ -1,4 +1,4 @@
-use std::{env, path::PathBuf};
+use std::{env, path::PathBuf, process::Command};
 
 fn main() {
     let out_dir = env::var("OUT_DIR").expect("OUT_DIR not set");
@@ -36,8 +36,72 @@ fn main() {
     let dest = PathBuf::from(&out_dir).join("rust_analyzer.json");
     std::fs::write(&dest, json).expect("Failed to write rust_analyzer.json");
 
+    // Embed goldboot-sshd for the guests
+    if env::var_os("CARGO_FEATURE_INCLUDE_SSHD").is_some() {
+        build_guest_sshd(&workspace_root, &PathBuf::from(&out_dir));
+    }
+
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=../models");
     println!("cargo:rerun-if-changed=src/builder/os");
     println!("cargo:rerun-if-changed=src/builder/options");
 }
+
+/// Cross-compile goldboot-sshd for each guest target and generate
+/// `guest_sshd.rs`, which embeds the executables. A target that fails to
+/// build (usually because its standard library isn't installed) is left out
+/// with a warning and gets built at runtime instead.
+fn build_guest_sshd(workspace_root: &PathBuf, out_dir: &PathBuf) {
+    let targets = env::var("GOLDBOOT_SSHD_TARGETS").unwrap_or_else(|_| {
+        "x86_64-unknown-linux-musl,aarch64-unknown-linux-musl,x86_64-pc-windows-gnu".to_string()
+    });
+    let target_dir = out_dir.join("sshd");
+
+    let mut arms = String::new();
+    for target in targets.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        let executable = if target.contains("windows") {
+            "goldboot-sshd.exe"
+        } else {
+            "goldboot-sshd"
+        };
+
+        let status = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+            .args([
+                "build",
+                "--release",
+                "-p",
+                "goldboot-sshd",
+                "--target",
+                target,
+            ])
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .current_dir(workspace_root.join("../goldboot-sshd"))
+            // The host's flags don't apply to the guest targets
+            .env_remove("CARGO_ENCODED_RUSTFLAGS")
+            .status();
+        match status {
+            Ok(status) if status.success() => {
+                let path = target_dir.join(target).join("release").join(executable);
+                arms.push_str(&format!(
+                    "        {target:?} => Some(include_bytes!({:?})),\n",
+                    path.display().to_string()
+                ));
+            }
+            _ => println!(
+                "cargo:warning=goldboot-sshd could not be built for {target} and will be built at runtime"
+            ),
+        }
+    }
+
+    std::fs::write(
+        out_dir.join("guest_sshd.rs"),
+        format!(
+            "fn embedded_sshd(target: &str) -> Option<&'static [u8]> {{\n    match target {{\n{arms}        _ => None,\n    }}\n}}\n"
+        ),
+    )
+    .expect("Failed to write guest_sshd.rs");
+
+    println!("cargo:rerun-if-changed=../goldboot-sshd/src");
+    println!("cargo:rerun-if-env-changed=GOLDBOOT_SSHD_TARGETS");
+}
@@ -0,0 +1,372 @@
+//! Cache keys that decide whether an element's checkpoints can be reused.
+//!
+//! Every checkpoint is recorded with a key derived from what went into it.
+//! The install key covers the element's configuration without its post-steps
+//! (which includes the ISO URL and checksum), the base image it starts from
+//! and the contents of the files in the context directory the configuration
+//! names (or the files in the directories it names). Each post-step's key
+//! chains the previous key with the step and the files it names, and the
+//! complete checkpoint's key chains the last one with the offline steps. A
+//! checkpoint whose recorded key differs from the current one is stale, and
+//! it is discarded along with every checkpoint after it.
+
+use crate::builder::{checkpoint, os::OsConfig, steps::copy};
+use anyhow::Result;
+use goldboot_image::qcow::Qcow3;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tracing::{debug, info};
+
+/// Referenced files larger than this (e.g. imported disks) are keyed on their
+/// size and modification time instead of their contents.
+const MAX_HASHED_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The keys an element's checkpoints must have under its current
+/// configuration.
+#[derive(Debug)]
+pub struct CacheKeys {
+    install: String,
+    post_steps: Vec<String>,
+    complete: String,
+}
+
+impl CacheKeys {
+    /// Compute the keys of `element`, reading the files it references from
+    /// `context_dir`.
+    pub fn compute(element: &OsConfig, context_dir: &Path) -> Result<Self> {
+        let mut hasher = Sha256::new();
+        hasher.update(element.0.os_name());
+        hash_config(
+            &mut hasher,
+            &element.0.serialize_install_ron()?,
+            context_dir,
+        )?;
+        if let Some(base) = element.0.base_image() {
+            hasher.update(base.resolve()?.primary_header.content_id);
+        }
+        let install = hex::encode(hasher.finalize());
+
+        let mut post_steps: Vec<String> = Vec::new();
+        for step in element.0.post_steps() {
+            let mut hasher = Sha256::new();
+            hasher.update(post_steps.last().unwrap_or(&install));
+            hash_config(&mut hasher, &ron::ser::to_string(step)?, context_dir)?;
+            post_steps.push(hex::encode(hasher.finalize()));
+        }
+
+        let mut complete = post_steps.last().unwrap_or(&install).clone();
+        if !element.0.offline_steps().is_empty() {
+            let mut hasher = Sha256::new();
+            hasher.update(&complete);
+            hash_config(
+                &mut hasher,
+                &ron::ser::to_string(element.0.offline_steps())?,
+                context_dir,
+            )?;
+            complete = hex::encode(hasher.finalize());
+        }
+
+        Ok(Self {
+            install,
+            post_steps,
+            complete,
+        })
+    }
+
+    /// The key the checkpoint with the given name must have been taken with,
+    /// or `None` if the element no longer takes it.
+    pub fn expected(&self, name: &str) -> Option<&str> {
+        if name == checkpoint::COMPLETE {
+            return Some(&self.complete);
+        }
+        match checkpoint::post_step_index(name) {
+            Some(index) => self.post_steps.get(index).map(String::as_str),
+            // Checkpoints taken before or during the install
+            None => Some(&self.install),
+        }
+    }
+
+    /// Why the checkpoint with the given name can't be reused, if it can't.
+    fn stale_reason(&self, name: &str, recorded: Option<&str>) -> Option<String> {
+        let Some(expected) = self.expected(name) else {
+            return Some("the element no longer has this post-step".to_string());
+        };
+        let Some(recorded) = recorded else {
+            return Some("no cache key was recorded for it".to_string());
+        };
+        if recorded == expected {
+            return None;
+        }
+
+        Some(if name == checkpoint::COMPLETE {
+            "the post-steps or offline steps changed".to_string()
+        } else if let Some(index) = checkpoint::post_step_index(name) {
+            format!("post-step {} or a file it references changed", index + 1)
+        } else {
+            "the configuration or a file it references changed".to_string()
+        })
+    }
+}
+
+/// Hash a serialized configuration and the files in `context_dir` it names.
+fn hash_config(hasher: &mut Sha256, config: &str, context_dir: &Path) -> Result<()> {
+    hasher.update(config);
+
+    let mut strings = BTreeSet::new();
+    collect_strings(&ron::from_str(config)?, &mut strings);
+
+    for string in strings {
+        if string.is_empty() || string.contains('\n') {
+            continue;
+        }
+        // Only files in the context directory, not destinations in the guest
+        // that happen to exist on the host too
+        if Path::new(&string).is_absolute() || string.split(['/', '\\']).any(|c| c == "..") {
+            continue;
+        }
+        let path = context_dir.join(&string);
+        if path.is_file() {
+            hasher.update(&string);
+            hash_file(hasher, &path)?;
+        } else if path.is_dir() {
+            hasher.update(&string);
+            for relative in copy::walk(&path)? {
+                hasher.update(relative.to_string_lossy().as_bytes());
+                if path.join(&relative).is_file() {
+                    hash_file(hasher, &path.join(&relative))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn hash_file(hasher: &mut Sha256, path: &Path) -> Result<()> {
+    debug!(path = %path.display(), "Hashing referenced file");
+    let metadata = std::fs::metadata(path)?;
+    hasher.update(copy::file_mode(&metadata).to_be_bytes());
+    if metadata.len() > MAX_HASHED_FILE_SIZE {
+        hasher.update(metadata.len().to_be_bytes());
+        hasher.update(
+            metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)?
+                .as_nanos()
+                .to_be_bytes(),
+        );
+    } else {
+        hasher.update(std::fs::read(path)?);
+    }
+    Ok(())
+}
+
+fn collect_strings(value: &ron::Value, strings: &mut BTreeSet<String>) {
+    match value {
+        ron::Value::String(string) => {
+            strings.insert(string.clone());
+        }
+        ron::Value::Option(Some(value)) => collect_strings(value, strings),
+        ron::Value::Seq(values) => {
+            for value in values {
+                collect_strings(value, strings);
+            }
+        }
+        ron::Value::Map(map) => {
+            for (_, value) in map.iter() {
+                collect_strings(value, strings);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn record_path(qcow_path: &Path) -> PathBuf {
+    qcow_path.with_extension("state").join("checkpoints.json")
+}
+
+fn load_record(qcow_path: &Path) -> Result<BTreeMap<String, String>> {
+    let path = record_path(qcow_path);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+/// Discard the checkpoints of the qcow at `qcow_path` that were taken with a
+/// different key, along with every checkpoint after them. With `explain`,
+/// print why each checkpoint was reused or discarded.
+pub fn invalidate(qcow_path: &Path, keys: &CacheKeys, explain: bool) -> Result<()> {
+    if !qcow_path.exists() {
+        if explain {
+            println!("    (no checkpoints)");
+        }
+        return Ok(());
+    }
+
+    let qcow = Qcow3::open(qcow_path)?;
+    let record = load_record(qcow_path)?;
+
+    let mut stale = false;
+    for snapshot in &qcow.snapshots {
+        let name = &snapshot.name;
+        let reason = if stale {
+            Some("an earlier checkpoint is stale".to_string())
+        } else {
+            keys.stale_reason(name, record.get(name).map(String::as_str))
+        };
+
+        match reason {
+            Some(reason) => {
+                info!(checkpoint = %name, reason = %reason, "Discarding stale checkpoint");
+                if explain {
+                    println!("    {name:<20} discarded: {reason}");
+                }
+                qcow.delete_snapshot(name)?;
+                stale = true;
+            }
+            None if explain => println!("    {name:<20} reused"),
+            None => {}
+        }
+    }
+
+    if explain && qcow.snapshots.is_empty() {
+        println!("    (no checkpoints)");
+    }
+    Ok(())
+}
+
+/// Record the current keys of the checkpoints in the qcow at `qcow_path`.
+/// Older checkpoints survived [`invalidate`], so their keys are unchanged.
+pub fn record(qcow_path: &Path, keys: &CacheKeys) -> Result<()> {
+    if !qcow_path.exists() {
+        return Ok(());
+    }
+
+    let record: BTreeMap<&str, &str> = Qcow3::open(qcow_path)?
+        .snapshots
+        .iter()
+        .filter_map(|snapshot| {
+            keys.expected(&snapshot.name)
+                .map(|key| (snapshot.name.as_str(), key))
+        })
+        .collect();
+
+    let path = record_path(qcow_path);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_vec_pretty(&record)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::os::os_config_from_ron;
+
+    fn keys(config: &str, context_dir: &Path) -> Result<CacheKeys> {
+        CacheKeys::compute(&os_config_from_ron(config)?, context_dir)
+    }
+
+    #[test]
+    fn keys_follow_configuration_and_files() -> Result<()> {
+        let context = tempfile::tempdir()?;
+        std::fs::write(context.path().join("disk.raw"), [0u8; 512])?;
+        std::fs::write(context.path().join("first.sh"), "true")?;
+        std::fs::write(context.path().join("second.sh"), "true")?;
+
+        let config = r#"ImportImage(
+            arch: Amd64,
+            minimum_size: "16G",
+            source: (path: "disk.raw"),
+            post_steps: [
+                HostExecutable(path: "first.sh"),
+                HostExecutable(path: "second.sh"),
+            ],
+        )"#;
+        let original = keys(config, context.path())?;
+        assert_eq!(
+            original.expected(checkpoint::COMPLETE),
+            original.expected("post-step-2")
+        );
+        assert_eq!(original.expected("post-step-3"), None);
+
+        // Editing the second script keeps the install and the first step
+        std::fs::write(context.path().join("second.sh"), "false")?;
+        let edited = keys(config, context.path())?;
+        assert_eq!(
+            original.stale_reason(checkpoint::INSTALL, edited.expected(checkpoint::INSTALL)),
+            None
+        );
+        assert_eq!(
+            original.stale_reason("post-step-1", edited.expected("post-step-1")),
+            None
+        );
+        assert!(
+            original
+                .stale_reason("post-step-2", edited.expected("post-step-2"))
+                .is_some()
+        );
+
+        // Editing the source disk invalidates everything
+        std::fs::write(context.path().join("disk.raw"), [1u8; 512])?;
+        let edited = keys(config, context.path())?;
+        assert!(
+            original
+                .stale_reason(checkpoint::INSTALL, edited.expected(checkpoint::INSTALL))
+                .is_some()
+        );
+
+        // So does changing a field
+        let edited = keys(&config.replace("16G", "32G"), context.path())?;
+        assert_ne!(
+            original.expected(checkpoint::INSTALL),
+            edited.expected(checkpoint::INSTALL)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn offline_steps_only_change_complete_key() -> Result<()> {
+        let context = tempfile::tempdir()?;
+        std::fs::write(context.path().join("disk.raw"), [0u8; 512])?;
+        std::fs::write(context.path().join("motd"), "hello")?;
+
+        let config = r#"ImportImage(
+            arch: Amd64,
+            minimum_size: "16G",
+            source: (path: "disk.raw"),
+            post_steps: [
+                Shell(command: "true"),
+            ],
+            offline_steps: [
+                Copy(src: "motd", dest: "/etc/motd"),
+            ],
+        )"#;
+        let original = keys(config, context.path())?;
+        assert_ne!(
+            original.expected(checkpoint::COMPLETE),
+            original.expected("post-step-1")
+        );
+
+        std::fs::write(context.path().join("motd"), "goodbye")?;
+        let edited = keys(config, context.path())?;
+        assert_eq!(
+            original.expected(checkpoint::INSTALL),
+            edited.expected(checkpoint::INSTALL)
+        );
+        assert_eq!(
+            original.expected("post-step-1"),
+            edited.expected("post-step-1")
+        );
+        assert_ne!(
+            original.expected(checkpoint::COMPLETE),
+            edited.expected(checkpoint::COMPLETE)
+        );
+        Ok(())
+    }
+}
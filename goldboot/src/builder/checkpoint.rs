@@ -0,0 +1,214 @@
+//! Named snapshots of an element's working qcow2 that later builds resume
+//! from.
+//!
+//! Elements take live checkpoints (disk and VM state, restored with
+//! `-loadvm`) after their install and after each post-step. Elements whose
+//! installer powers the VM off take an offline (disk only) `install`
+//! checkpoint instead. The builder takes an offline `complete` checkpoint
+//! once the offline steps have run, before converting the element into the
+//! final image. A build resumes from the last checkpoint of each element
+//! unless `--from` picks an earlier one, and checkpoints after the one it
+//! resumed from are discarded.
+
+use crate::builder::qemu;
+use anyhow::{Result, bail};
+use goldboot_image::qcow::Qcow3;
+use sha2::{Digest, Sha256};
+use std::{path::Path, str::FromStr};
+use tracing::warn;
+
+/// Taken once the OS is installed (or the source disk is imported).
+pub const INSTALL: &str = "install";
+
+/// Taken once the element is fully built, before conversion.
+pub const COMPLETE: &str = "complete";
+
+/// The checkpoint taken after the post-step at `index` (0-based). Names are
+/// 1-based to match `--list-checkpoints`.
+pub fn post_step(index: usize) -> String {
+    format!("post-step-{}", index + 1)
+}
+
+/// The index of the post-step a checkpoint name refers to, if it is one.
+pub fn post_step_index(name: &str) -> Option<usize> {
+    name.strip_prefix("post-step-")?
+        .parse::<usize>()
+        .ok()?
+        .checked_sub(1)
+}
+
+/// What a checkpoint captures, for `--list-checkpoints`.
+pub fn describe(name: &str) -> String {
+    match (name, post_step_index(name)) {
+        (INSTALL, _) => "after the install".to_string(),
+        (COMPLETE, _) => "after the offline steps".to_string(),
+        (_, Some(index)) => format!("after post-step {}", index + 1),
+        _ => String::new(),
+    }
+}
+
+/// A checkpoint to resume from given as `[<element index>:]<name>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointTarget {
+    /// Index of the element, required for multiboot images
+    pub element: Option<usize>,
+    pub name: String,
+}
+
+impl FromStr for CheckpointTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (element, name) = match s.split_once(':') {
+            Some((element, name)) => (Some(element.parse::<usize>()?), name),
+            None => (None, s),
+        };
+        if name.is_empty() {
+            bail!("Empty checkpoint name");
+        }
+        Ok(Self {
+            element,
+            name: name.to_string(),
+        })
+    }
+}
+
+impl CheckpointTarget {
+    /// The element this target refers to in a build with `element_count`
+    /// elements.
+    pub fn element_index(&self, element_count: usize) -> Result<usize> {
+        match self.element {
+            Some(index) if index < element_count => Ok(index),
+            Some(index) => bail!("No element {index}; the image has {element_count}"),
+            None if element_count == 1 => Ok(0),
+            None => bail!(
+                "Multiboot images need the element in --from, e.g. 1:{}",
+                self.name
+            ),
+        }
+    }
+}
+
+/// The host port the VM's SSH server is forwarded to. It's picked once per
+/// working qcow2 and kept in its state directory, so later runs of the
+/// element find it again. A kept port that something else has taken since is
+/// replaced.
+pub fn ssh_port(state_dir: &Path) -> Result<u16> {
+    let path = state_dir.join("ssh_port");
+    if let Some(port) = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|port| port.trim().parse::<u16>().ok())
+    {
+        if qemu::claim_port(port) {
+            return Ok(port);
+        }
+        warn!(port, "The element's SSH port is in use, picking another");
+    }
+
+    let hash = Sha256::digest(state_dir.to_string_lossy().as_bytes());
+    let preferred = 10000 + u16::from_be_bytes([hash[0], hash[1]]) % 1000;
+    let port = qemu::reserve_port(preferred, 10000..11000)?;
+
+    std::fs::create_dir_all(state_dir)?;
+    std::fs::write(&path, port.to_string())?;
+    Ok(port)
+}
+
+/// Print the checkpoints of each element's working qcow2.
+pub fn list(elements: &[(&str, &Path)]) -> Result<()> {
+    for (i, (name, path)) in elements.iter().enumerate() {
+        let prefix = if elements.len() > 1 {
+            format!("{i}:")
+        } else {
+            String::new()
+        };
+        println!("[{i}] {name}");
+
+        if !path.exists() {
+            println!("    (not built)");
+            continue;
+        }
+
+        let qcow = Qcow3::open(path)?;
+        if qcow.snapshots.is_empty() {
+            println!("    (no checkpoints)");
+        }
+        for snapshot in &qcow.snapshots {
+            println!(
+                "    {prefix}{:<20} {}  {}  {}",
+                snapshot.name,
+                chrono::DateTime::from_timestamp(snapshot.time.secs as i64, 0)
+                    .unwrap_or_default()
+                    .format("%Y-%m-%d %H:%M:%S"),
+                if snapshot.has_vm_state() {
+                    "live"
+                } else {
+                    "disk"
+                },
+                describe(&snapshot.name)
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_target() -> Result<()> {
+        let target: CheckpointTarget = "post-step-3".parse()?;
+        assert_eq!(target.element, None);
+        assert_eq!(target.name, "post-step-3");
+        assert_eq!(target.element_index(1)?, 0);
+        assert!(target.element_index(2).is_err());
+
+        let target: CheckpointTarget = "1:install".parse()?;
+        assert_eq!(target.element, Some(1));
+        assert_eq!(target.name, INSTALL);
+        assert_eq!(target.element_index(2)?, 1);
+        assert!(target.element_index(1).is_err());
+
+        assert!("x:install".parse::<CheckpointTarget>().is_err());
+        assert!("0:".parse::<CheckpointTarget>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn ssh_port_is_kept() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let state_dir = tmp.path().join("abc.state");
+
+        let port = ssh_port(&state_dir)?;
+        assert!((10000..11000).contains(&port));
+        assert_eq!(port, ssh_port(&state_dir)?);
+
+        // Another element gets a different port
+        let other = ssh_port(&tmp.path().join("def.state"))?;
+        assert_ne!(port, other);
+
+        // A kept port that's been taken is replaced
+        let _listener = std::net::TcpListener::bind(("127.0.0.1", port))?;
+        let replacement = ssh_port(&state_dir)?;
+        assert_ne!(port, replacement);
+        assert_eq!(replacement, ssh_port(&state_dir)?);
+        Ok(())
+    }
+
+    #[test]
+    fn post_step_names() {
+        assert_eq!(post_step(2), "post-step-3");
+        assert_eq!(post_step_index("post-step-3"), Some(2));
+        assert_eq!(post_step_index("post-step-0"), None);
+        assert_eq!(post_step_index(INSTALL), None);
+    }
+
+    #[test]
+    fn checkpoints_are_taken_after_their_step() {
+        assert_eq!(describe(&post_step(2)), "after post-step 3");
+        assert_eq!(describe(INSTALL), "after the install");
+        assert_eq!(describe(COMPLETE), "after the offline steps");
+        assert_eq!(describe("manual"), "");
+    }
+}
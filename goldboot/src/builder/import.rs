@@ -0,0 +1,247 @@
+//! Importing existing disks into an element's working qcow2.
+
+use anyhow::{Context, Result, bail};
+use goldboot_image::{ImageHandle, qcow::Qcow3};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+use tracing::{debug, info};
+
+/// The on-disk format of an imported disk.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum DiskFormat {
+    Qcow2,
+    Raw,
+    Vmdk,
+    /// A goldboot image
+    Gb,
+}
+
+impl DiskFormat {
+    /// Infer the format from a file extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path
+            .extension()?
+            .to_string_lossy()
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "qcow2" | "qcow" => Some(Self::Qcow2),
+            "raw" | "img" => Some(Self::Raw),
+            "vmdk" => Some(Self::Vmdk),
+            "gb" => Some(Self::Gb),
+            _ => None,
+        }
+    }
+
+    /// The name `qemu-img` uses for this format.
+    fn qemu_img_format(&self) -> &'static str {
+        match self {
+            Self::Qcow2 => "qcow2",
+            Self::Raw | Self::Gb => "raw",
+            Self::Vmdk => "vmdk",
+        }
+    }
+}
+
+/// Replace the qcow2 at `dest` with a copy of `source`, grown to at least
+/// `minimum_size` bytes. Goldboot images are decrypted with `password`.
+pub fn import_disk(
+    source: &Path,
+    format: DiskFormat,
+    password: Option<String>,
+    dest: &Path,
+    minimum_size: u64,
+) -> Result<()> {
+    info!(source = ?source, format = ?format, "Importing disk");
+
+    if !source.exists() {
+        bail!("Source disk not found: {}", source.display());
+    }
+
+    if format == DiskFormat::Gb {
+        let mut image = ImageHandle::open(source)?;
+        image.load(password)?;
+        return import_image(&image, dest, minimum_size);
+    }
+
+    convert(source, format.qemu_img_format(), dest)?;
+    grow(dest, minimum_size)
+}
+
+/// Replace the qcow2 at `dest` with the virtual disk of a loaded goldboot
+/// image, grown to at least `minimum_size` bytes.
+pub fn import_image(image: &ImageHandle, dest: &Path, minimum_size: u64) -> Result<()> {
+    info!(
+        name = %image.primary_header.name_str(),
+        tag = %image.primary_header.tag_str(),
+        "Materializing goldboot image"
+    );
+
+    let raw_path = dest.with_extension("import.raw");
+    let result = write_sparse(image, &raw_path).and_then(|_| convert(&raw_path, "raw", dest));
+    let _ = std::fs::remove_file(&raw_path);
+    result?;
+
+    grow(dest, minimum_size)
+}
+
+/// Write the virtual disk of `image` to a raw file, leaving holes where the
+/// image has no clusters.
+fn write_sparse(image: &ImageHandle, path: &Path) -> Result<()> {
+    let mut reader = image.reader()?;
+    let size = reader.size();
+    let block_size = image
+        .protected_header
+        .as_ref()
+        .map(|header| header.block_size as u64)
+        .unwrap_or(65536);
+
+    let mut file = File::create(path)?;
+    file.set_len(size)?;
+
+    let mut block = vec![0u8; block_size as usize];
+    let mut offset = 0;
+    while offset < size {
+        let len = block_size.min(size - offset) as usize;
+        if reader.is_populated(offset) {
+            reader.seek(SeekFrom::Start(offset))?;
+            reader.read_exact(&mut block[..len])?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&block[..len])?;
+        }
+        offset += len as u64;
+    }
+
+    Ok(())
+}
+
+/// Convert `source` into a fresh qcow2 at `dest`.
+fn convert(source: &Path, format: &str, dest: &Path) -> Result<()> {
+    if dest.exists() {
+        std::fs::remove_file(dest)?;
+    }
+
+    debug!(dest = %dest.display(), "Converting source disk to qcow2");
+    let status = Command::new("qemu-img")
+        .args([
+            "convert",
+            "-f",
+            format,
+            "-O",
+            "qcow2",
+            "-o",
+            "cluster_size=65536",
+        ])
+        .arg(source)
+        .arg(dest)
+        .stdout(Stdio::null())
+        .status()
+        .context("failed to run qemu-img")?;
+    if !status.success() {
+        bail!("qemu-img convert failed");
+    }
+    Ok(())
+}
+
+/// Grow the qcow2 at `path` to `minimum_size` bytes if it is smaller.
+fn grow(path: &Path, minimum_size: u64) -> Result<()> {
+    let size = Qcow3::open(path)?.header.size;
+    if size >= minimum_size {
+        return Ok(());
+    }
+
+    debug!(size, minimum_size, "Growing imported disk");
+    let status = Command::new("qemu-img")
+        .arg("resize")
+        .arg(path)
+        .arg(minimum_size.to_string())
+        .stdout(Stdio::null())
+        .status()
+        .context("failed to run qemu-img")?;
+    if !status.success() {
+        bail!("qemu-img resize failed");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use goldboot_image::ConvertOptions;
+
+    #[test]
+    fn infer_format() {
+        assert_eq!(
+            DiskFormat::from_path(Path::new("base.qcow2")),
+            Some(DiskFormat::Qcow2)
+        );
+        assert_eq!(
+            DiskFormat::from_path(Path::new("vendor/appliance.VMDK")),
+            Some(DiskFormat::Vmdk)
+        );
+        assert_eq!(
+            DiskFormat::from_path(Path::new("disk.img")),
+            Some(DiskFormat::Raw)
+        );
+        assert_eq!(
+            DiskFormat::from_path(Path::new("golden.gb")),
+            Some(DiskFormat::Gb)
+        );
+        assert_eq!(DiskFormat::from_path(Path::new("disk")), None);
+        assert_eq!(DiskFormat::from_path(Path::new("installer.iso")), None);
+    }
+
+    #[test]
+    fn import_and_grow_raw_disk() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let source = tmp.path().join("disk.raw");
+        std::fs::write(&source, vec![0x5a; 1024 * 1024])?;
+
+        let dest = tmp.path().join("element.qcow2");
+        Qcow3::create(&dest, 4 * 1024 * 1024)?;
+
+        import_disk(&source, DiskFormat::Raw, None, &dest, 8 * 1024 * 1024)?;
+
+        let qcow = Qcow3::open(&dest)?;
+        assert_eq!(qcow.header.size, 8 * 1024 * 1024);
+        assert!(qcow.count_clusters()? > 0);
+        assert!(!dest.with_extension("import.raw").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn write_goldboot_image_sparse() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let qcow = Qcow3::open(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../goldboot-image/test/sparse.qcow2"
+        ))?;
+        let image_path = tmp.path().join("base.gb");
+        ImageHandle::from_qcow(
+            "base",
+            "v1",
+            vec![],
+            &qcow,
+            &image_path,
+            Some("pw".to_string()),
+            &ConvertOptions::default(),
+            |_, _| {},
+        )?;
+
+        let mut image = ImageHandle::open(&image_path)?;
+        image.load(Some("pw".to_string()))?;
+
+        let raw_path = tmp.path().join("base.raw");
+        write_sparse(&image, &raw_path)?;
+
+        let mut expected = Vec::new();
+        qcow.reader()?.read_to_end(&mut expected)?;
+        assert_eq!(std::fs::read(&raw_path)?, expected);
+        Ok(())
+    }
+}
@@ -1,34 +1,48 @@
+use self::observe::Observer;
 use self::qemu::{Accel, detect_accel};
+use self::recording::RecordMode;
 use crate::builder::os::OsConfig;
 use crate::cli::cmd::Commands;
 use crate::library::{ImageLibrary, alloy_qcow_cache_path, element_qcow_cache_path, qcow_cache_path};
 
 use anyhow::{Result, bail};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use dialoguer::Password;
 use goldboot_image::{
-    ElementHeader, ImageArch, ImageHandle, ImageRef, qcow::Qcow3, validate_ref_segment,
+    ConvertOptions, ElementHeader, ImageArch, ImageHandle, ImageRef, qcow::Qcow3,
+    validate_ref_segment,
 };
 use rand::RngExt;
+use sha2::{Digest, Sha256};
 use std::{
+    borrow::Cow,
     path::{Path, PathBuf},
-    time::SystemTime,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tracing::info;
+use tracing::{info, info_span};
 use validator::Validate;
 
 pub mod alloy;
+pub mod cache;
+pub mod checkpoint;
 pub mod config;
 pub mod http;
+pub mod import;
+pub mod observe;
+pub mod offline;
 pub mod options;
 pub mod os;
 pub mod ovmf;
+pub mod parallel;
 pub mod qemu;
+pub mod recording;
+pub mod reproducible;
 pub mod serial;
 pub mod sources;
 pub mod ssh;
 pub mod steps;
 pub mod vnc;
+pub mod winrm;
 
 /// Machinery that creates Goldboot images from image elements.
 #[derive(Validate)]
@@ -42,7 +56,22 @@ pub struct Builder {
 
     pub debug: bool,
 
-    pub record: bool,
+    pub record: Option<RecordMode>,
+
+    /// Time limit for each VNC wait command that doesn't set its own.
+    pub wait_timeout: Duration,
+
+    /// When the whole build must be finished.
+    pub build_deadline: Option<Instant>,
+
+    /// Where diagnostics are saved when a VNC wait times out.
+    pub failure_dir: PathBuf,
+
+    /// Receives live progress when the build is observed with `--observe`.
+    pub observer: Option<Observer>,
+
+    /// This element's line in the aggregated progress display
+    pub progress: Option<indicatif::ProgressBar>,
 
     /// Context directory containing goldboot.ron
     pub context_dir: PathBuf,
@@ -62,6 +91,9 @@ pub struct Builder {
 
     pub qcow: Option<Qcow3>,
 
+    /// Live checkpoint the element's VM restores instead of booting
+    pub resume: Option<String>,
+
     /// VNC port for the VM
     pub vnc_port: u16,
 
@@ -80,10 +112,16 @@ impl Builder {
         Self {
             accel: detect_accel(),
             debug: false,
-            record: false,
+            record: None,
+            wait_timeout: vnc::DEFAULT_WAIT_TIMEOUT,
+            build_deadline: None,
+            failure_dir: PathBuf::from("failures"),
+            observer: None,
+            progress: None,
             end_time: None,
             qcow: None,
             qcow_path,
+            resume: None,
             start_time: None,
             vnc_port: rand::rng().random_range(5900..5999),
             name,
@@ -103,6 +141,133 @@ impl Builder {
             .unwrap_or(false)
     }
 
+    /// Directory next to the working qcow2 for state that must survive
+    /// between runs along with its checkpoints (e.g. SSH keys).
+    pub fn state_dir(&self) -> PathBuf {
+        self.qcow_path.with_extension("state")
+    }
+
+    /// A builder for the element at `index` alone, with its own working
+    /// qcow2, tmp dir and VNC port so that elements can build concurrently.
+    fn element_worker(&self, index: usize, element: OsConfig, qcow_path: &Path) -> Result<Self> {
+        Ok(Self {
+            name: self.name.clone(),
+            elements: vec![element],
+            accel: self.accel,
+            debug: self.debug,
+            record: self.record,
+            wait_timeout: self.wait_timeout,
+            build_deadline: self.build_deadline,
+            failure_dir: self.failure_dir.clone(),
+            observer: None,
+            progress: None,
+            context_dir: self.context_dir.clone(),
+            effective_context_dir: self.effective_context_dir.clone(),
+            tmp: tempfile::tempdir()?,
+            ovmf_path: self.ovmf_path.clone(),
+            qcow_path: qcow_path.to_path_buf(),
+            qcow: None,
+            resume: None,
+            vnc_port: qemu::reserve_port(
+                5900 + (self.vnc_port - 5900 + index as u16) % 100,
+                5900..6000,
+            )?,
+            end_time: None,
+            start_time: self.start_time,
+        })
+    }
+
+    /// Discard the stale checkpoints of this worker's element, then pick the
+    /// one to resume from: `from` if given, or else the last one.
+    fn prepare(
+        &mut self,
+        index: usize,
+        from: Option<&str>,
+        explain_cache: bool,
+    ) -> Result<cache::CacheKeys> {
+        let path = self.qcow_path.clone();
+
+        // Checkpoints taken with a different configuration are stale
+        let keys = cache::CacheKeys::compute(&self.elements[0], &self.effective_context_dir)?;
+        if explain_cache {
+            println!("[{index}] {}", self.elements[0].0.os_name());
+        }
+        cache::invalidate(&path, &keys, explain_cache)?;
+
+        // Discard a snapshotless qcow left behind by a failed run
+        if path.exists() {
+            if let Ok(qcow) = Qcow3::open(&path) {
+                if qcow.snapshots.is_empty() {
+                    std::fs::remove_file(&path)?;
+                }
+            }
+        }
+
+        self.qcow = Some(if path.exists() {
+            Qcow3::open(&path)?
+        } else {
+            let minimum_size = self.elements[0].0.os_minimum_size();
+            // Truncate the minimum size to a power of two for the qcow storage
+            Qcow3::create(&path, minimum_size - (minimum_size % 2))?
+        });
+
+        let qcow = self.qcow.as_ref().unwrap();
+        let resume_from = match from {
+            Some(name) => Some(name.to_string()),
+            None => qcow.snapshots.last().map(|s| s.name.clone()),
+        };
+        if let Some(name) = resume_from {
+            let Some(position) = qcow.snapshots.iter().position(|s| s.name == name) else {
+                bail!("Element {index} has no checkpoint '{name}'");
+            };
+
+            // Later checkpoints are stale once an earlier one is resumed
+            for later in &qcow.snapshots[position + 1..] {
+                qcow.delete_snapshot(&later.name)?;
+            }
+
+            // Live checkpoints are restored by the VM itself
+            if qcow.snapshots[position].has_vm_state() {
+                self.resume = Some(name.clone());
+            } else {
+                qcow.revert(&name)?;
+            }
+            info!(checkpoint = %name, "Resuming from checkpoint");
+            self.qcow = Some(Qcow3::open(&path)?);
+        }
+
+        Ok(keys)
+    }
+
+    /// Build this worker's element unless it is already complete, apply its
+    /// offline steps, then checkpoint it and record the keys of its
+    /// checkpoints.
+    fn build_element(&mut self, keys: &cache::CacheKeys) -> Result<()> {
+        if self.has_checkpoint(checkpoint::COMPLETE) {
+            info!("Element already built");
+            return Ok(());
+        }
+
+        let result = self.elements[0]
+            .0
+            .build(self)
+            .and_then(|_| offline::run_offline_steps(self, self.elements[0].0.offline_steps()))
+            .and_then(|_| Qcow3::open(&self.qcow_path)?.create_snapshot(checkpoint::COMPLETE));
+
+        // Checkpoints taken before a failure are still reusable
+        let recorded = cache::record(&self.qcow_path, keys);
+        result?;
+        recorded
+    }
+
+    /// Show what the element is doing in the aggregated progress display of a
+    /// multiboot build.
+    pub fn set_status(&self, status: impl Into<Cow<'static, str>>) {
+        if let Some(progress) = &self.progress {
+            progress.set_message(status);
+        }
+    }
+
     /// The system architecture
     pub fn arch(&self) -> Result<ImageArch> {
         match self.elements.first() {
@@ -124,9 +289,19 @@ impl Builder {
             Commands::Build {
                 record,
                 debug,
+                wait_timeout,
+                timeout,
+                failure_dir,
+                observe,
                 read_password,
                 no_accel,
                 clean,
+                from,
+                list_checkpoints,
+                explain_cache,
+                jobs,
+                nonce_key,
+                verify_reproducible,
                 output,
                 path: _,
                 ovmf_path,
@@ -134,8 +309,39 @@ impl Builder {
                 tag,
                 name: _,
             } => {
+                // Each element builds into its own working qcow2, which its VM
+                // attaches as the primary drive.
+                let qcow_paths: Vec<PathBuf> = (0..self.elements.len())
+                    .map(|i| element_qcow_cache_path(&self.context_dir, i))
+                    .collect::<Result<_>>()?;
+                let alloy_path = alloy_qcow_cache_path(&self.context_dir)?;
+
+                if list_checkpoints {
+                    let elements: Vec<(&str, &Path)> = self
+                        .elements
+                        .iter()
+                        .zip(&qcow_paths)
+                        .map(|(e, path)| (e.0.os_name(), path.as_path()))
+                        .collect();
+                    return checkpoint::list(&elements);
+                }
+
+                let from = match from {
+                    Some(target) => Some((target.element_index(self.elements.len())?, target.name)),
+                    None => None,
+                };
+
                 self.debug = debug;
                 self.record = record;
+                self.wait_timeout = Duration::from_secs(wait_timeout);
+                self.build_deadline =
+                    timeout.map(|seconds| Instant::now() + Duration::from_secs(seconds));
+                if let Some(failure_dir) = failure_dir {
+                    self.failure_dir = failure_dir;
+                }
+                if let Some(address) = observe {
+                    self.observer = Some(Observer::serve(address)?);
+                }
 
                 // Set VNC port predictably in debug mode
                 if debug {
@@ -153,6 +359,23 @@ impl Builder {
                     None
                 };
 
+                if verify_reproducible && password.is_some() && nonce_key.is_none() {
+                    bail!("Encrypted images can only be reproducible with --nonce-key");
+                }
+
+                // Every build of the same sources gets the same timestamp
+                let timestamp = match goldboot_image::source_date_epoch()? {
+                    Some(timestamp) => timestamp,
+                    None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                };
+                let convert_options = ConvertOptions {
+                    timestamp: Some(timestamp),
+                    nonce_key: match nonce_key {
+                        Some(path) => Some(Sha256::digest(std::fs::read(path)?).into()),
+                        None => None,
+                    },
+                };
+
                 // Disable VM acceleration if requested
                 if no_accel {
                     self.accel = Accel::Tcg;
@@ -184,21 +407,6 @@ impl Builder {
                     bail!("No OVMF firmware found");
                 }
 
-                // Each element builds into its own working qcow2, which its VM
-                // attaches as the primary drive.
-                let qcow_paths: Vec<PathBuf> = (0..self.elements.len())
-                    .map(|i| element_qcow_cache_path(&self.context_dir, i))
-                    .collect::<Result<_>>()?;
-                let alloy_path = alloy_qcow_cache_path(&self.context_dir)?;
-
-                if clean {
-                    for path in qcow_paths.iter().chain([&alloy_path]) {
-                        if path.exists() {
-                            std::fs::remove_file(path)?;
-                        }
-                    }
-                }
-
                 // Multiboot images embed goldboot.efi as the default
                 // (chain-loader) bootloader; resolve it up front so a
                 // missing file fails before the lengthy element builds.
@@ -227,52 +435,21 @@ impl Builder {
                     }
                 }
 
-                for (i, path) in qcow_paths.iter().enumerate() {
-                    // Discard a snapshotless qcow left behind by a failed run
-                    if path.exists() {
-                        if let Ok(qcow) = Qcow3::open(path) {
-                            if qcow.snapshots.is_empty() {
-                                std::fs::remove_file(path)?;
-                            }
-                        }
-                    }
-
-                    self.qcow_path = path.clone();
-                    self.qcow = Some(if path.exists() {
-                        Qcow3::open(path)?
-                    } else {
-                        let minimum_size = self.elements[i].0.os_minimum_size();
-                        // Truncate the minimum size to a power of two for the qcow storage
-                        Qcow3::create(path, minimum_size - (minimum_size % 2))?
-                    });
-
-                    // Revert to the last snapshot if one exists
-                    if let Some(qcow) = &self.qcow {
-                        if let Some(last) = qcow.snapshots.last() {
-                            qcow.revert(&last.name)?;
-                        }
-                    }
-
-                    self.elements[i].0.build(self)?;
-                }
-
-                // Combine per-element disks into a single multiboot disk
-                if let Some(goldboot_efi_path) = goldboot_efi_path {
-                    let sources = qcow_paths
-                        .iter()
-                        .map(Qcow3::open)
-                        .collect::<Result<Vec<_>>>()?;
-                    alloy::merge_qcows(&sources, &alloy_path, &goldboot_efi_path, arch)?;
-                    self.qcow_path = alloy_path;
-                }
-
-                // Re-open qcow to pick up any new snapshots written during the build
-                self.qcow = Some(Qcow3::open(&self.qcow_path)?);
+                // Debug breakpoints prompt on the terminal, one VM at a time
+                let element_count = self.elements.len();
+                let jobs = if self.debug {
+                    1
+                } else {
+                    jobs.unwrap_or(element_count)
+                };
 
                 // Resolve the image tag: CLI override → timestamp default.
-                let resolved_tag = tag
-                    .clone()
-                    .unwrap_or_else(|| Utc::now().format("%Y%m%dT%H%M%S").to_string());
+                let resolved_tag = tag.clone().unwrap_or_else(|| {
+                    DateTime::from_timestamp(timestamp as i64, 0)
+                        .unwrap_or_else(Utc::now)
+                        .format("%Y%m%dT%H%M%S")
+                        .to_string()
+                });
                 validate_ref_segment(&resolved_tag)?;
 
                 // Convert into final immutable image. When --output is given
@@ -286,21 +463,138 @@ impl Builder {
                     library.temporary()
                 };
 
+                // Elements built on a base image record it as their parent
                 let element_headers: Vec<ElementHeader> = self
                     .elements
                     .iter()
-                    .map(|e| ElementHeader::new(e.0.os_name(), e.0.os_name()))
+                    .map(|e| {
+                        let header = ElementHeader::new(e.0.os_name(), e.0.os_name())?;
+                        Ok(match e.0.base_image() {
+                            Some(base) => {
+                                header.with_parent(base.resolve()?.primary_header.content_id)
+                            }
+                            None => header,
+                        })
+                    })
                     .collect::<Result<_>>()?;
 
-                ImageHandle::from_qcow(
-                    &self.name,
-                    &resolved_tag,
-                    element_headers,
-                    self.qcow.as_ref().unwrap(),
-                    &path,
-                    password,
-                    |_, _| {},
-                )?;
+                // A reproducibility check builds everything twice from
+                // scratch, each time into its own scratch qcows so the cached
+                // ones and their checkpoints are left alone
+                let runs = if verify_reproducible { 2 } else { 1 };
+                let mut builds = Vec::with_capacity(runs);
+                for run in 0..runs {
+                    let (qcow_paths, alloy_path) = if verify_reproducible {
+                        info!(
+                            run = run + 1,
+                            "Building from scratch to verify reproducibility"
+                        );
+                        let dir = self.tmp.path().join(format!("verify-{run}"));
+                        std::fs::create_dir_all(&dir)?;
+                        (
+                            (0..element_count)
+                                .map(|i| dir.join(format!("{i}.qcow2")))
+                                .collect::<Vec<_>>(),
+                            dir.join("alloy.qcow2"),
+                        )
+                    } else {
+                        (qcow_paths.clone(), alloy_path.clone())
+                    };
+
+                    if clean {
+                        for path in qcow_paths.iter().chain([&alloy_path]) {
+                            if path.exists() {
+                                std::fs::remove_file(path)?;
+                            }
+                            let state_dir = path.with_extension("state");
+                            if state_dir.exists() {
+                                std::fs::remove_dir_all(state_dir)?;
+                            }
+                        }
+                    }
+
+                    // Each element builds in its own worker. Stale checkpoints
+                    // are discarded up front so that --explain-cache output
+                    // isn't interleaved.
+                    let elements = std::mem::take(&mut self.elements);
+                    let mut workers = Vec::with_capacity(element_count);
+                    for (i, (element, path)) in elements.into_iter().zip(&qcow_paths).enumerate() {
+                        let mut worker = self.element_worker(i, element, path)?;
+                        if element_count > 1 {
+                            worker.failure_dir = self
+                                .failure_dir
+                                .join(format!("{i}-{}", worker.elements[0].0.os_name()));
+                        }
+
+                        // The observer page shows one VM at a time
+                        if jobs == 1 || i == 0 {
+                            worker.observer = self.observer.clone();
+                        }
+
+                        let from = match &from {
+                            Some((element, name)) if *element == i => Some(name.as_str()),
+                            _ => None,
+                        };
+                        let keys = {
+                            let span = info_span!(
+                                "element",
+                                index = i,
+                                name = %worker.elements[0].0.os_name()
+                            );
+                            let _entered = span.enter();
+                            worker.prepare(i, from, explain_cache)?
+                        };
+                        workers.push((worker, keys));
+                    }
+
+                    let result = parallel::build(&mut workers, jobs);
+                    self.elements = workers
+                        .into_iter()
+                        .map(|(mut worker, _)| worker.elements.remove(0))
+                        .collect();
+                    result?;
+
+                    // Combine per-element disks into a single multiboot disk
+                    if let Some(goldboot_efi_path) = &goldboot_efi_path {
+                        let sources = qcow_paths
+                            .iter()
+                            .map(Qcow3::open)
+                            .collect::<Result<Vec<_>>>()?;
+                        alloy::merge_qcows(&sources, &alloy_path, goldboot_efi_path, arch)?;
+                        self.qcow_path = alloy_path.clone();
+                    } else {
+                        self.qcow_path = qcow_paths[0].clone();
+                    }
+
+                    // Re-open qcow to pick up any new snapshots written during the build
+                    self.qcow = Some(Qcow3::open(&self.qcow_path)?);
+
+                    // Only the last build lands at the destination
+                    let image_path = if run + 1 < runs {
+                        self.tmp.path().join(format!("build-{run}.gb"))
+                    } else {
+                        path.clone()
+                    };
+                    builds.push(ImageHandle::from_qcow(
+                        &self.name,
+                        &resolved_tag,
+                        element_headers.clone(),
+                        self.qcow.as_ref().unwrap(),
+                        &image_path,
+                        password.clone(),
+                        &convert_options,
+                        |_, _| {},
+                    )?);
+                }
+
+                if verify_reproducible {
+                    let report =
+                        reproducible::compare(&builds[0], &builds[1], self.qcow.as_ref().unwrap())?;
+                    print!("{report}");
+                    if !report.is_reproducible() {
+                        bail!("The image is not reproducible");
+                    }
+                }
 
                 if output.is_none() {
                     // Freshly-built images have no host — they live directly
@@ -0,0 +1,254 @@
+//! A read-only web page for watching a build as it runs (`--observe`).
+//!
+//! The VNC automation publishes the latest screenshot and its progress
+//! through the boot commands into an [`Observer`], and the page polls for
+//! them along with the tail of the serial console.
+
+use crate::builder::{serial::SerialConnection, vnc::VncScreenshot};
+use anyhow::Result;
+use axum::{
+    Extension, Json, Router,
+    http::{StatusCode, header},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+};
+use serde::Serialize;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::runtime::Runtime;
+use tracing::{info, warn};
+
+/// Minimum time between two published screenshots, which each cost a png
+/// encode.
+const SCREEN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How much serial console output the page shows.
+const SERIAL_TAIL_LEN: usize = 32 * 1024;
+
+/// Build progress as served on `/status`.
+#[derive(Serialize, Clone, Default)]
+pub struct ObserveStatus {
+    /// The boot commands of the running VM, one entry per step.
+    pub commands: Vec<String>,
+
+    /// Index into `commands` of the step being run.
+    pub current: Option<usize>,
+
+    /// Recent serial console output.
+    pub serial: String,
+}
+
+#[derive(Default)]
+struct State {
+    commands: Vec<String>,
+    current: Option<usize>,
+    screen: Option<Vec<u8>>,
+    screen_time: Option<Instant>,
+    serial: Option<SerialConnection>,
+}
+
+/// Shared handle the builder publishes to. Cloning is cheap.
+#[derive(Clone, Default)]
+pub struct Observer {
+    state: Arc<Mutex<State>>,
+}
+
+impl Observer {
+    /// Serve the observation page on `address` from a background thread.
+    pub fn serve(address: SocketAddr) -> Result<Self> {
+        let observer = Self::default();
+
+        let listener = std::net::TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        info!("Observe the build at: http://{}", listener.local_addr()?);
+
+        let router = Router::new()
+            .route("/", get(page))
+            .route("/screen.png", get(screen))
+            .route("/status", get(status))
+            .layer(Extension(observer.clone()));
+
+        std::thread::spawn(move || {
+            Runtime::new().unwrap().block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                if let Err(e) = axum::serve(listener, router).await {
+                    warn!(error = ?e, "Observe server failed");
+                }
+            });
+        });
+
+        Ok(observer)
+    }
+
+    /// Replace the list of boot commands being run.
+    pub fn set_commands(&self, commands: Vec<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.commands = commands;
+        state.current = None;
+    }
+
+    /// Mark the step at `index` as running.
+    pub fn set_current(&self, index: usize) {
+        self.state.lock().unwrap().current = Some(index);
+    }
+
+    /// Publish a screenshot, unless one was published very recently.
+    pub fn publish_screen(&self, screenshot: &VncScreenshot) -> Result<()> {
+        if self
+            .state
+            .lock()
+            .unwrap()
+            .screen_time
+            .is_some_and(|time| time.elapsed() < SCREEN_INTERVAL)
+        {
+            return Ok(());
+        }
+
+        let png = screenshot.encode_png()?;
+        let mut state = self.state.lock().unwrap();
+        state.screen = Some(png);
+        state.screen_time = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Show output from the given serial console.
+    pub fn set_serial(&self, serial: SerialConnection) {
+        self.state.lock().unwrap().serial = Some(serial);
+    }
+
+    pub fn status(&self) -> ObserveStatus {
+        let state = self.state.lock().unwrap();
+        ObserveStatus {
+            commands: state.commands.clone(),
+            current: state.current,
+            serial: state
+                .serial
+                .as_ref()
+                .map(|serial| serial.tail(SERIAL_TAIL_LEN))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+async fn page() -> Html<&'static str> {
+    Html(PAGE)
+}
+
+async fn screen(Extension(observer): Extension<Observer>) -> Response {
+    match observer.state.lock().unwrap().screen.clone() {
+        Some(png) => (
+            [
+                (header::CONTENT_TYPE, "image/png"),
+                (header::CACHE_CONTROL, "no-store"),
+            ],
+            png,
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn status(Extension(observer): Extension<Observer>) -> Json<ObserveStatus> {
+    Json(observer.status())
+}
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>goldboot build</title>
+<style>
+body { background: #111; color: #ddd; font-family: monospace; margin: 1em; }
+#layout { display: flex; gap: 1em; }
+#screen { max-width: 70vw; border: 1px solid #444; }
+#commands { list-style: none; padding: 0; margin: 0; max-height: 90vh; overflow-y: auto; }
+#commands li { white-space: pre; color: #777; }
+#commands li.done { color: #aaa; }
+#commands li.current { color: #fc3; }
+#commands li.current::before { content: "> "; }
+pre { background: #000; padding: 0.5em; max-height: 30vh; overflow-y: auto; }
+</style>
+</head>
+<body>
+<div id="layout">
+<div><img id="screen" alt="Waiting for the first screenshot"></div>
+<ol id="commands"></ol>
+</div>
+<h3>Serial console</h3>
+<pre id="serial"></pre>
+<script>
+async function refresh() {
+  document.getElementById("screen").src = "screen.png?" + Date.now();
+  try {
+    const status = await (await fetch("status")).json();
+    const list = document.getElementById("commands");
+    list.replaceChildren(...status.commands.map((command, i) => {
+      const item = document.createElement("li");
+      item.textContent = command;
+      if (status.current !== null && i < status.current) item.className = "done";
+      if (i === status.current) item.className = "current";
+      return item;
+    }));
+    list.querySelector(".current")?.scrollIntoView({ block: "nearest" });
+    const serial = document.getElementById("serial");
+    const follow = serial.scrollTop + serial.clientHeight >= serial.scrollHeight - 4;
+    serial.textContent = status.serial;
+    if (follow) serial.scrollTop = serial.scrollHeight;
+  } catch (e) {}
+}
+refresh();
+setInterval(refresh, 2000);
+</script>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_screen_and_progress() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let observer = Observer::serve(address).unwrap();
+        let base = format!("http://{address}");
+
+        // Nothing captured yet
+        let resp = reqwest::blocking::get(format!("{base}/screen.png")).unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+
+        observer.set_commands(vec!["1: Enter".into(), "2: Wait(5)".into()]);
+        observer.set_current(1);
+        observer
+            .publish_screen(&VncScreenshot {
+                data: vec![0x40; 16 * 8 * 4],
+                width: 16,
+                height: 8,
+            })
+            .unwrap();
+
+        let png = reqwest::blocking::get(format!("{base}/screen.png"))
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .bytes()
+            .unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+
+        let status: serde_json::Value = reqwest::blocking::get(format!("{base}/status"))
+            .unwrap()
+            .json()
+            .unwrap();
+        assert_eq!(status["commands"][1], "2: Wait(5)");
+        assert_eq!(status["current"], 1);
+
+        let page = reqwest::blocking::get(&base).unwrap().text().unwrap();
+        assert!(page.contains("screen.png"));
+    }
+}
@@ -0,0 +1,1069 @@
+//! Offline steps, which edit an element's disk on the host once the element
+//! is built instead of booting it.
+//!
+//! Each step edits one partition of a raw view of the qcow2. Without root, the
+//! qcow2 is read in-process into a sparse raw copy, whose ext2/3/4 partitions
+//! are edited with `debugfs` (from e2fsprogs) and FAT partitions with the
+//! `fatfs` crate. The clusters that changed are then written back into the
+//! qcow2 with `qemu-img`, which keeps its checkpoints. This needs no
+//! privileges, FUSE or KVM.
+//!
+//! As root, the qcow2 is instead exported with `qemu-storage-daemon` (which
+//! needs its FUSE export and `/dev/fuse`) and each partition is mounted
+//! through a loop device, which supports any filesystem the kernel does.
+//!
+//! ```ron
+//! offline_steps: [
+//!     Copy(src: "motd", dest: "/etc/motd", mode: Some("644")),
+//!     Delete(path: "/etc/machine-id"),
+//!     Chown(path: "/home/user/.ssh", owner: "1000:1000"),
+//!     Cleanup(),
+//! ]
+//! ```
+
+use anyhow::{Context, Result, anyhow, bail};
+use goldboot_image::qcow::Qcow3;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+use crate::builder::{Builder, steps::copy};
+use crate::cli::prompt::Prompt;
+use crate::gpt::{PartitionEntry, read_gpt};
+
+const SECTOR: u64 = 512;
+
+/// A change made directly to a partition of the element's disk. Steps apply to
+/// the last partition on the disk (usually the root filesystem) unless
+/// `partition` gives its 1-based number.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum OfflineStep {
+    /// Copy a file or directory from the context directory. Files keep their
+    /// permissions unless `mode` is given (in octal), and are owned by root
+    /// unless `owner` is given as numeric `uid:gid`.
+    Copy {
+        src: String,
+        dest: String,
+        #[serde(default)]
+        mode: Option<String>,
+        #[serde(default)]
+        owner: Option<String>,
+        #[serde(default)]
+        partition: Option<u32>,
+    },
+
+    /// Delete a file or directory (with its contents).
+    Delete {
+        path: String,
+        #[serde(default)]
+        partition: Option<u32>,
+    },
+
+    /// Set the permissions of a file or directory (in octal).
+    Chmod {
+        path: String,
+        mode: String,
+        #[serde(default)]
+        partition: Option<u32>,
+    },
+
+    /// Set the owner of a file or directory as numeric `uid:gid`.
+    Chown {
+        path: String,
+        owner: String,
+        #[serde(default)]
+        partition: Option<u32>,
+    },
+
+    /// Scrub what makes two installs of the same system differ: empty
+    /// `/etc/machine-id` (so it is generated on first boot) and delete the SSH
+    /// host keys, random seeds and log files.
+    Cleanup {
+        #[serde(default)]
+        partition: Option<u32>,
+    },
+}
+
+impl Prompt for Vec<OfflineStep> {
+    fn prompt(&mut self, _: &Builder) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl OfflineStep {
+    fn partition(&self) -> Option<u32> {
+        match self {
+            Self::Copy { partition, .. }
+            | Self::Delete { partition, .. }
+            | Self::Chmod { partition, .. }
+            | Self::Chown { partition, .. }
+            | Self::Cleanup { partition } => *partition,
+        }
+    }
+
+    fn apply(&self, fs: &mut dyn Filesystem, context_dir: &Path) -> Result<()> {
+        match self {
+            Self::Copy {
+                src,
+                dest,
+                mode,
+                owner,
+                ..
+            } => {
+                info!(%src, %dest, "Running copy offline step");
+                let mode = mode.as_deref().map(parse_mode).transpose()?;
+                let owner = owner.as_deref().map(parse_owner).transpose()?;
+
+                let source = context_dir.join(src);
+                let metadata = std::fs::metadata(&source)?;
+                let dest = guest_path(dest)?;
+
+                let mut copied = vec![(dest.clone(), copy::file_mode(&metadata))];
+                if metadata.is_dir() {
+                    fs.create_dir_all(&dest)?;
+                    for relative in copy::walk(&source)? {
+                        let path = source.join(&relative);
+                        let target = copy::remote_path(&dest, &relative, '/');
+                        if path.is_dir() {
+                            fs.create_dir_all(&target)?;
+                        } else {
+                            fs.write(&target, &std::fs::read(&path)?)?;
+                        }
+                        copied.push((target, copy::file_mode(&std::fs::metadata(&path)?)));
+                    }
+                } else {
+                    if let Some((parent, _)) = dest.rsplit_once('/') {
+                        fs.create_dir_all(parent)?;
+                    }
+                    fs.write(&dest, &std::fs::read(&source)?)?;
+                }
+
+                for (path, host_mode) in copied {
+                    fs.chmod(&path, mode.unwrap_or(host_mode))?;
+                    if let Some((uid, gid)) = owner {
+                        fs.chown(&path, uid, gid)?;
+                    }
+                }
+                Ok(())
+            }
+            Self::Delete { path, .. } => {
+                info!(%path, "Running delete offline step");
+                let path = guest_path(path)?;
+                if path == "/" {
+                    bail!("Refusing to delete the root of a partition");
+                }
+                fs.remove(&path)
+            }
+            Self::Chmod { path, mode, .. } => {
+                info!(%path, %mode, "Running chmod offline step");
+                fs.chmod(&guest_path(path)?, parse_mode(mode)?)
+            }
+            Self::Chown { path, owner, .. } => {
+                info!(%path, %owner, "Running chown offline step");
+                let (uid, gid) = parse_owner(owner)?;
+                fs.chown(&guest_path(path)?, uid, gid)
+            }
+            Self::Cleanup { .. } => {
+                info!("Running cleanup offline step");
+                cleanup(fs)
+            }
+        }
+    }
+}
+
+/// Files the `Cleanup` step deletes.
+const SCRUBBED_FILES: &[&str] = &[
+    "/var/lib/dbus/machine-id",
+    "/var/lib/systemd/random-seed",
+    "/var/lib/systemd/credential.secret",
+    "/var/lib/random-seed",
+    "/var/lib/urandom/random-seed",
+];
+
+fn cleanup(fs: &mut dyn Filesystem) -> Result<()> {
+    if exists(fs, "/etc/machine-id")? {
+        fs.write("/etc/machine-id", b"")?;
+        fs.chmod("/etc/machine-id", 0o444)?;
+    }
+    for path in SCRUBBED_FILES {
+        if exists(fs, path)? {
+            fs.remove(path)?;
+        }
+    }
+    for (name, _) in fs.list("/etc/ssh")?.unwrap_or_default() {
+        if name.starts_with("ssh_host_") {
+            fs.remove(&format!("/etc/ssh/{name}"))?;
+        }
+    }
+    remove_files(fs, "/var/log")
+}
+
+fn exists(fs: &mut dyn Filesystem, path: &str) -> Result<bool> {
+    let (parent, name) = split(path);
+    Ok(fs
+        .list(parent)?
+        .is_some_and(|entries| entries.iter().any(|(entry, _)| entry == name)))
+}
+
+/// Remove the files under `dir`, keeping the directories services expect.
+fn remove_files(fs: &mut dyn Filesystem, dir: &str) -> Result<()> {
+    for (name, is_dir) in fs.list(dir)?.unwrap_or_default() {
+        let path = format!("{dir}/{name}");
+        if is_dir {
+            remove_files(fs, &path)?;
+        } else {
+            fs.remove(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Apply `steps` to the disk of the worker's element. The element's VM must
+/// not be running.
+pub fn run_offline_steps(worker: &Builder, steps: &[OfflineStep]) -> Result<()> {
+    if steps.is_empty() {
+        return Ok(());
+    }
+    worker.set_status("offline steps");
+
+    if unsafe { libc::geteuid() } == 0 {
+        let disk = RawDisk::export(&worker.qcow_path, worker.tmp.path())?;
+        let loop_device = LoopDevice::attach(&disk.path)?;
+        apply_steps(worker, steps, &disk.path, Some(&loop_device))
+    } else {
+        let disk = RawCopy::extract(&worker.qcow_path, worker.tmp.path())?;
+        apply_steps(worker, steps, &disk.path, None)?;
+        disk.write_back(&worker.qcow_path)
+    }
+}
+
+/// Apply `steps` to the raw disk at `disk`, mounting its partitions through
+/// `loop_device` if given.
+fn apply_steps(
+    worker: &Builder,
+    steps: &[OfflineStep],
+    disk: &Path,
+    loop_device: Option<&LoopDevice>,
+) -> Result<()> {
+    let partitions: Vec<PartitionEntry> = read_gpt(&mut File::open(disk)?)?
+        .ok_or_else(|| anyhow!("The element's disk has no GPT"))?
+        .entries
+        .into_iter()
+        .filter(PartitionEntry::is_used)
+        .collect();
+
+    // Steps on the same partition share one open filesystem
+    let mut open: Option<(u32, Box<dyn Filesystem>)> = None;
+    for step in steps {
+        let index = match step.partition() {
+            Some(index) => index,
+            None => {
+                partitions
+                    .last()
+                    .ok_or_else(|| anyhow!("The element's disk has no partitions"))?
+                    .index
+            }
+        };
+
+        if open.as_ref().is_none_or(|(open, _)| *open != index) {
+            if let Some((_, fs)) = open.take() {
+                fs.close()?;
+            }
+            let Some(entry) = partitions.iter().find(|entry| entry.index == index) else {
+                bail!("The element's disk has no partition {index}");
+            };
+            let fs = open_filesystem(disk, loop_device, entry, worker.tmp.path())?;
+            open = Some((index, fs));
+        }
+
+        let (_, fs) = open.as_mut().unwrap();
+        step.apply(fs.as_mut(), &worker.effective_context_dir)?;
+    }
+    if let Some((_, fs)) = open {
+        fs.close()?;
+    }
+    Ok(())
+}
+
+/// A guest path made absolute, without a trailing separator or `..`.
+fn guest_path(path: &str) -> Result<String> {
+    let components: Vec<&str> = path
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect();
+    if components.contains(&"..") {
+        bail!("Offline step paths cannot contain '..': {path}");
+    }
+    Ok(format!("/{}", components.join("/")))
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(mode, 8).with_context(|| format!("Invalid mode: {mode}"))?;
+    if mode > 0o7777 {
+        bail!("Invalid mode: {mode:o}");
+    }
+    Ok(mode)
+}
+
+fn parse_owner(owner: &str) -> Result<(u32, u32)> {
+    owner
+        .split_once(':')
+        .and_then(|(uid, gid)| Some((uid.parse().ok()?, gid.parse().ok()?)))
+        .ok_or_else(|| anyhow!("Offline step owners must be numeric uid:gid, not '{owner}'"))
+}
+
+/// Operations the steps need from a mounted partition. Paths are absolute
+/// within the partition.
+trait Filesystem {
+    /// Create a directory along with any missing parents.
+    fn create_dir_all(&mut self, path: &str) -> Result<()>;
+
+    /// Create or replace a file.
+    fn write(&mut self, path: &str, data: &[u8]) -> Result<()>;
+
+    /// Remove a file, or a directory with its contents.
+    fn remove(&mut self, path: &str) -> Result<()>;
+
+    /// The names of the entries in a directory and whether each is a
+    /// directory, or `None` if it doesn't exist.
+    fn list(&mut self, dir: &str) -> Result<Option<Vec<(String, bool)>>>;
+
+    fn chmod(&mut self, path: &str, mode: u32) -> Result<()>;
+
+    fn chown(&mut self, path: &str, uid: u32, gid: u32) -> Result<()>;
+
+    /// Flush the changes and release the partition.
+    fn close(self: Box<Self>) -> Result<()>;
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum FilesystemType {
+    Ext,
+    Fat,
+    Other,
+}
+
+/// Identify the filesystem at `offset` from its superblock.
+fn detect<D: Read + Seek>(disk: &mut D, offset: u64) -> Result<FilesystemType> {
+    let mut superblock = [0u8; 0x440];
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.read_exact(&mut superblock)?;
+
+    Ok(if superblock[0x438..0x43a] == [0x53, 0xef] {
+        FilesystemType::Ext
+    } else if &superblock[0x52..0x57] == b"FAT32" || &superblock[0x36..0x39] == b"FAT" {
+        FilesystemType::Fat
+    } else {
+        FilesystemType::Other
+    })
+}
+
+fn open_filesystem(
+    disk: &Path,
+    loop_device: Option<&LoopDevice>,
+    entry: &PartitionEntry,
+    tmp: &Path,
+) -> Result<Box<dyn Filesystem>> {
+    let start = entry.first_lba * SECTOR;
+    let end = (entry.last_lba + 1) * SECTOR;
+
+    if let Some(loop_device) = loop_device {
+        let device = crate::fs::partition_device_path(&loop_device.device, entry.index);
+        return Ok(Box::new(Mounted::mount(
+            &device,
+            &tmp.join(format!("partition{}", entry.index)),
+        )?));
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(disk)?;
+    match detect(&mut file, start)? {
+        FilesystemType::Ext => Ok(Box::new(Debugfs {
+            device: format!("{}?offset={start}", disk.display()),
+            scratch: tmp.join("offline-step"),
+        })),
+        FilesystemType::Fat => Ok(Box::new(Fat(fatfs::FileSystem::new(
+            fscommon::StreamSlice::new(file, start, end)?,
+            fatfs::FsOptions::new(),
+        )?))),
+        FilesystemType::Other => bail!(
+            "Partition {} is neither ext nor FAT; editing it requires root",
+            entry.index
+        ),
+    }
+}
+
+/// A writable raw view of a qcow2, exported over FUSE by `qemu-storage-daemon`
+/// until dropped.
+struct RawDisk {
+    path: PathBuf,
+    pid: i32,
+}
+
+impl RawDisk {
+    fn export(qcow_path: &Path, tmp: &Path) -> Result<Self> {
+        let path = tmp.join("disk.raw");
+        let pid_file = tmp.join("qemu-storage-daemon.pid");
+        File::create(&path)?;
+
+        debug!(qcow = %qcow_path.display(), "Exporting the element's disk");
+        let status = Command::new("qemu-storage-daemon")
+            .arg("--blockdev")
+            .arg(format!(
+                "driver=file,node-name=file,filename={}",
+                escape_option(qcow_path)
+            ))
+            .args(["--blockdev", "driver=qcow2,node-name=disk,file=file"])
+            .arg("--export")
+            .arg(format!(
+                "type=fuse,id=disk,node-name=disk,mountpoint={},writable=on",
+                escape_option(&path)
+            ))
+            .arg("--pidfile")
+            .arg(&pid_file)
+            .arg("--daemonize")
+            .stdout(Stdio::null())
+            .status()
+            .context(
+                "failed to run qemu-storage-daemon (offline steps need it when run as root)",
+            )?;
+        if !status.success() {
+            bail!(
+                "qemu-storage-daemon failed to export the element's disk; offline steps run as root need its FUSE export and access to /dev/fuse"
+            );
+        }
+
+        let pid = std::fs::read_to_string(&pid_file)?.trim().parse()?;
+        Ok(Self { path, pid })
+    }
+}
+
+impl Drop for RawDisk {
+    fn drop(&mut self) {
+        // The daemon flushes the qcow2 and removes the export as it exits
+        unsafe { libc::kill(self.pid, libc::SIGTERM) };
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while Path::new(&format!("/proc/{}", self.pid)).exists() {
+            if Instant::now() > deadline {
+                warn!(pid = self.pid, "qemu-storage-daemon did not exit");
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+/// A sparse raw copy of a qcow2 for editing without privileges, removed when
+/// dropped.
+struct RawCopy {
+    path: PathBuf,
+}
+
+impl RawCopy {
+    fn extract(qcow_path: &Path, tmp: &Path) -> Result<Self> {
+        let qcow = Qcow3::open(qcow_path)?;
+        let size = qcow.header.size;
+        let copy = Self {
+            path: tmp.join("disk.raw"),
+        };
+
+        debug!(qcow = %qcow_path.display(), "Copying the element's disk");
+        let mut reader = qcow.reader()?;
+        let mut file = File::create(&copy.path)?;
+        file.set_len(size)?;
+
+        let mut cluster = vec![0u8; qcow.header.cluster_size() as usize];
+        let mut offset = 0;
+        while offset < size {
+            let len = (cluster.len() as u64).min(size - offset) as usize;
+            reader.read_exact(&mut cluster[..len])?;
+            // Leave holes where the disk is zero
+            if cluster[..len].iter().any(|byte| *byte != 0) {
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(&cluster[..len])?;
+            }
+            offset += len as u64;
+        }
+        Ok(copy)
+    }
+
+    /// Write the clusters that differ from the qcow2 back into it in place.
+    fn write_back(self, qcow_path: &Path) -> Result<()> {
+        // An overlay of the copy rebased onto the qcow2 holds exactly the
+        // changed clusters, and committing it writes them into the qcow2
+        let qcow_path = std::fs::canonicalize(qcow_path)?;
+        let overlay = self.path.with_extension("qcow2");
+        debug!(qcow = %qcow_path.display(), "Writing the offline steps back");
+
+        let result = qemu_img(
+            Command::new("qemu-img")
+                .args(["create", "-f", "qcow2", "-b"])
+                .arg(&self.path)
+                .args(["-F", "raw"])
+                .arg(&overlay),
+        )
+        .and_then(|_| {
+            qemu_img(
+                Command::new("qemu-img")
+                    .args(["rebase", "-f", "qcow2", "-b"])
+                    .arg(&qcow_path)
+                    .args(["-F", "qcow2"])
+                    .arg(&overlay),
+            )
+        })
+        .and_then(|_| {
+            qemu_img(
+                Command::new("qemu-img")
+                    .args(["commit", "-f", "qcow2"])
+                    .arg(&overlay),
+            )
+        });
+        let _ = std::fs::remove_file(&overlay);
+        result
+    }
+}
+
+impl Drop for RawCopy {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Run a `qemu-img` command.
+fn qemu_img(command: &mut Command) -> Result<()> {
+    let status = command
+        .stdout(Stdio::null())
+        .status()
+        .context("failed to run qemu-img")?;
+    if !status.success() {
+        bail!("{command:?} failed");
+    }
+    Ok(())
+}
+
+/// Escape a path for a QEMU option value.
+fn escape_option(path: &Path) -> String {
+    path.display().to_string().replace(',', ",,")
+}
+
+/// A loop device with partitions over a raw disk, detached when dropped.
+struct LoopDevice {
+    device: PathBuf,
+}
+
+impl LoopDevice {
+    fn attach(path: &Path) -> Result<Self> {
+        let output = Command::new("losetup")
+            .args(["--find", "--show", "--partscan"])
+            .arg(path)
+            .output()
+            .context("failed to run losetup")?;
+        if !output.status.success() {
+            bail!(
+                "losetup failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let device = PathBuf::from(String::from_utf8(output.stdout)?.trim());
+        let _ = Command::new("udevadm").arg("settle").status();
+        debug!(device = %device.display(), "Attached loop device");
+        Ok(Self { device })
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        match Command::new("losetup").arg("-d").arg(&self.device).status() {
+            Ok(status) if status.success() => {}
+            _ => warn!(device = %self.device.display(), "Failed to detach loop device"),
+        }
+    }
+}
+
+/// A partition mounted through a loop device.
+struct Mounted {
+    mountpoint: PathBuf,
+    mounted: bool,
+}
+
+impl Mounted {
+    fn mount(device: &Path, mountpoint: &Path) -> Result<Self> {
+        std::fs::create_dir_all(mountpoint)?;
+        let status = Command::new("mount")
+            .arg(device)
+            .arg(mountpoint)
+            .status()
+            .context("failed to run mount")?;
+        if !status.success() {
+            bail!("Failed to mount {}", device.display());
+        }
+        Ok(Self {
+            mountpoint: mountpoint.to_path_buf(),
+            mounted: true,
+        })
+    }
+
+    fn host_path(&self, path: &str) -> PathBuf {
+        self.mountpoint.join(path.trim_start_matches('/'))
+    }
+}
+
+impl Filesystem for Mounted {
+    fn create_dir_all(&mut self, path: &str) -> Result<()> {
+        Ok(std::fs::create_dir_all(self.host_path(path))?)
+    }
+
+    fn write(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        Ok(std::fs::write(self.host_path(path), data)?)
+    }
+
+    fn remove(&mut self, path: &str) -> Result<()> {
+        let path = self.host_path(path);
+        if std::fs::symlink_metadata(&path)?.is_dir() {
+            std::fs::remove_dir_all(path)?;
+        } else {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn list(&mut self, dir: &str) -> Result<Option<Vec<(String, bool)>>> {
+        let entries = match std::fs::read_dir(self.host_path(dir)) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let mut list = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            list.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                entry.file_type()?.is_dir(),
+            ));
+        }
+        Ok(Some(list))
+    }
+
+    fn chmod(&mut self, path: &str, mode: u32) -> Result<()> {
+        Ok(std::fs::set_permissions(
+            self.host_path(path),
+            std::fs::Permissions::from_mode(mode),
+        )?)
+    }
+
+    fn chown(&mut self, path: &str, uid: u32, gid: u32) -> Result<()> {
+        Ok(std::os::unix::fs::chown(
+            self.host_path(path),
+            Some(uid),
+            Some(gid),
+        )?)
+    }
+
+    fn close(mut self: Box<Self>) -> Result<()> {
+        let status = Command::new("umount").arg(&self.mountpoint).status()?;
+        if !status.success() {
+            bail!("Failed to unmount {}", self.mountpoint.display());
+        }
+        self.mounted = false;
+        Ok(())
+    }
+}
+
+impl Drop for Mounted {
+    fn drop(&mut self) {
+        // A step failed before the partition was closed
+        if self.mounted {
+            let _ = Command::new("umount").arg(&self.mountpoint).status();
+        }
+    }
+}
+
+/// An ext2/3/4 partition edited with `debugfs`.
+struct Debugfs {
+    /// The raw disk with the partition's offset
+    device: String,
+
+    /// Where file contents are staged for `debugfs write`
+    scratch: PathBuf,
+}
+
+impl Debugfs {
+    /// Run `commands` in one `debugfs` session, returning its output and the
+    /// errors it reported.
+    fn run(&self, commands: &[String]) -> Result<(String, Vec<String>)> {
+        debug!(?commands, "Running debugfs");
+        let mut child = Command::new("debugfs")
+            .args(["-w", "-f", "-"])
+            .arg(&self.device)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("failed to run debugfs (offline steps on ext partitions need e2fsprogs unless run as root)")?;
+
+        let mut stdin = child.stdin.take().unwrap();
+        for command in commands {
+            writeln!(stdin, "{command}")?;
+        }
+        drop(stdin);
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            bail!(
+                "debugfs failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        // The version banner is the only stderr output that isn't an error
+        let errors = String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with("debugfs "))
+            .map(str::to_string)
+            .collect();
+        Ok((String::from_utf8(output.stdout)?, errors))
+    }
+
+    fn run_checked(&self, commands: &[String]) -> Result<String> {
+        let (output, errors) = self.run(commands)?;
+        if !errors.is_empty() {
+            bail!("debugfs failed: {}", errors.join("; "));
+        }
+        Ok(output)
+    }
+
+    /// The directory entries in `dir` with their inode modes, or `None` if
+    /// it doesn't exist.
+    fn entries(&self, dir: &str) -> Result<Option<Vec<(String, u32)>>> {
+        let (output, errors) = self.run(&[format!("ls -p {}", quote(dir)?)])?;
+        if errors.iter().any(|error| error.contains("not found")) {
+            return Ok(None);
+        }
+        if !errors.is_empty() {
+            bail!("debugfs failed: {}", errors.join("; "));
+        }
+        Ok(Some(parse_listing(&output)))
+    }
+
+    /// The inode mode of `path`, if it exists.
+    fn mode(&self, path: &str) -> Result<Option<u32>> {
+        let (parent, name) = split(path);
+        Ok(self
+            .entries(parent)?
+            .unwrap_or_default()
+            .into_iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, mode)| mode))
+    }
+}
+
+/// The entries of `debugfs ls -p` output, which look like
+/// `/inode/mode/uid/gid/name/size/`.
+fn parse_listing(output: &str) -> Vec<(String, u32)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.strip_prefix('/')?.split('/').collect();
+            let (mode, name) = (fields.get(1)?, fields.get(4)?);
+            if *name == "." || *name == ".." {
+                return None;
+            }
+            Some((name.to_string(), u32::from_str_radix(mode, 8).ok()?))
+        })
+        .collect()
+}
+
+/// Split an absolute path into its parent directory and name.
+fn split(path: &str) -> (&str, &str) {
+    match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((parent, name)) => (parent, name),
+        None => ("/", path),
+    }
+}
+
+/// Quote an argument for `debugfs`, which has no escapes.
+fn quote(value: &str) -> Result<String> {
+    if value.contains(['"', '\n']) {
+        bail!("debugfs cannot handle the path {value:?}");
+    }
+    Ok(format!("\"{value}\""))
+}
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+impl Filesystem for Debugfs {
+    fn create_dir_all(&mut self, path: &str) -> Result<()> {
+        let mut current = String::new();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            current = format!("{current}/{component}");
+            match self.mode(&current)? {
+                Some(mode) if mode & S_IFMT == S_IFDIR => {}
+                Some(_) => bail!("{current} exists and is not a directory"),
+                None => {
+                    self.run_checked(&[format!("mkdir {}", quote(&current)?)])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        if self.mode(path)?.is_some() {
+            self.remove(path)?;
+        }
+
+        // `write` creates its file in the current directory
+        std::fs::write(&self.scratch, data)?;
+        let (parent, name) = split(path);
+        let name = quote(name)?;
+        self.run_checked(&[
+            format!("cd {}", quote(parent)?),
+            format!(
+                "write {} {name}",
+                quote(&self.scratch.display().to_string())?
+            ),
+            format!("sif {name} uid 0"),
+            format!("sif {name} gid 0"),
+        ])?;
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &str) -> Result<()> {
+        let Some(mode) = self.mode(path)? else {
+            bail!("{path} does not exist");
+        };
+        if mode & S_IFMT == S_IFDIR {
+            for (name, _) in self.entries(path)?.unwrap_or_default() {
+                self.remove(&format!("{path}/{name}"))?;
+            }
+            self.run_checked(&[format!("rmdir {}", quote(path)?)])?;
+        } else {
+            self.run_checked(&[format!("rm {}", quote(path)?)])?;
+        }
+        Ok(())
+    }
+
+    fn list(&mut self, dir: &str) -> Result<Option<Vec<(String, bool)>>> {
+        Ok(self.entries(dir)?.map(|entries| {
+            entries
+                .into_iter()
+                .map(|(name, mode)| (name, mode & S_IFMT == S_IFDIR))
+                .collect()
+        }))
+    }
+
+    fn chmod(&mut self, path: &str, mode: u32) -> Result<()> {
+        if path == "/" {
+            bail!("Changing the root of a partition is not supported without root");
+        }
+        let Some(current) = self.mode(path)? else {
+            bail!("{path} does not exist");
+        };
+        self.run_checked(&[format!(
+            "sif {} mode 0{:o}",
+            quote(path)?,
+            (current & S_IFMT) | mode
+        )])?;
+        Ok(())
+    }
+
+    fn chown(&mut self, path: &str, uid: u32, gid: u32) -> Result<()> {
+        let path = quote(path)?;
+        self.run_checked(&[
+            format!("sif {path} uid {uid}"),
+            format!("sif {path} gid {gid}"),
+        ])?;
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) -> Result<()> {
+        if self.scratch.exists() {
+            std::fs::remove_file(&self.scratch)?;
+        }
+        Ok(())
+    }
+}
+
+/// A FAT partition, which has no permissions or owners.
+struct Fat(fatfs::FileSystem<fscommon::StreamSlice<File>>);
+
+impl Filesystem for Fat {
+    fn create_dir_all(&mut self, path: &str) -> Result<()> {
+        let mut dir = self.0.root_dir();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            // Opens the directory if it exists
+            dir = dir.create_dir(component)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        let mut file = self
+            .0
+            .root_dir()
+            .create_file(path.trim_start_matches('/'))?;
+        file.truncate()?;
+        file.write_all(data)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &str) -> Result<()> {
+        remove_fat_entry(&self.0.root_dir(), path.trim_start_matches('/'))
+    }
+
+    fn list(&mut self, dir: &str) -> Result<Option<Vec<(String, bool)>>> {
+        let root = self.0.root_dir();
+        let dir = match dir.trim_start_matches('/') {
+            "" => root,
+            path => match root.open_dir(path) {
+                Ok(dir) => dir,
+                Err(_) => return Ok(None),
+            },
+        };
+        let mut list = Vec::new();
+        for entry in dir.iter() {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name != "." && name != ".." {
+                list.push((name, entry.is_dir()));
+            }
+        }
+        Ok(Some(list))
+    }
+
+    fn chmod(&mut self, path: &str, _: u32) -> Result<()> {
+        debug!(%path, "Ignoring mode on FAT");
+        Ok(())
+    }
+
+    fn chown(&mut self, path: &str, _: u32, _: u32) -> Result<()> {
+        bail!("Cannot set the owner of {path}: FAT has no owners")
+    }
+
+    fn close(self: Box<Self>) -> Result<()> {
+        Ok(self.0.unmount()?)
+    }
+}
+
+fn remove_fat_entry<T: fatfs::ReadWriteSeek>(root: &fatfs::Dir<'_, T>, path: &str) -> Result<()> {
+    if let Ok(dir) = root.open_dir(path) {
+        let names: Vec<String> = dir
+            .iter()
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<Result<_>>()?;
+        for name in names.iter().filter(|name| *name != "." && *name != "..") {
+            remove_fat_entry(root, &format!("{path}/{name}"))?;
+        }
+    }
+    Ok(root.remove(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn parse_offline_steps() -> Result<()> {
+        let steps: Vec<OfflineStep> = ron::from_str(
+            r#"[
+                Copy(src: "motd", dest: "/etc/motd", mode: Some("644")),
+                Delete(path: "/etc/machine-id", partition: Some(3)),
+                Chown(path: "/home/user", owner: "1000:1000"),
+                Cleanup(),
+            ]"#,
+        )?;
+        assert_eq!(steps.len(), 4);
+        assert_eq!(steps[3].partition(), None);
+        assert_eq!(steps[0].partition(), None);
+        assert_eq!(steps[1].partition(), Some(3));
+
+        assert_eq!(parse_mode("0755")?, 0o755);
+        assert!(parse_mode("999").is_err());
+        assert_eq!(parse_owner("1000:100")?, (1000, 100));
+        assert!(parse_owner("user:user").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn normalize_guest_paths() -> Result<()> {
+        assert_eq!(guest_path("etc//ssh/")?, "/etc/ssh");
+        assert_eq!(guest_path("/./")?, "/");
+        assert!(guest_path("/etc/../root").is_err());
+        assert_eq!(split("/etc/ssh"), ("/etc", "ssh"));
+        assert_eq!(split("/etc"), ("/", "etc"));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_debugfs_listing() {
+        let output = "debugfs: ls -p \"/etc\"\n\
+            /12/040755/0/0/./\n\
+            /2/040755/0/0/../\n\
+            /13/100644/0/0/motd/21/\n\
+            /14/040700/1000/1000/ssh//\n";
+        assert_eq!(
+            parse_listing(output),
+            vec![
+                ("motd".to_string(), 0o100644),
+                ("ssh".to_string(), 0o040700)
+            ]
+        );
+    }
+
+    #[test]
+    fn write_back_raw_copy() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let qcow_path = tmp.path().join("element.qcow2");
+        let qcow = Qcow3::create(&qcow_path, 4 * 1024 * 1024)?;
+        qcow.create_snapshot(crate::builder::checkpoint::INSTALL)?;
+
+        let copy = RawCopy::extract(&qcow_path, tmp.path())?;
+        let mut file = OpenOptions::new().write(true).open(&copy.path)?;
+        file.seek(SeekFrom::Start(1024 * 1024))?;
+        file.write_all(b"goldboot")?;
+        drop(file);
+        copy.write_back(&qcow_path)?;
+        assert!(!tmp.path().join("disk.raw").exists());
+
+        let qcow = Qcow3::open(&qcow_path)?;
+        let mut contents = Vec::new();
+        qcow.reader()?.read_to_end(&mut contents)?;
+        assert_eq!(&contents[1024 * 1024..][..8], b"goldboot");
+        assert_eq!(contents.iter().filter(|byte| **byte != 0).count(), 8);
+
+        // Only the changed cluster is written and the checkpoint survives
+        assert_eq!(qcow.count_clusters()?, 1);
+        assert!(
+            qcow.snapshot_by_name(crate::builder::checkpoint::INSTALL)
+                .is_some()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn detect_filesystems() -> Result<()> {
+        let mut ext = vec![0u8; 4096];
+        ext[0x438..0x43a].copy_from_slice(&[0x53, 0xef]);
+        assert_eq!(detect(&mut Cursor::new(ext), 0)?, FilesystemType::Ext);
+
+        let mut fat = vec![0u8; 8192];
+        fat[4096 + 0x52..4096 + 0x57].copy_from_slice(b"FAT32");
+        assert_eq!(detect(&mut Cursor::new(fat), 4096)?, FilesystemType::Fat);
+
+        assert_eq!(
+            detect(&mut Cursor::new(vec![0u8; 4096]), 0)?,
+            FilesystemType::Other
+        );
+        Ok(())
+    }
+}
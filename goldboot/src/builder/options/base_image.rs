@@ -0,0 +1,82 @@
+use crate::{
+    builder::Builder,
+    cli::prompt::Prompt,
+    library::ImageLibrary,
+    registry::{Client, ImageRef},
+};
+use anyhow::{Result, bail};
+use goldboot_image::ImageHandle;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// A previously built goldboot image to start from, like a Dockerfile
+/// `FROM`. Library references (`name:tag`) are opened from the local
+/// library and registry references (`host/name:tag`) are pulled into it
+/// first unless they were pulled before.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct BaseImage {
+    /// The image reference
+    pub image: String,
+
+    /// Password if the base image is encrypted
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl BaseImage {
+    /// Locate the base image in the library, pulling it if necessary.
+    pub fn resolve(&self) -> Result<ImageHandle> {
+        let r = ImageRef::parse(&self.image)?;
+        r.validate()?;
+
+        let library = ImageLibrary::open();
+        if let Ok(image) = library.find_by_ref(&r) {
+            return Ok(image);
+        }
+
+        let Some(host) = r.host.clone() else {
+            bail!("Base image '{r}' is not in the library");
+        };
+        let Some(tag) = r.tag.clone() else {
+            bail!("Base image '{r}' needs a tag to be pulled from its registry");
+        };
+
+        // Registries that require credentials need a `goldboot image pull`
+        // beforehand
+        info!(image = %r, "Pulling base image");
+        let tmp = library.temporary();
+        if let Err(e) = Client::new(&host, None)?.pull_to_file(&r.name, &tag, &tmp) {
+            let _ = std::fs::remove_file(&tmp);
+            bail!("Failed to pull base image '{r}': {e}");
+        }
+        ImageHandle::open(library.add_built(&tmp, &r)?)
+    }
+
+    /// Resolve and decrypt the base image.
+    pub fn load(&self) -> Result<ImageHandle> {
+        let mut image = self.resolve()?;
+        image.load(self.password.clone())?;
+        Ok(image)
+    }
+}
+
+impl Prompt for BaseImage {
+    fn prompt(&mut self, _: &Builder) -> Result<()> {
+        use dialoguer::Input;
+        let theme = crate::cli::cmd::init::theme();
+
+        loop {
+            let image: String = Input::with_theme(&theme)
+                .with_prompt("Base image (name:tag or host/name:tag)")
+                .interact_text()?;
+
+            match ImageRef::parse(&image).and_then(|r| r.validate()) {
+                Ok(_) => {
+                    self.image = image;
+                    return Ok(());
+                }
+                Err(e) => eprintln!("Invalid image reference: {e}"),
+            }
+        }
+    }
+}
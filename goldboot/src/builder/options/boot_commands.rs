@@ -0,0 +1,189 @@
+use crate::{
+    builder::{
+        Builder,
+        vnc::{ReferencePng, VncCmd},
+    },
+    cli::prompt::Prompt,
+};
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// A key that can be pressed by a boot command.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum BootKey {
+    Enter,
+    Escape,
+    LeftSuper,
+    Spacebar,
+    Tab,
+}
+
+/// One step of a scripted installer, the serializable equivalent of a
+/// [`VncCmd`].
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum BootCommand {
+    /// Type the given text. A `\n` presses enter.
+    Type(String),
+
+    /// Press a key.
+    Key(BootKey),
+
+    /// Wait the given number of seconds.
+    Wait(u64),
+
+    /// Wait for the screen to match a hash captured with `--debug`.
+    WaitScreen(String),
+
+    /// Wait for the screen to be at least `threshold` (0.0 - 1.0) similar to
+    /// a reference png in the context directory.
+    WaitScreenSimilar { reference: PathBuf, threshold: f32 },
+
+    /// Wait for text matching the given regex to appear on screen via OCR.
+    WaitOcr(String),
+
+    /// Wait for text matching the given regex to appear on the serial
+    /// console.
+    WaitSerial(String),
+
+    /// Run a wait with its own timeout in seconds.
+    Timeout(u64, Box<BootCommand>),
+}
+
+impl BootCommand {
+    fn validate(&self) -> Result<()> {
+        match self {
+            Self::WaitScreenSimilar { threshold, .. } if !(0.0..=1.0).contains(threshold) => {
+                bail!("Similarity threshold must be between 0.0 and 1.0: {threshold}")
+            }
+            Self::WaitOcr(pattern) | Self::WaitSerial(pattern) => {
+                regex::Regex::new(pattern)?;
+            }
+            Self::Timeout(_, command) => command.validate()?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn to_vnc(&self, context_dir: &Path) -> Result<VncCmd> {
+        Ok(match self {
+            Self::Type(text) => VncCmd::Type(text.clone()),
+            Self::Key(BootKey::Enter) => VncCmd::Enter,
+            Self::Key(BootKey::Escape) => VncCmd::Escape,
+            Self::Key(BootKey::LeftSuper) => VncCmd::LeftSuper,
+            Self::Key(BootKey::Spacebar) => VncCmd::Spacebar,
+            Self::Key(BootKey::Tab) => VncCmd::Tab,
+            Self::Wait(seconds) => VncCmd::Wait(*seconds),
+            Self::WaitScreen(hash) => VncCmd::WaitScreen(hash.clone()),
+            Self::WaitScreenSimilar {
+                reference,
+                threshold,
+            } => {
+                let path = context_dir.join(reference);
+                let png = std::fs::read(&path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                VncCmd::WaitScreenSimilar(ReferencePng(png.into()), *threshold)
+            }
+            Self::WaitOcr(pattern) => VncCmd::WaitTextOcr(pattern.clone()),
+            Self::WaitSerial(pattern) => VncCmd::WaitTextSerial(pattern.clone()),
+            Self::Timeout(seconds, command) => {
+                VncCmd::WithTimeout(*seconds, Box::new(command.to_vnc(context_dir)?))
+            }
+        })
+    }
+}
+
+/// A script of boot commands that drives an installer over VNC.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct BootCommands(pub Vec<BootCommand>);
+
+impl BootCommands {
+    /// Check for invalid patterns and thresholds before the VM boots.
+    pub fn validate(&self) -> Result<()> {
+        for (i, command) in self.0.iter().enumerate() {
+            if let Err(e) = command.validate() {
+                bail!("Invalid boot command {} ({command:?}): {e}", i + 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert to VNC commands, one group per boot command so errors refer
+    /// to the same numbering as the script. Reference screenshots are read
+    /// from `context_dir`.
+    pub fn to_vnc(&self, context_dir: &Path) -> Result<Vec<Vec<VncCmd>>> {
+        self.0
+            .iter()
+            .map(|command| Ok(vec![command.to_vnc(context_dir)?]))
+            .collect()
+    }
+}
+
+impl Prompt for BootCommands {
+    fn prompt(&mut self, _: &Builder) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_convert() -> Result<()> {
+        let commands: BootCommands = ron::from_str(
+            r#"[
+                Wait(10),
+                WaitOcr("login:"),
+                Type("root\n"),
+                Key(Tab),
+                WaitScreenSimilar(reference: "screens/done.png", threshold: 0.95),
+                Timeout(7200, WaitSerial("Installation complete")),
+            ]"#,
+        )?;
+        commands.validate()?;
+
+        let context = tempfile::tempdir()?;
+        std::fs::create_dir(context.path().join("screens"))?;
+        std::fs::write(context.path().join("screens/done.png"), b"png")?;
+        let vnc = commands.to_vnc(context.path())?;
+        assert_eq!(vnc.len(), 6);
+        assert!(matches!(vnc[1][0], VncCmd::WaitTextOcr(ref p) if p == "login:"));
+        assert!(matches!(vnc[3][0], VncCmd::Tab));
+        assert!(matches!(
+            vnc[4][0],
+            VncCmd::WaitScreenSimilar(ref png, t) if *png.0 == *b"png" && t == 0.95
+        ));
+        assert!(matches!(
+            &vnc[5][0],
+            VncCmd::WithTimeout(7200, inner) if matches!(**inner, VncCmd::WaitTextSerial(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_commands() {
+        let commands = BootCommands(vec![
+            BootCommand::Wait(1),
+            BootCommand::WaitSerial("(unclosed".into()),
+        ]);
+        let err = commands.validate().unwrap_err();
+        assert!(
+            err.to_string().starts_with("Invalid boot command 2"),
+            "{err}"
+        );
+
+        let commands = BootCommands(vec![BootCommand::WaitScreenSimilar {
+            reference: "x.png".into(),
+            threshold: 1.5,
+        }]);
+        assert!(commands.validate().is_err());
+
+        // A missing reference fails before the VM boots
+        let commands = BootCommands(vec![BootCommand::WaitScreenSimilar {
+            reference: "missing.png".into(),
+            threshold: 0.9,
+        }]);
+        assert!(commands.to_vnc(Path::new("/nonexistent")).is_err());
+    }
+}
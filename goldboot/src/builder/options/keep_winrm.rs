@@ -0,0 +1,23 @@
+use crate::{builder::Builder, cli::prompt::Prompt};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use smart_default::SmartDefault;
+
+/// Whether to leave WinRM enabled (over unencrypted HTTP with basic
+/// authentication) in the image after the post-steps have used it.
+#[derive(Clone, Serialize, Deserialize, Debug, SmartDefault)]
+pub struct KeepWinrm(#[default(false)] pub bool);
+
+impl Prompt for KeepWinrm {
+    fn prompt(&mut self, _: &Builder) -> Result<()> {
+        use dialoguer::Confirm;
+        let theme = crate::cli::cmd::init::theme();
+
+        self.0 = Confirm::with_theme(&theme)
+            .with_prompt("Leave unencrypted WinRM enabled in the image?")
+            .default(self.0)
+            .interact()?;
+
+        Ok(())
+    }
+}
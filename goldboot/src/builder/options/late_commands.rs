@@ -0,0 +1,14 @@
+use crate::{builder::Builder, cli::prompt::Prompt};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Shell commands that run as root in the installed system at the end of an
+/// unattended install.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct LateCommands(pub Vec<String>);
+
+impl Prompt for LateCommands {
+    fn prompt(&mut self, _: &Builder) -> Result<()> {
+        Ok(())
+    }
+}
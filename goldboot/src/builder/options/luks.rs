@@ -6,6 +6,9 @@ use smart_default::SmartDefault;
 use validator::Validate;
 
 /// Configures a LUKS encrypted root filesystem.
+///
+/// Deprecated in favor of [`PartitionLayout::UefiLuks`](super::partition_layout::PartitionLayout::UefiLuks),
+/// which elements that still accept it map it to.
 #[derive(Clone, Serialize, Deserialize, Validate, Debug, SmartDefault)]
 pub struct Luks {
     /// The LUKS passphrase
@@ -1,6 +1,10 @@
 pub mod arch;
+pub mod base_image;
+pub mod boot_commands;
 pub mod hostname;
 pub mod iso;
+pub mod keep_winrm;
+pub mod late_commands;
 pub mod locale;
 pub mod luks;
 pub mod minimum_size;
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{Result, bail};
 use serde::{Deserialize, Serialize};
 use smart_default::SmartDefault;
 
@@ -20,9 +20,185 @@ pub enum PartitionLayout {
 
     /// GPT: 512 MB EFI + LUKS2-encrypted ext4 root
     UefiLuks { passphrase: String },
+
+    /// GPT: 512 MB EFI + LVM volume group holding an ext4 root and an
+    /// optional swap volume
+    UefiLvm {
+        /// Name of the volume group
+        #[serde(default = "default_volume_group")]
+        volume_group: String,
+
+        /// Swap volume size in MiB
+        #[serde(default)]
+        swap_size_mib: Option<u64>,
+    },
+
+    /// GPT: 512 MB EFI + btrfs root split into subvolumes
+    UefiBtrfs {
+        #[serde(default = "default_subvolumes")]
+        subvolumes: Vec<Subvolume>,
+    },
+
+    /// GPT: 512 MB EFI + XFS root
+    UefiXfs,
+
+    /// GPT: the given partitions, in order
+    Custom { partitions: Vec<Partition> },
+}
+
+/// A btrfs subvolume and where it's mounted.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Subvolume {
+    pub name: String,
+    pub mountpoint: String,
+}
+
+/// A partition of a [`PartitionLayout::Custom`] layout.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Partition {
+    /// Size in MiB. Only the last partition may omit it to take the rest of
+    /// the disk.
+    #[serde(default)]
+    pub size_mib: Option<u64>,
+
+    pub filesystem: Filesystem,
+
+    /// Where the filesystem is mounted. The EFI system partition is mounted
+    /// wherever the installer expects it and swap isn't mounted, so neither
+    /// takes one.
+    #[serde(default)]
+    pub mountpoint: Option<String>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum Filesystem {
+    /// FAT32 EFI system partition
+    Efi,
+    Ext4,
+    Xfs,
+    Btrfs,
+    Swap,
+}
+
+impl Filesystem {
+    /// The filesystem's name as installers spell it (`ext4`, `xfs`, ...).
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Filesystem::Efi => "fat32",
+            Filesystem::Ext4 => "ext4",
+            Filesystem::Xfs => "xfs",
+            Filesystem::Btrfs => "btrfs",
+            Filesystem::Swap => "swap",
+        }
+    }
+}
+
+fn default_volume_group() -> String {
+    "vg0".to_string()
+}
+
+fn default_subvolumes() -> Vec<Subvolume> {
+    [("@", "/"), ("@home", "/home"), ("@log", "/var/log")]
+        .into_iter()
+        .map(|(name, mountpoint)| Subvolume {
+            name: name.to_string(),
+            mountpoint: mountpoint.to_string(),
+        })
+        .collect()
+}
+
+/// Sort mountpoints so that every one comes after its parent.
+fn mount_order<T>(items: &mut [(T, &str)]) {
+    items.sort_by_key(|(_, mountpoint)| mountpoint.trim_end_matches('/').matches('/').count());
+}
+
+/// Where a mountpoint of the installed system is mounted in the live shell.
+fn target(mountpoint: &str) -> String {
+    format!("/mnt{}", mountpoint.trim_end_matches('/'))
 }
 
 impl PartitionLayout {
+    /// Check that the layout can be installed: a custom layout needs exactly
+    /// one EFI system partition and root filesystem, and subvolumes need a
+    /// root.
+    pub fn check(&self) -> Result<()> {
+        let mountpoints: Vec<&str> = match self {
+            PartitionLayout::UefiLvm { volume_group, .. } => {
+                if volume_group.is_empty() {
+                    bail!("The volume group needs a name");
+                }
+                return Ok(());
+            }
+            PartitionLayout::UefiBtrfs { subvolumes } => subvolumes
+                .iter()
+                .map(|subvolume| subvolume.mountpoint.as_str())
+                .collect(),
+            PartitionLayout::Custom { partitions } => {
+                let efi = partitions
+                    .iter()
+                    .filter(|partition| partition.filesystem == Filesystem::Efi)
+                    .count();
+                if efi != 1 {
+                    bail!("A custom layout needs exactly one EFI system partition");
+                }
+                if partitions
+                    .iter()
+                    .position(|partition| partition.size_mib.is_none())
+                    .is_some_and(|index| index != partitions.len() - 1)
+                {
+                    bail!("Only the last partition may omit its size");
+                }
+                for partition in partitions {
+                    match partition.filesystem {
+                        Filesystem::Efi | Filesystem::Swap => {}
+                        _ if partition.mountpoint.is_none() => {
+                            bail!(
+                                "A {} partition needs a mountpoint",
+                                partition.filesystem.as_str()
+                            )
+                        }
+                        _ => {}
+                    }
+                }
+                partitions
+                    .iter()
+                    .filter(|partition| {
+                        !matches!(partition.filesystem, Filesystem::Efi | Filesystem::Swap)
+                    })
+                    .filter_map(|partition| partition.mountpoint.as_deref())
+                    .collect()
+            }
+            _ => return Ok(()),
+        };
+
+        if mountpoints
+            .iter()
+            .filter(|&&mountpoint| mountpoint == "/")
+            .count()
+            != 1
+        {
+            bail!("The layout needs exactly one root (\"/\") mountpoint");
+        }
+        for (i, mountpoint) in mountpoints.iter().enumerate() {
+            if !mountpoint.starts_with('/') {
+                bail!("Mountpoint {mountpoint} isn't absolute");
+            }
+            if mountpoints[..i].contains(mountpoint) {
+                bail!("Mountpoint {mountpoint} is used twice");
+            }
+        }
+        Ok(())
+    }
+
+    /// The passphrase the installed system asks for at boot, if the layout
+    /// is encrypted.
+    pub fn passphrase(&self) -> Option<&str> {
+        match self {
+            PartitionLayout::UefiLuks { passphrase } => Some(passphrase),
+            _ => None,
+        }
+    }
+
     /// Shell commands to partition, format, and mount `device` at `/mnt`.
     /// Used by builders that operate from a live shell (e.g. NixOS).
     pub fn mount_commands(&self, device: &str) -> Vec<String> {
@@ -57,8 +233,223 @@ impl PartitionLayout {
                 "mkdir -p /mnt/boot".into(),
                 format!("mount {device}1 /mnt/boot"),
             ],
+            PartitionLayout::UefiLvm {
+                volume_group,
+                swap_size_mib,
+            } => {
+                let mut commands = vec![
+                    format!("sgdisk -n1:0:+512M -t1:ef00 -n2:0:0 -t2:8e00 {device}"),
+                    format!("mkfs.fat -F32 {device}1"),
+                    format!("pvcreate {device}2"),
+                    format!("vgcreate {volume_group} {device}2"),
+                ];
+                if let Some(swap_size_mib) = swap_size_mib {
+                    commands.extend([
+                        format!("lvcreate -L {swap_size_mib}M -n swap {volume_group}"),
+                        format!("mkswap /dev/{volume_group}/swap"),
+                        format!("swapon /dev/{volume_group}/swap"),
+                    ]);
+                }
+                commands.extend([
+                    format!("lvcreate -l 100%FREE -n root {volume_group}"),
+                    format!("mkfs.ext4 /dev/{volume_group}/root"),
+                    format!("mount /dev/{volume_group}/root /mnt"),
+                    "mkdir -p /mnt/boot".into(),
+                    format!("mount {device}1 /mnt/boot"),
+                ]);
+                commands
+            }
+            PartitionLayout::UefiBtrfs { subvolumes } => {
+                let mut commands = vec![
+                    format!("sgdisk -n1:0:+512M -t1:ef00 -n2:0:0 -t2:8300 {device}"),
+                    format!("mkfs.fat -F32 {device}1"),
+                    format!("mkfs.btrfs -f {device}2"),
+                    format!("mount {device}2 /mnt"),
+                ];
+                for subvolume in subvolumes {
+                    commands.push(format!("btrfs subvolume create /mnt/{}", subvolume.name));
+                }
+                commands.push("umount /mnt".into());
+
+                let mut subvolumes: Vec<_> = subvolumes
+                    .iter()
+                    .map(|subvolume| (&subvolume.name, subvolume.mountpoint.as_str()))
+                    .collect();
+                mount_order(&mut subvolumes);
+                for (name, mountpoint) in subvolumes {
+                    let target = target(mountpoint);
+                    commands.extend([
+                        format!("mkdir -p {target}"),
+                        format!("mount -o subvol={name} {device}2 {target}"),
+                    ]);
+                }
+
+                commands.extend([
+                    "mkdir -p /mnt/boot".into(),
+                    format!("mount {device}1 /mnt/boot"),
+                ]);
+                commands
+            }
+            PartitionLayout::UefiXfs => vec![
+                format!("sgdisk -n1:0:+512M -t1:ef00 -n2:0:0 -t2:8300 {device}"),
+                format!("mkfs.fat -F32 {device}1"),
+                format!("mkfs.xfs -f {device}2"),
+                format!("mount {device}2 /mnt"),
+                "mkdir -p /mnt/boot".into(),
+                format!("mount {device}1 /mnt/boot"),
+            ],
+            PartitionLayout::Custom { partitions } => {
+                let mut sgdisk = String::from("sgdisk");
+                let mut commands = Vec::new();
+                let mut mounts = Vec::new();
+                let mut efi = String::new();
+
+                for (i, partition) in partitions.iter().enumerate() {
+                    let number = i + 1;
+                    let path = format!("{device}{number}");
+                    let size = match partition.size_mib {
+                        Some(size_mib) => format!("+{size_mib}M"),
+                        None => "0".to_string(),
+                    };
+                    let type_code = match partition.filesystem {
+                        Filesystem::Efi => "ef00",
+                        Filesystem::Swap => "8200",
+                        _ => "8300",
+                    };
+                    sgdisk.push_str(&format!(" -n{number}:0:{size} -t{number}:{type_code}"));
+
+                    match partition.filesystem {
+                        Filesystem::Efi => {
+                            commands.push(format!("mkfs.fat -F32 {path}"));
+                            efi = path.clone();
+                        }
+                        Filesystem::Swap => {
+                            commands.push(format!("mkswap {path}"));
+                            commands.push(format!("swapon {path}"));
+                        }
+                        Filesystem::Ext4 => commands.push(format!("mkfs.ext4 {path}")),
+                        Filesystem::Xfs => commands.push(format!("mkfs.xfs -f {path}")),
+                        Filesystem::Btrfs => commands.push(format!("mkfs.btrfs -f {path}")),
+                    }
+                    if let (
+                        Filesystem::Ext4 | Filesystem::Xfs | Filesystem::Btrfs,
+                        Some(mountpoint),
+                    ) = (partition.filesystem, &partition.mountpoint)
+                    {
+                        mounts.push((path, mountpoint.as_str()));
+                    }
+                }
+                sgdisk.push_str(&format!(" {device}"));
+                commands.insert(0, sgdisk);
+
+                mount_order(&mut mounts);
+                for (path, mountpoint) in mounts {
+                    let target = target(mountpoint);
+                    commands.extend([
+                        format!("mkdir -p {target}"),
+                        format!("mount {path} {target}"),
+                    ]);
+                }
+                commands.extend([
+                    "mkdir -p /mnt/boot".into(),
+                    format!("mount {efi} /mnt/boot"),
+                ]);
+                commands
+            }
         }
     }
+
+    /// Preseed directives that make partman partition the disk (debian-installer
+    /// and ubiquity). The disk itself is selected by `partman-auto/disk`.
+    pub fn partman(&self) -> Result<String> {
+        fn expert_recipe(method: &str, recipe: &str) -> String {
+            format!(
+                "d-i partman-auto/method string {method}\n\
+                 d-i partman-auto/expert_recipe string goldboot :: {recipe}\n\
+                 d-i partman-auto/choose_recipe select goldboot\n"
+            )
+        }
+        fn filesystem(filesystem: &str, mountpoint: &str) -> String {
+            format!(
+                "{filesystem} method{{ format }} format{{ }} use_filesystem{{ }} \
+                 filesystem{{ {filesystem} }} mountpoint{{ {mountpoint} }} ."
+            )
+        }
+
+        let efi = "512 512 512 free $iflabel{ gpt } $reusemethod{ } method{ efi } format{ } .";
+        let swap = |size_mib: u64| {
+            format!("{size_mib} {size_mib} {size_mib} linux-swap method{{ swap }} format{{ }} .")
+        };
+        let root = |fs: &str| format!("1000 10000 -1 {}", filesystem(fs, "/"));
+
+        Ok(match self {
+            PartitionLayout::Uefi => expert_recipe("regular", &format!("{efi} {}", root("ext4"))),
+            PartitionLayout::UefiWithSwap { swap_size_mib } => expert_recipe(
+                "regular",
+                &format!("{efi} {} {}", swap(*swap_size_mib), root("ext4")),
+            ),
+            PartitionLayout::UefiLuks { passphrase } => format!(
+                "d-i partman-auto/method string crypto\n\
+                 d-i partman-crypto/passphrase password {passphrase}\n\
+                 d-i partman-crypto/passphrase-again password {passphrase}\n\
+                 d-i partman-crypto/weak_passphrase boolean true\n\
+                 d-i partman-auto-crypto/erase_disks boolean false\n\
+                 d-i partman-auto/choose_recipe select atomic\n"
+            ),
+            PartitionLayout::UefiLvm {
+                volume_group,
+                swap_size_mib,
+            } => {
+                // /boot stays outside of the volume group
+                let mut recipe = format!("{efi} 1024 1024 1024 {}", filesystem("ext4", "/boot"));
+                if let Some(swap_size_mib) = swap_size_mib {
+                    recipe.push_str(&format!(
+                        " {swap_size_mib} {swap_size_mib} {swap_size_mib} linux-swap $lvmok{{ }} \
+                         lv_name{{ swap }} method{{ swap }} format{{ }} ."
+                    ));
+                }
+                recipe.push_str(
+                    " 1000 10000 -1 ext4 $lvmok{ } lv_name{ root } method{ format } format{ } \
+                     use_filesystem{ } filesystem{ ext4 } mountpoint{ / } .",
+                );
+                format!(
+                    "d-i partman-auto-lvm/new_vg_name string {volume_group}\n{}",
+                    expert_recipe("lvm", &recipe)
+                )
+            }
+            PartitionLayout::UefiBtrfs { .. } => {
+                bail!(
+                    "partman can't create btrfs subvolumes; use a Custom layout with a Btrfs root"
+                )
+            }
+            PartitionLayout::UefiXfs => expert_recipe("regular", &format!("{efi} {}", root("xfs"))),
+            PartitionLayout::Custom { partitions } => {
+                let recipe = partitions
+                    .iter()
+                    .map(|partition| {
+                        let size = match partition.size_mib {
+                            Some(size_mib) => format!("{size_mib} {size_mib} {size_mib}"),
+                            None => "1000 10000 -1".to_string(),
+                        };
+                        match (partition.filesystem, &partition.mountpoint) {
+                            (Filesystem::Efi, _) => format!(
+                                "{size} free $iflabel{{ gpt }} $reusemethod{{ }} method{{ efi }} format{{ }} ."
+                            ),
+                            (Filesystem::Swap, _) => {
+                                format!("{size} linux-swap method{{ swap }} format{{ }} .")
+                            }
+                            (fs, mountpoint) => format!(
+                                "{size} {}",
+                                filesystem(fs.as_str(), mountpoint.as_deref().unwrap_or("/"))
+                            ),
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                expert_recipe("regular", &recipe)
+            }
+        })
+    }
 }
 
 impl Prompt for PartitionLayout {
@@ -70,6 +461,9 @@ impl Prompt for PartitionLayout {
             "UEFI (EFI + ext4 root)",
             "UEFI with swap (EFI + swap + ext4 root)",
             "UEFI with LUKS (EFI + encrypted ext4 root)",
+            "UEFI with LVM (EFI + volume group with ext4 root)",
+            "UEFI with btrfs (EFI + btrfs root with subvolumes)",
+            "UEFI with XFS (EFI + XFS root)",
         ];
 
         let selection = Select::with_theme(&theme)
@@ -86,13 +480,150 @@ impl Prompt for PartitionLayout {
                     .default(4096)
                     .interact_text()?,
             },
-            _ => PartitionLayout::UefiLuks {
+            2 => PartitionLayout::UefiLuks {
                 passphrase: Password::with_theme(&theme)
                     .with_prompt("LUKS passphrase")
                     .interact()?,
             },
+            3 => PartitionLayout::UefiLvm {
+                volume_group: Input::with_theme(&theme)
+                    .with_prompt("Volume group name")
+                    .default(default_volume_group())
+                    .interact_text()?,
+                swap_size_mib: None,
+            },
+            4 => PartitionLayout::UefiBtrfs {
+                subvolumes: default_subvolumes(),
+            },
+            _ => PartitionLayout::UefiXfs,
         };
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn custom() -> PartitionLayout {
+        ron::from_str(
+            r#"Custom(partitions: [
+                (size_mib: Some(512), filesystem: Efi),
+                (size_mib: Some(2048), filesystem: Swap),
+                (size_mib: Some(8192), filesystem: Xfs, mountpoint: Some("/")),
+                (filesystem: Ext4, mountpoint: Some("/home")),
+            ])"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn check_layouts() {
+        assert!(custom().check().is_ok());
+        assert!(
+            PartitionLayout::UefiBtrfs {
+                subvolumes: default_subvolumes()
+            }
+            .check()
+            .is_ok()
+        );
+
+        // No root
+        assert!(
+            PartitionLayout::UefiBtrfs {
+                subvolumes: default_subvolumes()[1..].to_vec()
+            }
+            .check()
+            .is_err()
+        );
+
+        // Unsized partition before the last
+        let PartitionLayout::Custom { mut partitions } = custom() else {
+            unreachable!()
+        };
+        partitions.swap(2, 3);
+        assert!(PartitionLayout::Custom { partitions }.check().is_err());
+    }
+
+    #[test]
+    fn custom_mount_commands() {
+        assert_eq!(
+            custom().mount_commands("/dev/vda"),
+            vec![
+                "sgdisk -n1:0:+512M -t1:ef00 -n2:0:+2048M -t2:8200 -n3:0:+8192M -t3:8300 -n4:0:0 -t4:8300 /dev/vda",
+                "mkfs.fat -F32 /dev/vda1",
+                "mkswap /dev/vda2",
+                "swapon /dev/vda2",
+                "mkfs.xfs -f /dev/vda3",
+                "mkfs.ext4 /dev/vda4",
+                "mkdir -p /mnt",
+                "mount /dev/vda3 /mnt",
+                "mkdir -p /mnt/home",
+                "mount /dev/vda4 /mnt/home",
+                "mkdir -p /mnt/boot",
+                "mount /dev/vda1 /mnt/boot",
+            ]
+        );
+    }
+
+    #[test]
+    fn btrfs_mount_commands() {
+        let commands = PartitionLayout::UefiBtrfs {
+            subvolumes: default_subvolumes(),
+        }
+        .mount_commands("/dev/vda");
+        assert!(commands.contains(&"btrfs subvolume create /mnt/@home".to_string()));
+        let root = commands
+            .iter()
+            .position(|command| command == "mount -o subvol=@ /dev/vda2 /mnt")
+            .unwrap();
+        let home = commands
+            .iter()
+            .position(|command| command == "mount -o subvol=@home /dev/vda2 /mnt/home")
+            .unwrap();
+        assert!(root < home);
+    }
+
+    #[test]
+    fn lvm_partman() -> Result<()> {
+        let partman = PartitionLayout::UefiLvm {
+            volume_group: "system".to_string(),
+            swap_size_mib: Some(1024),
+        }
+        .partman()?;
+        // Each partition names its filesystem exactly once
+        assert_eq!(
+            partman,
+            "d-i partman-auto-lvm/new_vg_name string system\n\
+             d-i partman-auto/method string lvm\n\
+             d-i partman-auto/expert_recipe string goldboot :: \
+             512 512 512 free $iflabel{ gpt } $reusemethod{ } method{ efi } format{ } . \
+             1024 1024 1024 ext4 method{ format } format{ } use_filesystem{ } filesystem{ ext4 } mountpoint{ /boot } . \
+             1024 1024 1024 linux-swap $lvmok{ } lv_name{ swap } method{ swap } format{ } . \
+             1000 10000 -1 ext4 $lvmok{ } lv_name{ root } method{ format } format{ } \
+             use_filesystem{ } filesystem{ ext4 } mountpoint{ / } .\n\
+             d-i partman-auto/choose_recipe select goldboot\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn luks_partman() -> Result<()> {
+        let partman = PartitionLayout::UefiLuks {
+            passphrase: "secret".to_string(),
+        }
+        .partman()?;
+        // Erasing would fill the disk (and so the image) with random data
+        assert_eq!(
+            partman,
+            "d-i partman-auto/method string crypto\n\
+             d-i partman-crypto/passphrase password secret\n\
+             d-i partman-crypto/passphrase-again password secret\n\
+             d-i partman-crypto/weak_passphrase boolean true\n\
+             d-i partman-auto-crypto/erase_disks boolean false\n\
+             d-i partman-auto/choose_recipe select atomic\n"
+        );
+        Ok(())
+    }
+}
@@ -1,20 +1,23 @@
-use anyhow::Result;
+use anyhow::{Result, bail};
 use serde::{Deserialize, Serialize};
 use smart_default::SmartDefault;
 use std::collections::HashMap;
 use std::fmt::Display;
 use strum::{Display, EnumIter, IntoEnumIterator};
+use tracing::warn;
 use validator::Validate;
 
 use crate::{
     builder::{
-        Builder,
+        Builder, checkpoint,
+        offline::OfflineStep,
         options::{
             hostname::Hostname, iso::Iso, locale::Locale, luks::Luks, minimum_size::MinimumSize,
-            ntp::Ntp, packages::Packages, root_password::RootPassword, timezone::Timezone,
-            unix_users::UnixUsers,
+            ntp::Ntp, packages::Packages, partition_layout::PartitionLayout,
+            root_password::RootPassword, timezone::Timezone, unix_users::UnixUsers,
         },
         qemu::{OsCategory, QemuBuilder},
+        ssh::GUEST_HOST_KEY_PATH,
     },
     cli::prompt::Prompt,
     enter, wait, wait_text_serial,
@@ -53,7 +56,12 @@ pub struct AlpineLinux {
     #[serde(default)]
     pub ntp: Ntp,
 
-    /// LUKS disk encryption
+    /// Disk partition layout and optional LUKS encryption
+    #[serde(default)]
+    pub partition_layout: PartitionLayout,
+
+    /// Deprecated: use the `UefiLuks` partition layout instead
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub luks: Option<Luks>,
 
     #[default(Iso {
@@ -61,29 +69,56 @@ pub struct AlpineLinux {
         checksum: Some("sha256:966d6bf4d4c79958d43abde84a3e5bbeb4f8c757c164a49d3ec8432be6d36f16".to_string()),
     })]
     pub iso: Iso,
+
+    /// Steps that edit the disk on the host after the build.
+    #[serde(default)]
+    pub offline_steps: Vec<OfflineStep>,
+}
+
+impl AlpineLinux {
+    /// The partition layout, with the deprecated `luks` option mapped to
+    /// `UefiLuks`.
+    fn partition_layout(&self) -> Result<PartitionLayout> {
+        let Some(luks) = &self.luks else {
+            return Ok(self.partition_layout.clone());
+        };
+        if !matches!(self.partition_layout, PartitionLayout::Uefi) {
+            bail!("`luks` can't be combined with a partition layout; use the `UefiLuks` layout");
+        }
+        if luks.tpm {
+            bail!("Enrolling the LUKS passphrase in a TPM is not supported");
+        }
+        warn!("`luks` is deprecated; use the `UefiLuks` partition layout instead");
+        Ok(PartitionLayout::UefiLuks {
+            passphrase: luks.passphrase.clone(),
+        })
+    }
 }
 
 impl BuildImage for AlpineLinux {
     fn build(&self, worker: &Builder) -> Result<()> {
-        let qemu_builder = QemuBuilder::new(worker, OsCategory::Linux)
+        let partition_layout = self.partition_layout()?;
+        partition_layout.check()?;
+
+        let mut qemu_builder = QemuBuilder::new(worker, OsCategory::Linux)
             .with_iso(&self.iso)?
             // Forward the host SSH port to the installed system's sshd so
             // the post-install configuration can connect after the reboot.
             .forward_ssh(22);
         let public_key = qemu_builder.ssh_public_key()?;
+        let host_key = qemu_builder.pin_host_key()?;
 
         // The config drive (/dev/vdb) carries the goldboot public key used
-        // to reach the installed system over SSH.
+        // to reach the installed system over SSH and the host key it presents.
         let mut qemu = qemu_builder
-            .drive_files(HashMap::from([("public_key".to_string(), public_key)]))?
+            .drive_files(HashMap::from([
+                ("public_key".to_string(), public_key),
+                ("host_key".to_string(), host_key.private.into_bytes()),
+                ("host_key.pub".to_string(), host_key.public.into_bytes()),
+            ]))?
             .start()?;
 
         let ntp_opts = if self.ntp.0 { "-c openntpd" } else { "-c none" };
-        let disk_opts = if self.luks.is_some() {
-            "-m sys -e /dev/vda"
-        } else {
-            "-m sys /dev/vda"
-        };
         let root_password = match &self.root_password {
             RootPassword::Plaintext(p) => p.clone(),
             RootPassword::PlaintextEnv(name) => {
@@ -93,7 +128,7 @@ impl BuildImage for AlpineLinux {
 
         // Send boot command
         #[rustfmt::skip]
-		qemu.vnc.run(vec![
+		let mut cmds = vec![
 			// Initial wait
 			wait!(30),
 			// Root login
@@ -115,24 +150,51 @@ iface eth0 inet dhcp
 			enter!("export APKREPOSOPTS='-r'"),
 			enter!("export SSHDOPTS='-c openssh'"),
 			enter!(format!("export NTPOPTS='{ntp_opts}'")),
-			enter!(format!("export DISKOPTS='{disk_opts}'")),
-			// Start install (password, password confirmation, no user account,
-			// erase-disk confirmation). Mirror the installer output to the
-			// serial port so we can wait for it without OCR.
-			enter!(format!("echo -e '{root_password}\n{root_password}\nno\ny' | setup-alpine 2>&1 | tee /dev/ttyS0")),
+			// The disk is partitioned separately
+			enter!("export DISKOPTS='none'"),
+			// Configure the live system (password, password confirmation, no
+			// user account). Mirror the output to the serial port so we can
+			// wait for it without OCR.
+			enter!(format!("echo -e '{root_password}\n{root_password}\nno' | setup-alpine 2>&1 | tee /dev/ttyS0; echo 'Setup is complete' >/dev/ttyS0")),
+			wait_text_serial!("Setup is complete"),
+			enter!("apk add sgdisk dosfstools e2fsprogs xfsprogs btrfs-progs lvm2 cryptsetup"),
+		];
+
+        // Partition the disk and mount it at /mnt
+        for cmd in partition_layout.mount_commands("/dev/vda") {
+            cmds.push(enter!(cmd));
+        }
+
+        #[rustfmt::skip]
+		cmds.extend(vec![
+			// Install onto the mounted disk
+			enter!("setup-disk -m sys /mnt 2>&1 | tee /dev/ttyS0; echo 'Installation is complete' >/dev/ttyS0"),
 			wait_text_serial!("Installation is complete"),
-			// Authorize the goldboot key on the installed system
-			enter!("mount -t ext4 /dev/vda3 /mnt"),
+			// Authorize the goldboot key on the installed system and install
+			// the pinned host key
 			enter!("mkdir /goldboot && mount /dev/vdb /goldboot"),
 			enter!("mkdir -p /mnt/root/.ssh && cp /goldboot/public_key /mnt/root/.ssh/authorized_keys"),
 			enter!("chmod 700 /mnt/root/.ssh && chmod 600 /mnt/root/.ssh/authorized_keys"),
+			enter!(format!("cp /goldboot/host_key /mnt{GUEST_HOST_KEY_PATH} && cp /goldboot/host_key.pub /mnt{GUEST_HOST_KEY_PATH}.pub")),
+			enter!(format!("chmod 600 /mnt{GUEST_HOST_KEY_PATH}")),
 			enter!("umount /goldboot"),
 			// Reboot into installation
 			enter!("apk add efibootmgr; efibootmgr -n 0003; reboot"),
-		])?;
+		]);
+        // Unlock the root filesystem when the installed system boots
+        if let Some(passphrase) = partition_layout.passphrase() {
+            cmds.push(wait_text_ocr!("passphrase"));
+            cmds.push(enter!(passphrase));
+        }
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            qemu.vnc.run(cmds)?;
+        }
 
         // Wait for SSH
         let mut ssh = qemu.ssh("root")?;
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            qemu.checkpoint(checkpoint::INSTALL)?;
+        }
 
         // Install extra packages
         if let Some(packages) = &self.packages {
@@ -156,6 +218,7 @@ iface eth0 inet dhcp
         }
 
         // Shutdown
+        ssh.remove_host_key()?;
         ssh.shutdown("poweroff")?;
         qemu.shutdown_wait()?;
         Ok(())
@@ -3,7 +3,7 @@ use serde::Serialize;
 use serde_json::Value;
 use uuid::Uuid;
 
-use crate::builder::options::partition_layout::PartitionLayout;
+use crate::builder::options::partition_layout::{Filesystem, PartitionLayout};
 use crate::builder::options::root_password::RootPassword;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -72,6 +72,8 @@ pub struct BootloaderConfig {
 pub struct DiskLayoutConfiguration {
     pub config_type: String,
     pub device_modifications: Vec<DeviceModification>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lvm_config: Option<LvmConfiguration>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -193,150 +195,179 @@ pub struct ArchinstallConfig {
     pub timezone: String,
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LvmConfiguration {
+    pub config_type: String,
+    pub vol_groups: Vec<LvmVolumeGroup>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LvmVolumeGroup {
+    pub name: String,
+    pub lvm_pvs: Vec<String>,
+    pub volumes: Vec<LvmVolume>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LvmVolume {
+    pub obj_id: String,
+    pub status: String,
+    pub name: String,
+    pub fs_type: String,
+    pub length: Size,
+    pub mountpoint: String,
+    pub mount_options: Vec<Value>,
+    pub btrfs: Vec<Value>,
+}
+
 /// Converts a `PartitionLayout` into the archinstall disk_config and optional
 /// disk_encryption structures. The root partition's obj_id is shared between
 /// both so that archinstall correctly associates the encryption entry with the
 /// right partition.
 fn disk_config_from(layout: &PartitionLayout) -> (DiskLayoutConfiguration, Option<DiskEncryption>) {
-    fn sector_size() -> SectorSize {
-        SectorSize {
-            value: 512,
-            unit: "B".to_string(),
-        }
-    }
-    fn mib(value: i64, offset: i64) -> (Size, Size) {
-        (
-            Size {
-                sector_size: sector_size(),
-                unit: "MiB".to_string(),
-                value: offset,
+    fn mib(value: i64) -> Size {
+        Size {
+            sector_size: SectorSize {
+                value: 512,
+                unit: "B".to_string(),
             },
-            Size {
-                sector_size: sector_size(),
-                unit: "MiB".to_string(),
-                value,
-            },
-        )
+            unit: "MiB".to_string(),
+            value,
+        }
     }
 
-    let efi_obj_id = Uuid::new_v4().to_string();
-    let (efi_start, efi_size) = mib(512, 1);
-    let efi_part = PartitionModification {
-        btrfs: vec![],
-        flags: vec!["boot".to_string(), "esp".to_string()],
-        fs_type: "fat32".to_string(),
-        obj_id: efi_obj_id,
-        size: efi_size,
-        mount_options: vec![],
-        mountpoint: "/boot".to_string(),
-        start: efi_start,
-        status: "create".to_string(),
-        type_field: "primary".to_string(),
-        dev_path: Some("/dev/vda1".to_string()),
-    };
+    /// Lays out partitions back to back after the first MiB. A size of -1
+    /// takes the rest of the disk.
+    struct Partitions(Vec<PartitionModification>, i64);
 
-    match layout {
-        PartitionLayout::Uefi => {
-            let (root_start, root_size) = mib(-1, 513);
-            let root_part = PartitionModification {
+    impl Partitions {
+        fn push(&mut self, fs_type: &str, mountpoint: &str, size: i64, flags: &[&str]) -> String {
+            let obj_id = Uuid::new_v4().to_string();
+            self.0.push(PartitionModification {
                 btrfs: vec![],
-                flags: vec![],
-                fs_type: "ext4".to_string(),
-                obj_id: Uuid::new_v4().to_string(),
-                size: root_size,
+                flags: flags.iter().map(|flag| flag.to_string()).collect(),
+                fs_type: fs_type.to_string(),
+                obj_id: obj_id.clone(),
+                size: mib(size),
                 mount_options: vec![],
-                mountpoint: "/".to_string(),
-                start: root_start,
+                mountpoint: mountpoint.to_string(),
+                start: mib(self.1),
                 status: "create".to_string(),
                 type_field: "primary".to_string(),
-                dev_path: Some("/dev/vda2".to_string()),
-            };
-            (
-                DiskLayoutConfiguration {
-                    config_type: "manual_partitioning".to_string(),
-                    device_modifications: vec![DeviceModification {
-                        device: "/dev/vda".to_string(),
-                        wipe: true,
-                        partitions: vec![efi_part, root_part],
-                    }],
-                },
-                None,
-            )
+                dev_path: Some(format!("/dev/vda{}", self.0.len() + 1)),
+            });
+            self.1 += size;
+            obj_id
+        }
+
+        fn efi(&mut self, size: i64) -> String {
+            self.push("fat32", "/boot", size, &["boot", "esp"])
+        }
+    }
+
+    let mut partitions = Partitions(vec![], 1);
+    let mut disk_encryption = None;
+    let mut lvm_config = None;
+
+    if !matches!(layout, PartitionLayout::Custom { .. }) {
+        partitions.efi(512);
+    }
+    match layout {
+        PartitionLayout::Uefi => {
+            partitions.push("ext4", "/", -1, &[]);
         }
         PartitionLayout::UefiWithSwap { swap_size_mib } => {
-            let swap_size_mib = *swap_size_mib as i64;
-            let (swap_start, swap_size) = mib(swap_size_mib, 513);
-            let swap_part = PartitionModification {
-                btrfs: vec![],
-                flags: vec!["swap".to_string()],
-                fs_type: "swap".to_string(),
+            partitions.push("swap", "", *swap_size_mib as i64, &["swap"]);
+            partitions.push("ext4", "/", -1, &[]);
+        }
+        PartitionLayout::UefiLuks { .. } => {
+            let root_obj_id = partitions.push("ext4", "/", -1, &[]);
+            disk_encryption = Some(DiskEncryption {
+                partitions: vec![root_obj_id],
+                encryption_type: "luks2".to_string(),
+            });
+        }
+        PartitionLayout::UefiLvm {
+            volume_group,
+            swap_size_mib,
+        } => {
+            // The physical volume has no filesystem of its own
+            let pv_obj_id = partitions.push("", "", -1, &[]);
+
+            let volume = |name: &str, fs_type: &str, mountpoint: &str, size: i64| LvmVolume {
                 obj_id: Uuid::new_v4().to_string(),
-                size: swap_size,
-                mount_options: vec![],
-                mountpoint: String::new(),
-                start: swap_start,
                 status: "create".to_string(),
-                type_field: "primary".to_string(),
-                dev_path: Some("/dev/vda2".to_string()),
-            };
-            let (root_start, root_size) = mib(-1, 513 + swap_size_mib);
-            let root_part = PartitionModification {
-                btrfs: vec![],
-                flags: vec![],
-                fs_type: "ext4".to_string(),
-                obj_id: Uuid::new_v4().to_string(),
-                size: root_size,
+                name: name.to_string(),
+                fs_type: fs_type.to_string(),
+                length: mib(size),
+                mountpoint: mountpoint.to_string(),
                 mount_options: vec![],
-                mountpoint: "/".to_string(),
-                start: root_start,
-                status: "create".to_string(),
-                type_field: "primary".to_string(),
-                dev_path: Some("/dev/vda3".to_string()),
-            };
-            (
-                DiskLayoutConfiguration {
-                    config_type: "manual_partitioning".to_string(),
-                    device_modifications: vec![DeviceModification {
-                        device: "/dev/vda".to_string(),
-                        wipe: true,
-                        partitions: vec![efi_part, swap_part, root_part],
-                    }],
-                },
-                None,
-            )
-        }
-        PartitionLayout::UefiLuks { .. } => {
-            let root_obj_id = Uuid::new_v4().to_string();
-            let (root_start, root_size) = mib(-1, 513);
-            let root_part = PartitionModification {
                 btrfs: vec![],
-                flags: vec![],
-                fs_type: "ext4".to_string(),
-                obj_id: root_obj_id.clone(),
-                size: root_size,
-                mount_options: vec![],
-                mountpoint: "/".to_string(),
-                start: root_start,
-                status: "create".to_string(),
-                type_field: "primary".to_string(),
-                dev_path: Some("/dev/vda2".to_string()),
             };
-            (
-                DiskLayoutConfiguration {
-                    config_type: "manual_partitioning".to_string(),
-                    device_modifications: vec![DeviceModification {
-                        device: "/dev/vda".to_string(),
-                        wipe: true,
-                        partitions: vec![efi_part, root_part],
-                    }],
-                },
-                Some(DiskEncryption {
-                    partitions: vec![root_obj_id],
-                    encryption_type: "luks2".to_string(),
-                }),
-            )
+            let mut volumes = vec![];
+            if let Some(swap_size_mib) = swap_size_mib {
+                volumes.push(volume("swap", "swap", "", *swap_size_mib as i64));
+            }
+            volumes.push(volume("root", "ext4", "/", -1));
+
+            lvm_config = Some(LvmConfiguration {
+                config_type: "default".to_string(),
+                vol_groups: vec![LvmVolumeGroup {
+                    name: volume_group.clone(),
+                    lvm_pvs: vec![pv_obj_id],
+                    volumes,
+                }],
+            });
+        }
+        PartitionLayout::UefiBtrfs { subvolumes } => {
+            // The subvolumes carry the mountpoints
+            partitions.push("btrfs", "", -1, &[]);
+            if let Some(root) = partitions.0.last_mut() {
+                root.btrfs = subvolumes
+                    .iter()
+                    .map(|subvolume| {
+                        serde_json::json!({
+                            "name": subvolume.name,
+                            "mountpoint": subvolume.mountpoint,
+                        })
+                    })
+                    .collect();
+            }
+        }
+        PartitionLayout::UefiXfs => {
+            partitions.push("xfs", "/", -1, &[]);
+        }
+        PartitionLayout::Custom {
+            partitions: custom_partitions,
+        } => {
+            for partition in custom_partitions {
+                let size = partition.size_mib.map(|size| size as i64).unwrap_or(-1);
+                match partition.filesystem {
+                    Filesystem::Efi => partitions.efi(size),
+                    Filesystem::Swap => partitions.push("swap", "", size, &["swap"]),
+                    filesystem => partitions.push(
+                        filesystem.as_str(),
+                        partition.mountpoint.as_deref().unwrap_or_default(),
+                        size,
+                        &[],
+                    ),
+                };
+            }
         }
     }
+
+    (
+        DiskLayoutConfiguration {
+            config_type: "manual_partitioning".to_string(),
+            device_modifications: vec![DeviceModification {
+                device: "/dev/vda".to_string(),
+                wipe: true,
+                partitions: partitions.0,
+            }],
+            lvm_config,
+        },
+        disk_encryption,
+    )
 }
 
 impl From<&super::ArchLinux> for ArchinstallConfig {
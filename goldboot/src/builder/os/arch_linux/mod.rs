@@ -1,5 +1,7 @@
 use super::BuildImage;
+use crate::builder::checkpoint;
 use crate::builder::http::HttpServer;
+use crate::builder::offline::OfflineStep;
 use crate::builder::options::arch::Arch;
 use crate::builder::options::hostname::Hostname;
 use crate::builder::options::iso::Iso;
@@ -117,10 +119,16 @@ pub struct ArchLinux {
     #[serde(default = "default_iso")]
     #[default(_code = "default_iso()")]
     pub iso: Iso,
+
+    /// Steps that edit the disk on the host after the build.
+    #[serde(default)]
+    pub offline_steps: Vec<OfflineStep>,
 }
 
 impl BuildImage for ArchLinux {
     fn build(&self, worker: &Builder) -> Result<()> {
+        self.partition_layout.check()?;
+
         let mut qemu = QemuBuilder::new(worker, OsCategory::Linux)
             .with_iso(&self.iso)?
             .prepare_ssh()?
@@ -164,7 +172,7 @@ impl BuildImage for ArchLinux {
         // Wait for SSH
         let mut ssh = qemu.ssh("root")?;
 
-        if !worker.has_checkpoint("install") {
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
             // Run install script
             info!("Running base installation");
             match ssh.upload_exec(
@@ -178,7 +186,7 @@ impl BuildImage for ArchLinux {
                 _ => bail!("Installation failed"),
             }
 
-            qemu.checkpoint("install")?;
+            qemu.checkpoint(checkpoint::INSTALL)?;
         }
 
         // Shutdown
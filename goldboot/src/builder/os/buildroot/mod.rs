@@ -1,154 +1,213 @@
-use crate::{
-    build::BuildWorker,
-    cache::{MediaCache, MediaFormat},
-    provisioners::*,
-    qemu::QemuArgs,
-    templates::*,
-};
-use tracing::info;
+use anyhow::{Context, Result, bail};
+use goldboot_image::{ImageArch, qcow::Qcow3};
 use serde::{Deserialize, Serialize};
-use anyhow::bail;
+use smart_default::SmartDefault;
 use std::{
-    error::Error,
-    io::{BufRead, BufReader},
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
 };
+use tracing::info;
 use validator::Validate;
 
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
-pub struct BuildrootTemplate {
+use crate::{
+    builder::{
+        Builder, checkpoint,
+        import::{DiskFormat, import_disk},
+        offline::OfflineStep,
+        options::{arch::Arch, minimum_size::MinimumSize},
+    },
+    cli::prompt::Prompt,
+};
+
+use super::BuildImage;
+
+/// Buildroot generates embedded Linux systems through cross-compilation. The
+/// source tree is extracted from a local tarball and built on the host, so no
+/// VM is involved. A tarball whose `dl/` directory already holds the package
+/// sources builds without network access.
+///
+/// ```ron
+/// Buildroot(
+///     source: (path: "buildroot-2025.02.tar.gz"),
+///     configuration: (
+///         options: ["BR2_PACKAGE_DROPBEAR=y"],
+///     ),
+/// )
+/// ```
+///
+/// Upstream: https://buildroot.org
+/// Maintainer: cilki
+#[goldboot_macros::Os(architectures(Amd64, Arm64))]
+#[derive(Clone, Serialize, Deserialize, Validate, Debug, SmartDefault, goldboot_macros::Prompt)]
+pub struct Buildroot {
+    pub arch: Arch,
+    pub minimum_size: MinimumSize,
+
+    /// The Buildroot source tree
     pub source: BuildrootSource,
-    pub provisioners: Option<Vec<BuildrootProvisioner>>,
-}
 
-pub enum ArchSource {
-    Iso(IsoSource),
-}
+    /// What to build
+    #[serde(default)]
+    pub configuration: BuildrootConfiguration,
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-pub enum ArchProvisioner {
-    Ansible(AnsibleProvisioner)
-    Mirrorlist(ArchMirrorlistProvisioner),
-    Hostname(HostnameProvisioner),
+    /// Steps that edit the disk on the host after the build.
+    #[serde(default)]
+    pub offline_steps: Vec<OfflineStep>,
 }
 
-impl Default for ArchTemplate {
-    fn default() -> Self {
-        Self {
-            source: None,
-            provisioners: None,
+impl BuildImage for Buildroot {
+    fn build(&self, worker: &Builder) -> Result<()> {
+        if worker.has_checkpoint(checkpoint::INSTALL) {
+            return Ok(());
         }
-    }
-}
 
-impl BuildTemplate for ArchTemplate {
-    fn build(&self, context: &BuildWorker) -> Result<()> {
-        info!("Starting {} build", console::style("ArchLinux").blue());
-
-        let mut qemuargs = QemuArgs::new(&context);
-
-        qemuargs.drive.push(format!(
-            "file={},if=virtio,cache=writeback,discard=ignore,format=qcow2",
-            context.image_path
-        ));
-        qemuargs.drive.push(format!(
-            "file={},media=cdrom",
-            MediaCache::get(self.iso.url.clone(), &self.iso.checksum, MediaFormat::Iso)?
-        ));
-
-        // Start VM
-        let mut qemu = qemuargs.start_process()?;
-
-        // Send boot command
-        #[rustfmt::skip]
-		qemu.vnc.boot_command(vec![
-			// Initial wait
-			wait!(30),
-			// Wait for login
-			wait_screen_rect!("5b3ca88689e9d671903b3040889c7fa1cb5f244a", 100, 0, 1024, 400),
-			// Configure root password
-			enter!("passwd"), enter!(self.root_password), enter!(self.root_password),
-			// Configure SSH
-			enter!("echo 'AcceptEnv *' >>/etc/ssh/sshd_config"),
-			enter!("echo 'PermitRootLogin yes' >>/etc/ssh/sshd_config"),
-			// Start sshd
-			enter!("systemctl restart sshd"),
-		])?;
-
-        // Wait for SSH
-        let mut ssh = qemu.ssh_wait(context.ssh_port, "root", &self.root_password)?;
-
-        // Run install script
-        if let Some(resource) = Resources::get("install.sh") {
-            info!("Running base installation");
-            match ssh.upload_exec(
-                resource.data.to_vec(),
-                vec![
-                    ("GB_MIRRORLIST", &self.format_mirrorlist()),
-                    ("GB_ROOT_PASSWORD", &self.root_password),
-                ],
-            ) {
-                Ok(0) => debug!("Installation completed successfully"),
-                _ => bail!("Installation failed"),
+        let tarball = worker.effective_context_dir.join(&self.source.path);
+        let tree = worker.tmp.path().join("buildroot");
+        extract(&tarball, &tree)?;
+
+        let defconfig = match &self.configuration.defconfig {
+            Some(defconfig) => defconfig.clone(),
+            None => default_defconfig(self.arch.0)?.to_string(),
+        };
+        make(&tree, &[&defconfig])?;
+
+        if !self.configuration.options.is_empty() {
+            let mut config = std::fs::OpenOptions::new()
+                .append(true)
+                .open(tree.join(".config"))?;
+            for option in &self.configuration.options {
+                writeln!(config, "{option}")?;
             }
+            make(&tree, &["olddefconfig"])?;
         }
 
-        // Run provisioners
-        self.provisioners.run(&mut ssh)?;
-
-        // Shutdown
-        ssh.shutdown("poweroff")?;
-        qemu.shutdown_wait()?;
+        info!(defconfig, "Building Buildroot");
+        make(&tree, &[])?;
+
+        import_disk(
+            &tree.join("output/images").join(&self.configuration.image),
+            DiskFormat::Raw,
+            None,
+            &worker.qcow_path,
+            self.minimum_size.clone().into(),
+        )?;
+        Qcow3::open(&worker.qcow_path)?.create_snapshot(checkpoint::INSTALL)?;
         Ok(())
     }
 }
 
-/// This provisioner configures the Archlinux mirror list.
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
-pub struct ArchMirrorlistProvisioner {
-    pub mirrors: Vec<String>,
+/// The defconfig that builds a GPT disk image booted by UEFI firmware.
+fn default_defconfig(arch: ImageArch) -> Result<&'static str> {
+    Ok(match arch {
+        ImageArch::Amd64 => "pc_x86_64_efi_defconfig",
+        ImageArch::Arm64 => "aarch64_efi_defconfig",
+        _ => bail!("Unsupported architecture"),
+    })
 }
 
-impl Default for ArchMirrorlistProvisioner {
-    fn default() -> Self {
-        Self {
-            mirrors: vec![
-                String::from("https://geo.mirror.pkgbuild.com/"),
-                String::from("https://mirror.rackspace.com/archlinux/"),
-                String::from("https://mirrors.edge.kernel.org/archlinux/"),
-            ],
-        }
+/// Extract a source tarball into `dest`, dropping its top-level directory.
+fn extract(tarball: &Path, dest: &Path) -> Result<()> {
+    if !tarball.is_file() {
+        bail!("Buildroot tarball not found: {}", tarball.display());
+    }
+    std::fs::create_dir_all(dest)?;
+
+    let status = Command::new("tar")
+        .arg("-xf")
+        .arg(tarball)
+        .arg("-C")
+        .arg(dest)
+        .arg("--strip-components=1")
+        .status()
+        .context("failed to run tar")?;
+    if !status.success() {
+        bail!("Failed to extract {}", tarball.display());
     }
+    Ok(())
 }
 
-impl ArchMirrorlistProvisioner {
-    pub fn format_mirrorlist(&self) -> String {
-        self.mirrors
-            .iter()
-            .map(|s| format!("Server = {}", s))
-            .collect::<Vec<String>>()
-            .join("\n")
+/// Run `make` in the source tree.
+fn make(tree: &Path, targets: &[&str]) -> Result<()> {
+    let status = Command::new("make")
+        .arg("-C")
+        .arg(tree)
+        .args(targets)
+        .status()
+        .context("failed to run make")?;
+    if !status.success() {
+        bail!("make {} failed", targets.join(" "));
     }
+    Ok(())
 }
 
-/// Fetch the latest installation ISO
-fn fetch_latest_iso(mirrorlist: ArchMirrorlistProvisioner) -> Result<IsoSource> {
-    for mirror in mirrorlist.mirrors {
-        let rs = reqwest::blocking::get(format!("{mirror}/iso/latest/sha1sums.txt"))?;
-        if rs.status().is_success() {
-            for line in BufReader::new(rs).lines().filter_map(|result| result.ok()) {
-                if line.ends_with(".iso") {
-                    let split: Vec<&str> = line.split_whitespace().collect();
-                    if let [hash, filename] = split[..] {
-                        return Ok(IsoSource {
-                            url: format!("{mirror}/iso/latest/{filename}"),
-                            checksum: format!("sha1:{hash}"),
-                        });
-                    }
-                }
-            }
-        }
+/// A Buildroot source tree.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct BuildrootSource {
+    /// Path to the source tarball, relative to the context directory
+    pub path: PathBuf,
+}
+
+impl Prompt for BuildrootSource {
+    fn prompt(&mut self, _: &Builder) -> Result<()> {
+        use dialoguer::Input;
+        let theme = crate::cli::cmd::init::theme();
+
+        let path: String = Input::with_theme(&theme)
+            .with_prompt("Buildroot source tarball")
+            .interact_text()?;
+        self.path = PathBuf::from(path);
+        Ok(())
     }
-    bail!("Failed to request latest ISO");
 }
 
+/// The Buildroot configuration to build.
+#[derive(Clone, Serialize, Deserialize, Debug, SmartDefault)]
+pub struct BuildrootConfiguration {
+    /// Defconfig to start from. Defaults to the architecture's EFI
+    /// defconfig.
+    #[serde(default)]
+    pub defconfig: Option<String>,
+
+    /// Options added to the defconfig (e.g. `BR2_PACKAGE_DROPBEAR=y`)
+    #[serde(default)]
+    pub options: Vec<String>,
+
+    /// The disk image in `output/images` to import
+    #[serde(default = "default_image")]
+    #[default(default_image())]
+    pub image: String,
+}
+
+fn default_image() -> String {
+    "disk.img".to_string()
+}
+
+impl Prompt for BuildrootConfiguration {
+    fn prompt(&mut self, _: &Builder) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_buildroot() -> Result<()> {
+        let element: Buildroot = ron::from_str(
+            r#"Buildroot(
+                arch: Arm64,
+                minimum_size: "1G",
+                source: (path: "buildroot-2025.02.tar.gz"),
+                configuration: (
+                    options: ["BR2_PACKAGE_DROPBEAR=y"],
+                ),
+            )"#,
+        )?;
+        assert!(element.configuration.defconfig.is_none());
+        assert_eq!(element.configuration.image, "disk.img");
+        assert_eq!(default_defconfig(element.arch.0)?, "aarch64_efi_defconfig");
+        Ok(())
+    }
+}
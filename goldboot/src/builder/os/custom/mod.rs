@@ -0,0 +1,109 @@
+use anyhow::{Result, bail};
+use goldboot_image::qcow::Qcow3;
+use serde::{Deserialize, Serialize};
+use smart_default::SmartDefault;
+use std::collections::HashMap;
+use validator::Validate;
+
+use crate::{
+    builder::{
+        Builder, checkpoint,
+        options::{arch::Arch, boot_commands::BootCommands, iso::Iso, minimum_size::MinimumSize},
+        qemu::{OsCategory, QemuBuilder},
+        steps::PreStep,
+    },
+    cli::prompt::Prompt,
+};
+
+use super::BuildImage;
+
+/// Installs any ISO by running a boot command script from `goldboot.ron`,
+/// for distributions (or releases) goldboot has no dedicated element for.
+///
+/// The script must finish the install and power the VM off:
+///
+/// ```ron
+/// Custom(
+///     iso: (url: "https://example.com/installer.iso", checksum: None),
+///     files: ["answers.cfg"],
+///     boot_commands: [
+///         WaitOcr("boot:"),
+///         Type("install auto=true file=/dev/vdb/answers.cfg\n"),
+///         Timeout(7200, WaitSerial("reboot: Power down")),
+///     ],
+/// )
+/// ```
+#[goldboot_macros::Os(architectures(Amd64, Arm64))]
+#[derive(Clone, Serialize, Deserialize, Validate, Debug, SmartDefault, goldboot_macros::Prompt)]
+pub struct Custom {
+    pub arch: Arch,
+    pub minimum_size: MinimumSize,
+
+    #[default(Iso {
+        url: "https://example.com/installer.iso".parse().unwrap(),
+        checksum: None,
+    })]
+    pub iso: Iso,
+
+    /// Files from the context directory to put on a FAT drive attached to
+    /// the VM (usually `/dev/vdb`), such as answer files for the installer
+    #[serde(default)]
+    pub files: CustomFiles,
+
+    /// The commands that drive the installer
+    pub boot_commands: BootCommands,
+
+    /// Steps that run on the host before the VM boots (e.g. rendering an
+    /// answer file from a template).
+    #[serde(default)]
+    pub pre_steps: Vec<PreStep>,
+}
+
+impl BuildImage for Custom {
+    fn build(&self, worker: &Builder) -> Result<()> {
+        if worker.has_checkpoint(checkpoint::INSTALL) {
+            return Ok(());
+        }
+
+        if self.boot_commands.0.is_empty() {
+            bail!("Custom elements need at least one boot command");
+        }
+        self.boot_commands.validate()?;
+
+        let mut qemu_builder = QemuBuilder::new(worker, OsCategory::Linux).with_iso(&self.iso)?;
+
+        if !self.files.0.is_empty() {
+            let files = self
+                .files
+                .0
+                .iter()
+                .map(|name| {
+                    let path = worker.effective_context_dir.join(name);
+                    match std::fs::read(&path) {
+                        Ok(content) => Ok((name.clone(), content)),
+                        Err(e) => bail!("Failed to read {path:?}: {e}"),
+                    }
+                })
+                .collect::<Result<HashMap<_, _>>>()?;
+            qemu_builder = qemu_builder.drive_files(files)?;
+        }
+
+        let boot_commands = self.boot_commands.to_vnc(&worker.effective_context_dir)?;
+        let mut qemu = qemu_builder.start()?;
+        qemu.vnc.run(boot_commands)?;
+
+        qemu.shutdown_wait()?;
+        Qcow3::open(&worker.qcow_path)?.create_snapshot(checkpoint::INSTALL)?;
+        Ok(())
+    }
+}
+
+/// Context directory files made available to the VM.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct CustomFiles(pub Vec<String>);
+
+impl Prompt for CustomFiles {
+    fn prompt(&mut self, _: &Builder) -> Result<()> {
+        Ok(())
+    }
+}
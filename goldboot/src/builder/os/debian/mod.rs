@@ -8,12 +8,13 @@ use validator::Validate;
 
 use crate::{
     builder::{
-        Builder,
+        Builder, checkpoint,
         http::HttpServer,
+        offline::OfflineStep,
         options::{
             arch::Arch, hostname::Hostname, iso::Iso, locale::Locale, minimum_size::MinimumSize,
-            ntp::Ntp, packages::Packages, root_password::RootPassword, timezone::Timezone,
-            unix_users::UnixUsers,
+            ntp::Ntp, packages::Packages, partition_layout::PartitionLayout,
+            root_password::RootPassword, timezone::Timezone, unix_users::UnixUsers,
         },
         qemu::{OsCategory, QemuBuilder},
     },
@@ -58,15 +59,23 @@ pub struct Debian {
     #[serde(default)]
     pub ntp: Ntp,
 
+    /// Disk partition layout and optional LUKS encryption
+    #[serde(default)]
+    pub partition_layout: PartitionLayout,
+
     #[default(Iso {
         url: "https://cdimage.debian.org/cdimage/release/current/amd64/iso-cd/debian-13.4.0-amd64-netinst.iso".parse().unwrap(),
         checksum: Some("sha256:0b813535dd76f2ea96eff908c65e8521512c92a0631fd41c95756ffd7d4896dc".to_string()),
     })]
     pub iso: Iso,
+
+    /// Steps that edit the disk on the host after the build.
+    #[serde(default)]
+    pub offline_steps: Vec<OfflineStep>,
 }
 
 impl Debian {
-    fn generate_preseed(&self) -> String {
+    fn generate_preseed(&self) -> Result<String> {
         let root_password = match &self.root_password {
             RootPassword::Plaintext(p) => p.clone(),
             RootPassword::PlaintextEnv(name) => {
@@ -83,8 +92,9 @@ impl Debian {
         };
 
         let late_command = self.generate_late_command();
+        let partitioning = self.partition_layout.partman()?;
 
-        format!(
+        Ok(format!(
             r#"#_preseed_V1
 ### Localization
 d-i debian-installer/locale string {language}.{encoding}
@@ -116,13 +126,11 @@ d-i clock-setup/ntp boolean {ntp}
 
 ### Partitioning
 d-i partman-auto/disk string /dev/vda
-d-i partman-auto/method string regular
-d-i partman-auto-lvm/guided_size string max
+{partitioning}d-i partman-auto-lvm/guided_size string max
 d-i partman-lvm/device_remove_lvm boolean true
 d-i partman-md/device_remove_md boolean true
 d-i partman-lvm/confirm boolean true
 d-i partman-lvm/confirm_nooverwrite boolean true
-d-i partman-auto/choose_recipe select atomic
 d-i partman-partitioning/confirm_write_new_label boolean true
 d-i partman/choose_partition select finish
 d-i partman/confirm boolean true
@@ -154,7 +162,7 @@ d-i preseed/late_command string {late_command}
             ntp = self.ntp.0,
             extra_packages = extra_packages,
             late_command = late_command,
-        )
+        ))
     }
 
     fn generate_late_command(&self) -> String {
@@ -188,6 +196,9 @@ d-i preseed/late_command string {late_command}
 
 impl BuildImage for Debian {
     fn build(&self, worker: &Builder) -> Result<()> {
+        self.partition_layout.check()?;
+        let preseed = self.generate_preseed()?;
+
         let mut qemu = QemuBuilder::new(worker, OsCategory::Linux)
             .vga("cirrus")
             .with_iso(&self.iso)?
@@ -196,43 +207,57 @@ impl BuildImage for Debian {
 
         // Start HTTP with generated preseed
         let http = HttpServer::builder()?
-            .file("preseed.cfg", self.generate_preseed().into_bytes())?
+            .file("preseed.cfg", preseed.into_bytes())?
             .serve();
 
         // Send boot command
-        #[rustfmt::skip]
-		qemu.vnc.run(vec![
-            // Wait for boot menu
-			wait_screen_rect!("f6852e8b6e072d15270b2b215bbada3da30fd733", 100, 100, 400, 400),
-            // Trigger unattended install
-			input!("aa"),
-            // Wait for preseed URL prompt
-            match self.edition {
-                DebianEdition::Bullseye => todo!(),
-                DebianEdition::Bookworm => wait_screen!("6ee7873098bceb5a2124db82dae6abdae214ce7e"),
-                DebianEdition::Trixie   => wait_screen!("6ee7873098bceb5a2124db82dae6abdae214ce7e"),
-                DebianEdition::Forky    => todo!(),
-                DebianEdition::Sid      => todo!(),
-            },
-			enter!(format!("http://{}:{}/preseed.cfg", http.address, http.port)),
-            // Wait for login prompt
-            match self.edition {
-                DebianEdition::Bullseye => todo!(),
-                DebianEdition::Bookworm => wait_screen!("2eb1ef517849c86a322ba60bb05386decbf00ba5"),
-                DebianEdition::Trixie   => wait_screen!("2eb1ef517849c86a322ba60bb05386decbf00ba5"),
-                DebianEdition::Forky    => todo!(),
-                DebianEdition::Sid      => todo!(),
-            },
-            // Login as root
-            enter!("root"),
-            enter!(match &self.root_password {
-                RootPassword::Plaintext(p) => p.clone(),
-                RootPassword::PlaintextEnv(name) => std::env::var(name).expect("environment variable not found"),
-            }),
-		])?;
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            #[rustfmt::skip]
+    		let mut cmds = vec![
+                // Wait for boot menu
+    			wait_screen_rect!("f6852e8b6e072d15270b2b215bbada3da30fd733", 100, 100, 400, 400),
+                // Trigger unattended install
+    			input!("aa"),
+                // Wait for preseed URL prompt
+                match self.edition {
+                    DebianEdition::Bullseye => todo!(),
+                    DebianEdition::Bookworm => wait_screen!("6ee7873098bceb5a2124db82dae6abdae214ce7e"),
+                    DebianEdition::Trixie   => wait_screen!("6ee7873098bceb5a2124db82dae6abdae214ce7e"),
+                    DebianEdition::Forky    => todo!(),
+                    DebianEdition::Sid      => todo!(),
+                },
+    			enter!(format!("http://{}:{}/preseed.cfg", http.address, http.port)),
+            ];
+            // Unlock the root filesystem when the installed system boots
+            if let Some(passphrase) = self.partition_layout.passphrase() {
+                cmds.push(wait_text_ocr!("passphrase"));
+                cmds.push(enter!(passphrase));
+            }
+            #[rustfmt::skip]
+            cmds.extend([
+                // Wait for login prompt
+                match self.edition {
+                    DebianEdition::Bullseye => todo!(),
+                    DebianEdition::Bookworm => wait_screen!("2eb1ef517849c86a322ba60bb05386decbf00ba5"),
+                    DebianEdition::Trixie   => wait_screen!("2eb1ef517849c86a322ba60bb05386decbf00ba5"),
+                    DebianEdition::Forky    => todo!(),
+                    DebianEdition::Sid      => todo!(),
+                },
+                // Login as root
+                enter!("root"),
+                enter!(match &self.root_password {
+                    RootPassword::Plaintext(p) => p.clone(),
+                    RootPassword::PlaintextEnv(name) => std::env::var(name).expect("environment variable not found"),
+                }),
+    		]);
+            qemu.vnc.run(cmds)?;
+        }
 
         // Wait for SSH
         let ssh = qemu.ssh("root")?;
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            qemu.checkpoint(checkpoint::INSTALL)?;
+        }
 
         // Shutdown
         ssh.shutdown("poweroff")?;
@@ -0,0 +1,443 @@
+use anyhow::{Result, bail};
+use goldboot_image::ImageArch;
+use serde::{Deserialize, Serialize};
+use smart_default::SmartDefault;
+use strum::{Display, EnumIter, IntoEnumIterator};
+use validator::Validate;
+
+use crate::{
+    builder::{
+        Builder, checkpoint,
+        http::HttpServer,
+        offline::OfflineStep,
+        options::{
+            arch::Arch,
+            hostname::Hostname,
+            iso::Iso,
+            locale::Locale,
+            minimum_size::MinimumSize,
+            packages::Packages,
+            partition_layout::{Filesystem, PartitionLayout},
+            root_password::RootPassword,
+            timezone::Timezone,
+            unix_users::UnixUsers,
+        },
+        qemu::{OsCategory, QemuBuilder},
+        ssh::{GUEST_HOST_KEY_PATH, HostKey},
+    },
+    cli::prompt::Prompt,
+    enter, input, wait_text_ocr,
+};
+
+use super::BuildImage;
+
+/// Fedora is a Linux distribution developed by the Fedora Project, sponsored
+/// by Red Hat, that focuses on innovation and upstream free software.
+///
+/// The install is unattended through a generated Kickstart file.
+///
+/// Upstream: https://fedoraproject.org
+/// Maintainer: cilki
+#[goldboot_macros::Os(architectures(Amd64, Arm64))]
+#[derive(Clone, Serialize, Deserialize, Validate, Debug, SmartDefault, goldboot_macros::Prompt)]
+pub struct Fedora {
+    pub arch: Arch,
+    pub minimum_size: MinimumSize,
+    #[serde(default)]
+    pub release: FedoraRelease,
+    #[serde(default)]
+    pub hostname: Hostname,
+    #[serde(default)]
+    pub root_password: RootPassword,
+
+    /// Additional user accounts to create
+    pub users: Option<UnixUsers>,
+
+    /// Packages to install
+    pub packages: Option<Packages>,
+
+    /// System timezone
+    #[serde(default)]
+    pub timezone: Timezone,
+
+    /// Locale and keyboard settings
+    #[serde(default)]
+    pub locale: Locale,
+
+    /// Disk partition layout and optional LUKS encryption
+    #[serde(default)]
+    pub partition_layout: PartitionLayout,
+
+    /// Installation media. Defaults to the Everything netinst ISO of the
+    /// release.
+    pub iso: Option<Iso>,
+
+    /// Steps that edit the disk on the host after the build.
+    #[serde(default)]
+    pub offline_steps: Vec<OfflineStep>,
+}
+
+impl Fedora {
+    /// Generate the Kickstart file. `public_key` is authorized for root so
+    /// that goldboot can reach the installed system over SSH, and `host_key`
+    /// is installed so that goldboot can verify it.
+    fn generate_kickstart(&self, public_key: &str, host_key: &HostKey) -> String {
+        let root_password = match &self.root_password {
+            RootPassword::Plaintext(p) => p.clone(),
+            RootPassword::PlaintextEnv(name) => {
+                std::env::var(name).expect("environment variable not found")
+            }
+        };
+
+        let arch = match self.arch.0 {
+            ImageArch::Arm64 => "aarch64",
+            _ => "x86_64",
+        };
+
+        let users: String = self
+            .users
+            .as_ref()
+            .map(|users| {
+                users
+                    .0
+                    .iter()
+                    .map(|u| {
+                        let groups = if u.sudo { " --groups=wheel" } else { "" };
+                        format!(
+                            "user --name={} --password={} --plaintext{groups}\n",
+                            u.username,
+                            quote(&u.password)
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let packages: String = self
+            .packages
+            .as_ref()
+            .map(|p| p.0.iter().map(|p| format!("{p}\n")).collect())
+            .unwrap_or_default();
+
+        format!(
+            r#"# Generated by goldboot
+text
+lang {language}.{encoding}
+keyboard --vckeymap={keyboard} --xlayouts='{keyboard}'
+timezone {timezone} --utc
+network --bootproto=dhcp --hostname={hostname} --activate
+url --mirrorlist="https://mirrors.fedoraproject.org/mirrorlist?repo=fedora-{release}&arch={arch}"
+
+rootpw --plaintext {root_password}
+{users}sshkey --username=root {public_key}
+firstboot --disable
+services --enabled=sshd
+
+zerombr
+clearpart --all --initlabel --disklabel=gpt
+bootloader
+{partitions}
+%packages
+@^minimal-environment
+openssh-server
+{packages}%end
+
+%post
+cat > {host_key_path} <<'GOLDBOOT'
+{host_key}
+GOLDBOOT
+cat > {host_key_path}.pub <<'GOLDBOOT'
+{host_public_key}
+GOLDBOOT
+chmod 600 {host_key_path}
+restorecon {host_key_path} {host_key_path}.pub
+%end
+
+reboot
+"#,
+            language = self.locale.language,
+            encoding = self.locale.encoding,
+            keyboard = self.locale.keyboard,
+            timezone = self.timezone.0,
+            hostname = self.hostname.0,
+            release = self.release,
+            root_password = quote(&root_password),
+            public_key = quote(public_key.trim()),
+            partitions = partitions(&self.partition_layout),
+            host_key_path = GUEST_HOST_KEY_PATH,
+            host_key = host_key.private.trim_end(),
+            host_public_key = host_key.public.trim_end(),
+        )
+    }
+}
+
+/// Kickstart partitioning commands for a partition layout. LVM and btrfs
+/// layouts get a separate `/boot`, which Anaconda requires.
+fn partitions(layout: &PartitionLayout) -> String {
+    let efi = "part /boot/efi --fstype=efi --size=512\n";
+    let boot = "part /boot --fstype=ext4 --size=1024\n";
+    match layout {
+        PartitionLayout::Uefi => format!("{efi}part / --fstype=ext4 --grow\n"),
+        PartitionLayout::UefiWithSwap { swap_size_mib } => {
+            format!("{efi}part swap --size={swap_size_mib}\npart / --fstype=ext4 --grow\n")
+        }
+        PartitionLayout::UefiLuks { passphrase } => format!(
+            "{efi}part / --fstype=ext4 --grow --encrypted --luks-version=luks2 --passphrase={}\n",
+            quote(passphrase)
+        ),
+        PartitionLayout::UefiLvm {
+            volume_group,
+            swap_size_mib,
+        } => {
+            let mut commands =
+                format!("{efi}{boot}part pv.01 --grow\nvolgroup {volume_group} pv.01\n");
+            if let Some(swap_size_mib) = swap_size_mib {
+                commands.push_str(&format!(
+                    "logvol swap --vgname={volume_group} --name=swap --size={swap_size_mib}\n"
+                ));
+            }
+            commands.push_str(&format!(
+                "logvol / --vgname={volume_group} --name=root --fstype=ext4 --grow\n"
+            ));
+            commands
+        }
+        PartitionLayout::UefiBtrfs { subvolumes } => {
+            let mut commands =
+                format!("{efi}{boot}part btrfs.01 --grow\nbtrfs none --label=root btrfs.01\n");
+            for subvolume in subvolumes {
+                commands.push_str(&format!(
+                    "btrfs {} --subvol --name={} LABEL=root\n",
+                    subvolume.mountpoint, subvolume.name
+                ));
+            }
+            commands
+        }
+        PartitionLayout::UefiXfs => format!("{efi}part / --fstype=xfs --grow\n"),
+        PartitionLayout::Custom { partitions } => partitions
+            .iter()
+            .map(|partition| {
+                let size = match partition.size_mib {
+                    Some(size_mib) => format!("--size={size_mib}"),
+                    None => "--grow".to_string(),
+                };
+                match (partition.filesystem, &partition.mountpoint) {
+                    (Filesystem::Efi, _) => format!("part /boot/efi --fstype=efi {size}\n"),
+                    (Filesystem::Swap, _) => format!("part swap {size}\n"),
+                    (filesystem, mountpoint) => format!(
+                        "part {} --fstype={} {size}\n",
+                        mountpoint.as_deref().unwrap_or("/"),
+                        filesystem.as_str()
+                    ),
+                }
+            })
+            .collect(),
+    }
+}
+
+/// Quote a Kickstart argument, which is split like a shell command.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', r"\\").replace('"', "\\\""))
+}
+
+impl BuildImage for Fedora {
+    fn build(&self, worker: &Builder) -> Result<()> {
+        self.partition_layout.check()?;
+
+        let iso = match &self.iso {
+            Some(iso) => iso.clone(),
+            None => self.release.iso(self.arch.0)?,
+        };
+
+        let mut qemu_builder = QemuBuilder::new(worker, OsCategory::Linux)
+            .with_iso(&iso)?
+            // The installed system runs its own sshd
+            .forward_ssh(22);
+        let public_key = String::from_utf8(qemu_builder.ssh_public_key()?)?;
+        let host_key = qemu_builder.pin_host_key()?;
+        let mut qemu = qemu_builder.start()?;
+
+        // Start HTTP with generated Kickstart
+        let http = HttpServer::builder()?
+            .file(
+                "ks.cfg",
+                self.generate_kickstart(&public_key, &host_key).into_bytes(),
+            )?
+            .serve();
+
+        // Send boot command
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            // The x86_64 and aarch64 ISOs share the same layout
+            #[rustfmt::skip]
+            let mut cmds = vec![
+                // Wait for boot menu
+                wait_text_ocr!("Install Fedora"),
+                // Boot the installer from the GRUB command line with the Kickstart
+                input!("c"),
+                enter!(format!(
+                    "linux /images/pxeboot/vmlinuz inst.stage2=cdrom inst.ks=http://{}:{}/ks.cfg",
+                    http.address, http.port
+                )),
+                enter!("initrd /images/pxeboot/initrd.img"),
+                enter!("boot"),
+            ];
+            // Unlock the root filesystem when the installed system boots
+            if let Some(passphrase) = self.partition_layout.passphrase() {
+                cmds.push(wait_text_ocr!("passphrase"));
+                cmds.push(enter!(passphrase));
+            }
+            // Wait for the installed system to boot
+            cmds.push(wait_text_ocr!(format!("{} login", self.hostname.0)));
+            qemu.vnc.run(cmds)?;
+        }
+
+        // Wait for SSH
+        let mut ssh = qemu.ssh("root")?;
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            qemu.checkpoint(checkpoint::INSTALL)?;
+        }
+
+        // Revoke the build key
+        let Some(key) = public_key.split_whitespace().nth(1) else {
+            bail!("Malformed public key");
+        };
+        if ssh.exec(&format!("sed -i '\\|{key}|d' /root/.ssh/authorized_keys"))? != 0 {
+            bail!("Failed to revoke the build SSH key");
+        }
+        ssh.remove_host_key()?;
+
+        // Shutdown
+        ssh.shutdown("poweroff")?;
+        qemu.shutdown_wait()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, EnumIter, Display, SmartDefault)]
+pub enum FedoraRelease {
+    #[serde(rename = "43")]
+    #[strum(to_string = "43")]
+    F43,
+    #[serde(rename = "42")]
+    #[strum(to_string = "42")]
+    #[default]
+    F42,
+    #[serde(rename = "41")]
+    #[strum(to_string = "41")]
+    F41,
+}
+
+impl FedoraRelease {
+    /// The Everything netinst ISO of the release.
+    fn iso(&self, arch: ImageArch) -> Result<Iso> {
+        let arch = match arch {
+            ImageArch::Amd64 => "x86_64",
+            ImageArch::Arm64 => "aarch64",
+            _ => bail!("Unsupported architecture"),
+        };
+        let compose = match self {
+            Self::F43 => "1.6",
+            Self::F42 => "1.1",
+            Self::F41 => "1.4",
+        };
+        Ok(Iso {
+            url: format!(
+                "https://download.fedoraproject.org/pub/fedora/linux/releases/{self}/Everything/{arch}/iso/Fedora-Everything-netinst-{arch}-{self}-{compose}.iso"
+            )
+            .parse()
+            .unwrap(),
+            checksum: None,
+        })
+    }
+}
+
+impl Prompt for FedoraRelease {
+    fn prompt(&mut self, _: &Builder) -> Result<()> {
+        let releases: Vec<FedoraRelease> = FedoraRelease::iter().collect();
+        let index = dialoguer::Select::with_theme(&crate::cli::cmd::init::theme())
+            .with_prompt("Choose Fedora release")
+            .default(1)
+            .items(&releases)
+            .interact()?;
+        *self = releases[index];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::options::unix_users::UnixUser;
+
+    #[test]
+    fn generate_kickstart() {
+        let fedora = Fedora {
+            users: Some(UnixUsers(vec![UnixUser {
+                username: "alice".to_string(),
+                password: "p\"w".to_string(),
+                sudo: true,
+            }])),
+            packages: Some(Packages(vec!["vim".to_string(), "git".to_string()])),
+            ..Default::default()
+        };
+
+        let kickstart = fedora.generate_kickstart(
+            "ssh-ed25519 AAAA goldboot\n",
+            &HostKey {
+                private: "PRIVATE\n".to_string(),
+                public: "ssh-ed25519 BBBB goldboot\n".to_string(),
+            },
+        );
+        assert!(kickstart.contains("rootpw --plaintext \"root\"\n"));
+        assert!(
+            kickstart
+                .contains("user --name=alice --password=\"p\\\"w\" --plaintext --groups=wheel\n")
+        );
+        assert!(kickstart.contains("sshkey --username=root \"ssh-ed25519 AAAA goldboot\"\n"));
+        assert!(kickstart.contains("network --bootproto=dhcp --hostname=goldboot --activate\n"));
+        assert!(kickstart.contains("repo=fedora-42&arch=x86_64"));
+        assert!(kickstart.contains("openssh-server\nvim\ngit\n%end"));
+        assert!(kickstart.contains("part / --fstype=ext4 --grow\n"));
+        assert!(
+            kickstart
+                .contains("cat > /etc/ssh/ssh_host_ed25519_key <<'GOLDBOOT'\nPRIVATE\nGOLDBOOT\n")
+        );
+    }
+
+    #[test]
+    fn release_iso() -> Result<()> {
+        assert_eq!(
+            FedoraRelease::F42.iso(ImageArch::Amd64)?.url.as_str(),
+            "https://download.fedoraproject.org/pub/fedora/linux/releases/42/Everything/x86_64/iso/Fedora-Everything-netinst-x86_64-42-1.1.iso"
+        );
+        assert_eq!(
+            FedoraRelease::F43.iso(ImageArch::Arm64)?.url.as_str(),
+            "https://download.fedoraproject.org/pub/fedora/linux/releases/43/Everything/aarch64/iso/Fedora-Everything-netinst-aarch64-43-1.6.iso"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn kickstart_partitions() {
+        assert_eq!(
+            partitions(&PartitionLayout::UefiWithSwap {
+                swap_size_mib: 2048
+            }),
+            "part /boot/efi --fstype=efi --size=512\npart swap --size=2048\npart / --fstype=ext4 --grow\n"
+        );
+        assert!(
+            partitions(&PartitionLayout::UefiLuks {
+                passphrase: "secret".to_string()
+            })
+            .ends_with("--encrypted --luks-version=luks2 --passphrase=\"secret\"\n")
+        );
+        assert_eq!(
+            partitions(&PartitionLayout::UefiLvm {
+                volume_group: "fedora".to_string(),
+                swap_size_mib: None,
+            }),
+            "part /boot/efi --fstype=efi --size=512\npart /boot --fstype=ext4 --size=1024\n\
+             part pv.01 --grow\nvolgroup fedora pv.01\n\
+             logvol / --vgname=fedora --name=root --fstype=ext4 --grow\n"
+        );
+    }
+}
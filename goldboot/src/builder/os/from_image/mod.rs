@@ -0,0 +1,110 @@
+use anyhow::{Result, bail};
+use goldboot_image::qcow::Qcow3;
+use serde::{Deserialize, Serialize};
+use smart_default::SmartDefault;
+use validator::Validate;
+
+use crate::{
+    builder::{
+        Builder, checkpoint,
+        import::import_image,
+        offline::OfflineStep,
+        options::{arch::Arch, base_image::BaseImage, minimum_size::MinimumSize},
+        steps::{PostStep, PreStep},
+    },
+    cli::prompt::Prompt,
+};
+
+use super::{
+    BuildImage,
+    import_image::{ImportAccess, customize},
+};
+
+/// Extends a previously built goldboot image, like a Dockerfile `FROM`. The
+/// base image's disk is copied into the element and only this element's
+/// `post_steps` run against it. The base image's `content_id` is recorded as
+/// the element's parent in the new image.
+///
+/// Images built by goldboot don't keep the build's SSH key, so the base
+/// either runs cloud-init (the default `access`) or trusts a key given with
+/// `PrivateKey` access.
+///
+/// ```ron
+/// FromImage(
+///     base_image: (image: "registry.example.com/team-base:2024.10"),
+///     post_steps: [
+///         Ansible(playbook: "department.yml"),
+///     ],
+/// )
+/// ```
+#[goldboot_macros::Os(architectures(Amd64, Arm64))]
+#[derive(Clone, Serialize, Deserialize, Validate, Debug, SmartDefault, goldboot_macros::Prompt)]
+pub struct FromImage {
+    pub arch: Arch,
+    pub minimum_size: MinimumSize,
+
+    /// The image to start from
+    pub base_image: BaseImage,
+
+    /// How goldboot logs into the base image's system
+    #[serde(default)]
+    pub access: ImportAccess,
+
+    /// Steps that run on the host before the VM boots.
+    #[serde(default)]
+    pub pre_steps: Vec<PreStep>,
+
+    /// Steps that run over SSH on top of the base image.
+    #[serde(default)]
+    pub post_steps: Vec<PostStep>,
+
+    /// Steps that edit the disk on the host after the post-steps.
+    #[serde(default)]
+    pub offline_steps: Vec<OfflineStep>,
+}
+
+impl BuildImage for FromImage {
+    fn build(&self, worker: &Builder) -> Result<()> {
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            let image = self.base_image.load()?;
+            if image.primary_header.arch != self.arch.0 {
+                bail!(
+                    "Base image '{}' is {:?}, not {:?}",
+                    self.base_image.image,
+                    image.primary_header.arch,
+                    self.arch.0
+                );
+            }
+
+            import_image(&image, &worker.qcow_path, self.minimum_size.clone().into())?;
+            Qcow3::open(&worker.qcow_path)?.create_snapshot(checkpoint::INSTALL)?;
+        }
+
+        customize(worker, &self.access, &self.post_steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_from_image() -> Result<()> {
+        let element: FromImage = ron::from_str(
+            r#"FromImage(
+                arch: Amd64,
+                minimum_size: "16G",
+                base_image: (image: "registry.example.com/team-base:2024.10"),
+                access: PrivateKey(user: "admin", private_key: "keys/admin"),
+                post_steps: [HostExecutable(path: "department.sh")],
+            )"#,
+        )?;
+        assert_eq!(
+            element.base_image.image,
+            "registry.example.com/team-base:2024.10"
+        );
+        assert!(element.base_image.password.is_none());
+        assert_eq!(element.post_steps.len(), 1);
+        Ok(())
+    }
+}
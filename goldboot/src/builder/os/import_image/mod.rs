@@ -0,0 +1,276 @@
+use anyhow::{Result, bail};
+use goldboot_image::qcow::Qcow3;
+use serde::{Deserialize, Serialize};
+use smart_default::SmartDefault;
+use std::path::PathBuf;
+use tracing::warn;
+use validator::Validate;
+
+use crate::{
+    builder::{
+        Builder, checkpoint,
+        import::{DiskFormat, import_disk},
+        offline::OfflineStep,
+        options::{arch::Arch, minimum_size::MinimumSize},
+        qemu::{OsCategory, QemuBuilder},
+        ssh::SshConnection,
+        steps::{PostStep, PreStep, run_post_steps},
+    },
+    cli::prompt::Prompt,
+};
+
+use super::BuildImage;
+
+/// Starts from an existing disk (a vendor-supplied image, an earlier golden
+/// qcow2, a goldboot image) instead of an installer ISO. The disk is copied
+/// into the element, grown to `minimum_size` if it is smaller, booted and
+/// customized with `post_steps` over SSH. `offline_steps` edit the disk
+/// without booting it, so an element with only those never needs a VM.
+///
+/// ```ron
+/// ImportImage(
+///     source: (path: "debian-12-genericcloud-amd64.qcow2"),
+///     post_steps: [
+///         HostExecutable(path: "provision.sh"),
+///     ],
+/// )
+/// ```
+#[goldboot_macros::Os(architectures(Amd64, Arm64))]
+#[derive(Clone, Serialize, Deserialize, Validate, Debug, SmartDefault, goldboot_macros::Prompt)]
+pub struct ImportImage {
+    pub arch: Arch,
+    pub minimum_size: MinimumSize,
+
+    /// The disk to import
+    pub source: ImportSource,
+
+    /// How goldboot logs into the imported system
+    #[serde(default)]
+    pub access: ImportAccess,
+
+    /// Steps that run on the host before the VM boots.
+    #[serde(default)]
+    pub pre_steps: Vec<PreStep>,
+
+    /// Steps that run over SSH against the imported system.
+    #[serde(default)]
+    pub post_steps: Vec<PostStep>,
+
+    /// Steps that edit the disk on the host after the post-steps.
+    #[serde(default)]
+    pub offline_steps: Vec<OfflineStep>,
+}
+
+impl BuildImage for ImportImage {
+    fn build(&self, worker: &Builder) -> Result<()> {
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            let source = worker.effective_context_dir.join(&self.source.path);
+            let Some(format) = self
+                .source
+                .format
+                .or_else(|| DiskFormat::from_path(&source))
+            else {
+                bail!(
+                    "Cannot tell the format of {} from its extension; set `format`",
+                    source.display()
+                );
+            };
+
+            import_disk(
+                &source,
+                format,
+                self.source.password.clone(),
+                &worker.qcow_path,
+                self.minimum_size.clone().into(),
+            )?;
+            Qcow3::open(&worker.qcow_path)?.create_snapshot(checkpoint::INSTALL)?;
+        }
+
+        customize(worker, &self.access, &self.post_steps)
+    }
+}
+
+/// Boot the element's working qcow and run `post_steps` against it over SSH.
+/// Does nothing when there are no post-steps.
+pub(super) fn customize(
+    worker: &Builder,
+    access: &ImportAccess,
+    post_steps: &[PostStep],
+) -> Result<()> {
+    if post_steps.is_empty() {
+        return Ok(());
+    }
+
+    let qemu_builder = QemuBuilder::new(worker, OsCategory::Linux).forward_ssh(22);
+    let public_key = String::from_utf8(qemu_builder.ssh_public_key()?)?;
+
+    let mut qemu = match access {
+        ImportAccess::CloudInit { user } => qemu_builder.cloud_init_ssh(user)?.start()?,
+        ImportAccess::PrivateKey { .. } => qemu_builder.start()?,
+    };
+
+    let mut ssh = match access {
+        ImportAccess::CloudInit { user } => qemu.ssh(user)?,
+        ImportAccess::PrivateKey {
+            user,
+            private_key,
+            host_key,
+        } => {
+            if host_key.is_none() {
+                warn!(
+                    "No host key given for the imported image; trusting the first one it presents"
+                );
+            }
+            SshConnection::new(
+                user,
+                &worker.effective_context_dir.join(private_key),
+                qemu.ssh_port,
+                host_key
+                    .as_ref()
+                    .map(|path| worker.effective_context_dir.join(path))
+                    .as_deref(),
+            )?
+        }
+    };
+
+    run_post_steps(worker, &mut qemu, &mut ssh, post_steps)?;
+
+    let sudo = if access.user() == "root" { "" } else { "sudo " };
+
+    if let ImportAccess::CloudInit { .. } = access {
+        // Revoke the build key and let cloud-init run again (with the
+        // real datasource) on the first boot of the deployed image
+        let Some(key) = public_key.split_whitespace().nth(1) else {
+            bail!("Malformed public key");
+        };
+        let revoke = format!(
+            "{sudo}sh -c 'sed -i \"\\|{key}|d\" /root/.ssh/authorized_keys /home/*/.ssh/authorized_keys 2>/dev/null; cloud-init clean --logs'"
+        );
+        if ssh.exec(&revoke)? != 0 {
+            bail!("Failed to revoke the build SSH key");
+        }
+    }
+
+    ssh.shutdown(&format!("{sudo}poweroff"))?;
+    qemu.shutdown_wait()?;
+    Ok(())
+}
+
+/// An existing disk to import.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct ImportSource {
+    /// Path to the disk, relative to the context directory
+    pub path: PathBuf,
+
+    /// Format of the disk. Inferred from the extension when not given.
+    #[serde(default)]
+    pub format: Option<DiskFormat>,
+
+    /// Password for an encrypted goldboot image
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl Prompt for ImportSource {
+    fn prompt(&mut self, _: &Builder) -> Result<()> {
+        use dialoguer::Input;
+        let theme = crate::cli::cmd::init::theme();
+
+        let path: String = Input::with_theme(&theme)
+            .with_prompt("Disk to import (qcow2, raw, vmdk or gb)")
+            .interact_text()?;
+        self.path = PathBuf::from(path);
+        Ok(())
+    }
+}
+
+/// How goldboot gets an SSH session on the imported system.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum ImportAccess {
+    /// Authorize a temporary key through a cloud-init NoCloud seed. The key
+    /// is revoked after the post-steps run.
+    CloudInit {
+        #[serde(default = "default_user")]
+        user: String,
+    },
+
+    /// Log in with a private key (relative to the context directory) that
+    /// the imported system already trusts. `host_key` is the system's SSH
+    /// host public key (also relative to the context directory), which the
+    /// connection and ansible verify.
+    PrivateKey {
+        user: String,
+        private_key: PathBuf,
+        #[serde(default)]
+        host_key: Option<PathBuf>,
+    },
+}
+
+impl ImportAccess {
+    fn user(&self) -> &str {
+        match self {
+            Self::CloudInit { user } | Self::PrivateKey { user, .. } => user,
+        }
+    }
+}
+
+fn default_user() -> String {
+    "root".to_string()
+}
+
+impl Default for ImportAccess {
+    fn default() -> Self {
+        Self::CloudInit {
+            user: default_user(),
+        }
+    }
+}
+
+impl Prompt for ImportAccess {
+    fn prompt(&mut self, _: &Builder) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn parse_import_image() -> Result<()> {
+        let element: ImportImage = ron::from_str(
+            r#"ImportImage(
+                arch: Amd64,
+                minimum_size: "32G",
+                source: (path: "appliance.img", format: Some(Raw)),
+                access: PrivateKey(
+                    user: "admin",
+                    private_key: "keys/admin",
+                    host_key: Some("keys/ssh_host_ed25519_key.pub"),
+                ),
+                post_steps: [HostExecutable(path: "provision.sh")],
+            )"#,
+        )?;
+        assert_eq!(element.source.format, Some(DiskFormat::Raw));
+        assert!(matches!(
+            element.access,
+            ImportAccess::PrivateKey { ref user, host_key: Some(ref host_key), .. }
+                if user == "admin" && host_key == Path::new("keys/ssh_host_ed25519_key.pub")
+        ));
+        assert_eq!(element.post_steps.len(), 1);
+
+        let element: ImportImage = ron::from_str(
+            r#"ImportImage(
+                arch: Amd64,
+                minimum_size: "16G",
+                source: (path: "golden.gb"),
+            )"#,
+        )?;
+        assert!(matches!(
+            element.access,
+            ImportAccess::CloudInit { ref user } if user == "root"
+        ));
+        Ok(())
+    }
+}
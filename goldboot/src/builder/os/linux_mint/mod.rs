@@ -0,0 +1,211 @@
+use anyhow::Result;
+use goldboot_image::qcow::Qcow3;
+use serde::{Deserialize, Serialize};
+use smart_default::SmartDefault;
+use strum::{Display, EnumIter, IntoEnumIterator};
+use tracing::debug;
+use validator::Validate;
+
+use crate::{
+    builder::{
+        Builder, checkpoint,
+        http::HttpServer,
+        offline::OfflineStep,
+        options::{
+            arch::Arch, hostname::Hostname, iso::Iso, late_commands::LateCommands, locale::Locale,
+            minimum_size::MinimumSize, ntp::Ntp, packages::Packages,
+            partition_layout::PartitionLayout, root_password::RootPassword, timezone::Timezone,
+            unix_users::UnixUsers,
+        },
+        qemu::{OsCategory, QemuBuilder},
+    },
+    cli::prompt::Prompt,
+    enter, input, wait_text_ocr,
+};
+
+use super::{BuildImage, ubuntu::autoinstall::Autoinstall};
+
+/// Linux Mint is a Linux distribution based on Ubuntu that ships the
+/// Cinnamon, MATE and Xfce desktops.
+///
+/// The install is unattended through a generated preseed. The installer
+/// requires at least one user in `users`.
+///
+/// Upstream: https://linuxmint.com
+/// Maintainer: cilki
+#[goldboot_macros::Os(architectures(Amd64))]
+#[derive(Clone, Serialize, Deserialize, Validate, Debug, SmartDefault, goldboot_macros::Prompt)]
+pub struct LinuxMint {
+    pub arch: Arch,
+    pub minimum_size: MinimumSize,
+    #[serde(default)]
+    pub release: LinuxMintRelease,
+    #[serde(default)]
+    pub edition: LinuxMintEdition,
+    #[serde(default)]
+    pub hostname: Hostname,
+    #[serde(default)]
+    pub root_password: RootPassword,
+
+    /// User accounts to create. The first is the installer's primary user.
+    pub users: Option<UnixUsers>,
+
+    /// Packages to install
+    pub packages: Option<Packages>,
+
+    /// System timezone
+    #[serde(default)]
+    pub timezone: Timezone,
+
+    /// Locale and keyboard settings
+    #[serde(default)]
+    pub locale: Locale,
+
+    /// Enable NTP time synchronization
+    #[serde(default)]
+    pub ntp: Ntp,
+
+    /// Disk partition layout and optional LUKS encryption
+    #[serde(default)]
+    pub partition_layout: PartitionLayout,
+
+    /// Shell commands that run in the installed system at the end of the
+    /// install
+    #[serde(default)]
+    pub late_commands: LateCommands,
+
+    /// Installation media. Defaults to the release and edition's ISO.
+    pub iso: Option<Iso>,
+
+    /// Steps that edit the disk on the host after the build.
+    #[serde(default)]
+    pub offline_steps: Vec<OfflineStep>,
+}
+
+impl LinuxMint {
+    fn autoinstall(&self) -> Autoinstall {
+        Autoinstall {
+            hostname: self.hostname.clone(),
+            root_password: self.root_password.clone(),
+            users: self.users.clone(),
+            packages: self.packages.clone().unwrap_or_default().0,
+            timezone: self.timezone.clone(),
+            locale: self.locale.clone(),
+            partition_layout: self.partition_layout.clone(),
+            ntp: self.ntp.0,
+            late_commands: self.late_commands.0.clone(),
+        }
+    }
+}
+
+impl BuildImage for LinuxMint {
+    fn build(&self, worker: &Builder) -> Result<()> {
+        if worker.has_checkpoint(checkpoint::INSTALL) {
+            return Ok(());
+        }
+
+        let iso = match &self.iso {
+            Some(iso) => iso.clone(),
+            None => self.release.iso(self.edition),
+        };
+
+        // Fail before booting anything if the config is incomplete
+        let preseed = self.autoinstall().preseed()?;
+        debug!(preseed, "Generated preseed");
+
+        let mut qemu = QemuBuilder::new(worker, OsCategory::Linux)
+            .with_iso(&iso)?
+            .start()?;
+
+        // Start HTTP with generated preseed
+        let http = HttpServer::builder()?
+            .file("preseed.cfg", preseed.into_bytes())?
+            .serve();
+
+        // Send boot command
+        #[rustfmt::skip]
+        qemu.vnc.run(vec![
+            // Wait for GRUB menu
+            wait_text_ocr!("Start Linux Mint"),
+            // Boot the live system from the GRUB command line with the preseed
+            input!("c"),
+            enter!(format!(
+                "linux /casper/vmlinuz boot=casper automatic-ubiquity noprompt url=http://{}:{}/preseed.cfg --",
+                http.address, http.port
+            )),
+            enter!("initrd /casper/initrd.lz"),
+            enter!("boot"),
+        ])?;
+
+        // The installer powers off when it's done
+        qemu.shutdown_wait()?;
+        Qcow3::open(&worker.qcow_path)?.create_snapshot(checkpoint::INSTALL)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, EnumIter, Display, Default)]
+pub enum LinuxMintRelease {
+    /// 22.2 (Zara)
+    #[serde(rename = "22.2")]
+    #[strum(to_string = "22.2")]
+    #[default]
+    V22_2,
+    /// 22.1 (Xia)
+    #[serde(rename = "22.1")]
+    #[strum(to_string = "22.1")]
+    V22_1,
+}
+
+impl LinuxMintRelease {
+    /// The ISO of the release and edition.
+    fn iso(&self, edition: LinuxMintEdition) -> Iso {
+        let edition = match edition {
+            LinuxMintEdition::Cinnamon => "cinnamon",
+            LinuxMintEdition::Mate => "mate",
+            LinuxMintEdition::Xfce => "xfce",
+        };
+        Iso {
+            url: format!(
+                "https://mirrors.edge.kernel.org/linuxmint/stable/{self}/linuxmint-{self}-{edition}-64bit.iso"
+            )
+            .parse()
+            .unwrap(),
+            checksum: None,
+        }
+    }
+}
+
+impl Prompt for LinuxMintRelease {
+    fn prompt(&mut self, _: &Builder) -> Result<()> {
+        let releases: Vec<LinuxMintRelease> = LinuxMintRelease::iter().collect();
+        let index = dialoguer::Select::with_theme(&crate::cli::cmd::init::theme())
+            .with_prompt("Choose Linux Mint release")
+            .default(0)
+            .items(&releases)
+            .interact()?;
+        *self = releases[index];
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, EnumIter, Display)]
+pub enum LinuxMintEdition {
+    #[default]
+    Cinnamon,
+    Mate,
+    Xfce,
+}
+
+impl Prompt for LinuxMintEdition {
+    fn prompt(&mut self, _: &Builder) -> Result<()> {
+        let editions: Vec<LinuxMintEdition> = LinuxMintEdition::iter().collect();
+        let index = dialoguer::Select::with_theme(&crate::cli::cmd::init::theme())
+            .with_prompt("Choose Linux Mint edition")
+            .default(0)
+            .items(&editions)
+            .interact()?;
+        *self = editions[index];
+        Ok(())
+    }
+}
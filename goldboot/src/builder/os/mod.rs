@@ -1,4 +1,6 @@
 use crate::builder::Builder;
+use crate::builder::offline::OfflineStep;
+use crate::builder::options::base_image::BaseImage;
 use crate::builder::steps::{PostStep, PreStep};
 use crate::cli::prompt::Prompt;
 use anyhow::Result;
@@ -7,11 +9,20 @@ use serde::{Serialize, ser::Serializer};
 
 pub mod alpine_linux;
 pub mod arch_linux;
+pub mod buildroot;
+pub mod custom;
 pub mod debian;
+pub mod fedora;
+pub mod from_image;
+pub mod import_image;
+pub mod linux_mint;
+pub mod open_suse;
 pub mod tiny_core;
 // pub mod goldboot;
 pub mod nix;
 pub mod pop_os;
+pub mod steam_deck;
+pub mod steam_os;
 pub mod ubuntu;
 pub mod windows_10;
 pub mod windows_11;
@@ -37,12 +48,24 @@ pub trait OsTrait: BuildImage + Prompt + Send + Sync {
     fn pre_steps(&self) -> &[PreStep] {
         &[]
     }
-    /// Post-steps declared on this OS element. These run over SSH against the
-    /// installed system after the build completes.
+    /// Post-steps declared on this OS element. These run over SSH (or WinRM
+    /// for Windows) against the installed system after the build completes.
     fn post_steps(&self) -> &[PostStep] {
         &[]
     }
+    /// Offline steps declared on this OS element. These edit the element's
+    /// disk on the host once it is built, without booting it.
+    fn offline_steps(&self) -> &[OfflineStep] {
+        &[]
+    }
+    /// The goldboot image this element is built on top of, if any.
+    fn base_image(&self) -> Option<&BaseImage> {
+        None
+    }
     fn serialize_ron(&self, config: &ron::ser::PrettyConfig) -> anyhow::Result<String>;
+    /// Serialize everything that determines the installed system, i.e. the
+    /// configuration without its post-steps or offline steps.
+    fn serialize_install_ron(&self) -> anyhow::Result<String>;
 }
 
 /// Descriptor registered at link time via `inventory` for each OS type.
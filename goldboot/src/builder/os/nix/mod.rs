@@ -9,12 +9,14 @@ use validator::Validate;
 
 use crate::{
     builder::{
-        Builder,
+        Builder, checkpoint,
+        offline::OfflineStep,
         options::{
             arch::Arch, iso::Iso, minimum_size::MinimumSize, partition_layout::PartitionLayout,
         },
         qemu::{OsCategory, QemuBuilder},
-        steps::{PostStep, PreStep},
+        ssh::GUEST_HOST_KEY_PATH,
+        steps::{PostStep, PreStep, run_post_steps},
     },
     cli::prompt::Prompt,
     enter, wait, wait_text_ocr,
@@ -58,22 +60,30 @@ pub struct Nix {
     /// `configuration.nix` enables `services.openssh`).
     #[serde(default)]
     pub post_steps: Vec<PostStep>,
+
+    /// Steps that edit the disk on the host after the post-steps.
+    #[serde(default)]
+    pub offline_steps: Vec<OfflineStep>,
 }
 
 impl BuildImage for Nix {
     fn build(&self, worker: &Builder) -> Result<()> {
+        self.partition_layout.check()?;
+
         let has_post_steps = !self.post_steps.is_empty();
 
         // TODO enable serial instead of VNC
-        let qemu_builder = QemuBuilder::new(worker, OsCategory::Linux)
+        let mut qemu_builder = QemuBuilder::new(worker, OsCategory::Linux)
             .with_iso(&self.iso)?
             // Forward the host SSH port to the installed system's sshd so
             // post-steps can connect after the VM reboots.
             .forward_ssh(22);
         let public_key = qemu_builder.ssh_public_key()?;
+        let host_key = qemu_builder.pin_host_key()?;
 
-        // The config drive (/dev/vdb) carries the configuration and the
-        // goldboot public key used to reach the installed system over SSH.
+        // The config drive (/dev/vdb) carries the configuration, the goldboot
+        // public key used to reach the installed system over SSH and the host
+        // key it presents.
         let mut qemu = qemu_builder
             .drive_files(HashMap::from([
                 (
@@ -81,6 +91,8 @@ impl BuildImage for Nix {
                     self.configuration.load(&worker.effective_context_dir)?,
                 ),
                 ("public_key".to_string(), public_key),
+                ("host_key".to_string(), host_key.private.into_bytes()),
+                ("host_key.pub".to_string(), host_key.public.into_bytes()),
             ]))?
             .start()?;
 
@@ -102,12 +114,18 @@ impl BuildImage for Nix {
         ]);
 
         if has_post_steps {
-            // Authorize the goldboot key for root, then reboot into the
-            // installed system so post-steps can run against it over SSH.
+            // Authorize the goldboot key for root and install the pinned host
+            // key, then reboot into the installed system so post-steps can
+            // run against it over SSH.
             cmds.extend(vec![
                 enter!("mkdir -p /mnt/root/.ssh"),
                 enter!("cp /goldboot/public_key /mnt/root/.ssh/authorized_keys"),
                 enter!("chmod 700 /mnt/root/.ssh && chmod 600 /mnt/root/.ssh/authorized_keys"),
+                enter!("mkdir -p /mnt/etc/ssh"),
+                enter!(format!(
+                    "cp /goldboot/host_key /mnt{GUEST_HOST_KEY_PATH} && cp /goldboot/host_key.pub /mnt{GUEST_HOST_KEY_PATH}.pub"
+                )),
+                enter!(format!("chmod 600 /mnt{GUEST_HOST_KEY_PATH}")),
                 enter!("umount /goldboot"),
                 enter!("reboot"),
             ]);
@@ -115,13 +133,19 @@ impl BuildImage for Nix {
             cmds.extend(vec![enter!("umount /goldboot"), enter!("poweroff")]);
         }
 
-        qemu.vnc.run(cmds)?;
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            qemu.vnc.run(cmds)?;
+        }
 
-        if has_post_steps {
+        // A VM restored from the install checkpoint is running the installed
+        // system even if the post-steps have since been removed
+        if has_post_steps || worker.has_checkpoint(checkpoint::INSTALL) {
             let mut ssh = qemu.ssh("root")?;
-            for step in &self.post_steps {
-                step.run(&mut ssh, &worker.effective_context_dir)?;
+            if !worker.has_checkpoint(checkpoint::INSTALL) {
+                qemu.checkpoint(checkpoint::INSTALL)?;
             }
+            run_post_steps(worker, &mut qemu, &mut ssh, &self.post_steps)?;
+            ssh.remove_host_key()?;
             ssh.shutdown("poweroff")?;
         }
 
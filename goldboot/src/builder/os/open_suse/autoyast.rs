@@ -0,0 +1,572 @@
+use serde::Serialize;
+
+use crate::builder::options::partition_layout::{Filesystem, PartitionLayout};
+use crate::builder::options::root_password::RootPassword;
+use crate::builder::ssh::{GUEST_HOST_KEY_PATH, HostKey};
+
+/// The `config:type` of a list element.
+const LIST: &str = "list";
+
+/// A scalar with an explicit `config:type` (AutoYaST reads untyped values as
+/// strings).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Typed<T> {
+    #[serde(rename = "@config:type")]
+    pub config_type: &'static str,
+    #[serde(rename = "$text")]
+    pub value: T,
+}
+
+fn boolean(value: bool) -> Typed<bool> {
+    Typed {
+        config_type: "boolean",
+        value,
+    }
+}
+
+fn symbol(value: &'static str) -> Typed<&'static str> {
+    Typed {
+        config_type: "symbol",
+        value,
+    }
+}
+
+fn integer(value: u64) -> Typed<u64> {
+    Typed {
+        config_type: "integer",
+        value,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Mode {
+    pub confirm: Typed<bool>,
+    pub final_reboot: Typed<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct General {
+    pub mode: Mode,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Language {
+    pub language: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Keyboard {
+    pub keymap: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Timezone {
+    pub hwclock: String,
+    pub timezone: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Dns {
+    pub hostname: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Networking {
+    pub dns: Dns,
+    pub keep_install_network: Typed<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Partition {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mount: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filesystem: Option<Typed<&'static str>>,
+    pub size: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partition_id: Option<Typed<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crypt_method: Option<Typed<&'static str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crypt_key: Option<String>,
+    /// The volume group this partition is a physical volume of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lvm_group: Option<String>,
+    /// The name of a logical volume
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lv_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subvolumes_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subvolumes: Option<Subvolumes>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Subvolume {
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Subvolumes {
+    #[serde(rename = "@config:type")]
+    pub config_type: &'static str,
+    pub subvolume: Vec<Subvolume>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Partitions {
+    #[serde(rename = "@config:type")]
+    pub config_type: &'static str,
+    pub partition: Vec<Partition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Drive {
+    pub device: String,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_field: Option<Typed<&'static str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disklabel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initialize: Option<Typed<bool>>,
+    #[serde(rename = "use")]
+    pub use_field: String,
+    pub partitions: Partitions,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Partitioning {
+    #[serde(rename = "@config:type")]
+    pub config_type: &'static str,
+    pub drive: Vec<Drive>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bootloader {
+    pub loader_type: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Patterns {
+    #[serde(rename = "@config:type")]
+    pub config_type: &'static str,
+    pub pattern: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Packages {
+    #[serde(rename = "@config:type")]
+    pub config_type: &'static str,
+    pub package: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Software {
+    pub install_recommended: Typed<bool>,
+    pub patterns: Patterns,
+    pub packages: Packages,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ServiceList {
+    #[serde(rename = "@config:type")]
+    pub config_type: &'static str,
+    pub service: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Services {
+    pub enable: ServiceList,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ServicesManager {
+    pub services: Services,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Zone {
+    pub name: String,
+    pub services: ServiceList,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Zones {
+    #[serde(rename = "@config:type")]
+    pub config_type: &'static str,
+    pub zone: Vec<Zone>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Firewall {
+    pub enable_firewall: Typed<bool>,
+    pub start_firewall: Typed<bool>,
+    pub zones: Zones,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuthorizedKeys {
+    #[serde(rename = "@config:type")]
+    pub config_type: &'static str,
+    pub listentry: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct User {
+    pub username: String,
+    pub user_password: String,
+    pub encrypted: Typed<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorized_keys: Option<AuthorizedKeys>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Users {
+    #[serde(rename = "@config:type")]
+    pub config_type: &'static str,
+    pub user: Vec<User>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct File {
+    pub file_path: String,
+    pub file_contents: String,
+    pub file_owner: String,
+    pub file_permissions: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Files {
+    #[serde(rename = "@config:type")]
+    pub config_type: &'static str,
+    pub file: Vec<File>,
+}
+
+/// An AutoYaST control file.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename = "profile")]
+pub struct Profile {
+    #[serde(rename = "@xmlns")]
+    pub xmlns: &'static str,
+    #[serde(rename = "@xmlns:config")]
+    pub xmlns_config: &'static str,
+    pub general: General,
+    pub language: Language,
+    pub keyboard: Keyboard,
+    pub timezone: Timezone,
+    pub networking: Networking,
+    pub partitioning: Partitioning,
+    pub bootloader: Bootloader,
+    pub software: Software,
+    #[serde(rename = "services-manager")]
+    pub services_manager: ServicesManager,
+    pub firewall: Firewall,
+    pub users: Users,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<Files>,
+}
+
+impl Profile {
+    /// Build the profile for an element. `public_key` is authorized for root
+    /// so that goldboot can reach the installed system over SSH, and
+    /// `host_key` is installed so that goldboot can verify it.
+    pub fn new(value: &super::OpenSuse, public_key: &str, host_key: &HostKey) -> Self {
+        let root_password = match &value.root_password {
+            RootPassword::Plaintext(p) => p.to_string(),
+            RootPassword::PlaintextEnv(name) => {
+                std::env::var(name).expect("environment variable not found")
+            }
+        };
+
+        let mut users = vec![User {
+            username: "root".to_string(),
+            user_password: root_password,
+            encrypted: boolean(false),
+            authorized_keys: Some(AuthorizedKeys {
+                config_type: LIST,
+                listentry: vec![public_key.trim().to_string()],
+            }),
+        }];
+        let mut files = vec![
+            File {
+                file_path: GUEST_HOST_KEY_PATH.to_string(),
+                file_contents: host_key.private.clone(),
+                file_owner: "root.root".to_string(),
+                file_permissions: "600".to_string(),
+            },
+            File {
+                file_path: format!("{GUEST_HOST_KEY_PATH}.pub"),
+                file_contents: host_key.public.clone(),
+                file_owner: "root.root".to_string(),
+                file_permissions: "644".to_string(),
+            },
+        ];
+        let mut sudoers = String::new();
+        for user in value.users.iter().flat_map(|users| users.0.iter()) {
+            users.push(User {
+                username: user.username.clone(),
+                user_password: user.password.clone(),
+                encrypted: boolean(false),
+                authorized_keys: None,
+            });
+            if user.sudo {
+                // openSUSE's sudo asks for the root password by default
+                sudoers.push_str(&format!(
+                    "Defaults:{0} !targetpw\n{0} ALL=(ALL:ALL) ALL\n",
+                    user.username
+                ));
+            }
+        }
+
+        if !sudoers.is_empty() {
+            files.push(File {
+                file_path: "/etc/sudoers.d/goldboot".to_string(),
+                file_contents: sudoers,
+                file_owner: "root.root".to_string(),
+                file_permissions: "440".to_string(),
+            });
+        }
+
+        let mut packages = vec!["openssh".to_string()];
+        if let Some(extra) = &value.packages {
+            packages.extend(extra.0.iter().cloned());
+        }
+
+        Self {
+            xmlns: "http://www.suse.com/1.0/yast2ns",
+            xmlns_config: "http://www.suse.com/1.0/configns",
+            general: General {
+                mode: Mode {
+                    confirm: boolean(false),
+                    final_reboot: boolean(true),
+                },
+            },
+            language: Language {
+                language: value.locale.language.clone(),
+            },
+            keyboard: Keyboard {
+                keymap: keymap(&value.locale.keyboard),
+            },
+            timezone: Timezone {
+                hwclock: "UTC".to_string(),
+                timezone: value.timezone.0.clone(),
+            },
+            networking: Networking {
+                dns: Dns {
+                    hostname: value.hostname.0.clone(),
+                },
+                keep_install_network: boolean(true),
+            },
+            partitioning: Partitioning {
+                config_type: LIST,
+                drive: drives_from(&value.partition_layout),
+            },
+            bootloader: Bootloader {
+                loader_type: "grub2-efi".to_string(),
+            },
+            software: Software {
+                install_recommended: boolean(false),
+                patterns: Patterns {
+                    config_type: LIST,
+                    pattern: vec!["base".to_string(), "enhanced_base".to_string()],
+                },
+                packages: Packages {
+                    config_type: LIST,
+                    package: packages,
+                },
+            },
+            services_manager: ServicesManager {
+                services: Services {
+                    enable: ServiceList {
+                        config_type: LIST,
+                        service: vec!["sshd".to_string()],
+                    },
+                },
+            },
+            firewall: Firewall {
+                enable_firewall: boolean(true),
+                start_firewall: boolean(true),
+                zones: Zones {
+                    config_type: LIST,
+                    zone: vec![Zone {
+                        name: "public".to_string(),
+                        services: ServiceList {
+                            config_type: LIST,
+                            service: vec!["ssh".to_string()],
+                        },
+                    }],
+                },
+            },
+            users: Users {
+                config_type: LIST,
+                user: users,
+            },
+            files: Some(Files {
+                config_type: LIST,
+                file: files,
+            }),
+        }
+    }
+
+    /// Render the profile as an XML document.
+    pub fn to_xml(&self) -> anyhow::Result<String> {
+        Ok(format!(
+            "<?xml version=\"1.0\"?>\n<!DOCTYPE profile>\n{}",
+            quick_xml::se::to_string(self)?
+        ))
+    }
+}
+
+/// Converts a `PartitionLayout` into the disk and, for LVM, the volume group.
+fn drives_from(layout: &PartitionLayout) -> Vec<Drive> {
+    fn partition(mount: &str, filesystem: &'static str, size: String) -> Partition {
+        Partition {
+            mount: Some(mount.to_string()),
+            filesystem: Some(symbol(filesystem)),
+            size,
+            partition_id: None,
+            crypt_method: None,
+            crypt_key: None,
+            lvm_group: None,
+            lv_name: None,
+            subvolumes_prefix: None,
+            subvolumes: None,
+        }
+    }
+    fn drive(device: String, partitions: Vec<Partition>) -> Drive {
+        Drive {
+            device,
+            type_field: None,
+            disklabel: Some("gpt".to_string()),
+            initialize: Some(boolean(true)),
+            use_field: "all".to_string(),
+            partitions: Partitions {
+                config_type: LIST,
+                partition: partitions,
+            },
+        }
+    }
+    fn size(size_mib: Option<u64>) -> String {
+        match size_mib {
+            Some(size_mib) => format!("{size_mib}MiB"),
+            None => "max".to_string(),
+        }
+    }
+
+    let efi = |size_mib| Partition {
+        // The EFI system partition
+        partition_id: Some(integer(259)),
+        ..partition("/boot/efi", "vfat", size(size_mib))
+    };
+    let root = |filesystem| partition("/", filesystem, size(None));
+
+    let partitions = match layout {
+        PartitionLayout::Uefi => vec![efi(Some(512)), root("ext4")],
+        PartitionLayout::UefiWithSwap { swap_size_mib } => vec![
+            efi(Some(512)),
+            partition("swap", "swap", size(Some(*swap_size_mib))),
+            root("ext4"),
+        ],
+        PartitionLayout::UefiLuks { passphrase } => vec![
+            efi(Some(512)),
+            Partition {
+                crypt_method: Some(symbol("luks2")),
+                crypt_key: Some(passphrase.clone()),
+                ..root("ext4")
+            },
+        ],
+        PartitionLayout::UefiLvm {
+            volume_group,
+            swap_size_mib,
+        } => {
+            let mut volumes = vec![];
+            if let Some(swap_size_mib) = swap_size_mib {
+                volumes.push(Partition {
+                    lv_name: Some("swap".to_string()),
+                    ..partition("swap", "swap", size(Some(*swap_size_mib)))
+                });
+            }
+            volumes.push(Partition {
+                lv_name: Some("root".to_string()),
+                ..root("ext4")
+            });
+
+            let physical_volume = Partition {
+                mount: None,
+                filesystem: None,
+                // Linux LVM
+                partition_id: Some(integer(142)),
+                lvm_group: Some(volume_group.clone()),
+                ..partition("", "", size(None))
+            };
+            return vec![
+                drive(
+                    "/dev/vda".to_string(),
+                    vec![efi(Some(512)), physical_volume],
+                ),
+                Drive {
+                    type_field: Some(symbol("CT_LVM")),
+                    disklabel: None,
+                    initialize: None,
+                    ..drive(format!("/dev/{volume_group}"), volumes)
+                },
+            ];
+        }
+        PartitionLayout::UefiBtrfs { subvolumes } => {
+            // AutoYaST nests the subvolumes under the root's and names them
+            // after their mountpoints
+            let prefix = subvolumes
+                .iter()
+                .find(|subvolume| subvolume.mountpoint == "/")
+                .map(|subvolume| subvolume.name.clone());
+            vec![
+                efi(Some(512)),
+                Partition {
+                    subvolumes_prefix: prefix,
+                    subvolumes: Some(Subvolumes {
+                        config_type: LIST,
+                        subvolume: subvolumes
+                            .iter()
+                            .filter(|subvolume| subvolume.mountpoint != "/")
+                            .map(|subvolume| Subvolume {
+                                path: subvolume.mountpoint.trim_start_matches('/').to_string(),
+                            })
+                            .collect(),
+                    }),
+                    ..root("btrfs")
+                },
+            ]
+        }
+        PartitionLayout::UefiXfs => vec![efi(Some(512)), root("xfs")],
+        PartitionLayout::Custom { partitions } => partitions
+            .iter()
+            .map(|custom| match (custom.filesystem, &custom.mountpoint) {
+                (Filesystem::Efi, _) => efi(custom.size_mib),
+                (Filesystem::Swap, _) => partition("swap", "swap", size(custom.size_mib)),
+                (filesystem, mountpoint) => partition(
+                    mountpoint.as_deref().unwrap_or("/"),
+                    filesystem.as_str(),
+                    size(custom.size_mib),
+                ),
+            })
+            .collect(),
+    };
+
+    vec![drive("/dev/vda".to_string(), partitions)]
+}
+
+/// Map an X keyboard layout (e.g. "us") to a YaST keymap name. Unknown
+/// layouts are passed through.
+fn keymap(layout: &str) -> String {
+    match layout {
+        "us" => "english-us",
+        "gb" | "uk" => "english-uk",
+        "de" => "german",
+        "fr" => "french",
+        "es" => "spanish",
+        "it" => "italian",
+        "jp" => "japanese",
+        other => other,
+    }
+    .to_string()
+}
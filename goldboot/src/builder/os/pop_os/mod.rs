@@ -1,24 +1,27 @@
-use anyhow::Result;
+use anyhow::{Result, bail};
 use serde::{Deserialize, Serialize};
 use smart_default::SmartDefault;
+use std::collections::HashMap;
 use strum::{Display, EnumIter, IntoEnumIterator};
 use validator::Validate;
 
 use crate::{
     builder::{
-        Builder,
+        Builder, checkpoint,
+        offline::OfflineStep,
         options::{
-            arch::Arch, hostname::Hostname, iso::Iso, locale::Locale, minimum_size::MinimumSize,
-            ntp::Ntp, packages::Packages, root_password::RootPassword, timezone::Timezone,
-            unix_users::UnixUsers,
+            arch::Arch, hostname::Hostname, iso::Iso, late_commands::LateCommands, locale::Locale,
+            minimum_size::MinimumSize, ntp::Ntp, packages::Packages, root_password::RootPassword,
+            timezone::Timezone, unix_users::UnixUsers,
         },
         qemu::{OsCategory, QemuBuilder},
+        ssh::GUEST_HOST_KEY_PATH,
     },
     cli::prompt::Prompt,
     enter, input, spacebar, tab, wait, wait_screen_rect,
 };
 
-use super::BuildImage;
+use super::{BuildImage, ubuntu::autoinstall::Autoinstall};
 
 /// Pop!\_OS is a Linux distribution developed by System76, based on Ubuntu.
 ///
@@ -58,18 +61,56 @@ pub struct PopOs {
     #[serde(default)]
     pub ntp: Ntp,
 
+    /// Shell commands that run in the installed system at the end of the
+    /// install
+    #[serde(default)]
+    pub late_commands: LateCommands,
+
     #[default(Iso {
         url: "https://iso.pop-os.org/24.04/amd64/generic/23/pop-os_24.04_amd64_generic_23.iso".parse().unwrap(),
         checksum: Some("sha256:7eb7c1a21674d0bd7d51a95b159bea25df5f97da8f2f7cd32c58dfc17746f70d".to_string()),
     })]
     pub iso: Iso,
+
+    /// Steps that edit the disk on the host after the build.
+    #[serde(default)]
+    pub offline_steps: Vec<OfflineStep>,
+}
+
+impl PopOs {
+    fn autoinstall(&self) -> Autoinstall {
+        Autoinstall {
+            hostname: self.hostname.clone(),
+            root_password: self.root_password.clone(),
+            users: self.users.clone(),
+            packages: self.packages.clone().unwrap_or_default().0,
+            timezone: self.timezone.clone(),
+            locale: self.locale.clone(),
+            ntp: self.ntp.0,
+            late_commands: self.late_commands.0.clone(),
+            // The graphical installer partitions the disk
+            ..Default::default()
+        }
+    }
 }
 
 impl BuildImage for PopOs {
     fn build(&self, worker: &Builder) -> Result<()> {
-        let mut qemu = QemuBuilder::new(worker, OsCategory::Linux)
+        let mut qemu_builder = QemuBuilder::new(worker, OsCategory::Linux)
             .with_iso(&self.iso)?
-            .prepare_ssh()?
+            // The installed system runs its own sshd
+            .forward_ssh(22);
+        let public_key = String::from_utf8(qemu_builder.ssh_public_key()?)?;
+        let host_key = qemu_builder.pin_host_key()?;
+
+        // The config drive (/dev/vdb) carries the goldboot public key used
+        // to reach the installed system over SSH and the host key it presents.
+        let mut qemu = qemu_builder
+            .drive_files(HashMap::from([
+                ("public_key".to_string(), public_key.clone().into_bytes()),
+                ("host_key".to_string(), host_key.private.into_bytes()),
+                ("host_key.pub".to_string(), host_key.public.into_bytes()),
+            ]))?
             .start()?;
 
         let root_password = match &self.root_password {
@@ -80,81 +121,89 @@ impl BuildImage for PopOs {
         };
 
         // Send boot command — drives the Pop!_OS graphical installer via VNC
-        #[rustfmt::skip]
-        qemu.vnc.run(vec![
-            // Wait for live environment to boot
-            wait!(120),
-            // Select language: English
-            enter!(),
-            // Select location: United States
-            enter!(),
-            // Select keyboard layout
-            enter!(),
-            enter!(),
-            // Select clean install
-            spacebar!(),
-            enter!(),
-            // Select disk (/dev/vda)
-            spacebar!(),
-            enter!(),
-            // Configure username
-            enter!(self.user.username),
-            // Configure password
-            input!(self.user.password),
-            tab!(),
-            enter!(self.user.password),
-            // Skip disk encryption
-            enter!(),
-            // Wait for installation
-            wait_screen_rect!("TODO", 100, 0, 1024, 200),
-            // Reboot
-            enter!(),
-            wait!(60),
-            // Login as primary user
-            enter!(self.user.password),
-            wait!(30),
-            // Open terminal and escalate to root
-            enter!("sudo -i"),
-            enter!(self.user.password),
-            // Set root password
-            enter!("passwd"),
-            enter!(root_password),
-            enter!(root_password),
-            // Set hostname
-            enter!(format!("hostnamectl set-hostname {}", self.hostname.0)),
-            // Set timezone
-            enter!(format!("timedatectl set-timezone {}", self.timezone.0)),
-            // Enable/disable NTP
-            enter!(format!("timedatectl set-ntp {}", self.ntp.0)),
-            // Enable SSH
-            enter!("apt install -y openssh-server"),
-            enter!("echo 'PermitRootLogin yes' >>/etc/ssh/sshd_config"),
-            enter!("systemctl restart sshd"),
-        ])?;
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            #[rustfmt::skip]
+            qemu.vnc.run(vec![
+                // Wait for live environment to boot
+                wait!(120),
+                // Select language: English
+                enter!(),
+                // Select location: United States
+                enter!(),
+                // Select keyboard layout
+                enter!(),
+                enter!(),
+                // Select clean install
+                spacebar!(),
+                enter!(),
+                // Select disk (/dev/vda)
+                spacebar!(),
+                enter!(),
+                // Configure username
+                enter!(self.user.username),
+                // Configure password
+                input!(self.user.password),
+                tab!(),
+                enter!(self.user.password),
+                // Skip disk encryption
+                enter!(),
+                // Wait for installation
+                wait_screen_rect!("TODO", 100, 0, 1024, 200),
+                // Reboot
+                enter!(),
+                wait!(60),
+                // Login as primary user
+                enter!(self.user.password),
+                wait!(30),
+                // Open terminal and escalate to root
+                enter!("sudo -i"),
+                enter!(self.user.password),
+                // Set root password
+                enter!("passwd"),
+                enter!(root_password),
+                enter!(root_password),
+                // Set hostname
+                enter!(format!("hostnamectl set-hostname {}", self.hostname.0)),
+                // Set timezone
+                enter!(format!("timedatectl set-timezone {}", self.timezone.0)),
+                // Enable/disable NTP
+                enter!(format!("timedatectl set-ntp {}", self.ntp.0)),
+                // Enable SSH with the goldboot key authorized for root and
+                // the pinned host key
+                enter!("apt install -y openssh-server"),
+                enter!("echo 'PermitRootLogin yes' >>/etc/ssh/sshd_config"),
+                enter!("mkdir /tmp/goldboot && mount /dev/vdb /tmp/goldboot"),
+                enter!("mkdir -p /root/.ssh && cp /tmp/goldboot/public_key /root/.ssh/authorized_keys"),
+                enter!("chmod 700 /root/.ssh && chmod 600 /root/.ssh/authorized_keys"),
+                enter!(format!("cp /tmp/goldboot/host_key {GUEST_HOST_KEY_PATH} && cp /tmp/goldboot/host_key.pub {GUEST_HOST_KEY_PATH}.pub")),
+                enter!(format!("chmod 600 {GUEST_HOST_KEY_PATH}")),
+                enter!("umount /tmp/goldboot"),
+                enter!("systemctl restart ssh"),
+            ])?;
+        }
 
         // Wait for SSH
         let mut ssh = qemu.ssh("root")?;
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            qemu.checkpoint(checkpoint::INSTALL)?;
+        }
 
-        // Install extra packages
-        if let Some(packages) = &self.packages {
-            if !packages.0.is_empty() {
-                ssh.exec(&format!("apt install -y {}", packages.0.join(" ")))?;
+        // The installer has no unattended mode, so create the extra users,
+        // install the packages and run the late commands here
+        for command in self.autoinstall().provision_commands() {
+            if ssh.exec(&command)? != 0 {
+                bail!("Provisioning command failed: {command}");
             }
         }
 
-        // Create extra users
-        if let Some(users) = &self.users {
-            for user in &users.0 {
-                ssh.exec(&format!("useradd -m -s /bin/bash {}", user.username))?;
-                ssh.exec(&format!(
-                    "echo '{}:{}' | chpasswd",
-                    user.username, user.password
-                ))?;
-                if user.sudo {
-                    ssh.exec(&format!("usermod -aG sudo {}", user.username))?;
-                }
-            }
+        // Revoke the build key
+        let Some(key) = public_key.split_whitespace().nth(1) else {
+            bail!("Malformed public key");
+        };
+        if ssh.exec(&format!("sed -i '\\|{key}|d' /root/.ssh/authorized_keys"))? != 0 {
+            bail!("Failed to revoke the build SSH key");
         }
+        ssh.remove_host_key()?;
 
         // Shutdown
         ssh.shutdown("poweroff")?;
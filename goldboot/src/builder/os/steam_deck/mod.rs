@@ -1,89 +1,90 @@
-use crate::{
-    build::BuildWorker,
-    cache::{MediaCache, MediaFormat},
-    provisioners::*,
-    qemu::QemuArgs,
-    templates::*,
-};
+use anyhow::{Context, Result, bail};
+use goldboot_image::qcow::Qcow3;
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use smart_default::SmartDefault;
+use std::{fs::File, process::Command};
 use validator::Validate;
 
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
-pub struct SteamDeckTemplate {
-    pub id: TemplateId,
-    pub recovery_url: String,
+use crate::{
+    builder::{
+        Builder, checkpoint,
+        options::{arch::Arch, iso::Iso, minimum_size::MinimumSize},
+        qemu::{OsCategory, QemuBuilder},
+        sources::SourceCache,
+    },
+    enter, leftSuper, wait, wait_text_ocr,
+};
 
-    pub recovery_checksum: String,
-}
+use super::BuildImage;
 
-impl Default for SteamDeckTemplate {
-    fn default() -> Self {
-        Self {
-            recovery_url: String::from(
-                "https://steamdeck-images.steamos.cloud/recovery/steamdeck-recovery-1.img.bz2",
-            ),
-            recovery_checksum: String::from(
-                "sha256:5086bcc4fe0fb230dff7265ff6a387dd00045e3d9ae6312de72003e1e82d4526",
-            ),
-            general: GeneralContainer {
-                base: TemplateBase::SteamDeck,
-                storage_size: String::from("15 GiB"),
-                ..Default::default()
-            },
-        }
-    }
+/// SteamOS as shipped on the Steam Deck, installed by running Valve's
+/// recovery image against an emulated NVMe drive.
+///
+/// Upstream: https://store.steampowered.com/steamos
+/// Maintainer: cilki
+#[goldboot_macros::Os(architectures(Amd64))]
+#[derive(Clone, Serialize, Deserialize, Validate, Debug, SmartDefault, goldboot_macros::Prompt)]
+pub struct SteamDeck {
+    pub arch: Arch,
+    pub minimum_size: MinimumSize,
+
+    /// The bzip2-compressed recovery image
+    #[default(Iso {
+        url: "https://steamdeck-images.steamos.cloud/recovery/steamdeck-repair-latest.img.bz2".parse().unwrap(),
+        checksum: None,
+    })]
+    pub recovery: Iso,
 }
 
-impl Template for SteamDeckTemplate {
-    fn build(&self, context: &BuildWorker) -> Result<()> {
-        let mut qemuargs = QemuArgs::new(&context);
+impl BuildImage for SteamDeck {
+    fn build(&self, worker: &Builder) -> Result<()> {
+        if worker.has_checkpoint(checkpoint::INSTALL) {
+            return Ok(());
+        }
 
-        qemuargs.drive.push(format!(
-            "file={},format=raw",
-            MediaCache::get(
-                self.recovery_url.clone(),
-                &self.recovery_checksum,
-                MediaFormat::Bzip2
-            )?
-        ));
-        qemuargs.drive.push(format!(
-            "file={},if=none,cache=writeback,discard=ignore,format=qcow2,id=nvme",
-            context.image_path
-        ));
+        let compressed = SourceCache::open()?.get(
+            self.recovery.url.to_string(),
+            self.recovery.checksum.clone(),
+        )?;
 
-        // Make the storage looks like an nvme drive
-        qemuargs
-            .device
-            .push(String::from("nvme,serial=cafebabe,drive=nvme"));
+        // The recovery image is booted from a scratch copy
+        let recovery = worker.tmp.path().join("recovery.img");
+        let status = Command::new("bzip2")
+            .arg("-dc")
+            .arg(&compressed)
+            .stdout(File::create(&recovery)?)
+            .status()
+            .context("failed to run bzip2")?;
+        if !status.success() {
+            bail!("Failed to decompress the recovery image");
+        }
 
-        // Start VM
-        let mut qemu = qemuargs.start_process()?;
+        let mut qemu = QemuBuilder::new(worker, OsCategory::Linux)
+            // The reimage script only targets NVMe drives
+            .nvme()
+            .drive(&format!("file={},format=raw,if=virtio", recovery.display()))
+            .start()?;
 
         // Send boot command
         #[rustfmt::skip]
-		qemu.vnc.boot_command(vec![
-			// Initial wait
-			wait!(20),
-			// Wait for login
-			wait_screen_rect!("ba99ede257ef4ee2056a328eb3feffa65e821e0d", 0, 0, 1024, 700),
-			// Open terminal
-			leftSuper!(), enter!("terminal"),
-			// Disable Zenity prompt
-			enter!("sed -i '/zenity/d' ./tools/repair_device.sh"),
-			// Poweroff instead of reboot on completion
-			enter!("sed -i 's/systemctl reboot/systemctl poweroff/' ./tools/repair_device.sh"),
-			// Begin reimage
-			enter!("./tools/repair_reimage.sh"),
-		])?;
+        qemu.vnc.run(vec![
+            // Initial wait
+            wait!(20),
+            // Wait for the recovery desktop
+            wait_text_ocr!("Re-image Steam Deck"),
+            // Open terminal
+            leftSuper!(), enter!("terminal"),
+            // Disable Zenity prompt
+            enter!("sed -i '/zenity/d' ./tools/repair_device.sh"),
+            // Poweroff instead of reboot on completion
+            enter!("sed -i 's/systemctl reboot/systemctl poweroff/' ./tools/repair_device.sh"),
+            // Begin reimage
+            enter!("./tools/repair_reimage.sh"),
+        ])?;
 
         // Wait for shutdown
         qemu.shutdown_wait()?;
-
+        Qcow3::open(&worker.qcow_path)?.create_snapshot(checkpoint::INSTALL)?;
         Ok(())
     }
-
-    fn general(&self) -> GeneralContainer {
-        self.general.clone()
-    }
 }
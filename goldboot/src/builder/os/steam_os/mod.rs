@@ -1,93 +1,64 @@
-use crate::{
-    build::BuildWorker,
-    cache::{MediaCache, MediaFormat},
-    provisioners::*,
-    qemu::QemuArgs,
-    templates::*,
-};
+use anyhow::Result;
+use goldboot_image::qcow::Qcow3;
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use smart_default::SmartDefault;
 use validator::Validate;
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub enum SteamOsVersion {
-    Brewmaster2_195,
-}
-
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
-pub struct SteamOsTemplate {
-    pub id: TemplateId,
-    pub version: SteamOsVersion,
-
-    pub iso: IsoSource,
-    pub hostname: HostnameProvisioner,
+use crate::{
+    builder::{
+        Builder, checkpoint,
+        options::{arch::Arch, iso::Iso, minimum_size::MinimumSize},
+        qemu::{OsCategory, QemuBuilder},
+    },
+    enter, input, wait_text_ocr,
+};
 
-    pub root_password: String,
+use super::BuildImage;
 
-    pub ansible: Option<Vec<AnsibleProvisioner>>,
-}
+/// SteamOS 2 (brewmaster) is Valve's Debian-based distribution for the Steam
+/// Machines. It's installed with the preseed that ships on the DVD.
+///
+/// Upstream: https://store.steampowered.com/steamos
+/// Maintainer: cilki
+#[goldboot_macros::Os(architectures(Amd64))]
+#[derive(Clone, Serialize, Deserialize, Validate, Debug, SmartDefault, goldboot_macros::Prompt)]
+pub struct SteamOs {
+    pub arch: Arch,
+    pub minimum_size: MinimumSize,
 
-impl Default for SteamOsTemplate {
-    fn default() -> Self {
-        Self {
-			iso: IsoContainer {
-				url: String::from(
-					"https://repo.steampowered.com/download/brewmaster/2.195/SteamOSDVD.iso",
-				),
-				checksum: String::from("sha512:0ce55048d2c5e8a695f309abe22303dded003c93386ad28c6daafc977b3d5b403ed94d7c38917c8c837a2b1fe560184cf3cc12b9f2c4069fd70ed0deab47eb7c"),
-			},
-			root_password: String::from("root"),
-			version: SteamOsVersion::Brewmaster2_195,
-			general: GeneralContainer{
-				base: TemplateBase::SteamOs,
-				storage_size: String::from("15 GiB"),
-				.. Default::default()
-			},
-			provisioners: ProvisionersContainer::default(),
-		}
-    }
+    #[default(Iso {
+        url: "https://repo.steampowered.com/download/brewmaster/2.195/SteamOSDVD.iso".parse().unwrap(),
+        checksum: Some("sha512:0ce55048d2c5e8a695f309abe22303dded003c93386ad28c6daafc977b3d5b403ed94d7c38917c8c837a2b1fe560184cf3cc12b9f2c4069fd70ed0deab47eb7c".to_string()),
+    })]
+    pub iso: Iso,
 }
 
-impl Template for SteamOsTemplate {
-    fn build(&self, context: &BuildWorker) -> Result<()> {
-        let mut qemuargs = QemuArgs::new(&context);
+impl BuildImage for SteamOs {
+    fn build(&self, worker: &Builder) -> Result<()> {
+        if worker.has_checkpoint(checkpoint::INSTALL) {
+            return Ok(());
+        }
 
-        qemuargs.drive.push(format!(
-            "file={},if=virtio,cache=writeback,discard=ignore,format=qcow2",
-            context.image_path
-        ));
-        qemuargs.drive.push(format!(
-            "file={},media=cdrom",
-            MediaCache::get(self.iso.url.clone(), &self.iso.checksum, MediaFormat::Iso)?
-        ));
-
-        // Start VM
-        let mut qemu = qemuargs.start_process()?;
+        let mut qemu = QemuBuilder::new(worker, OsCategory::Linux)
+            .with_iso(&self.iso)?
+            .start()?;
 
         // Send boot command
         #[rustfmt::skip]
-		qemu.vnc.boot_command(vec![
-			// Wait for bootloader
-			wait_screen!("28fe084e08242584908114a5d21960fdf072adf9"),
-			// Start automated install
-			enter!(),
-			// Wait for completion
-			wait_screen!(""),
-		])?;
-
-        // Wait for SSH
-        let mut ssh = qemu.ssh_wait(context.ssh_port, "root", &self.root_password)?;
+        qemu.vnc.run(vec![
+            // Wait for GRUB menu
+            wait_text_ocr!("Automated install"),
+            // Boot the automated install from the GRUB command line, powering
+            // off instead of rebooting at the end
+            input!("c"),
+            enter!("linux /install.amd/vmlinuz auto=true priority=critical preseed/file=/cdrom/default.preseed debian-installer/exit/poweroff=true"),
+            enter!("initrd /install.amd/initrd.gz"),
+            enter!("boot"),
+        ])?;
 
-        // Run provisioners
-        self.provisioners.run(&mut ssh)?;
-
-        // Shutdown
-        ssh.shutdown("poweroff")?;
+        // The installer powers off when it's done
         qemu.shutdown_wait()?;
+        Qcow3::open(&worker.qcow_path)?.create_snapshot(checkpoint::INSTALL)?;
         Ok(())
     }
-
-    fn general(&self) -> GeneralContainer {
-        self.general.clone()
-    }
 }
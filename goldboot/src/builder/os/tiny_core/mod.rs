@@ -7,7 +7,8 @@ use validator::Validate;
 
 use crate::{
     builder::{
-        Builder,
+        Builder, checkpoint,
+        offline::OfflineStep,
         options::{
             hostname::Hostname, iso::Iso, minimum_size::MinimumSize, packages::Packages,
             root_password::RootPassword, unix_users::UnixUsers,
@@ -46,6 +47,10 @@ pub struct TinyCore {
         checksum: None,
     })]
     pub iso: Iso,
+
+    /// Steps that edit the disk on the host after the build.
+    #[serde(default)]
+    pub offline_steps: Vec<OfflineStep>,
 }
 
 impl BuildImage for TinyCore {
@@ -62,22 +67,27 @@ impl BuildImage for TinyCore {
         //   -s <size>   : swap size in MB (0 = no swap)
         //   -b          : install bootloader
         //   -r          : remaster (copy current running extensions)
-        #[rustfmt::skip]
-        qemu.vnc.run(vec![
-            // Wait for boot to complete
-            wait!(30),
-            // Run tc-install for a frugal sys install onto /dev/vda
-            enter!("sudo tc-install -f -z -d /dev/vda -s 0 -b"),
-            // tc-install prompts: confirm install
-            wait_screen_rect!("tc_install_confirm", 0, 0, 1024, 768),
-            enter!("y"),
-            // Wait for install to finish and reboot prompt
-            wait_screen_rect!("tc_install_done", 0, 0, 1024, 768),
-            enter!("sudo reboot"),
-        ])?;
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            #[rustfmt::skip]
+            qemu.vnc.run(vec![
+                // Wait for boot to complete
+                wait!(30),
+                // Run tc-install for a frugal sys install onto /dev/vda
+                enter!("sudo tc-install -f -z -d /dev/vda -s 0 -b"),
+                // tc-install prompts: confirm install
+                wait_screen_rect!("tc_install_confirm", 0, 0, 1024, 768),
+                enter!("y"),
+                // Wait for install to finish and reboot prompt
+                wait_screen_rect!("tc_install_done", 0, 0, 1024, 768),
+                enter!("sudo reboot"),
+            ])?;
+        }
 
         // Wait for SSH after reboot
         let mut ssh = qemu.ssh("tc")?;
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            qemu.checkpoint(checkpoint::INSTALL)?;
+        }
 
         // Set root password
         ssh.exec(&format!(
@@ -0,0 +1,581 @@
+//! Unattended install configuration shared by the Ubuntu family. Ubuntu's
+//! installer (subiquity) reads an autoinstall `user-data` document, Linux
+//! Mint's (ubiquity) reads a preseed, and Pop!\_OS's has no unattended mode at
+//! all, so the same options are rendered for each of them.
+
+use anyhow::{Result, bail};
+use serde::Serialize;
+
+use crate::builder::options::{
+    hostname::Hostname,
+    locale::Locale,
+    partition_layout::{Filesystem, PartitionLayout},
+    root_password::RootPassword,
+    timezone::Timezone,
+    unix_users::UnixUsers,
+};
+
+/// Options of an Ubuntu-family element that the installers apply.
+#[derive(Clone, Debug, Default)]
+pub struct Autoinstall {
+    pub hostname: Hostname,
+    pub root_password: RootPassword,
+    pub users: Option<UnixUsers>,
+    pub packages: Vec<String>,
+    pub timezone: Timezone,
+    pub locale: Locale,
+    pub partition_layout: PartitionLayout,
+    pub ntp: bool,
+
+    /// Shell commands that run in the installed system at the end of the
+    /// install
+    pub late_commands: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CloudConfig {
+    autoinstall: Config,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Config {
+    version: u8,
+    locale: String,
+    keyboard: Keyboard,
+    timezone: String,
+    storage: Storage,
+    ssh: Ssh,
+    user_data: UserData,
+    late_commands: Vec<String>,
+    shutdown: &'static str,
+}
+
+#[derive(Serialize)]
+struct Keyboard {
+    layout: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Ssh {
+    install_server: bool,
+    allow_pw: bool,
+}
+
+#[derive(Serialize)]
+struct UserData {
+    hostname: String,
+    disable_root: bool,
+    ntp: Ntp,
+}
+
+#[derive(Serialize)]
+struct Ntp {
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct Storage {
+    config: Vec<Action>,
+    swap: Swap,
+}
+
+#[derive(Serialize)]
+struct Swap {
+    size: u64,
+}
+
+/// A curtin storage action.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Action {
+    Disk {
+        id: String,
+        path: &'static str,
+        ptable: &'static str,
+        wipe: &'static str,
+        preserve: bool,
+    },
+    Partition {
+        id: String,
+        device: String,
+        number: usize,
+        /// Size in bytes, or -1 for the rest of the disk
+        size: i64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        flag: Option<&'static str>,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        grub_device: bool,
+    },
+    DmCrypt {
+        id: String,
+        volume: String,
+        dm_name: String,
+        key: String,
+    },
+    LvmVolgroup {
+        id: String,
+        name: String,
+        devices: Vec<String>,
+    },
+    LvmPartition {
+        id: String,
+        name: String,
+        volgroup: String,
+        /// Size in bytes, or the rest of the volume group when absent
+        #[serde(skip_serializing_if = "Option::is_none")]
+        size: Option<i64>,
+    },
+    Format {
+        id: String,
+        volume: String,
+        fstype: &'static str,
+    },
+    Mount {
+        id: String,
+        device: String,
+        path: String,
+    },
+}
+
+const MIB: i64 = 1024 * 1024;
+
+/// Converts a `PartitionLayout` into curtin storage actions on `/dev/vda`. An
+/// encrypted or LVM root gets a separate `/boot` so GRUB can read the kernel.
+fn storage_from(layout: &PartitionLayout) -> Result<Vec<Action>> {
+    /// The actions and the mounts, which curtin needs after everything else.
+    struct Storage {
+        actions: Vec<Action>,
+        mounts: Vec<Action>,
+        partitions: usize,
+    }
+
+    impl Storage {
+        /// Add a partition of `size_mib` (the rest of the disk if absent).
+        fn partition(&mut self, id: &str, size_mib: Option<u64>, flag: Option<&'static str>) {
+            self.partitions += 1;
+            self.actions.push(Action::Partition {
+                id: id.to_string(),
+                device: "disk".to_string(),
+                number: self.partitions,
+                size: size_mib.map(|size_mib| size_mib as i64 * MIB).unwrap_or(-1),
+                flag,
+                grub_device: flag == Some("boot"),
+            });
+        }
+
+        /// Format the volume `id` and mount it at `path`.
+        fn filesystem(&mut self, id: &str, fstype: &'static str, path: &str) {
+            self.actions.push(Action::Format {
+                id: format!("{id}-fs"),
+                volume: id.to_string(),
+                fstype,
+            });
+            self.mounts.push(Action::Mount {
+                id: format!("{id}-mount"),
+                device: format!("{id}-fs"),
+                path: path.to_string(),
+            });
+        }
+    }
+
+    let mut storage = Storage {
+        actions: vec![Action::Disk {
+            id: "disk".to_string(),
+            path: "/dev/vda",
+            ptable: "gpt",
+            wipe: "superblock-recursive",
+            preserve: false,
+        }],
+        mounts: vec![],
+        partitions: 0,
+    };
+
+    if !matches!(layout, PartitionLayout::Custom { .. }) {
+        storage.partition("efi", Some(512), Some("boot"));
+        storage.filesystem("efi", "fat32", "/boot/efi");
+    }
+
+    match layout {
+        PartitionLayout::Uefi => {
+            storage.partition("root", None, None);
+            storage.filesystem("root", "ext4", "/");
+        }
+        PartitionLayout::UefiWithSwap { swap_size_mib } => {
+            storage.partition("swap", Some(*swap_size_mib), Some("swap"));
+            storage.filesystem("swap", "swap", "none");
+            storage.partition("root", None, None);
+            storage.filesystem("root", "ext4", "/");
+        }
+        PartitionLayout::UefiLuks { passphrase } => {
+            storage.partition("boot", Some(1024), None);
+            storage.filesystem("boot", "ext4", "/boot");
+            storage.partition("crypt", None, None);
+            storage.actions.push(Action::DmCrypt {
+                id: "root".to_string(),
+                volume: "crypt".to_string(),
+                dm_name: "root".to_string(),
+                key: passphrase.clone(),
+            });
+            storage.filesystem("root", "ext4", "/");
+        }
+        PartitionLayout::UefiLvm {
+            volume_group,
+            swap_size_mib,
+        } => {
+            storage.partition("boot", Some(1024), None);
+            storage.filesystem("boot", "ext4", "/boot");
+            storage.partition("pv", None, None);
+            storage.actions.push(Action::LvmVolgroup {
+                id: "vg".to_string(),
+                name: volume_group.clone(),
+                devices: vec!["pv".to_string()],
+            });
+            let volume = |name: &str, size_mib: Option<u64>| Action::LvmPartition {
+                id: name.to_string(),
+                name: name.to_string(),
+                volgroup: "vg".to_string(),
+                size: size_mib.map(|size_mib| size_mib as i64 * MIB),
+            };
+            if let Some(swap_size_mib) = swap_size_mib {
+                storage.actions.push(volume("swap", Some(*swap_size_mib)));
+                storage.filesystem("swap", "swap", "none");
+            }
+            storage.actions.push(volume("root", None));
+            storage.filesystem("root", "ext4", "/");
+        }
+        PartitionLayout::UefiBtrfs { .. } => {
+            bail!("curtin can't create btrfs subvolumes; use a Custom layout with a Btrfs root")
+        }
+        PartitionLayout::UefiXfs => {
+            storage.partition("root", None, None);
+            storage.filesystem("root", "xfs", "/");
+        }
+        PartitionLayout::Custom { partitions } => {
+            for (i, partition) in partitions.iter().enumerate() {
+                let id = format!("part{}", i + 1);
+                match (partition.filesystem, &partition.mountpoint) {
+                    (Filesystem::Efi, _) => {
+                        storage.partition(&id, partition.size_mib, Some("boot"));
+                        storage.filesystem(&id, "fat32", "/boot/efi");
+                    }
+                    (Filesystem::Swap, _) => {
+                        storage.partition(&id, partition.size_mib, Some("swap"));
+                        storage.filesystem(&id, "swap", "none");
+                    }
+                    (filesystem, mountpoint) => {
+                        storage.partition(&id, partition.size_mib, None);
+                        storage.filesystem(
+                            &id,
+                            filesystem.as_str(),
+                            mountpoint.as_deref().unwrap_or("/"),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // Parents are mounted before their children
+    storage.mounts.sort_by_key(|mount| match mount {
+        Action::Mount { path, .. } if path != "none" => {
+            path.trim_end_matches('/').matches('/').count()
+        }
+        _ => usize::MAX,
+    });
+    storage.actions.extend(storage.mounts);
+    Ok(storage.actions)
+}
+
+/// Quote a string for `sh`.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+impl Autoinstall {
+    fn root_password(&self) -> String {
+        match &self.root_password {
+            RootPassword::Plaintext(p) => p.clone(),
+            RootPassword::PlaintextEnv(name) => {
+                std::env::var(name).expect("environment variable not found")
+            }
+        }
+    }
+
+    /// Commands that configure the installed system: the root password,
+    /// users, packages and then the late commands. They run as root inside
+    /// the installed system.
+    pub fn provision_commands(&self) -> Vec<String> {
+        let mut commands = vec![
+            format!("echo {} >/etc/hostname", quote(&self.hostname.0)),
+            format!(
+                "echo {} | chpasswd",
+                quote(&format!("root:{}", self.root_password()))
+            ),
+        ];
+
+        for user in self.users.iter().flat_map(|users| users.0.iter()) {
+            commands.push(format!(
+                "id {0} || useradd -m -s /bin/bash {0}",
+                quote(&user.username)
+            ));
+            commands.push(format!(
+                "echo {} | chpasswd",
+                quote(&format!("{}:{}", user.username, user.password))
+            ));
+            if user.sudo {
+                commands.push(format!("usermod -aG sudo {}", quote(&user.username)));
+            }
+        }
+
+        if !self.packages.is_empty() {
+            commands.push(format!(
+                "DEBIAN_FRONTEND=noninteractive apt-get install -y {}",
+                self.packages.join(" ")
+            ));
+        }
+
+        commands.extend(self.late_commands.iter().cloned());
+        commands
+    }
+
+    /// Subiquity's autoinstall `user-data` (Ubuntu Server and Desktop). The
+    /// installer powers the machine off when it's done.
+    pub fn user_data(&self) -> Result<String> {
+        self.partition_layout.check()?;
+        let config = CloudConfig {
+            autoinstall: Config {
+                version: 1,
+                locale: format!("{}.{}", self.locale.language, self.locale.encoding),
+                keyboard: Keyboard {
+                    layout: self.locale.keyboard.clone(),
+                },
+                timezone: self.timezone.0.clone(),
+                storage: Storage {
+                    config: storage_from(&self.partition_layout)?,
+                    // Only a swap partition, if the layout has one
+                    swap: Swap { size: 0 },
+                },
+                ssh: Ssh {
+                    install_server: true,
+                    allow_pw: true,
+                },
+                user_data: UserData {
+                    hostname: self.hostname.0.clone(),
+                    disable_root: false,
+                    ntp: Ntp { enabled: self.ntp },
+                },
+                late_commands: self
+                    .provision_commands()
+                    .iter()
+                    .map(|command| format!("curtin in-target -- sh -c {}", quote(command)))
+                    .collect(),
+                shutdown: "poweroff",
+            },
+        };
+
+        // JSON is also YAML
+        Ok(format!(
+            "#cloud-config\n{}\n",
+            serde_json::to_string_pretty(&config)?
+        ))
+    }
+
+    /// The NoCloud `meta-data` that accompanies [`Self::user_data`].
+    pub fn meta_data(&self) -> String {
+        format!(
+            "instance-id: goldboot\nlocal-hostname: {}\n",
+            self.hostname.0
+        )
+    }
+
+    /// Ubiquity's preseed (Linux Mint). Ubiquity always creates a user, so
+    /// the first of `users` is created by the installer and the rest by the
+    /// provisioning commands. The installer powers the machine off when it's
+    /// done.
+    pub fn preseed(&self) -> Result<String> {
+        self.partition_layout.check()?;
+        let Some(user) = self.users.as_ref().and_then(|users| users.0.first()) else {
+            bail!("The installer requires at least one user");
+        };
+
+        let partitioning = self.partition_layout.partman()?;
+
+        let success_command = self
+            .provision_commands()
+            .iter()
+            .map(|command| format!("in-target sh -c {}", quote(command)))
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        Ok(format!(
+            r#"### Localization
+d-i debian-installer/locale string {language}.{encoding}
+d-i keyboard-configuration/xkb-keymap select {keyboard}
+d-i keyboard-configuration/layoutcode string {keyboard}
+
+### Network configuration
+d-i netcfg/get_hostname string {hostname}
+
+### Account setup
+d-i passwd/user-fullname string {username}
+d-i passwd/username string {username}
+d-i passwd/user-password password {password}
+d-i passwd/user-password-again password {password}
+d-i user-setup/allow-password-weak boolean true
+d-i passwd/auto-login boolean false
+
+### Clock and time zone setup
+d-i clock-setup/utc boolean true
+d-i time/zone string {timezone}
+d-i clock-setup/ntp boolean {ntp}
+
+### Partitioning
+d-i partman-auto/disk string /dev/vda
+{partitioning}d-i partman-auto-lvm/guided_size string max
+d-i partman-lvm/device_remove_lvm boolean true
+d-i partman-lvm/confirm boolean true
+d-i partman-lvm/confirm_nooverwrite boolean true
+d-i partman-efi/non_efi_system boolean true
+d-i partman-partitioning/choose_label select gpt
+d-i partman-partitioning/default_label string gpt
+d-i partman-partitioning/confirm_write_new_label boolean true
+d-i partman-basicfilesystems/no_swap boolean false
+d-i partman/choose_partition select finish
+d-i partman/confirm boolean true
+d-i partman/confirm_nooverwrite boolean true
+
+### Finishing up
+ubiquity ubiquity/success_command string {success_command}
+ubiquity ubiquity/poweroff boolean true
+"#,
+            language = self.locale.language,
+            encoding = self.locale.encoding,
+            keyboard = self.locale.keyboard,
+            hostname = self.hostname.0,
+            username = user.username,
+            password = user.password,
+            timezone = self.timezone.0,
+            ntp = self.ntp,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::options::unix_users::UnixUser;
+
+    fn autoinstall(partition_layout: PartitionLayout) -> Autoinstall {
+        Autoinstall {
+            users: Some(UnixUsers(vec![UnixUser {
+                username: "alice".to_string(),
+                password: "it's".to_string(),
+                sudo: true,
+            }])),
+            packages: vec!["vim".to_string()],
+            partition_layout,
+            late_commands: vec!["touch /etc/goldboot".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn generate_user_data() -> Result<()> {
+        let user_data = autoinstall(PartitionLayout::UefiLuks {
+            passphrase: "secret".to_string(),
+        })
+        .user_data()?;
+        assert!(user_data.starts_with("#cloud-config\n"));
+
+        let config: serde_json::Value =
+            serde_json::from_str(user_data.trim_start_matches("#cloud-config\n"))?;
+        let config = &config["autoinstall"];
+        assert_eq!(config["shutdown"], "poweroff");
+        assert_eq!(config["user-data"]["hostname"], "goldboot");
+
+        let actions = config["storage"]["config"].as_array().unwrap();
+        let crypt = actions.iter().find(|a| a["type"] == "dm_crypt").unwrap();
+        assert_eq!(crypt["key"], "secret");
+        assert!(
+            actions
+                .iter()
+                .any(|a| a["type"] == "mount" && a["path"] == "/boot")
+        );
+
+        let late_commands = config["late-commands"].as_array().unwrap();
+        assert_eq!(
+            late_commands.last().unwrap(),
+            "curtin in-target -- sh -c 'touch /etc/goldboot'"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn lvm_storage() -> Result<()> {
+        let actions = serde_json::to_value(storage_from(&PartitionLayout::UefiLvm {
+            volume_group: "ubuntu-vg".to_string(),
+            swap_size_mib: None,
+        })?)?;
+        let actions = actions.as_array().unwrap();
+        let volume_group = actions
+            .iter()
+            .find(|a| a["type"] == "lvm_volgroup")
+            .unwrap();
+        assert_eq!(volume_group["name"], "ubuntu-vg");
+        let root = actions
+            .iter()
+            .find(|a| a["type"] == "lvm_partition")
+            .unwrap();
+        assert!(root.get("size").is_none());
+
+        let mounts: Vec<_> = actions
+            .iter()
+            .filter(|a| a["type"] == "mount")
+            .map(|a| a["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(mounts, vec!["/", "/boot", "/boot/efi"]);
+
+        assert!(storage_from(&PartitionLayout::UefiBtrfs { subvolumes: vec![] }).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn quote_for_sh() {
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn generate_provision_commands() {
+        assert_eq!(
+            autoinstall(PartitionLayout::Uefi).provision_commands(),
+            vec![
+                "echo 'goldboot' >/etc/hostname",
+                "echo 'root:root' | chpasswd",
+                "id 'alice' || useradd -m -s /bin/bash 'alice'",
+                r"echo 'alice:it'\''s' | chpasswd",
+                "usermod -aG sudo 'alice'",
+                "DEBIAN_FRONTEND=noninteractive apt-get install -y vim",
+                "touch /etc/goldboot",
+            ]
+        );
+    }
+
+    #[test]
+    fn generate_preseed() -> Result<()> {
+        let preseed = autoinstall(PartitionLayout::UefiWithSwap {
+            swap_size_mib: 2048,
+        })
+        .preseed()?;
+        assert!(preseed.contains("d-i passwd/username string alice\n"));
+        assert!(preseed.contains("2048 2048 2048 linux-swap"));
+        assert!(preseed.contains("ubiquity ubiquity/poweroff boolean true\n"));
+        assert!(
+            preseed.contains("ubiquity ubiquity/success_command string in-target sh -c 'echo ")
+        );
+
+        assert!(Autoinstall::default().preseed().is_err());
+        Ok(())
+    }
+}
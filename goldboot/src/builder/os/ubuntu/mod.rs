@@ -1,5 +1,5 @@
 use anyhow::{Result, bail};
-use goldboot_image::ImageArch;
+use goldboot_image::{ImageArch, qcow::Qcow3};
 use serde::{Deserialize, Serialize};
 use smart_default::SmartDefault;
 use std::io::{BufRead, BufReader};
@@ -8,24 +8,33 @@ use validator::Validate;
 
 use crate::{
     builder::{
-        Builder,
+        Builder, checkpoint,
         http::HttpServer,
+        offline::OfflineStep,
         options::{
-            arch::Arch, hostname::Hostname, iso::Iso, locale::Locale, minimum_size::MinimumSize,
-            ntp::Ntp, packages::Packages, root_password::RootPassword, timezone::Timezone,
+            arch::Arch, hostname::Hostname, iso::Iso, late_commands::LateCommands, locale::Locale,
+            minimum_size::MinimumSize, ntp::Ntp, packages::Packages,
+            partition_layout::PartitionLayout, root_password::RootPassword, timezone::Timezone,
             unix_users::UnixUsers,
         },
         qemu::{OsCategory, QemuBuilder},
     },
     cli::prompt::Prompt,
-    enter, wait_screen_rect,
+    enter, input, wait_text_ocr,
 };
 
+use self::autoinstall::Autoinstall;
+
 use super::BuildImage;
 
+pub mod autoinstall;
+
 /// Ubuntu is a Linux distribution derived from Debian and composed mostly of
 /// free and open-source software.
 ///
+/// The Server and Desktop installers are both unattended through a generated
+/// autoinstall config.
+///
 /// Upstream: https://ubuntu.com
 /// Maintainer: cilki
 #[goldboot_macros::Os(architectures(Amd64, Arm64))]
@@ -35,6 +44,8 @@ pub struct Ubuntu {
     pub minimum_size: MinimumSize,
     pub release: UbuntuRelease,
     #[serde(default)]
+    pub edition: UbuntuEdition,
+    #[serde(default)]
     pub hostname: Hostname,
     #[serde(default)]
     pub root_password: RootPassword,
@@ -57,147 +68,78 @@ pub struct Ubuntu {
     #[serde(default)]
     pub ntp: Ntp,
 
+    /// Disk partition layout and optional LUKS encryption
+    #[serde(default)]
+    pub partition_layout: PartitionLayout,
+
+    /// Shell commands that run in the installed system at the end of the
+    /// install
+    #[serde(default)]
+    pub late_commands: LateCommands,
+
+    /// Installation media, which must match the edition
     #[default(Iso {
         url: "https://releases.ubuntu.com/noble/ubuntu-24.04.4-live-server-amd64.iso".parse().unwrap(),
         checksum: Some("sha256:e907d92eeec9df64163a7e454cbc8d7755e8ddc7ed42f99dbc80c40f1a138433".to_string()),
     })]
     pub iso: Iso,
+
+    /// Steps that edit the disk on the host after the build.
+    #[serde(default)]
+    pub offline_steps: Vec<OfflineStep>,
 }
 
 impl Ubuntu {
-    /// Generate an autoinstall user-data YAML document.
-    fn generate_autoinstall(&self) -> String {
-        let root_password = match &self.root_password {
-            RootPassword::Plaintext(p) => p.clone(),
-            RootPassword::PlaintextEnv(name) => {
-                std::env::var(name).expect("environment variable not found")
-            }
-        };
-
-        let extra_packages: Vec<String> = self
-            .packages
-            .as_ref()
-            .map(|p| p.0.clone())
-            .unwrap_or_default();
-
-        let packages_yaml = if extra_packages.is_empty() {
-            String::new()
-        } else {
-            format!(
-                "packages:\n{}\n",
-                extra_packages
-                    .iter()
-                    .map(|p| format!("  - {p}"))
-                    .collect::<Vec<_>>()
-                    .join("\n")
-            )
-        };
-
-        let extra_users_yaml = self
-            .users
-            .as_ref()
-            .map(|users| {
-                users
-                    .0
-                    .iter()
-                    .map(|u| {
-                        let groups = if u.sudo { "    groups: [sudo]\n" } else { "" };
-                        format!(
-                            "  - name: {}\n    passwd: {}\n    lock_passwd: false\n{}",
-                            u.username, u.password, groups
-                        )
-                    })
-                    .collect::<Vec<_>>()
-                    .join("")
-            })
-            .unwrap_or_default();
-
-        let users_section = format!(
-            "users:\n  - name: root\n    passwd: {root_password}\n    lock_passwd: false\n{extra_users_yaml}"
-        );
-
-        format!(
-            r#"#cloud-config
-autoinstall:
-  version: 1
-  locale: {language}.{encoding}
-  keyboard:
-    layout: {keyboard}
-  network:
-    network:
-      version: 2
-      ethernets:
-        any:
-          match:
-            name: "en*"
-          dhcp4: true
-  storage:
-    layout:
-      name: direct
-  {packages_yaml}identity:
-    hostname: {hostname}
-    username: root
-    password: {root_password}
-  {users_section}
-  ntp:
-    enabled: {ntp}
-  timezone: {timezone}
-  ssh:
-    install-server: true
-    allow-pw: true
-  late-commands: []
-"#,
-            language = self.locale.language,
-            encoding = self.locale.encoding,
-            keyboard = self.locale.keyboard,
-            hostname = self.hostname.0,
-            root_password = root_password,
-            ntp = self.ntp.0,
-            timezone = self.timezone.0,
-            packages_yaml = packages_yaml,
-            users_section = users_section,
-        )
+    fn autoinstall(&self) -> Autoinstall {
+        Autoinstall {
+            hostname: self.hostname.clone(),
+            root_password: self.root_password.clone(),
+            users: self.users.clone(),
+            packages: self.packages.clone().unwrap_or_default().0,
+            timezone: self.timezone.clone(),
+            locale: self.locale.clone(),
+            partition_layout: self.partition_layout.clone(),
+            ntp: self.ntp.0,
+            late_commands: self.late_commands.0.clone(),
+        }
     }
 }
 
 impl BuildImage for Ubuntu {
     fn build(&self, worker: &Builder) -> Result<()> {
+        if worker.has_checkpoint(checkpoint::INSTALL) {
+            return Ok(());
+        }
+
         let mut qemu = QemuBuilder::new(worker, OsCategory::Linux)
             .with_iso(&self.iso)?
-            .prepare_ssh()?
             .start()?;
 
         // Serve autoinstall config via HTTP
+        let autoinstall = self.autoinstall();
         let http = HttpServer::builder()?
-            .file("user-data", self.generate_autoinstall().into_bytes())?
-            .file("meta-data", b"")?
+            .file("user-data", autoinstall.user_data()?.into_bytes())?
+            .file("meta-data", autoinstall.meta_data().into_bytes())?
             .serve();
 
-        // Send boot command — at the GRUB menu, append autoinstall ds= kernel param
+        // Send boot command
         #[rustfmt::skip]
         qemu.vnc.run(vec![
             // Wait for GRUB menu
-            wait_screen_rect!("TODO", 100, 0, 1024, 200),
-            // Select "Try or Install Ubuntu Server", append kernel params
+            wait_text_ocr!("Try or Install Ubuntu"),
+            // Boot the installer from the GRUB command line with the autoinstall datasource
+            input!("c"),
             enter!(format!(
-                " autoinstall ds=nocloud;s=http://{}:{}/",
+                "linux /casper/vmlinuz autoinstall 'ds=nocloud-net;s=http://{}:{}/' ---",
                 http.address, http.port
             )),
-            // Wait for install to complete and reach login
-            wait_screen_rect!("TODO", 100, 0, 1024, 200),
-            enter!("root"),
-            enter!(match &self.root_password {
-                RootPassword::Plaintext(p) => p.clone(),
-                RootPassword::PlaintextEnv(name) => std::env::var(name).expect("environment variable not found"),
-            }),
+            enter!("initrd /casper/initrd"),
+            enter!("boot"),
         ])?;
 
-        // Wait for SSH
-        let ssh = qemu.ssh("root")?;
-
-        // Shutdown
-        ssh.shutdown("poweroff")?;
+        // The installer powers off when it's done
         qemu.shutdown_wait()?;
+        Qcow3::open(&worker.qcow_path)?.create_snapshot(checkpoint::INSTALL)?;
         Ok(())
     }
 }
@@ -239,19 +181,49 @@ impl Prompt for UbuntuRelease {
     }
 }
 
-/// Fetch live-server ISO info for a given release and arch.
-pub fn fetch_ubuntu_iso(release: UbuntuRelease, arch: ImageArch) -> Result<Iso> {
-    let arch_str = match arch {
-        ImageArch::Amd64 => "amd64",
-        ImageArch::Arm64 => "arm64",
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, EnumIter, Display, Default)]
+pub enum UbuntuEdition {
+    /// Live server installer
+    #[default]
+    Server,
+    /// Desktop installer
+    Desktop,
+}
+
+impl Prompt for UbuntuEdition {
+    fn prompt(&mut self, _: &Builder) -> Result<()> {
+        let editions: Vec<UbuntuEdition> = UbuntuEdition::iter().collect();
+        let index = dialoguer::Select::with_theme(&crate::cli::cmd::init::theme())
+            .with_prompt("Choose Ubuntu edition")
+            .default(0)
+            .items(&editions)
+            .interact()?;
+        *self = editions[index];
+        Ok(())
+    }
+}
+
+/// Fetch the ISO info for a given release, edition and arch.
+pub fn fetch_ubuntu_iso(
+    release: UbuntuRelease,
+    edition: UbuntuEdition,
+    arch: ImageArch,
+) -> Result<Iso> {
+    let arch_str = match (arch, edition) {
+        (ImageArch::Amd64, _) => "amd64",
+        (ImageArch::Arm64, UbuntuEdition::Server) => "arm64",
         _ => bail!("Unsupported architecture"),
     };
+    let kind = match edition {
+        UbuntuEdition::Server => "live-server",
+        UbuntuEdition::Desktop => "desktop",
+    };
     let codename = release.codename();
 
     let rs = reqwest::blocking::get(format!("https://releases.ubuntu.com/{codename}/SHA256SUMS"))?;
     if rs.status().is_success() {
         for line in BufReader::new(rs).lines().map_while(Result::ok) {
-            if line.contains("live-server") && line.contains(arch_str) && line.ends_with(".iso") {
+            if line.contains(kind) && line.contains(arch_str) && line.ends_with(".iso") {
                 let split: Vec<&str> = line.split_whitespace().collect();
                 if let [hash, filename] = split[..] {
                     // SHA256SUMS uses " *filename" format — strip leading '*'
@@ -266,5 +238,5 @@ pub fn fetch_ubuntu_iso(release: UbuntuRelease, arch: ImageArch) -> Result<Iso>
             }
         }
     }
-    bail!("Failed to fetch Ubuntu ISO info for {codename}/{arch_str}");
+    bail!("Failed to fetch Ubuntu {kind} ISO info for {codename}/{arch_str}");
 }
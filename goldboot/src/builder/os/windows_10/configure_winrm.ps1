@@ -5,7 +5,8 @@ New-Item -Path "HKLM:\SYSTEM\CurrentControlSet\Control\Network\NewNetworkWindowO
 $ifaceinfo = Get-NetConnectionProfile
 Set-NetConnectionProfile -InterfaceIndex $ifaceinfo.InterfaceIndex -NetworkCategory Private 
 
-# Configure WinRM itself
+# Configure WinRM itself (disable_winrm.ps1 reverts this after the post-steps
+# unless keep_winrm is set)
 winrm quickconfig -q
 winrm s "winrm/config" '@{MaxTimeoutms="1800000"}'
 winrm s "winrm/config/winrs" '@{MaxMemoryPerShellMB="2048"}'
@@ -0,0 +1,17 @@
+# Undo configure_winrm.ps1 so the image doesn't accept unencrypted WinRM with
+# basic authentication. This cuts off the WinRM session that started it, so it
+# runs as a scheduled task and powers the VM off when done.
+
+winrm s "winrm/config/service" '@{AllowUnencrypted="false"}'
+winrm s "winrm/config/service/auth" '@{Basic="false"}'
+winrm delete "winrm/config/Listener?Address=*+Transport=HTTP"
+
+# Remove the firewall rule enabled for the listener
+Remove-NetFirewallRule -DisplayName "Windows Remote Management (HTTP-In)"
+
+# WinRM doesn't start on its own by default
+Set-Service -Name WinRM -StartupType Manual
+
+schtasks /delete /tn goldboot-disable-winrm /f
+Remove-Item $PSCommandPath
+Stop-Computer -Force
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use anyhow::Result;
+use anyhow::{Result, anyhow, bail};
 use serde::{Deserialize, Serialize};
 use serde_win_unattend::*;
 use smart_default::SmartDefault;
@@ -9,18 +9,33 @@ use validator::Validate;
 
 use crate::{
     builder::{
-        Builder,
+        Builder, checkpoint,
         options::{
-            arch::Arch, hostname::Hostname, iso::Iso, locale::Locale, minimum_size::MinimumSize,
-            product_key, timezone::Timezone, unix_users::UnixUsers,
+            arch::Arch,
+            hostname::Hostname,
+            iso::Iso,
+            keep_winrm::KeepWinrm,
+            locale::Locale,
+            minimum_size::MinimumSize,
+            product_key,
+            timezone::Timezone,
+            unix_users::{UnixUser, UnixUsers},
         },
         qemu::{OsCategory, QemuBuilder},
+        steps::{PostStep, run_post_steps},
+        winrm::WinrmConnection,
     },
     enter, wait,
 };
 
 use super::BuildImage;
 
+/// Enables WinRM over HTTP with basic authentication for post-steps.
+pub(super) const CONFIGURE_WINRM: &[u8] = include_bytes!("configure_winrm.ps1");
+
+/// Undoes `configure_winrm.ps1` and powers off.
+const DISABLE_WINRM: &[u8] = include_bytes!("disable_winrm.ps1");
+
 /// Windows 10 is a major release of Microsoft's Windows NT operating system.
 ///
 /// Upstream: https://microsoft.com
@@ -52,6 +67,15 @@ pub struct Windows10 {
         checksum: None,
     })]
     pub iso: Iso,
+
+    /// Steps that run over WinRM against the installed system after the
+    /// install completes. WinRM logs in as the first user with `sudo`.
+    #[serde(default)]
+    pub post_steps: Vec<PostStep>,
+
+    /// Leave WinRM enabled after the post-steps instead of disabling it
+    #[serde(default)]
+    pub keep_winrm: KeepWinrm,
 }
 
 impl Windows10 {
@@ -108,6 +132,13 @@ impl Windows10 {
             }
         }
 
+        // Enable WinRM for the post-steps once the users exist
+        if !self.post_steps.is_empty() {
+            first_logon_commands.push(configure_winrm_command(
+                self.users.as_ref().map_or(0, |users| users.0.len()) + 2,
+            ));
+        }
+
         let unattended = UnattendXml {
             xmlns: "urn:schemas-microsoft-com:unattend".into(),
             settings: vec![
@@ -224,27 +255,45 @@ impl BuildImage for Windows10 {
         let unattended_xml = self.generate_unattend()?;
         debug!(xml = unattended_xml, "Generated Autounattend.xml");
 
-        let mut qemu = QemuBuilder::new(worker, OsCategory::Windows)
+        // A VM restored from the install checkpoint is running the installed
+        // system even if the post-steps have since been removed
+        let use_winrm = !self.post_steps.is_empty() || worker.has_checkpoint(checkpoint::INSTALL);
+
+        let mut floppy = HashMap::from([(
+            "Autounattend.xml".to_string(),
+            unattended_xml.as_bytes().to_vec(),
+        )]);
+        if use_winrm {
+            floppy.insert("configure_winrm.ps1".to_string(), CONFIGURE_WINRM.to_vec());
+        }
+
+        let mut qemu_builder = QemuBuilder::new(worker, OsCategory::Windows)
             .with_iso(&self.iso)?
-            .floppy_files(HashMap::from([(
-                "Autounattend.xml".to_string(),
-                unattended_xml.as_bytes().to_vec(),
-            )]))?
-            .prepare_ssh()?
-            .start()?;
-
-        // Send boot command
-        #[rustfmt::skip]
-        qemu.vnc.run(vec![
-            wait!(4),
-            enter!(),
-        ])?;
-
-        // Wait for SSH
-        // let mut ssh = qemu.ssh_wait(context.ssh_port, &self.username, &self.password)?;
-
-        // Shutdown
-        // ssh.shutdown("shutdown /s /t 0 /f /d p:4:1")?;
+            .floppy_files(floppy)?;
+        if use_winrm {
+            qemu_builder = qemu_builder.forward_winrm();
+        }
+        let mut qemu = qemu_builder.start()?;
+
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            // Send boot command
+            #[rustfmt::skip]
+            qemu.vnc.run(vec![
+                wait!(4),
+                enter!(),
+            ])?;
+        }
+
+        if use_winrm {
+            let user = winrm_user(&self.users)?;
+            let mut winrm = qemu.winrm(&user.username, &user.password)?;
+            if !worker.has_checkpoint(checkpoint::INSTALL) {
+                qemu.checkpoint(checkpoint::INSTALL)?;
+            }
+            run_post_steps(worker, &mut qemu, &mut winrm, &self.post_steps)?;
+            shutdown_winrm(winrm, &self.keep_winrm)?;
+        }
+
         qemu.shutdown_wait()?;
         Ok(())
     }
@@ -254,3 +303,43 @@ impl BuildImage for Windows10 {
 fn locale_to_windows_tag(locale: &Locale) -> String {
     locale.language.replace('_', "-")
 }
+
+/// The first-logon command that runs `configure_winrm.ps1` from the floppy.
+pub(super) fn configure_winrm_command(order: usize) -> SynchronousCommand {
+    SynchronousCommand {
+        CommandLine:
+            r#"powershell -NoProfile -ExecutionPolicy Bypass -File A:\configure_winrm.ps1"#.into(),
+        Description: Some("Enable WinRM for post-steps".into()),
+        Order: order.to_string(),
+        RequiresUserInput: None,
+        action: None,
+    }
+}
+
+/// Power off over WinRM, disabling WinRM on the way unless `keep_winrm` is
+/// set. Disabling it takes down the connection, so the script runs as a
+/// scheduled task that powers off when it's done.
+pub(super) fn shutdown_winrm(mut winrm: WinrmConnection, keep_winrm: &KeepWinrm) -> Result<()> {
+    if keep_winrm.0 {
+        return winrm.shutdown("shutdown /s /t 0 /f /d p:4:1");
+    }
+
+    let script = r"C:\Windows\Temp\goldboot-disable-winrm.ps1";
+    winrm.upload(DISABLE_WINRM, script)?;
+    if winrm.exec(&format!(
+        "schtasks /create /tn goldboot-disable-winrm /sc once /st 00:00 /ru SYSTEM /rl HIGHEST /f /tr \"powershell -NoProfile -ExecutionPolicy Bypass -File {script}\""
+    ))? != 0
+    {
+        bail!("Failed to schedule disabling WinRM");
+    }
+    winrm.shutdown("schtasks /run /tn goldboot-disable-winrm")
+}
+
+/// The account WinRM logs in as, which must be an Administrator.
+pub(super) fn winrm_user(users: &Option<UnixUsers>) -> Result<&UnixUser> {
+    users
+        .iter()
+        .flat_map(|users| users.0.iter())
+        .find(|user| user.sudo)
+        .ok_or_else(|| anyhow!("Windows post-steps need a user with sudo to log in over WinRM"))
+}
@@ -8,17 +8,21 @@ use validator::Validate;
 
 use crate::{
     builder::{
-        Builder,
+        Builder, checkpoint,
         options::{
-            arch::Arch, hostname::Hostname, iso::Iso, locale::Locale, minimum_size::MinimumSize,
-            product_key, timezone::Timezone, unix_users::UnixUsers,
+            arch::Arch, hostname::Hostname, iso::Iso, keep_winrm::KeepWinrm, locale::Locale,
+            minimum_size::MinimumSize, product_key, timezone::Timezone, unix_users::UnixUsers,
         },
         qemu::{OsCategory, QemuBuilder},
+        steps::{PostStep, run_post_steps},
     },
     enter, wait,
 };
 
-use super::BuildImage;
+use super::{
+    BuildImage,
+    windows_10::{CONFIGURE_WINRM, configure_winrm_command, shutdown_winrm, winrm_user},
+};
 
 /// Windows 11 is a major release of Microsoft's Windows NT operating system.
 ///
@@ -51,6 +55,15 @@ pub struct Windows11 {
         checksum: None,
     })]
     pub iso: Iso,
+
+    /// Steps that run over WinRM against the installed system after the
+    /// install completes. WinRM logs in as the first user with `sudo`.
+    #[serde(default)]
+    pub post_steps: Vec<PostStep>,
+
+    /// Leave WinRM enabled after the post-steps instead of disabling it
+    #[serde(default)]
+    pub keep_winrm: KeepWinrm,
 }
 
 impl Windows11 {
@@ -107,6 +120,13 @@ impl Windows11 {
             }
         }
 
+        // Enable WinRM for the post-steps once the users exist
+        if !self.post_steps.is_empty() {
+            first_logon_commands.push(configure_winrm_command(
+                self.users.as_ref().map_or(0, |users| users.0.len()) + 2,
+            ));
+        }
+
         let unattended = UnattendXml {
             xmlns: "urn:schemas-microsoft-com:unattend".into(),
             settings: vec![
@@ -240,28 +260,46 @@ impl BuildImage for Windows11 {
         let unattended_xml = self.generate_unattend()?;
         debug!(xml = unattended_xml, "Generated Autounattend.xml");
 
-        let mut qemu = QemuBuilder::new(worker, OsCategory::Windows)
+        // A VM restored from the install checkpoint is running the installed
+        // system even if the post-steps have since been removed
+        let use_winrm = !self.post_steps.is_empty() || worker.has_checkpoint(checkpoint::INSTALL);
+
+        let mut floppy = HashMap::from([(
+            "Autounattend.xml".to_string(),
+            unattended_xml.as_bytes().to_vec(),
+        )]);
+        if use_winrm {
+            floppy.insert("configure_winrm.ps1".to_string(), CONFIGURE_WINRM.to_vec());
+        }
+
+        let mut qemu_builder = QemuBuilder::new(worker, OsCategory::Windows)
             .with_iso(&self.iso)?
-            .floppy_files(HashMap::from([(
-                "Autounattend.xml".to_string(),
-                unattended_xml.as_bytes().to_vec(),
-            )]))?
-            .enable_tpm()?
-            // .prepare_ssh()?
-            .start()?;
+            .floppy_files(floppy)?
+            .enable_tpm()?;
+        if use_winrm {
+            qemu_builder = qemu_builder.forward_winrm();
+        }
+        let mut qemu = qemu_builder.start()?;
 
-        // Send boot command
-        #[rustfmt::skip]
-        qemu.vnc.run(vec![
-            wait!(4),
-            enter!(),
-        ])?;
+        if !worker.has_checkpoint(checkpoint::INSTALL) {
+            // Send boot command
+            #[rustfmt::skip]
+            qemu.vnc.run(vec![
+                wait!(4),
+                enter!(),
+            ])?;
+        }
 
-        // Wait for SSH
-        // let mut ssh = qemu.ssh_wait(context.ssh_port, &self.username, &self.password)?;
+        if use_winrm {
+            let user = winrm_user(&self.users)?;
+            let mut winrm = qemu.winrm(&user.username, &user.password)?;
+            if !worker.has_checkpoint(checkpoint::INSTALL) {
+                qemu.checkpoint(checkpoint::INSTALL)?;
+            }
+            run_post_steps(worker, &mut qemu, &mut winrm, &self.post_steps)?;
+            shutdown_winrm(winrm, &self.keep_winrm)?;
+        }
 
-        // Shutdown
-        // ssh.shutdown("shutdown /s /t 0 /f /d p:4:1")?;
         qemu.shutdown_wait()?;
         Ok(())
     }
@@ -0,0 +1,126 @@
+//! Building the elements of a multiboot image concurrently.
+//!
+//! Each element builds in its own worker [`Builder`] with its own working
+//! qcow2, tmp dir, VNC port and SSH port, so the elements' VMs don't interfere
+//! with each other. Logs are emitted inside a span naming the element.
+
+use crate::{
+    builder::{Builder, cache::CacheKeys},
+    cli::progress::show_progress,
+};
+use anyhow::Result;
+use std::{sync::Mutex, time::Duration};
+use tracing::{error, info_span};
+
+/// Build the element of each worker with up to `jobs` builds running at once.
+/// When a build fails, the running ones are allowed to finish but no more
+/// are started, then the first failure is returned.
+pub fn build(workers: &mut [(Builder, CacheKeys)], jobs: usize) -> Result<()> {
+    let progress = Progress::new(workers);
+    for (i, (worker, _)) in workers.iter_mut().enumerate() {
+        worker.progress = progress.bar(i);
+    }
+
+    let queue = Mutex::new(workers.iter_mut().enumerate());
+    let failures = Mutex::new(Vec::new());
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| {
+                loop {
+                    // Queued builds don't start once one has failed
+                    if !failures.lock().unwrap().is_empty() {
+                        break;
+                    }
+
+                    // Don't hold the lock while building
+                    let next = queue.lock().unwrap().next();
+                    let Some((i, (worker, keys))) = next else {
+                        break;
+                    };
+
+                    let span =
+                        info_span!("element", index = i, name = %worker.elements[0].0.os_name());
+                    let _entered = span.enter();
+
+                    progress.start(i);
+                    match worker.build_element(keys) {
+                        Ok(()) => progress.finish(i, "done"),
+                        Err(e) => {
+                            error!(error = ?e, "Element build failed");
+                            progress.finish(i, "failed");
+                            failures.lock().unwrap().push((i, e));
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let mut failures = failures.into_inner().unwrap();
+    failures.sort_by_key(|(i, _)| *i);
+    match failures.into_iter().next() {
+        Some((i, e)) => Err(e.context(format!("Failed to build element {i}"))),
+        None => Ok(()),
+    }
+}
+
+/// A spinner per element, shown when several elements build in a terminal.
+struct Progress {
+    bars: Vec<indicatif::ProgressBar>,
+
+    /// Keeps the bars drawn together
+    _multi: Option<indicatif::MultiProgress>,
+}
+
+impl Progress {
+    fn new(workers: &[(Builder, CacheKeys)]) -> Self {
+        if workers.len() < 2 || !show_progress() {
+            return Self {
+                bars: Vec::new(),
+                _multi: None,
+            };
+        }
+
+        let multi = indicatif::MultiProgress::new();
+        let bars = workers
+            .iter()
+            .enumerate()
+            .map(|(i, (worker, _))| {
+                let bar = multi.add(indicatif::ProgressBar::new_spinner());
+                bar.set_style(
+                    indicatif::ProgressStyle::with_template(
+                        "{spinner:.blue} [{elapsed_precise}] {prefix:<24} {msg}",
+                    )
+                    .unwrap(),
+                );
+                bar.set_prefix(format!("[{i}] {}", worker.elements[0].0.os_name()));
+                bar.set_message("queued");
+                bar
+            })
+            .collect();
+
+        Self {
+            bars,
+            _multi: Some(multi),
+        }
+    }
+
+    fn bar(&self, index: usize) -> Option<indicatif::ProgressBar> {
+        self.bars.get(index).cloned()
+    }
+
+    fn start(&self, index: usize) {
+        if let Some(bar) = self.bars.get(index) {
+            bar.reset_elapsed();
+            bar.enable_steady_tick(Duration::from_millis(100));
+            bar.set_message("building");
+        }
+    }
+
+    fn finish(&self, index: usize, message: &'static str) {
+        if let Some(bar) = self.bars.get(index) {
+            bar.finish_with_message(message);
+        }
+    }
+}
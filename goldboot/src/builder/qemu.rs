@@ -1,19 +1,29 @@
 use crate::{
-    builder::{Builder, serial::SerialConnection, ssh::SshConnection, vnc::VncConnection},
+    builder::{
+        Builder, checkpoint,
+        observe::Observer,
+        recording::RecordMode,
+        serial::SerialConnection,
+        ssh::{HostKey, SshConnection},
+        vnc::VncConnection,
+        winrm::{self, WinrmConnection},
+    },
     enter,
 };
 use anyhow::{Result, bail};
 use goldboot_image::ImageArch;
 use rand::RngExt;
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     fs::OpenOptions,
     io::{self, BufRead, BufReader, Write},
+    net::TcpListener,
+    ops::Range,
     os::unix::net::UnixStream,
     path::PathBuf,
     process::{Child, Command},
     sync::{
-        Arc,
+        Arc, Mutex,
         atomic::{AtomicBool, Ordering},
     },
     time::{Duration, Instant},
@@ -43,6 +53,7 @@ impl OsCategory {
 }
 
 /// Supported VM hardware acceleration.
+#[derive(Clone, Copy)]
 pub enum Accel {
     /// "Kernel VM" which requires Intel VT or AMD-V
     Kvm,
@@ -95,6 +106,50 @@ pub fn detect_accel() -> Accel {
 
 pub fn mimic_hardware() {}
 
+/// Port goldboot-sshd listens on inside the guest. It's independent of the
+/// forwarded host port so that a VM restored from a live checkpoint stays
+/// reachable when the host port has to change.
+const GUEST_SSHD_PORT: u16 = 10022;
+
+/// Host ports handed out to VMs of this process, so concurrently starting
+/// VMs don't pick the same one before either has bound it.
+static RESERVED_PORTS: Mutex<BTreeSet<u16>> = Mutex::new(BTreeSet::new());
+
+/// Whether nothing is listening on `port` on the loopback interface.
+fn port_is_free(port: u16) -> bool {
+    TcpListener::bind(("127.0.0.1", port)).is_ok()
+}
+
+/// Claim `port` for a VM of this process if nothing else listens on it. A
+/// port this process already handed out may be claimed again, since it's
+/// reused by the element it was picked for.
+pub fn claim_port(port: u16) -> bool {
+    let mut reserved = RESERVED_PORTS.lock().unwrap();
+    if port_is_free(port) {
+        reserved.insert(port);
+        true
+    } else {
+        false
+    }
+}
+
+/// Pick a free host port from `range` that no other VM of this process
+/// uses, trying `preferred` first and then the ports after it.
+pub fn reserve_port(preferred: u16, range: Range<u16>) -> Result<u16> {
+    let len = range.end.saturating_sub(range.start);
+    let offset = preferred.saturating_sub(range.start);
+
+    let mut reserved = RESERVED_PORTS.lock().unwrap();
+    for i in 0..len {
+        let port = range.start + (offset + i) % len;
+        if !reserved.contains(&port) && port_is_free(port) {
+            reserved.insert(port);
+            return Ok(port);
+        }
+    }
+    bail!("No free port in {range:?}");
+}
+
 /// Wraps a qemu process and provides easy access to VNC and SSH.
 pub struct QemuProcess {
     pub arch: ImageArch,
@@ -102,7 +157,10 @@ pub struct QemuProcess {
     pub tpm_process: Option<Child>,
     pub ssh_port: u16,
     pub private_key: PathBuf,
-    pub host_key: PathBuf,
+
+    /// Public key the guest's SSH server must present, when the guest uses
+    /// the host key goldboot generated
+    pub host_key: Option<PathBuf>,
     pub vnc: VncConnection,
     pub os_category: OsCategory,
     pub qmp_socket: PathBuf,
@@ -132,14 +190,25 @@ impl QemuProcess {
             enter!("mount -t vfat /dev/vdb /tmp/goldboot"),
 
             // Spawn the temporary SSH server
-            enter!(format!("/tmp/goldboot/sshdog {} /tmp/goldboot/host_key /tmp/goldboot/public_key", self.ssh_port)),
+            enter!(format!("/tmp/goldboot/goldboot-sshd {GUEST_SSHD_PORT} /tmp/goldboot/host_key /tmp/goldboot/public_key")),
         ])?;
 
         Ok(())
     }
 
     pub fn ssh(&mut self, username: &str) -> Result<SshConnection> {
-        SshConnection::new(username, &self.private_key, self.ssh_port)
+        SshConnection::new(
+            username,
+            &self.private_key,
+            self.ssh_port,
+            self.host_key.as_deref(),
+        )
+    }
+
+    /// Connect to the guest's WinRM service through the forwarded port (see
+    /// [`QemuBuilder::forward_winrm`]).
+    pub fn winrm(&mut self, username: &str, password: &str) -> Result<WinrmConnection> {
+        WinrmConnection::new(username, password, self.ssh_port)
     }
 
     /// Save a qcow2 snapshot of the current VM state with the given name.
@@ -214,6 +283,9 @@ pub struct QemuArgs {
     pub usbdevice: Vec<String>,
     pub vga: String,
     pub vnc: Vec<String>,
+
+    /// Live checkpoint to restore instead of booting
+    pub loadvm: Option<String>,
 }
 
 impl From<QemuArgs> for Vec<String> {
@@ -305,6 +377,11 @@ impl From<QemuArgs> for Vec<String> {
         cmdline.push(String::from("-vga"));
         cmdline.push(val.vga.to_string());
 
+        if let Some(loadvm) = &val.loadvm {
+            cmdline.push(String::from("-loadvm"));
+            cmdline.push(loadvm.clone());
+        }
+
         trace!("QEMU cmdline: {:?}", &cmdline);
         cmdline
     }
@@ -314,10 +391,15 @@ pub struct QemuBuilder {
     arch: ImageArch,
     args: QemuArgs,
     debug: bool,
-    record: bool,
+    record: Option<RecordMode>,
+    wait_timeout: Duration,
+    build_deadline: Option<Instant>,
+    failure_dir: PathBuf,
+    observer: Option<Observer>,
     ssh_port: u16,
     ssh_private_key: PathBuf,
     ssh_host_key: PathBuf,
+    pin_host_key: bool,
     vnc_port: u16,
     temp: PathBuf,
     os_category: OsCategory,
@@ -327,10 +409,14 @@ pub struct QemuBuilder {
 
 impl QemuBuilder {
     pub fn new(worker: &Builder, os_category: OsCategory) -> Self {
-        // TODO deterministic port for snapshots
-        let ssh_port = rand::rng().random_range(10000..11000);
-        let ssh_private_key = crate::builder::ssh::generate_key(worker.tmp.path()).unwrap();
-        let ssh_host_key = crate::builder::ssh::generate_key(worker.tmp.path()).unwrap();
+        // The keys must not change between runs, or a VM restored from a
+        // live checkpoint couldn't be reached
+        let state_dir = worker.state_dir();
+        let ssh_port = checkpoint::ssh_port(&state_dir).expect("no free port for SSH");
+        let ssh_private_key =
+            crate::builder::ssh::load_or_generate_key(&state_dir.join("id_ed25519")).unwrap();
+        let ssh_host_key =
+            crate::builder::ssh::load_or_generate_key(&state_dir.join("host_key")).unwrap();
         let qmp_socket = worker.tmp.path().join("qmp.sock");
         let serial_socket = worker.tmp.path().join("serial.sock");
 
@@ -384,14 +470,20 @@ impl QemuBuilder {
                 usbdevice: vec![],
                 vnc: vec![format!("127.0.0.1:{}", worker.vnc_port % 5900)],
                 vga: String::from("std"),
+                loadvm: worker.resume.clone(),
             },
             arch: worker.arch().expect("arch must be known"),
             debug: worker.debug,
             os_category,
             record: worker.record,
+            wait_timeout: worker.wait_timeout,
+            build_deadline: worker.build_deadline,
+            failure_dir: worker.failure_dir.clone(),
+            observer: worker.observer.clone(),
             ssh_port,
             ssh_private_key,
             ssh_host_key,
+            pin_host_key: false,
             temp: worker.tmp.path().to_path_buf(),
             vnc_port: worker.vnc_port,
             qmp_socket,
@@ -417,6 +509,23 @@ impl QemuBuilder {
         self
     }
 
+    /// Attach the output image as an NVMe drive instead of the OS category's
+    /// default interface.
+    pub fn nvme(mut self) -> Self {
+        self.args.drive[0] = self.args.drive[0]
+            .split(',')
+            .map(|option| match option {
+                option if option.starts_with("if=") => "if=none,id=nvme",
+                option => option,
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        self.args
+            .device
+            .push("nvme,serial=cafebabe,drive=nvme".into());
+        self
+    }
+
     /// Update -vga
     pub fn vga(mut self, arg: &str) -> Self {
         self.args.vga = arg.to_string();
@@ -431,7 +540,52 @@ impl QemuBuilder {
 
     /// Create a temporary FAT filesystem with the given contents and append it
     /// to the invocation.
-    pub fn drive_files(mut self, files: HashMap<String, Vec<u8>>) -> Result<Self> {
+    pub fn drive_files(self, files: HashMap<String, Vec<u8>>) -> Result<Self> {
+        self.labeled_drive_files(files, None)
+    }
+
+    /// Attach a cloud-init NoCloud seed that authorizes the goldboot SSH key
+    /// for `username`, so images that run cloud-init (most vendor cloud
+    /// images) can be reached without knowing their credentials. The seed
+    /// also installs the generated host key so the connection can verify it.
+    pub fn cloud_init_ssh(mut self, username: &str) -> Result<Self> {
+        let public_key = String::from_utf8(self.ssh_public_key()?)?;
+        let public_key = public_key.trim();
+
+        let mut user_data = if username == "root" {
+            format!("#cloud-config\ndisable_root: false\nssh_authorized_keys:\n  - {public_key}\n")
+        } else {
+            format!(
+                "#cloud-config\nusers:\n  - default\n  - name: {username}\n    sudo: ALL=(ALL) NOPASSWD:ALL\n    shell: /bin/sh\n    ssh_authorized_keys:\n      - {public_key}\n"
+            )
+        };
+
+        let host_key = std::fs::read_to_string(&self.ssh_host_key)?;
+        let host_public_key = std::fs::read_to_string(self.ssh_host_key.with_extension("pub"))?;
+        user_data.push_str("ssh_keys:\n  ed25519_private: |\n");
+        for line in host_key.lines() {
+            user_data.push_str(&format!("    {line}\n"));
+        }
+        user_data.push_str(&format!("  ed25519_public: {}\n", host_public_key.trim()));
+        self.pin_host_key = true;
+
+        self.labeled_drive_files(
+            HashMap::from([
+                ("user-data".to_string(), user_data.into_bytes()),
+                (
+                    "meta-data".to_string(),
+                    b"instance-id: goldboot\nlocal-hostname: goldboot\n".to_vec(),
+                ),
+            ]),
+            Some(*b"CIDATA     "),
+        )
+    }
+
+    fn labeled_drive_files(
+        mut self,
+        files: HashMap<String, Vec<u8>>,
+        label: Option<[u8; 11]>,
+    ) -> Result<Self> {
         let fs_name: String = rand::rng()
             .sample_iter(&rand::distr::Alphanumeric)
             .take(12)
@@ -456,10 +610,11 @@ impl QemuBuilder {
                 .open(&fs_path)?;
             fs_file.set_len(fs_size)?;
 
-            fatfs::format_volume(
-                fscommon::BufStream::new(fs_file),
-                fatfs::FormatVolumeOptions::new(),
-            )?;
+            let mut options = fatfs::FormatVolumeOptions::new();
+            if let Some(label) = label {
+                options = options.volume_label(label);
+            }
+            fatfs::format_volume(fscommon::BufStream::new(fs_file), options)?;
 
             let fs_file = OpenOptions::new()
                 .read(true)
@@ -551,9 +706,21 @@ impl QemuBuilder {
         Ok(std::fs::read(self.ssh_private_key.with_extension("pub"))?)
     }
 
+    /// Read the generated host key so the element can install it at
+    /// [`GUEST_HOST_KEY_PATH`](crate::builder::ssh::GUEST_HOST_KEY_PATH)
+    /// (e.g. through an answer file). Connections then verify the guest
+    /// presents it.
+    pub fn pin_host_key(&mut self) -> Result<HostKey> {
+        self.pin_host_key = true;
+        Ok(HostKey {
+            private: std::fs::read_to_string(&self.ssh_host_key)?,
+            public: std::fs::read_to_string(self.ssh_host_key.with_extension("pub"))?,
+        })
+    }
+
     /// Forward the host's SSH port to the given guest TCP port. Use this when
     /// the guest runs its own SSH daemon (e.g. an installed system whose sshd
-    /// listens on port 22) rather than the bundled sshdog.
+    /// listens on port 22) rather than goldboot-sshd.
     pub fn forward_ssh(mut self, guest_port: u16) -> Self {
         self.args.netdev = vec![format!(
             "user,id=user.0,hostfwd=tcp::{}-:{}",
@@ -562,19 +729,24 @@ impl QemuBuilder {
         self
     }
 
+    /// Forward the host's SSH port to the guest's WinRM service instead, for
+    /// Windows guests that run post-steps over WinRM.
+    pub fn forward_winrm(self) -> Self {
+        self.forward_ssh(winrm::PORT)
+    }
+
+    /// Put goldboot-sshd and its keys on a drive for [`QemuProcess::install_ssh`]
+    /// and forward its port.
     pub fn prepare_ssh(mut self) -> Result<Self> {
-        let sshdog = crate::builder::ssh::download_sshdog(self.arch, self.os_category)?;
+        let sshd = crate::builder::ssh::guest_sshd(self.arch, self.os_category)?;
         let host_key = std::fs::read(&self.ssh_host_key)?;
         let public_key = std::fs::read(self.ssh_private_key.with_extension("pub"))?;
 
-        self.args.netdev.truncate(0);
-        self.args.netdev.push(format!(
-            "user,id=user.0,hostfwd=tcp::{}-:{}",
-            self.ssh_port, self.ssh_port
-        ));
+        self = self.forward_ssh(GUEST_SSHD_PORT);
+        self.pin_host_key = true;
 
         self.drive_files(HashMap::from([
-            ("sshdog".to_string(), sshdog),
+            ("goldboot-sshd".to_string(), sshd),
             ("host_key".to_string(), host_key),
             ("public_key".to_string(), public_key),
         ]))
@@ -616,20 +788,21 @@ impl QemuBuilder {
 
         // Start the VM
         let cmdline: Vec<String> = self.args.into();
-        let mut process = Command::new(match &self.arch {
+        let program = match &self.arch {
             ImageArch::Amd64 => "qemu-system-x86_64",
             ImageArch::Arm64 => "qemu-system-aarch64",
             _ => bail!("Unknown arch"),
-        })
-        .args(cmdline.iter())
-        .spawn()?;
+        };
+        let mut process = Command::new(program).args(cmdline.iter()).spawn()?;
 
         // Log QEMU CPU usage every 30 seconds from /proc/{pid}/stat
         let cpu_logger_stop = Arc::new(AtomicBool::new(false));
         {
             let pid = process.id();
             let stop = cpu_logger_stop.clone();
+            let span = tracing::Span::current();
             std::thread::spawn(move || {
+                let _entered = span.enter();
                 let clk_tck = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
                 let mut prev_ticks: Option<u64> = None;
                 let mut prev_time = Instant::now();
@@ -707,6 +880,17 @@ impl QemuBuilder {
         // VNC, this connection survives guest reboots because QEMU itself is
         // the socket server.
         vnc.serial = Some(SerialConnection::connect(&self.serial_socket)?);
+        if let (Some(observer), Some(serial)) = (&self.observer, &vnc.serial) {
+            observer.set_serial(serial.clone());
+        }
+        vnc.observer = self.observer;
+
+        vnc.wait_timeout = self.wait_timeout;
+        vnc.build_deadline = self.build_deadline;
+        vnc.failure_dir = self.failure_dir;
+        vnc.qemu_cmdline = std::iter::once(program.to_string())
+            .chain(cmdline)
+            .collect();
 
         Ok(QemuProcess {
             arch: self.arch,
@@ -714,7 +898,9 @@ impl QemuBuilder {
             tpm_process,
             ssh_port: self.ssh_port,
             private_key: self.ssh_private_key,
-            host_key: self.ssh_host_key,
+            host_key: self
+                .pin_host_key
+                .then(|| self.ssh_host_key.with_extension("pub")),
             vnc,
             os_category: self.os_category,
             qmp_socket: self.qmp_socket,
@@ -0,0 +1,275 @@
+//! Continuous recording of the VM framebuffer for `--record=video`.
+//!
+//! Frames are captured whenever the VNC automation looks at the screen (and
+//! periodically during plain waits), stamped with the boot command that was
+//! running, and written out as a numbered PNG sequence with a tab separated
+//! index. When the recording ends, the sequence is assembled into a single
+//! animated PNG that plays back in real time.
+
+use crate::builder::vnc::VncScreenshot;
+use anyhow::{Result, bail};
+use font8x8::{BASIC_FONTS, UnicodeFonts};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Minimum time between two recorded frames.
+const FRAME_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Height of the caption band drawn over the top of each frame.
+const CAPTION_HEIGHT: usize = 12;
+
+/// What `--record` saves.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordMode {
+    /// A screenshot after each boot command
+    Screenshots,
+
+    /// A continuous recording of the whole build as an animated PNG
+    Video,
+}
+
+/// Writes a frame sequence into a directory and turns it into an animated
+/// PNG when dropped.
+pub struct Recorder {
+    dir: PathBuf,
+    index: BufWriter<File>,
+    start: Instant,
+    last_frame: Option<Instant>,
+    last_hash: Option<String>,
+    frame_count: usize,
+}
+
+impl Recorder {
+    pub fn new(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        info!(dir = ?dir, "Recording the build");
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            index: BufWriter::new(File::create(dir.join("index.tsv"))?),
+            start: Instant::now(),
+            last_frame: None,
+            last_hash: None,
+            frame_count: 0,
+        })
+    }
+
+    /// Record a frame captioned with `caption`, unless one was recorded very
+    /// recently or nothing has changed since the last.
+    pub fn capture(&mut self, screenshot: &VncScreenshot, caption: &str) -> Result<()> {
+        if self
+            .last_frame
+            .is_some_and(|last| last.elapsed() < FRAME_INTERVAL)
+        {
+            return Ok(());
+        }
+
+        let mut frame = VncScreenshot {
+            data: screenshot.data.clone(),
+            width: screenshot.width,
+            height: screenshot.height,
+        };
+        draw_caption(&mut frame, caption);
+
+        // An unchanged frame just extends the previous one on playback
+        let hash = frame.hash();
+        if self.last_hash.as_ref() == Some(&hash) {
+            return Ok(());
+        }
+
+        frame.write_png(&self.dir.join(format!("{:06}.png", self.frame_count)))?;
+        writeln!(
+            self.index,
+            "{}\t{}\t{}",
+            self.frame_count,
+            self.start.elapsed().as_millis(),
+            caption.replace(['\t', '\n'], " ")
+        )?;
+
+        self.frame_count += 1;
+        self.last_frame = Some(Instant::now());
+        self.last_hash = Some(hash);
+        Ok(())
+    }
+
+    /// Assemble the recorded frames into `recording.png` and return its path.
+    pub fn finish(&mut self) -> Result<PathBuf> {
+        self.index.flush()?;
+        if self.frame_count == 0 {
+            bail!("No frames were recorded");
+        }
+
+        // Each frame is shown until the next one was captured
+        let mut timestamps = Vec::with_capacity(self.frame_count);
+        for line in BufReader::new(File::open(self.dir.join("index.tsv"))?).lines() {
+            let line = line?;
+            let mut fields = line.split('\t');
+            match (fields.next(), fields.next()) {
+                (Some(_), Some(millis)) => timestamps.push(millis.parse::<u64>()?),
+                _ => bail!("Malformed recording index line: {line}"),
+            }
+        }
+        timestamps.push(self.start.elapsed().as_millis() as u64);
+
+        let frames = (0..self.frame_count)
+            .map(|i| self.dir.join(format!("{i:06}.png")))
+            .collect::<Vec<_>>();
+
+        // The framebuffer can change resolution during the build, so every
+        // frame is placed on a canvas big enough for the largest one
+        let (mut width, mut height) = (0, 0);
+        for frame in &frames {
+            let decoder = png::Decoder::new(BufReader::new(File::open(frame)?));
+            let info = decoder.read_info()?;
+            width = width.max(info.info().width);
+            height = height.max(info.info().height);
+        }
+
+        let output = self.dir.join("recording.png");
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(&output)?), width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_animated(frames.len() as u32, 0)?;
+        let mut writer = encoder.write_header()?;
+
+        for (i, frame) in frames.iter().enumerate() {
+            let screenshot = VncScreenshot::read_png(frame)?;
+            let delay = timestamps[i + 1].saturating_sub(timestamps[i]).max(1);
+            writer.set_frame_delay(delay.min(u16::MAX as u64) as u16, 1000)?;
+            writer.write_image_data(&pad(&screenshot, width as usize, height as usize))?;
+        }
+        writer.finish()?;
+
+        info!(path = ?output, frames = frames.len(), "Saved build recording");
+        Ok(output)
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if self.frame_count == 0 {
+            return;
+        }
+        if let Err(e) = self.finish() {
+            warn!(error = ?e, "Failed to assemble the build recording");
+        }
+    }
+}
+
+/// Packed RGB of `screenshot` in the top left of a black `width` x `height`
+/// canvas.
+fn pad(screenshot: &VncScreenshot, width: usize, height: usize) -> Vec<u8> {
+    let rgb = screenshot.to_rgb();
+    let row = screenshot.width as usize * 3;
+    let mut canvas = vec![0u8; width * height * 3];
+    for (y, src) in rgb.chunks_exact(row).enumerate().take(height) {
+        let dst = y * width * 3;
+        canvas[dst..dst + row].copy_from_slice(src);
+    }
+    canvas
+}
+
+/// Draw `caption` in white on a black band across the top of the frame.
+/// Characters without a glyph are drawn as `?` and anything past the right
+/// edge is cut off.
+fn draw_caption(frame: &mut VncScreenshot, caption: &str) {
+    let width = frame.width as usize;
+    let band = CAPTION_HEIGHT.min(frame.height as usize);
+    for pixel in frame.data[..width * band * 4].chunks_exact_mut(4) {
+        pixel.copy_from_slice(&[0, 0, 0, 0]);
+    }
+
+    for (i, ch) in caption
+        .chars()
+        .take(width.saturating_sub(4) / 8)
+        .enumerate()
+    {
+        let Some(glyph) = BASIC_FONTS.get(ch).or_else(|| BASIC_FONTS.get('?')) else {
+            continue;
+        };
+        for (row, bits) in glyph.iter().enumerate() {
+            let y = 2 + row;
+            if y >= band {
+                break;
+            }
+            for bit in 0..8 {
+                if bits & (1 << bit) != 0 {
+                    let offset = (y * width + 2 + i * 8 + bit) * 4;
+                    frame.data[offset..offset + 4].copy_from_slice(&[255, 255, 255, 0]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(width: u16, height: u16, value: u8) -> VncScreenshot {
+        VncScreenshot {
+            data: vec![value; width as usize * height as usize * 4],
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn caption_is_drawn_in_the_band() {
+        let mut frame = solid(64, 32, 0x80);
+        draw_caption(&mut frame, "1: Enter");
+
+        let band = &frame.data[..64 * CAPTION_HEIGHT * 4];
+        assert!(band.chunks_exact(4).any(|p| p[..3] == [255, 255, 255]));
+        assert!(band.chunks_exact(4).any(|p| p[..3] == [0, 0, 0]));
+        assert!(
+            frame.data[64 * CAPTION_HEIGHT * 4..]
+                .iter()
+                .all(|&v| v == 0x80)
+        );
+    }
+
+    #[test]
+    fn assembles_an_animated_png() {
+        let dir = std::env::temp_dir().join(format!("goldboot-recording-{}", std::process::id()));
+        {
+            let mut recorder = Recorder::new(&dir).unwrap();
+            recorder
+                .capture(&solid(64, 48, 0x10), "1: Wait(5)")
+                .unwrap();
+
+            // Too soon after the last frame
+            recorder
+                .capture(&solid(64, 48, 0x20), "1: Wait(5)")
+                .unwrap();
+            assert_eq!(recorder.frame_count, 1);
+
+            std::thread::sleep(FRAME_INTERVAL);
+            recorder.capture(&solid(80, 60, 0x30), "2: Enter").unwrap();
+
+            // Nothing changed
+            std::thread::sleep(FRAME_INTERVAL);
+            recorder.capture(&solid(80, 60, 0x30), "2: Enter").unwrap();
+            assert_eq!(recorder.frame_count, 2);
+        }
+
+        let index = std::fs::read_to_string(dir.join("index.tsv")).unwrap();
+        assert_eq!(index.lines().count(), 2);
+        assert!(index.lines().nth(1).unwrap().ends_with("\t2: Enter"));
+
+        let decoder = png::Decoder::new(BufReader::new(
+            File::open(dir.join("recording.png")).unwrap(),
+        ));
+        let reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        assert_eq!((info.width, info.height), (80, 60));
+        assert_eq!(info.animation_control.as_ref().unwrap().num_frames, 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
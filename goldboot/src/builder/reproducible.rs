@@ -0,0 +1,138 @@
+//! Comparison of two builds of the same image for
+//! `goldboot build --verify-reproducible`.
+
+use anyhow::{Result, bail};
+use goldboot_image::{DigestTableEntry, ImageHandle, qcow::Qcow3};
+use std::{collections::BTreeMap, fmt::Display};
+
+use crate::gpt::{PartitionEntry, read_gpt};
+
+const SECTOR: u64 = 512;
+
+/// Where the blocks of two builds differ.
+pub struct Report {
+    first: String,
+    second: String,
+    block_size: u64,
+
+    /// Offsets of the differing blocks by the partition they start in, with
+    /// `None` for blocks outside every partition (e.g. the partition table)
+    differences: BTreeMap<Option<(u32, String)>, Vec<u64>>,
+}
+
+impl Report {
+    pub fn is_reproducible(&self) -> bool {
+        self.first == self.second
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_reproducible() {
+            return writeln!(f, "Both builds have content ID {}", self.first);
+        }
+
+        writeln!(f, "Content IDs differ: {} != {}", self.first, self.second)?;
+        if self.differences.is_empty() {
+            // Same blocks, but the clusters were compressed or encrypted
+            // differently
+            return writeln!(
+                f,
+                "    every block matches; only the cluster encoding differs"
+            );
+        }
+        for (partition, blocks) in &self.differences {
+            let location = match partition {
+                Some((index, name)) if name.is_empty() => format!("partition {index}"),
+                Some((index, name)) => format!("partition {index} ({name})"),
+                None => "outside partitions".to_string(),
+            };
+            writeln!(
+                f,
+                "    {location}: {} of {} byte blocks differ, first at offset {:#x}",
+                blocks.len(),
+                self.block_size,
+                blocks[0]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare two images converted from builds of the same configuration.
+/// `disk` is the second build's disk, whose partition table locates the
+/// differing blocks.
+pub fn compare(first: &ImageHandle, second: &ImageHandle, disk: &Qcow3) -> Result<Report> {
+    let (Some(first_table), Some(second_table), Some(protected_header)) = (
+        &first.digest_table,
+        &second.digest_table,
+        &second.protected_header,
+    ) else {
+        bail!("Image not loaded");
+    };
+
+    let partitions: Vec<PartitionEntry> = read_gpt(&mut disk.reader()?)?
+        .map(|gpt| gpt.entries.into_iter().filter(|e| e.is_used()).collect())
+        .unwrap_or_default();
+
+    let mut differences: BTreeMap<_, Vec<u64>> = BTreeMap::new();
+    for offset in differing_blocks(&first_table.digest_table, &second_table.digest_table) {
+        let partition = partitions
+            .iter()
+            .find(|entry| {
+                (entry.first_lba * SECTOR..(entry.last_lba + 1) * SECTOR).contains(&offset)
+            })
+            .map(|entry| (entry.index, entry.name.clone()));
+        differences.entry(partition).or_default().push(offset);
+    }
+
+    Ok(Report {
+        first: first.primary_header.content_id_hex(),
+        second: second.primary_header.content_id_hex(),
+        block_size: protected_header.block_size as u64,
+        differences,
+    })
+}
+
+/// The offsets of the blocks whose digests differ, including blocks that are
+/// zero (and so have no entry) in only one of the tables.
+fn differing_blocks(first: &[DigestTableEntry], second: &[DigestTableEntry]) -> Vec<u64> {
+    let digests = |table: &[DigestTableEntry]| -> BTreeMap<u64, [u8; 32]> {
+        table
+            .iter()
+            .map(|entry| (entry.block_offset, entry.digest))
+            .collect()
+    };
+    let (first, second) = (digests(first), digests(second));
+
+    let mut offsets: Vec<u64> = first
+        .keys()
+        .chain(second.keys())
+        .copied()
+        .filter(|offset| first.get(offset) != second.get(offset))
+        .collect();
+    offsets.sort_unstable();
+    offsets.dedup();
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(block_offset: u64, digest: u8) -> DigestTableEntry {
+        DigestTableEntry {
+            cluster_offset: 0,
+            block_offset,
+            digest: [digest; 32],
+        }
+    }
+
+    #[test]
+    fn find_differing_blocks() {
+        let first = [entry(0, 1), entry(4096, 2), entry(8192, 3)];
+        let second = [entry(0, 1), entry(4096, 9), entry(12288, 4)];
+        assert_eq!(differing_blocks(&first, &second), vec![4096, 8192, 12288]);
+        assert!(differing_blocks(&first, &first).is_empty());
+    }
+}
@@ -3,22 +3,29 @@
 //! builders should prefer it whenever the guest can direct output to the
 //! serial port.
 
-use anyhow::{Result, bail};
+use crate::builder::vnc::WaitTimedOut;
+use anyhow::Result;
 use std::{
     io::Read,
     os::unix::net::UnixStream,
     path::Path,
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tracing::{debug, trace};
 
+/// How much recent output is retained for failure diagnostics.
+const HISTORY_LEN: usize = 64 * 1024;
+
 /// Represents a connection to the serial console of a running VM. A background
 /// thread continuously drains the socket into a buffer so guest output isn't
 /// dropped while nobody is waiting on it.
 #[derive(Clone)]
 pub struct SerialConnection {
     buffer: Arc<Mutex<String>>,
+
+    /// Recent output, unlike `buffer` never consumed by waits.
+    history: Arc<Mutex<String>>,
 }
 
 impl SerialConnection {
@@ -27,8 +34,10 @@ impl SerialConnection {
         let mut stream = UnixStream::connect(socket_path)?;
 
         let buffer = Arc::new(Mutex::new(String::new()));
+        let history = Arc::new(Mutex::new(String::new()));
         {
             let buffer = buffer.clone();
+            let history = history.clone();
 
             // The thread exits once QEMU stops and closes the socket
             std::thread::spawn(move || {
@@ -40,19 +49,33 @@ impl SerialConnection {
                             let text = String::from_utf8_lossy(&chunk[..n]);
                             trace!("Serial console output: {}", &text);
                             buffer.lock().unwrap().push_str(&text);
+
+                            let mut history = history.lock().unwrap();
+                            history.push_str(&text);
+                            if history.len() > HISTORY_LEN * 2 {
+                                let start = tail_start(&history, HISTORY_LEN);
+                                history.drain(..start);
+                            }
                         }
                     }
                 }
             });
         }
 
-        Ok(Self { buffer })
+        Ok(Self { buffer, history })
+    }
+
+    /// The last `max` bytes (or fewer) of console output.
+    pub fn tail(&self, max: usize) -> String {
+        let history = self.history.lock().unwrap();
+        history[tail_start(&history, max)..].to_string()
     }
 
     /// Wait for text matching the given regex to appear on the serial console.
     /// The buffer is consumed through the end of the match so subsequent waits
-    /// don't re-match old output.
-    pub fn wait_for_match(&self, pattern: &str) -> Result<()> {
+    /// don't re-match old output. Returns `false` if `deadline` passes first,
+    /// and fails with [`WaitTimedOut::Stalled`] if the guest goes quiet.
+    pub fn wait_for_match(&self, pattern: &str, deadline: Instant) -> Result<bool> {
         let re = regex::Regex::new(pattern)?;
         debug!("Waiting for serial console text matching: {}", pattern);
 
@@ -69,21 +92,22 @@ impl SerialConnection {
                     debug!(total_count, "Finished serial text wait");
                     let end = m.end();
                     buffer.drain(..end);
-                    break;
+                    return Ok(true);
+                }
+
+                if Instant::now() >= deadline {
+                    return Ok(false);
                 }
 
                 if buffer.len() == running_len {
                     // TODO configurable
                     if running_count > 1200 {
                         // Include the most recent output to aid debugging
-                        let mut start = buffer.len().saturating_sub(512);
-                        while !buffer.is_char_boundary(start) {
-                            start += 1;
-                        }
-                        bail!(
-                            "No serial console output in 600 sec; buffer tail: {:?}",
-                            &buffer[start..]
-                        );
+                        return Err(WaitTimedOut::Stalled(format!(
+                            "there was no serial console output in 600 sec; buffer tail: {:?}",
+                            &buffer[tail_start(&buffer, 512)..]
+                        ))
+                        .into());
                     }
                     running_count += 1;
                 } else {
@@ -95,6 +119,14 @@ impl SerialConnection {
             total_count += 1;
             std::thread::sleep(Duration::from_millis(500));
         }
-        Ok(())
     }
 }
+
+/// Index of the first char boundary at most `max` bytes from the end.
+fn tail_start(text: &str, max: usize) -> usize {
+    let mut start = text.len().saturating_sub(max);
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    start
+}
@@ -1,17 +1,40 @@
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
 use goldboot_image::ImageArch;
 use rand::RngExt;
+use russh::{
+    ChannelMsg,
+    client::{self, Handle},
+    keys::{PrivateKeyWithHashAlg, PublicKey, load_secret_key},
+};
+use russh_sftp::{client::SftpSession, protocol::FileAttributes};
 use ssh_key::{Algorithm, LineEnding, PrivateKey};
-use ssh2::Session;
 use std::{
-    io::{BufRead, BufReader, Cursor, Read},
-    net::TcpStream,
     path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex},
     time::Duration,
 };
-use tracing::{debug, info};
+use tokio::{io::AsyncWriteExt, runtime::Runtime};
+use tracing::{debug, info, warn};
+
+use super::{
+    qemu::OsCategory,
+    steps::{Transport, copy},
+};
+
+/// Where guests keep the ed25519 host key that goldboot pins.
+pub const GUEST_HOST_KEY_PATH: &str = "/etc/ssh/ssh_host_ed25519_key";
 
-use super::qemu::OsCategory;
+/// The generated host key, in OpenSSH format, for elements that install it
+/// into the guest themselves.
+pub struct HostKey {
+    pub private: String,
+    pub public: String,
+}
+
+/// Name the VM's host key is recorded under in the known_hosts file given to
+/// ansible.
+const ANSIBLE_HOST_KEY_ALIAS: &str = "goldboot";
 
 /// Generate a new random SSH keypair
 pub fn generate_key(directory: &Path) -> Result<PathBuf> {
@@ -21,45 +44,142 @@ pub fn generate_key(directory: &Path) -> Result<PathBuf> {
         .map(char::from)
         .collect();
     let key_path = directory.join(key_name);
+    write_key(&key_path)?;
+    Ok(key_path)
+}
 
+/// Reuse the SSH keypair at `key_path`, generating it on first use. Keys that
+/// live next to the working qcow2 survive between runs, so a VM restored from
+/// a checkpoint still trusts them.
+pub fn load_or_generate_key(key_path: &Path) -> Result<PathBuf> {
+    if !key_path.exists() {
+        if let Some(parent) = key_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        write_key(key_path)?;
+    }
+    Ok(key_path.to_path_buf())
+}
+
+fn write_key(key_path: &Path) -> Result<()> {
     let private_key = PrivateKey::random(&mut ssh_key::rand_core::OsRng, Algorithm::Ed25519)?;
-    std::fs::write(&key_path, private_key.to_openssh(LineEnding::LF)?)?;
+    std::fs::write(key_path, private_key.to_openssh(LineEnding::LF)?)?;
     std::fs::write(
         key_path.with_extension("pub"),
         private_key.public_key().to_openssh()?,
     )?;
+    Ok(())
+}
 
-    Ok(key_path)
+/// Get the goldboot-sshd executable for a guest with the given architecture
+/// and OS.
+///
+/// The executable is taken from `$GOLDBOOT_SSHD` if set, then from the copy
+/// embedded with the `include_sshd` feature, then from the cache. Otherwise
+/// (in development builds) it's built from the goldboot-sshd crate next to
+/// this one, which requires the guest's Rust target to be installed.
+pub fn guest_sshd(arch: ImageArch, os_category: OsCategory) -> Result<Vec<u8>> {
+    if let Ok(path) = std::env::var("GOLDBOOT_SSHD") {
+        debug!(path, "Using goldboot-sshd from GOLDBOOT_SSHD");
+        return std::fs::read(&path).with_context(|| format!("reading {path}"));
+    }
+
+    let target = match (os_category, arch) {
+        (OsCategory::Linux, ImageArch::Amd64) => "x86_64-unknown-linux-musl",
+        (OsCategory::Linux, ImageArch::Arm64) => "aarch64-unknown-linux-musl",
+        (OsCategory::Windows, ImageArch::Amd64) => "x86_64-pc-windows-gnu",
+        _ => bail!("goldboot-sshd is not available for {os_category} on {arch}"),
+    };
+    let executable = match os_category {
+        OsCategory::Windows => "goldboot-sshd.exe",
+        _ => "goldboot-sshd",
+    };
+
+    #[cfg(feature = "include_sshd")]
+    if let Some(sshd) = embedded_sshd(target) {
+        debug!(target, "Using embedded goldboot-sshd");
+        return Ok(sshd.to_vec());
+    }
+
+    let cache = crate::library::cache_dir().with_file_name("sshd");
+    let cached = cache.join(format!("{target}-{executable}"));
+    if cached.exists() {
+        debug!(path = %cached.display(), "Using cached goldboot-sshd");
+        return Ok(std::fs::read(cached)?);
+    }
+
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("../goldboot-sshd");
+    if !source.exists() {
+        bail!(
+            "goldboot-sshd for {target} was not found; build it and set GOLDBOOT_SSHD to its path"
+        );
+    }
+
+    info!(target, "Building goldboot-sshd");
+    let target_dir = cache.join("target");
+    let status = Command::new("cargo")
+        .args([
+            "build",
+            "--release",
+            "-p",
+            "goldboot-sshd",
+            "--target",
+            target,
+        ])
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .current_dir(&source)
+        .status()
+        .context("running cargo")?;
+    if !status.success() {
+        bail!("Failed to build goldboot-sshd for {target} (is the target installed?)");
+    }
+
+    std::fs::create_dir_all(&cache)?;
+    std::fs::copy(
+        target_dir.join(target).join("release").join(executable),
+        &cached,
+    )?;
+    Ok(std::fs::read(cached)?)
+}
+
+#[cfg(feature = "include_sshd")]
+include!(concat!(env!("OUT_DIR"), "/guest_sshd.rs"));
+
+/// Verifies the VM's host key. A key that wasn't pinned when connecting is
+/// trusted on first use and then pinned for any reconnects.
+struct Client {
+    host_key: Arc<Mutex<Option<PublicKey>>>,
 }
 
-/// Download and extract sshdog.
-pub fn download_sshdog(arch: ImageArch, os_category: OsCategory) -> Result<Vec<u8>> {
-    // TODO embed binaries?
-    let url = format!(
-        "https://github.com/fossable/sshdog/releases/download/v0.2.1/sshdog_0.2.1_{}_{}.tar.gz",
-        os_category, arch,
-    )
-    .to_lowercase();
-
-    debug!(url, "Downloading sshdog");
-    let response = reqwest::blocking::get(url)?;
-    if !response.status().is_success() {
-        bail!("Failed to download");
-    }
-    let uncompressed = flate2::read::GzDecoder::new(response);
-    let mut archive = tar::Archive::new(uncompressed);
-
-    for entry in archive.entries()? {
-        let mut entry = entry?;
-
-        if entry.path()?.file_stem().unwrap().to_string_lossy() == "sshdog" {
-            let mut content = Vec::new();
-            entry.read_to_end(&mut content)?;
-            return Ok(content);
+impl client::Handler for Client {
+    type Error = anyhow::Error;
+
+    async fn check_server_key(&mut self, server_key: &PublicKey) -> Result<bool> {
+        let mut host_key = self.host_key.lock().unwrap();
+        match host_key.as_ref() {
+            Some(host_key) if host_key.key_data() == server_key.key_data() => Ok(true),
+            Some(_) => {
+                warn!("SSH host key does not match the expected key");
+                Ok(false)
+            }
+            None => {
+                debug!(
+                    fingerprint = %server_key.fingerprint(Default::default()),
+                    "Trusting SSH host key on first use"
+                );
+                *host_key = Some(server_key.clone());
+                Ok(true)
+            }
         }
     }
+}
 
-    bail!("Executable not found in archive");
+/// What a command run over SSH left behind.
+struct Output {
+    /// `None` when the connection was lost before the command exited
+    exit: Option<u32>,
+    stdout: String,
 }
 
 /// Represents an SSH session to a running VM.
@@ -67,27 +187,39 @@ pub struct SshConnection {
     pub username: String,
     pub private_key: PathBuf,
     pub port: u16,
-    pub session: ssh2::Session,
     pub os: OsCategory,
+    handle: Handle<Client>,
+    host_key: Arc<Mutex<Option<PublicKey>>>,
+    runtime: Runtime,
 }
 
 impl SshConnection {
-    pub fn new(username: &str, private_key: &Path, port: u16) -> Result<SshConnection> {
+    /// Connect to the VM, verifying that it presents the host key at
+    /// `host_key` (an OpenSSH public key file) if given.
+    pub fn new(
+        username: &str,
+        private_key: &Path,
+        port: u16,
+        host_key: Option<&Path>,
+    ) -> Result<SshConnection> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        let host_key = Arc::new(Mutex::new(match host_key {
+            Some(path) => Some(PublicKey::from_openssh(
+                std::fs::read_to_string(path)?.trim(),
+            )?),
+            None => None,
+        }));
+
         let mut i = 0;
-        Ok(loop {
+        let handle = loop {
             i += 1;
             debug!("Trying SSH: {}@localhost:{}", username, port);
 
-            match Self::connect(username, private_key, port) {
-                Ok(session) => {
-                    break SshConnection {
-                        username: username.to_string(),
-                        private_key: private_key.to_path_buf(),
-                        port,
-                        os: query_os(&session)?,
-                        session,
-                    };
-                }
+            match runtime.block_on(Self::connect(username, private_key, port, &host_key)) {
+                Ok(handle) => break handle,
                 Err(error) => debug!("{}", error),
             };
 
@@ -96,17 +228,43 @@ impl SshConnection {
             }
 
             std::thread::sleep(Duration::from_secs(5));
-        })
-    }
+        };
 
-    fn connect(username: &str, private_key: &Path, port: u16) -> Result<Session> {
-        let mut session = ssh2::Session::new()?;
-        session.set_tcp_stream(TcpStream::connect(format!("127.0.0.1:{port}"))?);
+        let mut ssh = SshConnection {
+            username: username.to_string(),
+            private_key: private_key.to_path_buf(),
+            port,
+            // Replaced below
+            os: OsCategory::Linux,
+            handle,
+            host_key,
+            runtime,
+        };
+        ssh.os = ssh.query_os()?;
+        debug!(os = %ssh.os, "Established SSH connection");
+        Ok(ssh)
+    }
 
-        session.handshake()?;
-        session.userauth_pubkey_file(username, None, private_key, None)?;
-        debug!("Established SSH connection");
-        Ok(session)
+    async fn connect(
+        username: &str,
+        private_key: &Path,
+        port: u16,
+        host_key: &Arc<Mutex<Option<PublicKey>>>,
+    ) -> Result<Handle<Client>> {
+        let config = Arc::new(client::Config::default());
+        let client = Client {
+            host_key: host_key.clone(),
+        };
+        let mut handle = client::connect(config, ("127.0.0.1", port), client).await?;
+
+        let key = load_secret_key(private_key, None)?;
+        let auth = handle
+            .authenticate_publickey(username, PrivateKeyWithHashAlg::new(Arc::new(key), None))
+            .await?;
+        if !auth.success() {
+            bail!("SSH public key authentication failed");
+        }
+        Ok(handle)
     }
 
     /// Send the shutdown command to the VM.
@@ -114,9 +272,14 @@ impl SshConnection {
         self.wipe_free()?;
 
         debug!("Sending shutdown command");
-        let mut channel = self.session.channel_session()?;
-        channel.exec(command)?;
-        Ok(())
+        self.runtime.block_on(async {
+            let mut channel = self.handle.channel_open_session().await?;
+            channel.exec(true, command).await?;
+
+            // The connection goes away with the VM, so don't wait for long
+            let _ = tokio::time::timeout(Duration::from_secs(5), channel.wait()).await;
+            Ok(())
+        })
     }
 
     /// Wipe free space which reduces final image size.
@@ -134,93 +297,303 @@ impl SshConnection {
     }
 
     pub fn upload_exec(&mut self, source: &[u8], env: Vec<(&str, &str)>) -> Result<i32> {
+        let path = match self.os {
+            OsCategory::Windows => self.temp_path(".exe"),
+            _ => self.temp_path(""),
+        };
+
+        self.upload(source, &path, 0o700)?;
+        let exit = self.exec_env(&path, env)?;
+
+        // Attempt to cleanup, but don't fail if we can't
+        self.remove(&path);
+        Ok(exit)
+    }
+
+    /// Upload a PowerShell script, run it and delete it.
+    pub fn powershell(&mut self, script: &str) -> Result<i32> {
+        let path = self.temp_path(".ps1");
+        self.upload(script.as_bytes(), &path, 0o600)?;
+        let exit = match self.os {
+            OsCategory::Windows => self.exec(&format!(
+                "powershell -NoProfile -NonInteractive -ExecutionPolicy Bypass -File {path}"
+            ))?,
+            _ => self.exec(&format!("pwsh -NoProfile -NonInteractive -File {path}"))?,
+        };
+
+        self.remove(&path);
+        Ok(exit)
+    }
+
+    /// A path for a temporary file on the VM.
+    fn temp_path(&self, extension: &str) -> String {
         let id: String = rand::rng()
             .sample_iter(&rand::distr::Alphanumeric)
             .take(12)
             .map(char::from)
             .collect();
-        let path = format!("/tmp/gb_{id}");
+        match self.os {
+            OsCategory::Windows => format!("C:\\Windows\\Temp\\gb_{id}{extension}"),
+            _ => format!("/tmp/gb_{id}{extension}"),
+        }
+    }
 
-        self.upload(source, &path)?;
-        let exit = self.exec_env(&path, env)?;
+    /// Delete a file on the VM, ignoring failures.
+    fn remove(&mut self, path: &str) {
+        _ = match self.os {
+            OsCategory::Windows => self.exec(&format!("del /f {path}")),
+            _ => self.exec(&format!("rm -f {path}")),
+        };
+    }
 
-        // Attempt to cleanup, but don't fail if we can't
-        _ = self.exec(&format!("rm -f {path}"));
-        Ok(exit)
+    /// Write `source` to `dest` on the VM with the given permissions (ignored
+    /// for Windows).
+    pub fn upload(&mut self, source: &[u8], dest: &str, mode: u32) -> Result<()> {
+        debug!(bytes = source.len(), dest, "Uploading file");
+        let cmdline = match self.os {
+            OsCategory::Windows => format!(
+                "powershell -NoProfile -Command \"$f = [IO.File]::Create('{dest}'); [Console]::OpenStandardInput().CopyTo($f); $f.Close()\""
+            ),
+            _ => format!("sh -c 'cat > \"{dest}\" && chmod {mode:o} \"{dest}\"'"),
+        };
+
+        match self.run(&cmdline, Vec::new(), source)?.exit {
+            Some(0) => Ok(()),
+            Some(exit) => bail!("Upload to {dest} failed with exit code {exit}"),
+            None => bail!("SSH disconnected during upload to {dest}"),
+        }
     }
 
-    pub fn upload(&self, source: &[u8], dest: &str) -> Result<()> {
-        debug!(bytes = source.len(), dest, "Uploading file with scp");
-        let mut channel =
-            self.session
-                .scp_send(Path::new(dest), 0o700, source.len().try_into()?, None)?;
-        std::io::copy(&mut Cursor::new(source), &mut channel)?;
+    /// Copy the directory `source` to `dest` on the VM over SFTP, keeping the
+    /// permissions of its files.
+    pub fn upload_dir(&mut self, source: &Path, dest: &str) -> Result<()> {
+        debug!(source = %source.display(), dest, "Uploading directory over SFTP");
+        let separator = match self.os {
+            OsCategory::Windows => '\\',
+            _ => '/',
+        };
+        let paths = copy::walk(source)?;
+
+        self.runtime.block_on(async {
+            let channel = self.handle.channel_open_session().await?;
+            channel.request_subsystem(true, "sftp").await?;
+            let sftp = SftpSession::new(channel.into_stream()).await?;
+
+            // The directory itself, then everything in it
+            for relative in std::iter::once(PathBuf::new()).chain(paths) {
+                let path = source.join(&relative);
+                let remote = copy::remote_path(dest, &relative, separator);
+                let metadata = std::fs::metadata(&path)?;
+
+                if metadata.is_dir() {
+                    if !sftp.try_exists(&remote).await? {
+                        sftp.create_dir(&remote).await?;
+                    }
+                } else {
+                    let mut file = sftp.create(&remote).await?;
+                    file.write_all(&std::fs::read(&path)?).await?;
+                    file.shutdown().await?;
+                }
 
-        channel.send_eof()?;
-        channel.wait_eof()?;
-        channel.close()?;
-        channel.wait_close()?;
+                sftp.set_metadata(
+                    &remote,
+                    FileAttributes {
+                        permissions: Some(copy::file_mode(&metadata)),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            }
 
-        Ok(())
+            sftp.close().await?;
+            Ok(())
+        })
     }
 
     /// Run a command on the VM with the given environment.
     pub fn exec_env(&mut self, cmdline: &str, env: Vec<(&str, &str)>) -> Result<i32> {
-        debug!(cmdline, environment = ?env, "Executing command over ssh");
+        match self.run(cmdline, env, &[])?.exit {
+            Some(exit) => {
+                debug!("Exit code: {}", exit);
+                Ok(exit as i32)
+            }
+            None => {
+                // The VM is probably rebooting, wait for SSH to come back up
+                info!("SSH disconnected; waiting for it to come back");
+                std::thread::sleep(Duration::from_secs(10));
+                for _ in 0..5 {
+                    match self.runtime.block_on(Self::connect(
+                        &self.username,
+                        &self.private_key,
+                        self.port,
+                        &self.host_key,
+                    )) {
+                        Ok(handle) => {
+                            self.handle = handle;
+                            return Ok(0);
+                        }
+                        Err(_) => std::thread::sleep(Duration::from_secs(50)),
+                    }
+                }
+                bail!("SSH did not come back in a reasonable amount of time");
+            }
+        }
+    }
 
-        let mut channel = self.session.channel_session()?;
+    /// Run a command on the VM.
+    pub fn exec(&mut self, cmdline: &str) -> Result<i32> {
+        self.exec_env(cmdline, Vec::new())
+    }
 
-        // Set environment
-        for (var, val) in env {
-            channel.setenv(var, val)?;
+    /// Delete the pinned host key from the VM so every image made from it
+    /// doesn't share one; sshd generates a fresh key on the next boot.
+    pub fn remove_host_key(&mut self) -> Result<()> {
+        if self.exec(&format!(
+            "rm -f {GUEST_HOST_KEY_PATH} {GUEST_HOST_KEY_PATH}.pub"
+        ))? != 0
+        {
+            bail!("Failed to remove the SSH host key");
         }
+        Ok(())
+    }
 
-        channel.exec(cmdline)?;
-
-        let mut stdout = BufReader::new(channel.stderr());
-
-        loop {
-            let mut line = String::new();
-            match stdout.read_line(&mut line) {
-                Ok(0) => break,
-                // TODO part of some span like goldboot::builder::steps::exe
-                Ok(_) => debug!(
-                    "(step) {}",
-                    line.strip_suffix("\r\n")
-                        .or(line.strip_suffix("\n"))
-                        .unwrap_or(&line)
-                ),
-                Err(_) => {
-                    // The VM is probably rebooting, wait for SSH to come back up
-                    info!("SSH disconnected; waiting for it to come back");
-                    std::thread::sleep(Duration::from_secs(10));
-                    for _ in 0..5 {
-                        match Self::connect(&self.username, &self.private_key, self.port) {
-                            Ok(session) => {
-                                self.session = session;
-                                return Ok(0);
-                            }
-                            Err(_) => std::thread::sleep(Duration::from_secs(50)),
-                        }
+    /// Run a command, feeding it `stdin` and logging its output.
+    fn run(&mut self, cmdline: &str, env: Vec<(&str, &str)>, stdin: &[u8]) -> Result<Output> {
+        debug!(cmdline, environment = ?env, "Executing command over ssh");
+
+        self.runtime.block_on(async {
+            let mut channel = self.handle.channel_open_session().await?;
+            for (var, val) in env {
+                channel.set_env(false, var, val).await?;
+            }
+            channel.exec(true, cmdline).await?;
+            if !stdin.is_empty() {
+                channel.data(stdin).await?;
+            }
+            channel.eof().await?;
+
+            let mut output = Output {
+                exit: None,
+                stdout: String::new(),
+            };
+            let mut stdout = Vec::new();
+            let mut stderr = Vec::new();
+            while let Some(message) = channel.wait().await {
+                match message {
+                    ChannelMsg::Data { data } => {
+                        output.stdout.push_str(&String::from_utf8_lossy(&data));
+                        log_lines(&mut stdout, &data);
                     }
-                    bail!("SSH did not come back in a reasonable amount of time");
+                    ChannelMsg::ExtendedData { data, .. } => log_lines(&mut stderr, &data),
+                    ChannelMsg::ExitStatus { exit_status } => output.exit = Some(exit_status),
+                    _ => {}
                 }
             }
+            log_lines(&mut stdout, b"\n");
+            log_lines(&mut stderr, b"\n");
+            Ok(output)
+        })
+    }
+
+    /// Figure out what kind of OS we're connected to.
+    fn query_os(&mut self) -> Result<OsCategory> {
+        if let Ok(Output {
+            exit: Some(0),
+            stdout,
+        }) = self.run("uname -s", Vec::new(), &[])
+        {
+            match stdout.trim() {
+                "Linux" => return Ok(OsCategory::Linux),
+                "Darwin" => return Ok(OsCategory::Darwin),
+                _ => {}
+            }
         }
 
-        channel.wait_close()?;
-        let exit = channel.exit_status()?;
-        debug!("Exit code: {}", exit);
-        Ok(exit)
+        // Windows shells have no uname
+        if let Ok(Output {
+            exit: Some(0),
+            stdout,
+        }) = self.run("cmd /C ver", Vec::new(), &[])
+        {
+            if stdout.contains("Windows") {
+                return Ok(OsCategory::Windows);
+            }
+        }
+
+        bail!("Failed to determine the OS of the VM");
     }
+}
 
-    /// Run a command on the VM.
-    pub fn exec(&mut self, cmdline: &str) -> Result<i32> {
-        self.exec_env(cmdline, Vec::new())
+impl Transport for SshConnection {
+    fn os(&self) -> OsCategory {
+        self.os
+    }
+
+    fn exec(&mut self, command: &str) -> Result<i32> {
+        SshConnection::exec(self, command)
+    }
+
+    fn upload(&mut self, source: &[u8], dest: &str, mode: u32) -> Result<()> {
+        SshConnection::upload(self, source, dest, mode)
+    }
+
+    fn upload_dir(&mut self, source: &Path, dest: &str) -> Result<()> {
+        SshConnection::upload_dir(self, source, dest)
+    }
+
+    fn upload_exec(&mut self, source: &[u8]) -> Result<i32> {
+        SshConnection::upload_exec(self, source, Vec::new())
+    }
+
+    fn powershell(&mut self, script: &str) -> Result<i32> {
+        SshConnection::powershell(self, script)
+    }
+
+    fn ansible_vars(&self, dir: &Path) -> Result<serde_json::Map<String, serde_json::Value>> {
+        // Ansible verifies the same host key as this connection did, under
+        // an alias so a custom inventory's host names don't matter
+        let Some(host_key) = self.host_key.lock().unwrap().clone() else {
+            bail!("The SSH host key is unknown");
+        };
+        let known_hosts = dir.join("known_hosts");
+        std::fs::write(
+            &known_hosts,
+            format!("{ANSIBLE_HOST_KEY_ALIAS} {}\n", host_key.to_openssh()?),
+        )?;
+
+        let mut vars = serde_json::Map::new();
+        vars.insert("ansible_connection".into(), "ssh".into());
+        vars.insert("ansible_port".into(), self.port.into());
+        vars.insert("ansible_user".into(), self.username.clone().into());
+        vars.insert(
+            "ansible_ssh_private_key_file".into(),
+            self.private_key.display().to_string().into(),
+        );
+        vars.insert(
+            "ansible_ssh_common_args".into(),
+            format!(
+                "-oHostKeyAlias={ANSIBLE_HOST_KEY_ALIAS} -oUserKnownHostsFile={} -oStrictHostKeyChecking=yes",
+                known_hosts.display()
+            )
+            .into(),
+        );
+        if let OsCategory::Windows = self.os {
+            vars.insert("ansible_shell_type".into(), "cmd".into());
+        }
+        Ok(vars)
     }
 }
 
-/// Figure out what kind of OS we're connected to
-fn query_os(_session: &ssh2::Session) -> Result<OsCategory> {
-    Ok(OsCategory::Linux)
+/// Log the complete lines in `buffer` after appending `data` to it.
+pub(crate) fn log_lines(buffer: &mut Vec<u8>, data: &[u8]) {
+    buffer.extend_from_slice(data);
+    while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+        let line: Vec<u8> = buffer.drain(..=end).collect();
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches(['\r', '\n']);
+        if !line.is_empty() {
+            // TODO part of some span like goldboot::builder::steps::exe
+            debug!("(step) {}", line);
+        }
+    }
 }
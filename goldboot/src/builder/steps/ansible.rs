@@ -1,19 +1,23 @@
 //! Shared `ansible-playbook` invocation for the ansible pre/post steps.
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 use std::process::Command;
 
 use anyhow::{Result, bail};
 use tracing::info;
 
-use crate::builder::ssh::SshConnection;
+use crate::builder::steps::Transport;
 
 /// How ansible reaches its target: `Local` runs the playbook on the host
-/// itself; `Ssh` runs it against the VM's forwarded SSH port.
+/// itself; `Remote` runs it against the VM through the transport's forwarded
+/// port, keeping the files it needs in the given private directory.
 pub enum Connection<'a> {
     Local,
-    Ssh(&'a SshConnection),
+    Remote(&'a dyn Transport, &'a Path),
 }
 
 /// Run `ansible-playbook` from `cwd` (the effective context directory), so
@@ -24,7 +28,7 @@ pub fn run_playbook(
     playbook: &str,
     inventory: Option<&str>,
     vars_files: &Option<Vec<String>>,
-    extra_vars: &Option<HashMap<String, String>>,
+    extra_vars: &Option<BTreeMap<String, String>>,
     connection: Connection<'_>,
 ) -> Result<()> {
     info!(%playbook, "Running ansible playbook");
@@ -45,7 +49,7 @@ fn playbook_command(
     playbook: &str,
     inventory: Option<&str>,
     vars_files: &Option<Vec<String>>,
-    extra_vars: &Option<HashMap<String, String>>,
+    extra_vars: &Option<BTreeMap<String, String>>,
     connection: Connection<'_>,
 ) -> Result<Command> {
     let mut cmd = Command::new("ansible-playbook");
@@ -55,24 +59,26 @@ fn playbook_command(
         Connection::Local => {
             cmd.arg("-i").arg("localhost,").arg("-c").arg("local");
         }
-        Connection::Ssh(ssh) => {
-            // Single-host inventory pointing at the VM's forwarded SSH port;
+        Connection::Remote(transport, dir) => {
+            // Single-host inventory pointing at the VM's forwarded port;
             // playbooks should target `hosts: all`.
-            cmd.arg("-i")
-                .arg(inventory.unwrap_or("127.0.0.1,"))
-                .arg("--ssh-common-args")
-                .arg("-oStrictHostKeyChecking=no");
+            cmd.arg("-i").arg(inventory.unwrap_or("127.0.0.1,"));
 
-            let mut vars = serde_json::Map::new();
-            vars.insert("ansible_port".into(), ssh.port.into());
-            vars.insert("ansible_user".into(), ssh.username.clone().into());
-            vars.insert(
-                "ansible_ssh_private_key_file".into(),
-                ssh.private_key.display().to_string().into(),
-            );
-            vars.insert("ansible_connection".into(), "ssh".into());
-            cmd.arg("-e")
-                .arg(serde_json::Value::Object(vars).to_string());
+            // The vars can hold the guest's password, so they go in a file
+            // only we can read rather than on the command line
+            let vars = dir.join("ansible_vars.json");
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&vars)?
+                .write_all(
+                    serde_json::Value::Object(transport.ansible_vars(dir)?)
+                        .to_string()
+                        .as_bytes(),
+                )?;
+            cmd.arg("-e").arg(format!("@{}", vars.display()));
         }
     }
 
@@ -92,7 +98,9 @@ fn playbook_command(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::builder::qemu::OsCategory;
     use std::ffi::OsStr;
+    use std::os::unix::fs::PermissionsExt;
 
     fn args(cmd: &Command) -> Vec<&OsStr> {
         cmd.get_args().collect()
@@ -100,7 +108,7 @@ mod tests {
 
     #[test]
     fn local_command_args() -> Result<()> {
-        let extra_vars = Some(HashMap::from([(
+        let extra_vars = Some(BTreeMap::from([(
             "hostname".to_string(),
             "with space".to_string(),
         )]));
@@ -130,4 +138,61 @@ mod tests {
         assert_eq!(cmd.get_current_dir(), Some(Path::new("/tmp")));
         Ok(())
     }
+
+    struct Password;
+
+    impl Transport for Password {
+        fn os(&self) -> OsCategory {
+            OsCategory::Windows
+        }
+        fn exec(&mut self, _: &str) -> Result<i32> {
+            unimplemented!()
+        }
+        fn upload(&mut self, _: &[u8], _: &str, _: u32) -> Result<()> {
+            unimplemented!()
+        }
+        fn upload_dir(&mut self, _: &Path, _: &str) -> Result<()> {
+            unimplemented!()
+        }
+        fn upload_exec(&mut self, _: &[u8]) -> Result<i32> {
+            unimplemented!()
+        }
+        fn powershell(&mut self, _: &str) -> Result<i32> {
+            unimplemented!()
+        }
+        fn ansible_vars(&self, _: &Path) -> Result<serde_json::Map<String, serde_json::Value>> {
+            let mut vars = serde_json::Map::new();
+            vars.insert("ansible_password".into(), "secret".into());
+            Ok(vars)
+        }
+    }
+
+    #[test]
+    fn remote_vars_stay_off_the_command_line() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let cmd = playbook_command(
+            Path::new("/tmp"),
+            "site.yml",
+            None,
+            &None,
+            &None,
+            Connection::Remote(&Password, tmp.path()),
+        )?;
+
+        let vars = tmp.path().join("ansible_vars.json");
+        let vars_arg = format!("@{}", vars.display());
+        assert_eq!(
+            args(&cmd),
+            vec!["-i", "127.0.0.1,", "-e", vars_arg.as_str(), "site.yml"]
+        );
+        assert_eq!(
+            std::fs::read_to_string(&vars)?,
+            r#"{"ansible_password":"secret"}"#
+        );
+        assert_eq!(
+            std::fs::metadata(&vars)?.permissions().mode() & 0o777,
+            0o600
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,198 @@
+//! The `Copy` and `Template` post-steps, which put files from the context
+//! directory into the VM.
+
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, bail};
+use regex::{Captures, Regex};
+use tracing::info;
+
+use crate::builder::qemu::OsCategory;
+use crate::builder::steps::Transport;
+
+/// Copy the file or directory `src` from the context directory to `dest` on
+/// the VM. Files keep their permissions unless `mode` is given.
+pub fn copy(
+    transport: &mut dyn Transport,
+    context_dir: &Path,
+    src: &str,
+    dest: &str,
+    mode: Option<&str>,
+    owner: Option<&str>,
+) -> Result<()> {
+    info!(%src, %dest, "Running copy post-step");
+
+    let path = context_dir.join(src);
+    let metadata = std::fs::metadata(&path)?;
+    if metadata.is_dir() {
+        transport.upload_dir(&path, dest)?;
+    } else {
+        transport.upload(&std::fs::read(&path)?, dest, file_mode(&metadata))?;
+    }
+
+    set_permissions(transport, dest, mode, owner, metadata.is_dir())
+}
+
+/// Render the file `src` from the context directory with `vars` and copy the
+/// result to `dest` on the VM.
+pub fn template(
+    transport: &mut dyn Transport,
+    context_dir: &Path,
+    src: &str,
+    dest: &str,
+    vars: &BTreeMap<String, String>,
+    mode: Option<&str>,
+    owner: Option<&str>,
+) -> Result<()> {
+    info!(%src, %dest, "Running template post-step");
+
+    let path = context_dir.join(src);
+    let rendered = render(&std::fs::read_to_string(&path)?, vars)?;
+    transport.upload(
+        rendered.as_bytes(),
+        dest,
+        file_mode(&std::fs::metadata(&path)?),
+    )?;
+
+    set_permissions(transport, dest, mode, owner, false)
+}
+
+/// Replace each `{{ name }}` in `template` with the value of `name`.
+pub fn render(template: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+    let pattern = Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}")?;
+
+    let mut missing = Vec::new();
+    let rendered = pattern.replace_all(template, |captures: &Captures| {
+        let name = &captures[1];
+        match vars.get(name) {
+            Some(value) => value.clone(),
+            None => {
+                missing.push(name.to_string());
+                String::new()
+            }
+        }
+    });
+
+    if !missing.is_empty() {
+        bail!("Template variables are not defined: {}", missing.join(", "));
+    }
+    Ok(rendered.into_owned())
+}
+
+/// The paths under `dir` relative to it, with each directory before its
+/// contents.
+pub fn walk(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    walk_into(dir, Path::new(""), &mut paths)?;
+    Ok(paths)
+}
+
+fn walk_into(root: &Path, relative: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = std::fs::read_dir(root.join(relative))?
+        .map(|entry| Ok(entry?.file_name()))
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort();
+
+    for name in entries {
+        let path = relative.join(name);
+        let is_dir = root.join(&path).is_dir();
+        paths.push(path.clone());
+        if is_dir {
+            walk_into(root, &path, paths)?;
+        }
+    }
+    Ok(())
+}
+
+/// Join a path relative to a walked directory onto `dest` with the guest's
+/// path separator.
+pub fn remote_path(dest: &str, relative: &Path, separator: char) -> String {
+    let mut path = dest.trim_end_matches(['/', '\\']).to_string();
+    for component in relative.components() {
+        path.push(separator);
+        path.push_str(&component.as_os_str().to_string_lossy());
+    }
+    path
+}
+
+/// The permission bits of a file on the host.
+pub fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    metadata.permissions().mode() & 0o7777
+}
+
+fn set_permissions(
+    transport: &mut dyn Transport,
+    dest: &str,
+    mode: Option<&str>,
+    owner: Option<&str>,
+    recursive: bool,
+) -> Result<()> {
+    if mode.is_none() && owner.is_none() {
+        return Ok(());
+    }
+    if let OsCategory::Windows = transport.os() {
+        bail!("mode and owner are not supported for Windows");
+    }
+
+    let flag = if recursive { "-R " } else { "" };
+    let dest = quote(dest);
+    if let Some(mode) = mode {
+        if transport.exec(&format!("chmod {flag}{} {dest}", quote(mode)))? != 0 {
+            bail!("Failed to set the mode of {dest}");
+        }
+    }
+    if let Some(owner) = owner {
+        if transport.exec(&format!("chown {flag}{} {dest}", quote(owner)))? != 0 {
+            bail!("Failed to set the owner of {dest}");
+        }
+    }
+    Ok(())
+}
+
+/// Quote a string for `sh`.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_template() -> Result<()> {
+        let vars = BTreeMap::from([
+            ("hostname".to_string(), "kiosk".to_string()),
+            ("port".to_string(), "8080".to_string()),
+        ]);
+        assert_eq!(
+            render("host={{hostname}} port={{ port }} {x}", &vars)?,
+            "host=kiosk port=8080 {x}"
+        );
+        assert!(render("{{ missing }}", &vars).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn walk_directory() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir_all(dir.path().join("b/c"))?;
+        std::fs::write(dir.path().join("a"), "")?;
+        std::fs::write(dir.path().join("b/c/d"), "")?;
+
+        let paths = walk(dir.path())?;
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("a"),
+                PathBuf::from("b"),
+                PathBuf::from("b/c"),
+                PathBuf::from("b/c/d"),
+            ]
+        );
+        assert_eq!(remote_path("/etc/app/", &paths[3], '/'), "/etc/app/b/c/d");
+        assert_eq!(remote_path("C:\\app", &paths[3], '\\'), "C:\\app\\b\\c\\d");
+        Ok(())
+    }
+}
@@ -1,20 +1,22 @@
 //! Build steps that run around the image build. A `PreStep` runs on the host
 //! before the VM boots and may freely modify the build's effective context
 //! directory (an ephemeral copy of the directory containing `goldboot.ron`).
-//! A `PostStep` runs against the booted VM over SSH after the install
-//! completes.
+//! A `PostStep` runs against the booted VM over a [`Transport`] (SSH, or
+//! WinRM for Windows) after the install completes.
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::path::Path;
 
 use crate::builder::Builder;
-use crate::builder::ssh::SshConnection;
+use crate::builder::checkpoint;
+use crate::builder::qemu::{OsCategory, QemuProcess};
 use crate::cli::prompt::Prompt;
 use anyhow::{Result, bail};
 use serde::{Deserialize, Serialize};
 use tracing::info;
 
 pub mod ansible;
+pub mod copy;
 
 /// A `PreStep` runs on the host before the VM boots. It receives the build's
 /// effective context directory and may modify it in place, e.g. to render
@@ -33,7 +35,7 @@ pub enum PreStep {
 
         /// Extra variables, passed to ansible-playbook as JSON via `-e`
         #[serde(default)]
-        extra_vars: Option<HashMap<String, String>>,
+        extra_vars: Option<BTreeMap<String, String>>,
     },
 }
 
@@ -56,12 +58,41 @@ impl PreStep {
     }
 }
 
-/// A `PostStep` runs against the booted VM over SSH after the install
-/// completes. All paths are relative to the effective context directory.
+/// A connection to a running VM that post-steps run over.
+pub trait Transport {
+    /// The kind of OS the VM runs.
+    fn os(&self) -> OsCategory;
+
+    /// Run a command with the guest's shell (`sh`, or `cmd` on Windows) and
+    /// return its exit code.
+    fn exec(&mut self, command: &str) -> Result<i32>;
+
+    /// Write a file on the VM with the given permissions (ignored for
+    /// Windows).
+    fn upload(&mut self, source: &[u8], dest: &str, mode: u32) -> Result<()>;
+
+    /// Copy a directory from the host to `dest` on the VM, keeping the
+    /// permissions of its files.
+    fn upload_dir(&mut self, source: &Path, dest: &str) -> Result<()>;
+
+    /// Upload an executable, run it and delete it.
+    fn upload_exec(&mut self, source: &[u8]) -> Result<i32>;
+
+    /// Run a PowerShell script.
+    fn powershell(&mut self, script: &str) -> Result<i32>;
+
+    /// Variables that point `ansible-playbook` at the VM. Files they refer
+    /// to (e.g. a known_hosts file) are written into `dir`, which only the
+    /// current user can read.
+    fn ansible_vars(&self, dir: &Path) -> Result<serde_json::Map<String, serde_json::Value>>;
+}
+
+/// A `PostStep` runs against the booted VM after the install completes. All
+/// paths are relative to the effective context directory.
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum PostStep {
-    /// Runs an Ansible playbook on the VM over SSH. The playbook should
-    /// target `hosts: all`.
+    /// Runs an Ansible playbook on the VM over SSH (or WinRM for Windows).
+    /// The playbook should target `hosts: all`.
     Ansible {
         /// The playbook file
         playbook: String,
@@ -77,7 +108,7 @@ pub enum PostStep {
 
         /// Extra variables, passed to ansible-playbook as JSON via `-e`
         #[serde(default)]
-        extra_vars: Option<HashMap<String, String>>,
+        extra_vars: Option<BTreeMap<String, String>>,
     },
     /// Uploads an executable from the host and runs it on the VM.
     HostExecutable {
@@ -89,10 +120,56 @@ pub enum PostStep {
         /// The command to run
         command: String,
     },
+    /// Runs an inline PowerShell script on the VM (`pwsh` on Linux).
+    PowerShell {
+        /// The script to run
+        script: String,
+    },
+    /// Copies a file, or a directory recursively, to the VM.
+    Copy {
+        /// The file or directory to copy
+        src: String,
+
+        /// Where to put it on the VM
+        dest: String,
+
+        /// Mode passed to `chmod` (recursively for directories); files keep
+        /// their permissions from the host by default
+        #[serde(default)]
+        mode: Option<String>,
+
+        /// Owner passed to `chown` (recursively for directories), e.g.
+        /// `"app:app"`
+        #[serde(default)]
+        owner: Option<String>,
+    },
+    /// Renders a file, replacing each `{{ name }}` with the value of the
+    /// variable `name`, and copies the result to the VM.
+    Template {
+        /// The template file
+        src: String,
+
+        /// Where to put the rendered file on the VM
+        dest: String,
+
+        /// Values of the template's variables
+        #[serde(default)]
+        vars: BTreeMap<String, String>,
+
+        /// Mode passed to `chmod`; the template's permissions by default
+        #[serde(default)]
+        mode: Option<String>,
+
+        /// Owner passed to `chown`
+        #[serde(default)]
+        owner: Option<String>,
+    },
 }
 
 impl PostStep {
-    pub fn run(&self, ssh: &mut SshConnection, context_dir: &Path) -> Result<()> {
+    /// Run the step against the VM. `tmp` is the worker's private directory
+    /// for per-run files.
+    pub fn run(&self, transport: &mut dyn Transport, context_dir: &Path, tmp: &Path) -> Result<()> {
         match self {
             Self::Ansible {
                 playbook,
@@ -105,24 +182,81 @@ impl PostStep {
                 inventory.as_deref(),
                 vars_files,
                 extra_vars,
-                ansible::Connection::Ssh(ssh),
+                ansible::Connection::Remote(transport, tmp),
             ),
             Self::HostExecutable { path } => {
                 info!(%path, "Running executable post-step");
-                if ssh.upload_exec(&std::fs::read(context_dir.join(path))?, vec![])? != 0 {
+                if transport.upload_exec(&std::fs::read(context_dir.join(path))?)? != 0 {
                     bail!("Executable failed");
                 }
                 Ok(())
             }
             Self::Shell { command } => {
                 info!("Running shell post-step");
-                if ssh.exec(command)? != 0 {
+                if transport.exec(command)? != 0 {
                     bail!("Shell command failed");
                 }
                 Ok(())
             }
+            Self::PowerShell { script } => {
+                info!("Running PowerShell post-step");
+                if transport.powershell(script)? != 0 {
+                    bail!("PowerShell script failed");
+                }
+                Ok(())
+            }
+            Self::Copy {
+                src,
+                dest,
+                mode,
+                owner,
+            } => copy::copy(
+                transport,
+                context_dir,
+                src,
+                dest,
+                mode.as_deref(),
+                owner.as_deref(),
+            ),
+            Self::Template {
+                src,
+                dest,
+                vars,
+                mode,
+                owner,
+            } => copy::template(
+                transport,
+                context_dir,
+                src,
+                dest,
+                vars,
+                mode.as_deref(),
+                owner.as_deref(),
+            ),
+        }
+    }
+}
+
+/// Run `steps` against the VM in order, taking a live checkpoint after each
+/// one. Steps whose checkpoint already exists ran in an earlier build (the VM
+/// was restored past them) and are skipped.
+pub fn run_post_steps(
+    worker: &Builder,
+    qemu: &mut QemuProcess,
+    transport: &mut dyn Transport,
+    steps: &[PostStep],
+) -> Result<()> {
+    for (i, step) in steps.iter().enumerate() {
+        let name = checkpoint::post_step(i);
+        if worker.has_checkpoint(&name) {
+            continue;
         }
+
+        worker.set_status(format!("post-step {}/{}", i + 1, steps.len()));
+        step.run(transport, &worker.effective_context_dir, worker.tmp.path())?;
+        qemu.checkpoint(&name)?;
     }
+    Ok(())
 }
 
 impl Prompt for Vec<PreStep> {
@@ -160,4 +294,25 @@ mod tests {
         assert!(matches!(config.0.post_steps(), [PostStep::Shell { .. }]));
         Ok(())
     }
+
+    #[test]
+    fn parse_copy_steps() -> Result<()> {
+        let steps: Vec<PostStep> = ron::from_str(
+            r#"[
+                Copy(src: "files/etc", dest: "/etc/app", owner: Some("app:app")),
+                Template(src: "app.conf", dest: "/etc/app.conf", vars: {"port": "8080"}),
+            ]"#,
+        )?;
+
+        assert!(matches!(
+            &steps[0],
+            PostStep::Copy { src, mode: None, owner: Some(owner), .. }
+                if src == "files/etc" && owner == "app:app"
+        ));
+        assert!(matches!(
+            &steps[1],
+            PostStep::Template { vars, .. } if vars["port"] == "8080"
+        ));
+        Ok(())
+    }
 }
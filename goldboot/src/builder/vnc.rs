@@ -2,15 +2,37 @@
 //! we compare the state of the screen with specifications from templates in order
 //! to act on timing events.
 
-use crate::builder::serial::SerialConnection;
+use crate::builder::{
+    observe::Observer,
+    recording::{RecordMode, Recorder},
+    serial::SerialConnection,
+};
+use anyhow::Context;
 use anyhow::Result;
 use anyhow::bail;
 use rand::RngExt;
 use sha1::{Digest, Sha1};
-use std::{fs::File, io::BufWriter, net::TcpStream, path::Path, time::Duration};
-use tracing::{debug, info, trace};
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{BufWriter, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, info, trace, warn};
 use vnc::client::Event;
 
+/// Default time limit for each wait command.
+pub const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// How much serial console output goes into a failure bundle.
+const SERIAL_TAIL_LEN: usize = 16 * 1024;
+
+/// Side length of the luma grid screenshots are reduced to before comparing
+/// them for similarity.
+const SIMILARITY_GRID: usize = 32;
+
 /// A rectangular snapshot of the entire screen or an arbitrary subsection.
 /// Data is stored as 32bpp BGRX (blue, green, red, padding byte).
 pub struct VncScreenshot {
@@ -41,24 +63,97 @@ impl VncScreenshot {
             })
             .collect()
     }
+
+    /// Reduce the screenshot to a `SIMILARITY_GRID` square of average luma
+    /// values. Each cell covers a proportional area of the screen, so
+    /// screenshots of different resolutions still line up.
+    fn luma_grid(&self) -> Vec<f32> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let mut grid = vec![0f32; SIMILARITY_GRID * SIMILARITY_GRID];
+        let mut counts = vec![0u32; SIMILARITY_GRID * SIMILARITY_GRID];
+
+        for (i, p) in self.data.chunks_exact(4).enumerate() {
+            let (x, y) = (i % width, i / width);
+            if y >= height {
+                break;
+            }
+            let cell =
+                (y * SIMILARITY_GRID / height) * SIMILARITY_GRID + x * SIMILARITY_GRID / width;
+            grid[cell] += 0.299 * p[2] as f32 + 0.587 * p[1] as f32 + 0.114 * p[0] as f32;
+            counts[cell] += 1;
+        }
+
+        for (value, count) in grid.iter_mut().zip(counts) {
+            if count > 0 {
+                *value /= count as f32;
+            }
+        }
+        grid
+    }
 }
 
 impl VncScreenshot {
+    /// Load a screenshot from a png file, such as a reference image
+    /// previously captured with `write_png`.
+    pub fn read_png(path: &Path) -> Result<VncScreenshot> {
+        Self::decode_png(&std::fs::read(path).with_context(|| format!("failed to open {path:?}"))?)
+    }
+
+    /// Decode a screenshot from png bytes, such as a reference image compiled
+    /// into an OS builder.
+    pub fn decode_png(png: &[u8]) -> Result<VncScreenshot> {
+        let mut decoder = png::Decoder::new(std::io::Cursor::new(png));
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size().context("png too large")?];
+        let info = reader.next_frame(&mut buffer)?;
+        let buffer = &buffer[..info.buffer_size()];
+
+        let data = match info.color_type {
+            png::ColorType::Rgb => buffer
+                .chunks_exact(3)
+                .flat_map(|p| [p[2], p[1], p[0], 0])
+                .collect(),
+            png::ColorType::Rgba => buffer
+                .chunks_exact(4)
+                .flat_map(|p| [p[2], p[1], p[0], 0])
+                .collect(),
+            png::ColorType::Grayscale => buffer.iter().flat_map(|&v| [v, v, v, 0]).collect(),
+            png::ColorType::GrayscaleAlpha => buffer
+                .chunks_exact(2)
+                .flat_map(|p| [p[0], p[0], p[0], 0])
+                .collect(),
+            other => bail!("Unsupported png color type: {other:?}"),
+        };
+
+        Ok(VncScreenshot {
+            data,
+            width: info.width.try_into()?,
+            height: info.height.try_into()?,
+        })
+    }
+
     /// Produce a hash of the data in the screenshot to be used for comparison.
     pub fn hash(&self) -> String {
         hex::encode(Sha1::new().chain_update(&self.data).finalize())
     }
 
+    /// Encode the screenshot as a png.
+    pub fn encode_png(&self) -> Result<Vec<u8>> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.to_rgb())?;
+        writer.finish()?;
+        Ok(png)
+    }
+
     /// Write the screenshot to a png file (probably for debugging).
     pub fn write_png(&self, output_path: &Path) -> Result<()> {
         std::fs::create_dir_all(output_path.parent().unwrap())?;
-        let w = &mut BufWriter::new(File::create(output_path)?);
-
-        let mut encoder = png::Encoder::new(w, self.width as u32, self.height as u32);
-        encoder.set_color(png::ColorType::Rgb);
-        encoder.set_depth(png::BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
-        writer.write_image_data(&self.to_rgb()).unwrap();
+        BufWriter::new(File::create(output_path)?).write_all(&self.encode_png()?)?;
 
         debug!(
             "Saved screenshot to: {:?}",
@@ -67,9 +162,25 @@ impl VncScreenshot {
         Ok(())
     }
 
-    /// Compute a percentage of how similar the given screenshot is to this one.
-    pub fn similarity(&self, _other: &VncScreenshot) -> f32 {
-        todo!();
+    /// Compute how similar the given screenshot is to this one, from 0.0
+    /// (opposite) to 1.0 (perceptually identical).
+    ///
+    /// Both screenshots are downscaled to a coarse luma grid first, so small
+    /// differences like a blinking cursor or font hinting only move the
+    /// result slightly instead of failing an exact comparison.
+    pub fn similarity(&self, other: &VncScreenshot) -> f32 {
+        if self.width == 0 || self.height == 0 || other.width == 0 || other.height == 0 {
+            return if (self.width, self.height) == (other.width, other.height) {
+                1.0
+            } else {
+                0.0
+            };
+        }
+
+        let a = self.luma_grid();
+        let b = other.luma_grid();
+        let difference: f32 = a.iter().zip(&b).map(|(a, b)| (a - b).abs()).sum();
+        1.0 - difference / (a.len() as f32 * 255.0)
     }
 
     /// Create a trimmed screenshot according to the given dimensions
@@ -109,6 +220,16 @@ impl VncScreenshot {
     }
 }
 
+/// The bytes of a reference png, which captions and errors show by size.
+#[derive(Clone)]
+pub struct ReferencePng(pub Cow<'static, [u8]>);
+
+impl std::fmt::Debug for ReferencePng {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<{} byte png>", self.0.len())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum VncCmd {
     /// Input the enter key.
@@ -138,26 +259,85 @@ pub enum VncCmd {
     /// Wait for a subsection of the screen to match the given hash.
     WaitScreenRect(String, u16, u16, u16, u16),
 
+    /// Wait for the screen to be at least the given similarity (0.0 - 1.0)
+    /// to a reference png.
+    WaitScreenSimilar(ReferencePng, f32),
+
     /// Wait for text matching the given regex to appear on screen via OCR.
     WaitTextOcr(String),
 
     /// Wait for text matching the given regex to appear on the serial console.
     WaitTextSerial(String),
+
+    /// Run the given command with its own timeout (in seconds) instead of the
+    /// build's default wait timeout.
+    WithTimeout(u64, Box<VncCmd>),
+}
+
+/// A wait command gave up.
+#[derive(Debug)]
+pub(super) enum WaitTimedOut {
+    /// The command's deadline passed.
+    Deadline,
+
+    /// The guest stopped making progress, so waiting for the deadline is
+    /// pointless. Holds what was observed.
+    Stalled(String),
 }
 
+impl std::fmt::Display for WaitTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WaitTimedOut::Deadline => f.write_str("wait timed out"),
+            WaitTimedOut::Stalled(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for WaitTimedOut {}
+
 /// Represents a VNC session to a running VM.
 pub struct VncConnection {
     pub width: u16,
     pub height: u16,
     pub vnc: vnc::Client,
-    pub record: bool,
+    pub record: Option<RecordMode>,
     pub debug: bool,
     pub serial: Option<SerialConnection>,
+
+    /// How long each wait command may take unless it specifies its own
+    /// timeout.
+    pub wait_timeout: Duration,
+
+    /// When the whole build must be finished, regardless of per-command
+    /// timeouts.
+    pub build_deadline: Option<Instant>,
+
+    /// Where diagnostics are saved when a wait times out.
+    pub failure_dir: PathBuf,
+
+    /// The QEMU command line, included in failure diagnostics.
+    pub qemu_cmdline: Vec<String>,
+
     ocr_engine: Option<ocrs::OcrEngine>,
+
+    /// Publishes progress for `--observe`.
+    pub observer: Option<Observer>,
+
+    /// Captures frames for `--record=video`.
+    recorder: Option<Recorder>,
+
+    /// The command currently running, used to caption recorded frames.
+    caption: String,
 }
 
 impl VncConnection {
-    pub fn new(host: &str, port: u16, record: bool, debug: bool) -> Result<VncConnection> {
+    pub fn new(
+        host: &str,
+        port: u16,
+        record: Option<RecordMode>,
+        debug: bool,
+    ) -> Result<VncConnection> {
         debug!("Attempting VNC connection to: {}:{}", host, port);
 
         let mut vnc =
@@ -183,6 +363,17 @@ impl VncConnection {
 
         debug!("Connected to VNC ({} x {})", width, height);
 
+        let recorder = match record {
+            Some(RecordMode::Video) => {
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                // Elements of a multiboot image may record at the same time
+                Some(Recorder::new(Path::new(&format!(
+                    "screenshots/recording-{timestamp}-{port}"
+                )))?)
+            }
+            _ => None,
+        };
+
         Ok(Self {
             width,
             height,
@@ -190,7 +381,14 @@ impl VncConnection {
             record,
             debug,
             serial: None,
+            wait_timeout: DEFAULT_WAIT_TIMEOUT,
+            build_deadline: None,
+            failure_dir: PathBuf::from("failures"),
+            qemu_cmdline: Vec::new(),
             ocr_engine: None,
+            observer: None,
+            recorder,
+            caption: String::new(),
         })
     }
 
@@ -214,7 +412,19 @@ impl VncConnection {
         Ok(self.ocr_engine.as_ref().unwrap())
     }
 
+    /// Capture the screen, also recording it as a video frame when enabled.
     pub fn screenshot(&mut self) -> Result<VncScreenshot> {
+        let screenshot = self.capture_screen()?;
+        if let Some(recorder) = &mut self.recorder {
+            recorder.capture(&screenshot, &self.caption)?;
+        }
+        if let Some(observer) = &self.observer {
+            observer.publish_screen(&screenshot)?;
+        }
+        Ok(screenshot)
+    }
+
+    fn capture_screen(&mut self) -> Result<VncScreenshot> {
         // Attempt to clear the framebuffer, but don't discard any resize events
         for event in self.vnc.poll_iter() {
             if let Event::Resize(width, height) = event {
@@ -304,169 +514,383 @@ impl VncConnection {
     pub fn run(&mut self, commands: Vec<Vec<VncCmd>>) -> Result<()> {
         info!("Running VNC commands");
 
+        if let Some(observer) = &self.observer {
+            observer.set_commands(
+                commands
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(i, cmd)| cmd.iter().map(move |step| format!("{}: {step:?}", i + 1)))
+                    .collect(),
+            );
+        }
+
         let mut cmd_number = 0;
+        let mut step_index = 0;
         for cmd in commands {
             cmd_number += 1;
             for step in cmd {
+                if let Some(observer) = &self.observer {
+                    observer.set_current(step_index);
+                }
+                step_index += 1;
+
                 if self.debug {
                     match &step {
                         VncCmd::Wait(_) => {}
                         _ => self.handle_breakpoint(&step)?,
                     }
                 }
-                match step {
-                    VncCmd::Type(ref text) => {
-                        for ch in text.chars() {
-                            if ch == '\n' {
-                                self.vnc.send_key_event(true, 0xff0d)?;
-                                self.vnc.send_key_event(false, 0xff0d)?;
-                            } else if ch.is_uppercase() {
-                                self.vnc.send_key_event(true, 0xffe1)?;
-                                self.vnc.send_key_event(true, 0x01000000 + ch as u32)?;
-                                self.vnc.send_key_event(false, 0x01000000 + ch as u32)?;
-                                self.vnc.send_key_event(false, 0xffe1)?;
-                            } else {
-                                match ch {
-                                    '~' | '!' | '@' | '#' | '$' | '%' | '^' | '&' | '*' | '('
-                                    | ')' | '_' | '+' | '{' | '}' | '|' | ':' | '"' | '<' | '>'
-                                    | '?' => {
-                                        self.vnc.send_key_event(true, 0xffe1)?;
-                                        self.vnc.send_key_event(true, 0x01000000 + ch as u32)?;
-                                        self.vnc.send_key_event(false, 0x01000000 + ch as u32)?;
-                                        self.vnc.send_key_event(false, 0xffe1)?;
-                                    }
-                                    _ => {
-                                        self.vnc.send_key_event(true, 0x01000000 + ch as u32)?;
-                                        self.vnc.send_key_event(false, 0x01000000 + ch as u32)?;
-                                    }
-                                }
-                            }
-                            std::thread::sleep(Duration::from_millis(50));
+
+                let (step, timeout) = match step {
+                    VncCmd::WithTimeout(seconds, step) => (*step, Duration::from_secs(seconds)),
+                    step => (step, self.wait_timeout),
+                };
+                self.caption = format!("{cmd_number}: {step:?}");
+                let started = Instant::now();
+                let deadline = step_deadline(started, timeout, self.build_deadline);
+
+                if let Err(error) = self.run_step(&step, deadline) {
+                    let Some(timed_out) = error.downcast_ref::<WaitTimedOut>() else {
+                        return Err(error);
+                    };
+
+                    let reason = timeout_reason(timed_out, deadline, self.build_deadline);
+                    let message = format!(
+                        "Boot command {cmd_number} ({step:?}) failed because {reason} after {} sec",
+                        started.elapsed().as_secs()
+                    );
+                    match self.write_failure_bundle(cmd_number, &message) {
+                        Ok(bundle) => bail!("{message}; diagnostics saved to {bundle:?}"),
+                        Err(e) => {
+                            warn!(error = ?e, "Failed to write failure bundle");
+                            bail!("{message}");
                         }
                     }
-                    VncCmd::Wait(duration) => {
-                        debug!("Waiting {} seconds", &duration);
-                        std::thread::sleep(Duration::from_secs(duration));
+                }
+
+                match self.record {
+                    Some(RecordMode::Screenshots) => {
+                        self.screenshot()?
+                            .write_png(Path::new(&format!("screenshots/{cmd_number}.png")))?;
+                    }
+                    Some(RecordMode::Video) => {
+                        self.screenshot()?;
                     }
-                    VncCmd::WaitScreen(hash) => {
-                        debug!("Waiting for screen hash to equal: {}", &hash);
+                    None => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail with [`WaitTimedOut`] once `deadline` has passed.
+    fn check_deadline(deadline: Instant) -> Result<()> {
+        if Instant::now() >= deadline {
+            return Err(WaitTimedOut::Deadline.into());
+        }
+        Ok(())
+    }
 
-                        // Track the screen hash over time because we should
-                        // exit if nothing happens for a really long time.
-                        let mut running_hash = String::new();
-                        let mut running_count = 0;
-                        let mut total_count = 0;
+    /// OCR the given screenshot.
+    fn ocr_text(&mut self, screenshot: &VncScreenshot) -> Result<String> {
+        let rgb = screenshot.to_ocr_rgb();
+        let ocr_engine = self.ocr_engine()?;
+        let input = ocr_engine.prepare_input(ocrs::ImageSource::from_bytes(
+            &rgb,
+            (screenshot.width as u32, screenshot.height as u32),
+        )?)?;
+        Ok(ocr_engine.get_text(&input)?)
+    }
 
-                        loop {
-                            total_count += 1;
-                            running_count += 1;
+    /// Save everything useful for debugging a stalled command into a new
+    /// directory under `failure_dir` and return its path. Individual
+    /// artifacts are best effort, since the VM may be in any state.
+    fn write_failure_bundle(&mut self, cmd_number: usize, message: &str) -> Result<PathBuf> {
+        let mut bundle = FailureBundle {
+            message: message.to_string(),
+            qemu_cmdline: self.qemu_cmdline.clone(),
+            serial_log: self
+                .serial
+                .as_ref()
+                .map(|serial| serial.tail(SERIAL_TAIL_LEN)),
+            screenshot: None,
+            ocr_text: None,
+        };
 
-                            std::thread::sleep(Duration::from_millis(
-                                rand::rng().random_range(500..1000),
-                            ));
+        match self.screenshot() {
+            Ok(screenshot) => {
+                match self.ocr_text(&screenshot) {
+                    Ok(text) => bundle.ocr_text = Some(text),
+                    Err(e) => warn!(error = ?e, "Failed to OCR the final screenshot"),
+                }
+                bundle.screenshot = Some(screenshot);
+            }
+            Err(e) => warn!(error = ?e, "Failed to capture the final screenshot"),
+        }
 
-                            let screenshot = self.screenshot()?;
-                            if screenshot.hash() == hash {
-                                debug!(total_count, "Finished screen wait");
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let dir = self
+            .failure_dir
+            .join(format!("{timestamp}-command-{cmd_number}"));
+        bundle.write(&dir)?;
+        Ok(dir)
+    }
 
-                                // Don't continue immediately
-                                std::thread::sleep(Duration::from_secs(1));
-                                break;
-                            } else if screenshot.hash() == running_hash {
-                                // TODO configurable
-                                if running_count > 600 {
-                                    // Write out the screen that's frozen
-                                    screenshot.write_png(Path::new("screenshots/frozen.png"))?;
-                                    bail!("Screen has not changed in 600 sec");
-                                }
-                            } else {
-                                running_hash = screenshot.hash();
-                                running_count = 0;
+    /// Run a single command, failing with [`WaitTimedOut`] if it's a wait
+    /// that doesn't finish before `deadline`.
+    fn run_step(&mut self, step: &VncCmd, deadline: Instant) -> Result<()> {
+        match step {
+            VncCmd::Type(text) => {
+                for ch in text.chars() {
+                    if ch == '\n' {
+                        self.vnc.send_key_event(true, 0xff0d)?;
+                        self.vnc.send_key_event(false, 0xff0d)?;
+                    } else if ch.is_uppercase() {
+                        self.vnc.send_key_event(true, 0xffe1)?;
+                        self.vnc.send_key_event(true, 0x01000000 + ch as u32)?;
+                        self.vnc.send_key_event(false, 0x01000000 + ch as u32)?;
+                        self.vnc.send_key_event(false, 0xffe1)?;
+                    } else {
+                        match ch {
+                            '~' | '!' | '@' | '#' | '$' | '%' | '^' | '&' | '*' | '(' | ')'
+                            | '_' | '+' | '{' | '}' | '|' | ':' | '"' | '<' | '>' | '?' => {
+                                self.vnc.send_key_event(true, 0xffe1)?;
+                                self.vnc.send_key_event(true, 0x01000000 + ch as u32)?;
+                                self.vnc.send_key_event(false, 0x01000000 + ch as u32)?;
+                                self.vnc.send_key_event(false, 0xffe1)?;
                             }
-                        }
-                    }
-                    VncCmd::WaitScreenRect(hash, top, left, width, height) => {
-                        debug!("Waiting for screen hash to equal: {}", &hash);
-                        loop {
-                            std::thread::sleep(Duration::from_secs(1));
-                            match self.screenshot()?.trim(vnc::Rect {
-                                top,
-                                left,
-                                width,
-                                height,
-                            }) {
-                                Ok(screenshot) => {
-                                    if screenshot.hash() == hash {
-                                        // Wait a few before continuing
-                                        std::thread::sleep(Duration::from_secs(1));
-                                        break;
-                                    }
-                                }
-                                // If the trim failed, the screen may not be the right size yet
-                                Err(_) => continue,
+                            _ => {
+                                self.vnc.send_key_event(true, 0x01000000 + ch as u32)?;
+                                self.vnc.send_key_event(false, 0x01000000 + ch as u32)?;
                             }
                         }
                     }
-                    VncCmd::Enter => {
-                        self.vnc.send_key_event(true, 0xff0d)?;
-                        self.vnc.send_key_event(false, 0xff0d)?;
-                    }
-                    VncCmd::Tab => {
-                        self.vnc.send_key_event(true, 0xff09)?;
-                        self.vnc.send_key_event(false, 0xff09)?;
-                    }
-                    VncCmd::Spacebar => {
-                        self.vnc.send_key_event(true, 0x0020)?;
-                        self.vnc.send_key_event(false, 0x0020)?;
-                    }
-                    VncCmd::LeftSuper => {
-                        self.vnc.send_key_event(true, 0xffeb)?;
-                        self.vnc.send_key_event(false, 0xffeb)?;
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+            }
+            VncCmd::Wait(duration) => {
+                debug!("Waiting {} seconds", &duration);
+                if self.recorder.is_some() || self.observer.is_some() {
+                    // Keep capturing while nothing else looks at the screen
+                    let end = Instant::now() + Duration::from_secs(*duration);
+                    while Instant::now() < end {
+                        self.screenshot()?;
+                        std::thread::sleep(
+                            Duration::from_secs(1)
+                                .min(end.saturating_duration_since(Instant::now())),
+                        );
                     }
-                    VncCmd::Escape => {
-                        self.vnc.send_key_event(true, 0xff1b)?;
-                        self.vnc.send_key_event(false, 0xff1b)?;
+                } else {
+                    std::thread::sleep(Duration::from_secs(*duration));
+                }
+            }
+            VncCmd::WaitScreen(hash) => {
+                debug!("Waiting for screen hash to equal: {}", &hash);
+
+                // Track the screen hash over time because we should
+                // exit if nothing happens for a really long time.
+                let mut running_hash = String::new();
+                let mut running_count = 0;
+                let mut total_count = 0;
+
+                loop {
+                    Self::check_deadline(deadline)?;
+                    total_count += 1;
+                    running_count += 1;
+
+                    std::thread::sleep(Duration::from_millis(rand::rng().random_range(500..1000)));
+
+                    let screenshot = self.screenshot()?;
+                    if screenshot.hash() == *hash {
+                        debug!(total_count, "Finished screen wait");
+
+                        // Don't continue immediately
+                        std::thread::sleep(Duration::from_secs(1));
+                        break;
+                    } else if screenshot.hash() == running_hash {
+                        // TODO configurable
+                        if running_count > 600 {
+                            // The failure bundle captures the frozen screen
+                            return Err(WaitTimedOut::Stalled(
+                                "the screen has not changed in 600 sec".to_string(),
+                            )
+                            .into());
+                        }
+                    } else {
+                        running_hash = screenshot.hash();
+                        running_count = 0;
                     }
-                    VncCmd::WaitTextOcr(pattern) => {
-                        let re = regex::Regex::new(&pattern)?;
-                        debug!("Waiting for text matching: {}", &pattern);
-
-                        let mut total_count = 0u64;
-                        loop {
-                            total_count += 1;
-                            std::thread::sleep(Duration::from_millis(
-                                rand::rng().random_range(500..1000),
-                            ));
-
-                            let screenshot = self.screenshot()?;
-                            let rgb = screenshot.to_ocr_rgb();
-                            let ocr_engine = self.ocr_engine()?;
-                            let input = ocr_engine.prepare_input(ocrs::ImageSource::from_bytes(
-                                &rgb,
-                                (screenshot.width as u32, screenshot.height as u32),
-                            )?)?;
-                            let text = ocr_engine.get_text(&input)?;
-                            trace!("OCR text: {}", &text);
-
-                            if re.is_match(&text) {
-                                debug!(total_count, "Finished text wait");
+                }
+            }
+            VncCmd::WaitScreenRect(hash, top, left, width, height) => {
+                debug!("Waiting for screen hash to equal: {}", &hash);
+                loop {
+                    Self::check_deadline(deadline)?;
+                    std::thread::sleep(Duration::from_secs(1));
+                    match self.screenshot()?.trim(vnc::Rect {
+                        top: *top,
+                        left: *left,
+                        width: *width,
+                        height: *height,
+                    }) {
+                        Ok(screenshot) => {
+                            if screenshot.hash() == *hash {
+                                // Wait a few before continuing
                                 std::thread::sleep(Duration::from_secs(1));
                                 break;
                             }
                         }
+                        // If the trim failed, the screen may not be the right size yet
+                        Err(_) => continue,
                     }
-                    VncCmd::WaitTextSerial(pattern) => {
-                        let Some(serial) = &self.serial else {
-                            bail!("The VM has no serial console connection");
-                        };
-                        serial.wait_for_match(&pattern)?;
+                }
+            }
+            VncCmd::WaitScreenSimilar(reference, threshold) => {
+                let reference = VncScreenshot::decode_png(&reference.0)?;
+                debug!(
+                    "Waiting for screen to be {} similar to the {}x{} reference",
+                    threshold, reference.width, reference.height
+                );
+
+                let mut running_hash = String::new();
+                let mut running_count = 0;
+                let mut total_count = 0;
+
+                loop {
+                    Self::check_deadline(deadline)?;
+                    total_count += 1;
+                    running_count += 1;
+
+                    std::thread::sleep(Duration::from_millis(rand::rng().random_range(500..1000)));
+
+                    let screenshot = self.screenshot()?;
+                    let similarity = screenshot.similarity(&reference);
+                    trace!(similarity, "Compared screen to reference");
+
+                    if similarity >= *threshold {
+                        debug!(total_count, similarity, "Finished screen wait");
+                        std::thread::sleep(Duration::from_secs(1));
+                        break;
+                    } else if screenshot.hash() == running_hash {
+                        if running_count > 600 {
+                            return Err(WaitTimedOut::Stalled(format!(
+                                "the screen has not changed in 600 sec (similarity {similarity})"
+                            ))
+                            .into());
+                        }
+                    } else {
+                        running_hash = screenshot.hash();
+                        running_count = 0;
                     }
                 }
-                if self.record {
-                    self.screenshot()?
-                        .write_png(Path::new(&format!("screenshots/{cmd_number}.png")))?;
+            }
+            VncCmd::Enter => {
+                self.vnc.send_key_event(true, 0xff0d)?;
+                self.vnc.send_key_event(false, 0xff0d)?;
+            }
+            VncCmd::Tab => {
+                self.vnc.send_key_event(true, 0xff09)?;
+                self.vnc.send_key_event(false, 0xff09)?;
+            }
+            VncCmd::Spacebar => {
+                self.vnc.send_key_event(true, 0x0020)?;
+                self.vnc.send_key_event(false, 0x0020)?;
+            }
+            VncCmd::LeftSuper => {
+                self.vnc.send_key_event(true, 0xffeb)?;
+                self.vnc.send_key_event(false, 0xffeb)?;
+            }
+            VncCmd::Escape => {
+                self.vnc.send_key_event(true, 0xff1b)?;
+                self.vnc.send_key_event(false, 0xff1b)?;
+            }
+            VncCmd::WaitTextOcr(pattern) => {
+                let re = regex::Regex::new(pattern)?;
+                debug!("Waiting for text matching: {}", &pattern);
+
+                let mut total_count = 0u64;
+                loop {
+                    Self::check_deadline(deadline)?;
+                    total_count += 1;
+                    std::thread::sleep(Duration::from_millis(rand::rng().random_range(500..1000)));
+
+                    let screenshot = self.screenshot()?;
+                    let text = self.ocr_text(&screenshot)?;
+                    trace!("OCR text: {}", &text);
+
+                    if re.is_match(&text) {
+                        debug!(total_count, "Finished text wait");
+                        std::thread::sleep(Duration::from_secs(1));
+                        break;
+                    }
                 }
             }
+            VncCmd::WaitTextSerial(pattern) => {
+                let Some(serial) = &self.serial else {
+                    bail!("The VM has no serial console connection");
+                };
+                if !serial.wait_for_match(pattern, deadline)? {
+                    return Err(WaitTimedOut::Deadline.into());
+                }
+            }
+            VncCmd::WithTimeout(_, step) => self.run_step(step, deadline)?,
+        }
+        Ok(())
+    }
+}
+
+/// When a command started at `started` must finish: after its own `timeout`,
+/// but never after the build's deadline.
+fn step_deadline(started: Instant, timeout: Duration, build_deadline: Option<Instant>) -> Instant {
+    match build_deadline {
+        Some(build_deadline) => build_deadline.min(started + timeout),
+        None => started + timeout,
+    }
+}
+
+/// Why a command whose deadline was `deadline` gave up, for the error
+/// message.
+fn timeout_reason(
+    timed_out: &WaitTimedOut,
+    deadline: Instant,
+    build_deadline: Option<Instant>,
+) -> String {
+    match timed_out {
+        WaitTimedOut::Stalled(reason) => reason.clone(),
+        WaitTimedOut::Deadline if build_deadline == Some(deadline) => {
+            "the build timeout expired".to_string()
+        }
+        WaitTimedOut::Deadline => "it timed out".to_string(),
+    }
+}
+
+/// Diagnostics saved when a boot command fails.
+struct FailureBundle {
+    message: String,
+    qemu_cmdline: Vec<String>,
+    serial_log: Option<String>,
+    screenshot: Option<VncScreenshot>,
+    ocr_text: Option<String>,
+}
+
+impl FailureBundle {
+    /// Write every artifact into `dir`, creating it.
+    fn write(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+
+        std::fs::write(dir.join("error.txt"), format!("{}\n", self.message))?;
+        std::fs::write(
+            dir.join("qemu-cmdline.txt"),
+            format!("{}\n", self.qemu_cmdline.join(" ")),
+        )?;
+        if let Some(serial_log) = &self.serial_log {
+            std::fs::write(dir.join("serial.log"), serial_log)?;
+        }
+        if let Some(screenshot) = &self.screenshot {
+            screenshot.write_png(&dir.join("screenshot.png"))?;
+        }
+        if let Some(ocr_text) = &self.ocr_text {
+            std::fs::write(dir.join("ocr.txt"), ocr_text)?;
         }
         Ok(())
     }
@@ -570,6 +994,33 @@ pub mod macros {
         };
     }
 
+    /// Wait for the screen to be at least `$threshold` similar to a
+    /// reference png captured from an earlier build. The png is compiled in
+    /// from a path relative to the invoking file.
+    #[macro_export]
+    macro_rules! wait_screen_similar {
+        ($reference:literal, $threshold:expr) => {
+            vec![$crate::builder::vnc::VncCmd::WaitScreenSimilar(
+                $crate::builder::vnc::ReferencePng(std::borrow::Cow::Borrowed(
+                    include_bytes!($reference).as_slice(),
+                )),
+                $threshold,
+            )]
+        };
+    }
+
+    /// Give the wrapped wait command its own timeout in seconds, e.g. for
+    /// a package installation known to take longer than usual.
+    #[macro_export]
+    macro_rules! with_timeout {
+        ($seconds:expr, $cmds:expr) => {
+            $cmds
+                .into_iter()
+                .map(|cmd| $crate::builder::vnc::VncCmd::WithTimeout($seconds, Box::new(cmd)))
+                .collect::<Vec<_>>()
+        };
+    }
+
     #[macro_export]
     macro_rules! input {
         ($text:expr) => {
@@ -596,7 +1047,9 @@ pub mod macros {
     #[macro_export]
     macro_rules! wait_text_ocr {
         ($pattern:expr) => {
-            vec![$crate::builder::vnc::VncCmd::WaitTextOcr($pattern.to_string())]
+            vec![$crate::builder::vnc::VncCmd::WaitTextOcr(
+                $pattern.to_string(),
+            )]
         };
     }
 
@@ -610,3 +1063,182 @@ pub mod macros {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A BGRX framebuffer where each pixel is produced by `f(x, y)`.
+    fn framebuffer(width: u16, height: u16, f: impl Fn(u16, u16) -> [u8; 3]) -> VncScreenshot {
+        let mut data = Vec::with_capacity(width as usize * height as usize * 4);
+        for y in 0..height {
+            for x in 0..width {
+                let [r, g, b] = f(x, y);
+                data.extend_from_slice(&[b, g, r, 0]);
+            }
+        }
+        VncScreenshot {
+            data,
+            width,
+            height,
+        }
+    }
+
+    /// Something resembling a login prompt: dark background with a light
+    /// box in the middle.
+    fn prompt(x: u16, y: u16) -> [u8; 3] {
+        if (200..440).contains(&x) && (200..280).contains(&y) {
+            [220, 220, 220]
+        } else {
+            [20, 20, 40]
+        }
+    }
+
+    #[test]
+    fn identical_screens_are_fully_similar() {
+        let a = framebuffer(640, 480, prompt);
+        let b = framebuffer(640, 480, prompt);
+        assert_eq!(a.similarity(&b), 1.0);
+    }
+
+    #[test]
+    fn small_changes_are_tolerated() {
+        let reference = framebuffer(640, 480, prompt);
+
+        // A blinking cursor
+        let cursor = framebuffer(640, 480, |x, y| {
+            if (210..218).contains(&x) && (260..276).contains(&y) {
+                [0, 0, 0]
+            } else {
+                prompt(x, y)
+            }
+        });
+        assert_ne!(reference.hash(), cursor.hash());
+        assert!(reference.similarity(&cursor) > 0.99);
+
+        // Slightly different colors, e.g. from another QEMU version
+        let tinted = framebuffer(640, 480, |x, y| prompt(x, y).map(|c| c.saturating_add(6)));
+        assert!(reference.similarity(&tinted) > 0.97);
+    }
+
+    #[test]
+    fn different_screens_are_dissimilar() {
+        let reference = framebuffer(640, 480, prompt);
+        let black = framebuffer(640, 480, |_, _| [0, 0, 0]);
+        let white = framebuffer(640, 480, |_, _| [255, 255, 255]);
+        let moved = framebuffer(640, 480, |x, y| prompt(x, (y + 200) % 480));
+
+        assert!(black.similarity(&white) < 0.01);
+        assert!(reference.similarity(&moved) < 0.95);
+        assert!(reference.similarity(&moved) > reference.similarity(&white));
+    }
+
+    #[test]
+    fn resolution_does_not_matter() {
+        let large = framebuffer(640, 480, prompt);
+        let small = framebuffer(320, 240, |x, y| prompt(x * 2, y * 2));
+        assert!(large.similarity(&small) > 0.99);
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let path = std::env::temp_dir().join(format!("goldboot-vnc-{}.png", std::process::id()));
+        let screenshot = framebuffer(64, 48, |x, y| [x as u8 * 4, y as u8 * 5, 128]);
+        screenshot.write_png(&path).unwrap();
+        let loaded = VncScreenshot::read_png(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((loaded.width, loaded.height), (64, 48));
+        assert_eq!(loaded.hash(), screenshot.hash());
+        assert_eq!(loaded.similarity(&screenshot), 1.0);
+    }
+
+    #[test]
+    fn reference_png_is_shown_by_size() {
+        let cmd = VncCmd::WaitScreenSimilar(ReferencePng(Cow::Borrowed(&[0; 16])), 0.9);
+        assert_eq!(format!("{cmd:?}"), "WaitScreenSimilar(<16 byte png>, 0.9)");
+    }
+
+    #[test]
+    fn the_earlier_deadline_wins() {
+        let started = Instant::now();
+        let timeout = Duration::from_secs(60);
+
+        assert_eq!(step_deadline(started, timeout, None), started + timeout);
+
+        let build_deadline = started + Duration::from_secs(10);
+        let deadline = step_deadline(started, timeout, Some(build_deadline));
+        assert_eq!(deadline, build_deadline);
+        assert_eq!(
+            timeout_reason(&WaitTimedOut::Deadline, deadline, Some(build_deadline)),
+            "the build timeout expired"
+        );
+
+        let build_deadline = started + Duration::from_secs(600);
+        let deadline = step_deadline(started, timeout, Some(build_deadline));
+        assert_eq!(deadline, started + timeout);
+        assert_eq!(
+            timeout_reason(&WaitTimedOut::Deadline, deadline, Some(build_deadline)),
+            "it timed out"
+        );
+        assert_eq!(
+            timeout_reason(
+                &WaitTimedOut::Stalled("the screen has not changed".to_string()),
+                deadline,
+                Some(build_deadline)
+            ),
+            "the screen has not changed"
+        );
+    }
+
+    #[test]
+    fn failure_bundle_contents() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+
+        let dir = tmp.path().join("full");
+        FailureBundle {
+            message: "Boot command 3 failed".to_string(),
+            qemu_cmdline: vec![
+                "qemu-system-x86_64".to_string(),
+                "-m".to_string(),
+                "2G".to_string(),
+            ],
+            serial_log: Some("login: ".to_string()),
+            screenshot: Some(framebuffer(64, 48, prompt)),
+            ocr_text: Some("localhost login".to_string()),
+        }
+        .write(&dir)?;
+        assert_eq!(
+            std::fs::read_to_string(dir.join("error.txt"))?,
+            "Boot command 3 failed\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("qemu-cmdline.txt"))?,
+            "qemu-system-x86_64 -m 2G\n"
+        );
+        assert_eq!(std::fs::read_to_string(dir.join("serial.log"))?, "login: ");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("ocr.txt"))?,
+            "localhost login"
+        );
+        let screenshot = VncScreenshot::read_png(&dir.join("screenshot.png"))?;
+        assert_eq!((screenshot.width, screenshot.height), (64, 48));
+
+        // Missing artifacts are left out rather than failing the bundle
+        let dir = tmp.path().join("partial");
+        FailureBundle {
+            message: "Boot command 1 failed".to_string(),
+            qemu_cmdline: Vec::new(),
+            serial_log: None,
+            screenshot: None,
+            ocr_text: None,
+        }
+        .write(&dir)?;
+        let mut files: Vec<_> = std::fs::read_dir(&dir)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<std::io::Result<_>>()?;
+        files.sort();
+        assert_eq!(files, ["error.txt", "qemu-cmdline.txt"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,506 @@
+//! A minimal WinRM (WS-Management) client for running commands in Windows
+//! VMs.
+//!
+//! Windows elements enable WinRM over HTTP with basic authentication (see
+//! `configure_winrm.ps1`), which is only reachable through the VM's forwarded
+//! port on the host. Every command runs in its own remote shell, with stdin
+//! used to upload files.
+
+use anyhow::{Result, bail};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use quick_xml::{Reader, events::Event};
+use rand::RngExt;
+use sha2::{Digest, Sha256};
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+use crate::builder::{
+    qemu::OsCategory,
+    ssh::log_lines,
+    steps::{Transport, copy},
+};
+
+/// The port WinRM listens on for HTTP in the guest.
+pub const PORT: u16 = 5985;
+
+const SHELL_URI: &str = "http://schemas.microsoft.com/wbem/wsman/1/windows/shell/cmd";
+const ACTION_CREATE: &str = "http://schemas.xmlsoap.org/ws/2004/09/transfer/Create";
+const ACTION_DELETE: &str = "http://schemas.xmlsoap.org/ws/2004/09/transfer/Delete";
+const ACTION_COMMAND: &str = "http://schemas.microsoft.com/wbem/wsman/1/windows/shell/Command";
+const ACTION_SEND: &str = "http://schemas.microsoft.com/wbem/wsman/1/windows/shell/Send";
+const ACTION_RECEIVE: &str = "http://schemas.microsoft.com/wbem/wsman/1/windows/shell/Receive";
+
+/// The WSManFault code of a Receive that timed out before the command
+/// produced anything.
+const FAULT_TIMED_OUT: &str = "2150858793";
+
+/// Stdin is sent in chunks well under the default 500 KB envelope limit.
+const STDIN_CHUNK_SIZE: usize = 128 * 1024;
+
+/// How long to wait for WinRM to come up, which happens at the end of the
+/// Windows install.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Represents a WinRM endpoint of a running VM.
+pub struct WinrmConnection {
+    pub username: String,
+    pub password: String,
+    pub port: u16,
+    client: reqwest::blocking::Client,
+}
+
+impl WinrmConnection {
+    /// Wait until WinRM accepts commands with the given credentials.
+    pub fn new(username: &str, password: &str, port: u16) -> Result<WinrmConnection> {
+        let mut winrm = WinrmConnection {
+            username: username.to_string(),
+            password: password.to_string(),
+            port,
+            client: reqwest::blocking::Client::builder()
+                .timeout(Duration::from_secs(120))
+                .build()?,
+        };
+
+        let start = Instant::now();
+        loop {
+            debug!("Trying WinRM: {}@localhost:{}", username, port);
+            match winrm.run("exit 0", Vec::new(), &[]) {
+                Ok(_) => break,
+                Err(error) => debug!("{}", error),
+            }
+
+            if start.elapsed() > CONNECT_TIMEOUT {
+                bail!("WinRM did not come up in a reasonable amount of time");
+            }
+            std::thread::sleep(Duration::from_secs(10));
+        }
+
+        debug!("Established WinRM connection");
+        Ok(winrm)
+    }
+
+    /// Run a command with `cmd` and return its exit code.
+    pub fn exec(&mut self, cmdline: &str) -> Result<i32> {
+        self.exec_env(cmdline, Vec::new())
+    }
+
+    /// Run a command with `cmd` and the given environment.
+    pub fn exec_env(&mut self, cmdline: &str, env: Vec<(&str, &str)>) -> Result<i32> {
+        debug!(cmdline, environment = ?env, "Executing command over WinRM");
+        let exit = self.run(cmdline, env, &[])?;
+        debug!("Exit code: {}", exit);
+        Ok(exit)
+    }
+
+    /// Write `source` to `dest` on the VM.
+    pub fn upload(&mut self, source: &[u8], dest: &str) -> Result<()> {
+        debug!(bytes = source.len(), dest, "Uploading file");
+        let cmdline = format!(
+            "powershell -NoProfile -Command \"$f = [IO.File]::Create('{dest}'); [Console]::OpenStandardInput().CopyTo($f); $f.Close()\""
+        );
+        match self.run(&cmdline, Vec::new(), source)? {
+            0 => {}
+            exit => bail!("Upload to {dest} failed with exit code {exit}"),
+        }
+
+        // Make sure the file arrived byte for byte
+        let digest = hex::encode(Sha256::digest(source));
+        let exit = self.exec(&format!(
+            "powershell -NoProfile -Command \"if ((Get-FileHash -Algorithm SHA256 '{dest}').Hash -ne '{digest}') {{ exit 1 }}\""
+        ))?;
+        if exit != 0 {
+            bail!("Upload to {dest} was corrupted");
+        }
+        Ok(())
+    }
+
+    /// Copy the directory `source` to `dest` on the VM one file at a time.
+    pub fn upload_dir(&mut self, source: &Path, dest: &str) -> Result<()> {
+        debug!(source = %source.display(), dest, "Uploading directory");
+        for relative in std::iter::once(PathBuf::new()).chain(copy::walk(source)?) {
+            let path = source.join(&relative);
+            let remote = copy::remote_path(dest, &relative, '\\');
+
+            if path.is_dir() {
+                let exit = self.exec(&format!(
+                    "powershell -NoProfile -Command \"New-Item -ItemType Directory -Force -Path '{remote}' | Out-Null\""
+                ))?;
+                if exit != 0 {
+                    bail!("Failed to create {remote}");
+                }
+            } else {
+                self.upload(&std::fs::read(&path)?, &remote)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Upload an executable, run it with the given environment and delete it.
+    pub fn upload_exec(&mut self, source: &[u8], env: Vec<(&str, &str)>) -> Result<i32> {
+        let path = temp_path("exe");
+        self.upload(source, &path)?;
+        let exit = self.exec_env(&path, env)?;
+
+        // Attempt to cleanup, but don't fail if we can't
+        _ = self.exec(&format!("del /f {path}"));
+        Ok(exit)
+    }
+
+    /// Upload a PowerShell script, run it and delete it.
+    pub fn powershell(&mut self, script: &str) -> Result<i32> {
+        let path = temp_path("ps1");
+        self.upload(script.as_bytes(), &path)?;
+        let exit = self.exec(&format!(
+            "powershell -NoProfile -NonInteractive -ExecutionPolicy Bypass -File {path}"
+        ))?;
+
+        _ = self.exec(&format!("del /f {path}"));
+        Ok(exit)
+    }
+
+    /// Send the shutdown command to the VM.
+    pub fn shutdown(mut self, command: &str) -> Result<()> {
+        debug!("Sending shutdown command");
+        // The VM may go away before the command returns
+        _ = self.exec(command);
+        Ok(())
+    }
+
+    /// Run a command in a new shell, feeding it `stdin` and logging its
+    /// output.
+    fn run(&mut self, cmdline: &str, env: Vec<(&str, &str)>, stdin: &[u8]) -> Result<i32> {
+        let environment: String = env
+            .iter()
+            .map(|(var, val)| {
+                format!(
+                    "<rsp:Variable Name=\"{}\">{}</rsp:Variable>",
+                    escape(var),
+                    escape(val)
+                )
+            })
+            .collect();
+        let environment = if environment.is_empty() {
+            environment
+        } else {
+            format!("<rsp:Environment>{environment}</rsp:Environment>")
+        };
+
+        let response = self.request(
+            ACTION_CREATE,
+            None,
+            "<w:OptionSet><w:Option Name=\"WINRS_NOPROFILE\">FALSE</w:Option><w:Option Name=\"WINRS_CODEPAGE\">65001</w:Option></w:OptionSet>",
+            &format!(
+                "<rsp:Shell>{environment}<rsp:InputStreams>stdin</rsp:InputStreams><rsp:OutputStreams>stdout stderr</rsp:OutputStreams></rsp:Shell>"
+            ),
+        )?;
+        let Some(shell) = element_text(&response, "ShellId")? else {
+            bail!("WinRM did not return a shell");
+        };
+
+        let exit = self.run_in_shell(&shell, cmdline, stdin);
+
+        // Shells count against a per-user quota, so always delete them
+        if let Err(error) = self.request(ACTION_DELETE, Some(&shell), "", "") {
+            debug!(%error, "Failed to delete WinRM shell");
+        }
+        exit
+    }
+
+    fn run_in_shell(&mut self, shell: &str, cmdline: &str, stdin: &[u8]) -> Result<i32> {
+        let response = self.request(
+            ACTION_COMMAND,
+            Some(shell),
+            &command_options(stdin),
+            &format!(
+                "<rsp:CommandLine><rsp:Command>{}</rsp:Command></rsp:CommandLine>",
+                escape(cmdline)
+            ),
+        )?;
+        let Some(command) = element_text(&response, "CommandId")? else {
+            bail!("WinRM did not return a command");
+        };
+
+        // An empty stdin still has to be closed
+        let chunks: Vec<&[u8]> = if stdin.is_empty() {
+            vec![&[]]
+        } else {
+            stdin.chunks(STDIN_CHUNK_SIZE).collect()
+        };
+        for (i, chunk) in chunks.iter().enumerate() {
+            let end = if i + 1 == chunks.len() {
+                " End=\"true\""
+            } else {
+                ""
+            };
+            self.request(
+                ACTION_SEND,
+                Some(shell),
+                "",
+                &format!(
+                    "<rsp:Send><rsp:Stream Name=\"stdin\" CommandId=\"{command}\"{end}>{}</rsp:Stream></rsp:Send>",
+                    BASE64.encode(chunk)
+                ),
+            )?;
+        }
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        loop {
+            let response = match self.request(
+                ACTION_RECEIVE,
+                Some(shell),
+                "",
+                &format!(
+                    "<rsp:Receive><rsp:DesiredStream CommandId=\"{command}\">stdout stderr</rsp:DesiredStream></rsp:Receive>"
+                ),
+            ) {
+                Ok(response) => response,
+                // Nothing happened before the operation timeout
+                Err(error) if error.to_string().contains(FAULT_TIMED_OUT) => continue,
+                Err(error) => return Err(error),
+            };
+
+            let received = Received::parse(&response)?;
+            log_lines(&mut stdout, &received.stdout);
+            log_lines(&mut stderr, &received.stderr);
+            if let Some(exit) = received.exit {
+                log_lines(&mut stdout, b"\n");
+                log_lines(&mut stderr, b"\n");
+                return Ok(exit);
+            }
+        }
+    }
+
+    /// Send a WS-Management request and return the response body.
+    fn request(
+        &self,
+        action: &str,
+        shell: Option<&str>,
+        options: &str,
+        body: &str,
+    ) -> Result<String> {
+        let url = format!("http://127.0.0.1:{}/wsman", self.port);
+        let message_id = uuid::Uuid::new_v4();
+        let selector = match shell {
+            Some(shell) => format!(
+                "<w:SelectorSet><w:Selector Name=\"ShellId\">{shell}</w:Selector></w:SelectorSet>"
+            ),
+            None => String::new(),
+        };
+
+        let envelope = format!(
+            r#"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" xmlns:a="http://schemas.xmlsoap.org/ws/2004/08/addressing" xmlns:w="http://schemas.dmtf.org/wbem/wsman/1/wsman.xsd" xmlns:rsp="http://schemas.microsoft.com/wbem/wsman/1/windows/shell"><s:Header><a:To>{url}</a:To><a:ReplyTo><a:Address s:mustUnderstand="true">http://schemas.xmlsoap.org/ws/2004/08/addressing/role/anonymous</a:Address></a:ReplyTo><a:Action s:mustUnderstand="true">{action}</a:Action><w:MaxEnvelopeSize s:mustUnderstand="true">512000</w:MaxEnvelopeSize><a:MessageID>uuid:{message_id}</a:MessageID><w:Locale xml:lang="en-US" s:mustUnderstand="false"/><w:OperationTimeout>PT60S</w:OperationTimeout><w:ResourceURI s:mustUnderstand="true">{SHELL_URI}</w:ResourceURI>{selector}{options}</s:Header><s:Body>{body}</s:Body></s:Envelope>"#
+        );
+
+        let response = self
+            .client
+            .post(&url)
+            .basic_auth(&self.username, Some(&self.password))
+            .header("Content-Type", "application/soap+xml;charset=UTF-8")
+            .body(envelope)
+            .send()?;
+        let status = response.status();
+        let text = response.text()?;
+        if !status.is_success() {
+            bail!("WinRM request failed with {status}: {text}");
+        }
+        Ok(text)
+    }
+}
+
+impl Transport for WinrmConnection {
+    fn os(&self) -> OsCategory {
+        OsCategory::Windows
+    }
+
+    fn exec(&mut self, command: &str) -> Result<i32> {
+        WinrmConnection::exec(self, command)
+    }
+
+    fn upload(&mut self, source: &[u8], dest: &str, _mode: u32) -> Result<()> {
+        WinrmConnection::upload(self, source, dest)
+    }
+
+    fn upload_dir(&mut self, source: &Path, dest: &str) -> Result<()> {
+        WinrmConnection::upload_dir(self, source, dest)
+    }
+
+    fn upload_exec(&mut self, source: &[u8]) -> Result<i32> {
+        WinrmConnection::upload_exec(self, source, Vec::new())
+    }
+
+    fn powershell(&mut self, script: &str) -> Result<i32> {
+        WinrmConnection::powershell(self, script)
+    }
+
+    fn ansible_vars(&self, _dir: &Path) -> Result<serde_json::Map<String, serde_json::Value>> {
+        let mut vars = serde_json::Map::new();
+        vars.insert("ansible_connection".into(), "winrm".into());
+        vars.insert("ansible_port".into(), self.port.into());
+        vars.insert("ansible_user".into(), self.username.clone().into());
+        vars.insert("ansible_password".into(), self.password.clone().into());
+        vars.insert("ansible_winrm_scheme".into(), "http".into());
+        vars.insert("ansible_winrm_transport".into(), "basic".into());
+        Ok(vars)
+    }
+}
+
+/// A path for a temporary file in the guest.
+fn temp_path(extension: &str) -> String {
+    let id: String = rand::rng()
+        .sample_iter(&rand::distr::Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect();
+    format!("C:\\Windows\\Temp\\gb_{id}.{extension}")
+}
+
+/// The options of a Command. Stdin that carries data must be a pipe: console
+/// input goes through the input code page and line editing, which mangles
+/// binary uploads.
+fn command_options(stdin: &[u8]) -> String {
+    let console = if stdin.is_empty() { "TRUE" } else { "FALSE" };
+    format!(
+        "<w:OptionSet><w:Option Name=\"WINRS_CONSOLEMODE_STDIN\">{console}</w:Option><w:Option Name=\"WINRS_SKIP_CMD_SHELL\">FALSE</w:Option></w:OptionSet>"
+    )
+}
+
+fn escape(value: &str) -> String {
+    quick_xml::escape::escape(value).into_owned()
+}
+
+/// The text of the first element with the given local name.
+fn element_text(xml: &str, name: &str) -> Result<Option<String>> {
+    let mut reader = Reader::from_str(xml);
+    let mut inside = false;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => inside = e.local_name().as_ref() == name.as_bytes(),
+            Event::Text(text) if inside => return Ok(Some(text.decode()?.trim().to_string())),
+            Event::End(_) => inside = false,
+            Event::Eof => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
+/// The output of a command from one Receive response.
+#[derive(Debug, Default)]
+struct Received {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+
+    /// Set once the command has finished
+    exit: Option<i32>,
+}
+
+impl Received {
+    fn parse(xml: &str) -> Result<Self> {
+        #[derive(PartialEq)]
+        enum Inside {
+            Nothing,
+            Stdout,
+            Stderr,
+            ExitCode,
+        }
+
+        let mut received = Received::default();
+        let mut done = false;
+        let mut exit = None;
+        let mut inside = Inside::Nothing;
+
+        let mut reader = Reader::from_str(xml);
+        loop {
+            match reader.read_event()? {
+                Event::Start(e) | Event::Empty(e) => {
+                    let attribute = |key: &[u8]| {
+                        e.attributes()
+                            .flatten()
+                            .find(|a| a.key.local_name().as_ref() == key)
+                            .map(|a| String::from_utf8_lossy(&a.value).into_owned())
+                    };
+
+                    inside = Inside::Nothing;
+                    match e.local_name().as_ref() {
+                        b"Stream" => match attribute(b"Name").as_deref() {
+                            Some("stdout") => inside = Inside::Stdout,
+                            Some("stderr") => inside = Inside::Stderr,
+                            _ => {}
+                        },
+                        b"CommandState" => {
+                            done = attribute(b"State")
+                                .is_some_and(|state| state.ends_with("CommandState/Done"));
+                        }
+                        b"ExitCode" => inside = Inside::ExitCode,
+                        _ => {}
+                    }
+                }
+                Event::Text(text) => {
+                    let text = text.decode()?;
+                    match inside {
+                        Inside::Stdout => received.stdout.extend(BASE64.decode(text.trim())?),
+                        Inside::Stderr => received.stderr.extend(BASE64.decode(text.trim())?),
+                        Inside::ExitCode => exit = Some(text.trim().parse::<i32>()?),
+                        Inside::Nothing => {}
+                    }
+                }
+                Event::End(_) => inside = Inside::Nothing,
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        if done {
+            received.exit = Some(exit.unwrap_or(0));
+        }
+        Ok(received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_receive() -> Result<()> {
+        let running = Received::parse(
+            r#"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" xmlns:rsp="http://schemas.microsoft.com/wbem/wsman/1/windows/shell"><s:Body><rsp:ReceiveResponse><rsp:Stream Name="stdout" CommandId="1">aGVsbG8K</rsp:Stream><rsp:Stream Name="stderr" CommandId="1"></rsp:Stream><rsp:CommandState CommandId="1" State="http://schemas.microsoft.com/wbem/wsman/1/windows/shell/CommandState/Running"/></rsp:ReceiveResponse></s:Body></s:Envelope>"#,
+        )?;
+        assert_eq!(running.stdout, b"hello\n");
+        assert!(running.stderr.is_empty());
+        assert_eq!(running.exit, None);
+
+        let done = Received::parse(
+            r#"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" xmlns:rsp="http://schemas.microsoft.com/wbem/wsman/1/windows/shell"><s:Body><rsp:ReceiveResponse><rsp:Stream Name="stderr" CommandId="1">b29wcwo=</rsp:Stream><rsp:Stream Name="stdout" CommandId="1" End="true"></rsp:Stream><rsp:CommandState CommandId="1" State="http://schemas.microsoft.com/wbem/wsman/1/windows/shell/CommandState/Done"><rsp:ExitCode>3</rsp:ExitCode></rsp:CommandState></rsp:ReceiveResponse></s:Body></s:Envelope>"#,
+        )?;
+        assert_eq!(done.stderr, b"oops\n");
+        assert_eq!(done.exit, Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn stdin_is_a_pipe_when_uploading() {
+        assert!(
+            command_options(&[])
+                .contains("<w:Option Name=\"WINRS_CONSOLEMODE_STDIN\">TRUE</w:Option>")
+        );
+        assert!(
+            command_options(&[0x00, 0x1a, 0x0d, 0xff])
+                .contains("<w:Option Name=\"WINRS_CONSOLEMODE_STDIN\">FALSE</w:Option>")
+        );
+    }
+
+    #[test]
+    fn find_shell_id() -> Result<()> {
+        let xml = r#"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" xmlns:rsp="http://schemas.microsoft.com/wbem/wsman/1/windows/shell"><s:Body><rsp:Shell><rsp:ShellId>11111111-2222-3333-4444-555555555555</rsp:ShellId></rsp:Shell></s:Body></s:Envelope>"#;
+        assert_eq!(
+            element_text(xml, "ShellId")?.as_deref(),
+            Some("11111111-2222-3333-4444-555555555555")
+        );
+        assert_eq!(element_text(xml, "CommandId")?, None);
+        Ok(())
+    }
+}
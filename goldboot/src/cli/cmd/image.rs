@@ -2,8 +2,10 @@ use std::process::ExitCode;
 
 use crate::{
     library::ImageLibrary,
-    registry::{Client, ImageRef, host_without_scheme},
+    registry::{Client, ImageRef, host_without_scheme, protocol::ImageQuery},
 };
+use anyhow::{Context, Result};
+use goldboot_image::ImageArch;
 use ubyte::ToByteUnit;
 
 #[allow(unreachable_code)]
@@ -15,6 +17,27 @@ pub fn run(cmd: super::Commands) -> ExitCode {
                 username,
                 password,
             } => list(registry, username, password),
+            super::ImageCommands::Search {
+                registry,
+                name,
+                arch,
+                os,
+                since,
+                until,
+                offset,
+                limit,
+                username,
+                password,
+            } => {
+                let query = match build_query(name, arch, os, since, until, offset, limit) {
+                    Ok(q) => q,
+                    Err(e) => {
+                        eprintln!("{e}");
+                        return ExitCode::FAILURE;
+                    }
+                };
+                search(registry, query, username, password)
+            }
             super::ImageCommands::Info { image } => info(image),
             super::ImageCommands::Delete { images } => delete(images),
             super::ImageCommands::Push {
@@ -124,6 +147,97 @@ fn list(registry: Option<String>, username: Option<String>, password: Option<Str
     }
 }
 
+/// Parse a `--since`/`--until` value: either a `YYYY-MM-DD` date (midnight
+/// UTC) or raw Unix seconds.
+fn parse_time(value: &str) -> Result<u64> {
+    if let Ok(secs) = value.parse::<u64>() {
+        return Ok(secs);
+    }
+    let date = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .with_context(|| format!("invalid date '{value}' (expected YYYY-MM-DD)"))?;
+    Ok(date
+        .and_time(chrono::NaiveTime::MIN)
+        .and_utc()
+        .timestamp()
+        .max(0) as u64)
+}
+
+fn build_query(
+    name: Option<String>,
+    arch: Option<String>,
+    os: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+) -> Result<ImageQuery> {
+    Ok(ImageQuery {
+        name_prefix: name,
+        arch: arch.map(ImageArch::try_from).transpose()?,
+        os,
+        since: since.as_deref().map(parse_time).transpose()?,
+        until: until.as_deref().map(parse_time).transpose()?,
+        offset,
+        limit,
+    })
+}
+
+fn search(
+    address: String,
+    query: ImageQuery,
+    username: Option<String>,
+    password: Option<String>,
+) -> ExitCode {
+    let auth = match super::registry::resolve_auth(username, password) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let client = match Client::new(&address, auth) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Bad registry address: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let result = match client.search_images(&query) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Failed to search registry images: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let host_display = host_without_scheme(&address);
+
+    println!(
+        "{:50} {:8} {:20} {:12} {:31}",
+        "Image", "Arch", "OS", "Minimum Size", "Build Date"
+    );
+    for entry in &result.images {
+        println!(
+            "{:50} {:8} {:20} {:12} {:31}",
+            render_ref(Some(host_display), &entry.name, &entry.tag),
+            entry.arch.to_string(),
+            entry.os.join(","),
+            entry.size.bytes().to_string(),
+            chrono::DateTime::from_timestamp(entry.timestamp as i64, 0)
+                .unwrap_or_default()
+                .to_rfc2822(),
+        );
+    }
+    if result.images.len() < result.total {
+        println!(
+            "\nShowing {} of {} matching images",
+            result.images.len(),
+            result.total
+        );
+    }
+    ExitCode::SUCCESS
+}
+
 fn info(reference: String) -> ExitCode {
     let r = match ImageRef::parse(&reference) {
         Ok(r) => r,
@@ -163,6 +277,9 @@ fn info(reference: String) -> ExitCode {
     println!("Elements:    {}", image.primary_header.element_count);
     for (i, element) in image.primary_header.elements.iter().enumerate() {
         println!("  [{}] os={} name={}", i, element.os(), element.name());
+        if let Some(parent) = element.parent_hex() {
+            println!("      parent={parent}");
+        }
     }
 
     ExitCode::SUCCESS
@@ -16,14 +16,42 @@ pub enum Commands {
     #[cfg(feature = "build")]
     Build {
         /// Save a screenshot to ./screenshots after each boot command for
-        /// debugging
-        #[clap(long, num_args = 0)]
-        record: bool,
+        /// debugging, or with `--record=video` a continuous recording of the
+        /// whole build
+        #[clap(
+            long,
+            value_enum,
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "screenshots"
+        )]
+        record: Option<crate::builder::recording::RecordMode>,
 
         /// Insert a breakpoint after each boot command
         #[clap(long, num_args = 0)]
         debug: bool,
 
+        /// Seconds each boot command may wait for the VM (screen, OCR or
+        /// serial console) before the build fails
+        #[clap(long, default_value_t = 3600)]
+        wait_timeout: u64,
+
+        /// Seconds the whole build may take. Boot commands still waiting
+        /// for the VM when it expires fail the build
+        #[clap(long)]
+        timeout: Option<u64>,
+
+        /// Where to save the screenshot, OCR text, serial log and QEMU
+        /// command line when a boot command times out (defaults to
+        /// ./failures)
+        #[clap(long)]
+        failure_dir: Option<PathBuf>,
+
+        /// Serve a read-only page showing the VM screen, serial console and
+        /// boot command progress on the given address (e.g. 127.0.0.1:8080)
+        #[clap(long)]
+        observe: Option<std::net::SocketAddr>,
+
         /// Read the encryption password from STDIN
         #[clap(long, num_args = 0)]
         read_password: bool,
@@ -46,11 +74,44 @@ pub enum Commands {
         goldboot_efi: Option<PathBuf>,
 
         /// Delete any cached build state and start fresh
-        #[clap(long, num_args = 0)]
+        #[clap(long, num_args = 0, conflicts_with = "from")]
         clean: bool,
 
+        /// Resume from the given checkpoint instead of the last one,
+        /// discarding the checkpoints after it. Checkpoints are taken after
+        /// their step, so `post-step-2` re-runs post-step 3 onward (use
+        /// `post-step-1` or `install` to re-run post-step 2). Multiboot images
+        /// prefix the element index, e.g. `1:post-step-2`
+        #[clap(long)]
+        from: Option<crate::builder::checkpoint::CheckpointTarget>,
+
+        /// List the checkpoints of each element and exit
+        #[clap(long, num_args = 0)]
+        list_checkpoints: bool,
+
+        /// Explain why each checkpoint is reused or discarded
+        #[clap(long, num_args = 0)]
+        explain_cache: bool,
+
+        /// How many elements of a multiboot image may build at once, each in
+        /// its own VM (defaults to all of them)
+        #[clap(long)]
+        jobs: Option<usize>,
+
+        /// Derive the image's cluster key and nonces from the SHA-256 of
+        /// this file instead of drawing them at random, so that encrypted
+        /// images of the same disk are identical
+        #[clap(long)]
+        nonce_key: Option<PathBuf>,
+
+        /// Build the image twice from scratch and fail unless both builds
+        /// have the same content, reporting the partitions and blocks that
+        /// differ
+        #[clap(long, num_args = 0, conflicts_with = "from")]
+        verify_reproducible: bool,
+
         /// Tag for the resulting image. Defaults to a UTC timestamp like
-        /// 20260606T143022.
+        /// 20260606T143022 (from `SOURCE_DATE_EPOCH` if it is set).
         #[clap(long)]
         tag: Option<String>,
 
@@ -170,6 +231,52 @@ pub enum ImageCommands {
         password: Option<String>,
     },
 
+    /// Search a remote registry's images by name, architecture, OS and
+    /// build date
+    Search {
+        /// Registry to query (e.g. registry.example.com)
+        #[clap(index = 1)]
+        registry: String,
+
+        /// Only images whose name starts with this prefix
+        #[clap(long)]
+        name: Option<String>,
+
+        /// Only images built for this architecture (e.g. amd64)
+        #[clap(long)]
+        arch: Option<String>,
+
+        /// Only images containing this OS (e.g. Debian)
+        #[clap(long)]
+        os: Option<String>,
+
+        /// Only images built on or after this date (YYYY-MM-DD or Unix
+        /// seconds)
+        #[clap(long)]
+        since: Option<String>,
+
+        /// Only images built on or before this date (YYYY-MM-DD or Unix
+        /// seconds)
+        #[clap(long)]
+        until: Option<String>,
+
+        /// Number of matching images to skip
+        #[clap(long)]
+        offset: Option<usize>,
+
+        /// Maximum number of images to show
+        #[clap(long)]
+        limit: Option<usize>,
+
+        /// HTTP Basic Auth username (if your registry's proxy requires auth)
+        #[clap(short = 'u', long, env = "GOLDBOOT_REGISTRY_USERNAME")]
+        username: Option<String>,
+
+        /// HTTP Basic Auth password
+        #[clap(short = 'p', long, env = "GOLDBOOT_REGISTRY_PASSWORD")]
+        password: Option<String>,
+    },
+
     /// Get detailed image info
     Info {
         /// Image reference: `<host>/<name>[:<tag>]`. Tag defaults to the
@@ -24,7 +24,6 @@ const OS_ICONS_2X: &[(&str, &[u8])] = &[
         "LinuxMint",
         include_bytes!("../builder/os/linux_mint/icon@2x.png"),
     ),
-    ("MacOs", include_bytes!("../builder/os/mac_os/icon@2x.png")),
     (
         "OpenSuse",
         include_bytes!("../builder/os/open_suse/icon@2x.png"),
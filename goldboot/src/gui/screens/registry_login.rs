@@ -90,6 +90,13 @@ pub fn render(ctx: &egui::Context, state: &mut AppState, _theme: &Theme) {
                 egui::TextEdit::singleline(&mut state.registry_password).password(true);
             ui.add(password_edit);
 
+            ui.add_space(10.0);
+
+            ui.label("Image password (optional, for encrypted images):");
+            let image_password_edit =
+                egui::TextEdit::singleline(&mut state.registry_image_password).password(true);
+            ui.add(image_password_edit);
+
             if let Some(err) = &state.registry_login_error {
                 ui.add_space(6.0);
                 ui.colored_label(egui::Color32::RED, err.clone());
@@ -214,10 +221,13 @@ fn list_rx_slot() -> &'static Mutex<Option<std::sync::mpsc::Receiver<ListEvent>>
     LIST_RX.get_or_init(|| Mutex::new(None))
 }
 
-fn kick_image_list(state: &mut AppState) {
+/// (Re)fetch the registry image list, filtered by the SelectImage search
+/// box. Results arrive through [`poll_list_events`].
+pub fn kick_image_list(state: &mut AppState) {
     let Some(client) = state.registry_client.clone() else {
         return;
     };
+    let query = super::select_image::search_query(&state.image_search);
     state.registry_list_loading = true;
     state.registry_list_error = None;
     let (tx, rx) = std::sync::mpsc::channel::<ListEvent>();
@@ -231,7 +241,7 @@ fn kick_image_list(state: &mut AppState) {
                     return;
                 }
             };
-            client.list_images()
+            client.search_images(&query).map(|r| r.images)
         };
         let ev = match result {
             Ok(images) => ListEvent::Ok(images),
@@ -5,7 +5,8 @@ use super::super::{
     widgets,
 };
 use super::Screen;
-use goldboot_image::ImageArch;
+use crate::registry::protocol::ImageQuery;
+use goldboot_image::{ImageArch, ImageHandle};
 use ubyte::ToByteUnit;
 
 /// One entry in the merged local + registry image list.
@@ -75,6 +76,45 @@ fn arch_label(arch: &ImageArch) -> &'static str {
     }
 }
 
+/// Turn the search box contents into a registry query. `arch:<arch>` and
+/// `os:<os>` terms filter on those fields; anything else is a name prefix.
+pub fn search_query(text: &str) -> ImageQuery {
+    let mut query = ImageQuery::default();
+    for term in text.split_whitespace() {
+        if let Some(arch) = term.strip_prefix("arch:") {
+            query.arch = ImageArch::try_from(arch.to_string()).ok();
+        } else if let Some(os) = term.strip_prefix("os:") {
+            query.os = Some(os.to_string());
+        } else {
+            query.name_prefix = Some(term.to_string());
+        }
+    }
+    query
+}
+
+/// Apply the same filters the registry would to a local image.
+fn local_matches(query: &ImageQuery, image: &ImageHandle) -> bool {
+    let header = &image.primary_header;
+    if let Some(prefix) = &query.name_prefix {
+        if !header.name_str().starts_with(prefix.as_str()) {
+            return false;
+        }
+    }
+    if query.arch.is_some_and(|arch| header.arch != arch) {
+        return false;
+    }
+    if let Some(os) = &query.os {
+        if !header
+            .elements
+            .iter()
+            .any(|e| e.os().eq_ignore_ascii_case(os))
+        {
+            return false;
+        }
+    }
+    true
+}
+
 pub fn render(
     ui: &mut egui::Ui,
     state: &mut AppState,
@@ -229,6 +269,25 @@ pub fn render(
 
                 ui.add_space(10.0);
 
+                // Search box. Local images are filtered in place; registry
+                // images are re-queried whenever the text changes.
+                let search_focused = ui
+                    .vertical_centered(|ui| {
+                        let response = ui.add(
+                            egui::TextEdit::singleline(&mut state.image_search)
+                                .hint_text("Search (name, arch:amd64, os:Debian)")
+                                .desired_width(400.0),
+                        );
+                        if response.changed() {
+                            super::registry_login::kick_image_list(state);
+                        }
+                        response.has_focus()
+                    })
+                    .inner;
+                let query = search_query(&state.image_search);
+
+                ui.add_space(10.0);
+
                 // Build merged display list: local images then registry
                 // images. The order is stable across frames so keyboard
                 // navigation is predictable.
@@ -238,7 +297,7 @@ pub fn render(
                     state.registry_address.clone()
                 };
                 let mut items: Vec<DisplayItem> = Vec::new();
-                for img in state.images.iter() {
+                for img in state.images.iter().filter(|i| local_matches(&query, i)) {
                     items.push(DisplayItem::Local {
                         id: img.id.clone(),
                         name: img.primary_header.name_str(),
@@ -263,7 +322,12 @@ pub fn render(
 
                 // Keyboard navigation across the merged list
                 if !items.is_empty() {
-                    if state.selected_image.is_none() {
+                    // Also reselect when the search hid the previous choice
+                    let visible = state
+                        .selected_image
+                        .as_ref()
+                        .is_some_and(|sel| items.iter().any(|d| d.matches(sel)));
+                    if !visible {
                         state.selected_image = Some(items[0].as_selected());
                     }
                     let current_idx = state
@@ -282,7 +346,10 @@ pub fn render(
                             let prev = current_idx.map(|i| i.saturating_sub(1)).unwrap_or(0);
                             state.selected_image = items.get(prev).map(|d| d.as_selected());
                         }
-                        if inp.key_pressed(egui::Key::Enter) && state.selected_image.is_some() {
+                        if inp.key_pressed(egui::Key::Enter)
+                            && state.selected_image.is_some()
+                            && !search_focused
+                        {
                             *screen = Screen::SelectDevice;
                         }
                     });
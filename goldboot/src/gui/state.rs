@@ -251,6 +251,8 @@ pub struct AppState {
     pub registry_address: String,
     pub registry_username: String,
     pub registry_password: String,
+    /// Password for encrypted registry images, kept for the session.
+    pub registry_image_password: String,
     pub show_registry_dialog: bool,
     pub registry_login_error: Option<String>,
     pub registry_login_in_progress: bool,
@@ -261,6 +263,8 @@ pub struct AppState {
     pub registry_images: Vec<RegistryImageEntry>,
     pub registry_list_loading: bool,
     pub registry_list_error: Option<String>,
+    /// Contents of the search box on the SelectImage screen.
+    pub image_search: String,
 
     // Image writing progress (shared with write thread)
     pub write_progress: Option<Arc<Mutex<WriteProgress>>>,
@@ -436,6 +440,7 @@ impl AppState {
             registry_address: String::new(),
             registry_username: String::new(),
             registry_password: String::new(),
+            registry_image_password: String::new(),
             show_registry_dialog: false,
             registry_login_error: None,
             registry_login_in_progress: false,
@@ -443,6 +448,7 @@ impl AppState {
             registry_images: Vec::new(),
             registry_list_loading: false,
             registry_list_error: None,
+            image_search: String::new(),
             write_progress: None,
             #[cfg(feature = "uki")]
             debug_shell: None,
@@ -551,13 +557,14 @@ impl AppState {
             .ok_or("Not logged in to a registry")?;
         let name = name.to_string();
         let tag = tag.to_string();
+        let password = Some(self.registry_image_password.clone()).filter(|p| !p.is_empty());
 
         // Fetch the manifest synchronously so we can build the progress
         // tracker before kicking off the streaming download.
         let (cluster_count, block_size) = {
             let client = client.lock().map_err(|_| "client poisoned")?;
             let (_p, protected, _d, digest, _start) = client
-                .fetch_manifest(&name, &tag)
+                .fetch_manifest(&name, &tag, password.clone())
                 .map_err(|e| e.to_string())?;
             (digest.digest_count as usize, protected.block_size as u64)
         };
@@ -573,6 +580,7 @@ impl AppState {
                 client.stream_write_to_dest(
                     &name,
                     &tag,
+                    password,
                     std::path::Path::new(&device_path),
                     move |idx, state| {
                         if let Ok(mut p) = progress_inner.lock() {
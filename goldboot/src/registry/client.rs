@@ -13,11 +13,14 @@
 //! `~/.config/goldboot/registry-cas.pem` when present. The client never
 //! disables certificate verification.
 
-use crate::registry::protocol::{ImageListResponse, MANIFEST_CONTENT_TYPE, RegistryImageEntry};
+use crate::registry::protocol::{
+    ErrorResponse, ImageListResponse, ImageQuery, MANIFEST_CONTENT_TYPE, RegistryImageEntry,
+    UsageResponse,
+};
 use anyhow::{Context, Result, bail};
 use goldboot_image::{
-    DigestTable, Directory, ImageHandle, ManifestBlob, PrimaryHeader, ProtectedHeader,
-    parse_manifest,
+    DigestTable, Directory, HeaderEncryptionType, ImageHandle, ManifestBlob, PrimaryHeader,
+    ProtectedHeader, parse_manifest, parse_manifest_directory,
 };
 use reqwest::blocking::{Client as HttpClient, ClientBuilder, RequestBuilder, Response};
 use rustls::{ClientConfig, RootCertStore};
@@ -84,6 +87,19 @@ fn build_tls_config() -> Result<ClientConfig> {
         .with_no_client_auth())
 }
 
+/// Like `error_for_status`, but surfaces the registry's `ErrorResponse`
+/// message when the body carries one.
+fn check_status(resp: Response) -> Result<Response> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    match resp.json::<ErrorResponse>() {
+        Ok(body) => bail!("registry returned {status}: {}", body.message),
+        Err(_) => bail!("registry returned {status}"),
+    }
+}
+
 impl Client {
     pub fn new(address: &str, auth: Option<(String, String)>) -> Result<Self> {
         let base = registry_root(address)?;
@@ -127,14 +143,17 @@ impl Client {
         Ok(body.images)
     }
 
-    /// Fetch and parse the manifest for an image. Returns the parsed
-    /// headers + the cluster region start offset so callers can pass it
-    /// straight to `stream_write`.
-    pub fn fetch_manifest(
-        &self,
-        name: &str,
-        tag: &str,
-    ) -> Result<(PrimaryHeader, ProtectedHeader, Directory, DigestTable, u64)> {
+    /// List the images matching `query`. The registry applies the filters
+    /// and pagination; `total` in the response counts every match.
+    pub fn search_images(&self, query: &ImageQuery) -> Result<ImageListResponse> {
+        let url = self.base.join("images")?;
+        let resp = self.auth(self.http.get(url).query(query)).send()?;
+        let resp = resp.error_for_status()?;
+        Ok(resp.json()?)
+    }
+
+    /// Download the raw manifest blob for an image.
+    fn fetch_manifest_blob(&self, name: &str, tag: &str) -> Result<ManifestBlob> {
         let url = self
             .base
             .join(&format!("images/{name}/tags/{tag}/manifest"))?;
@@ -146,13 +165,41 @@ impl Client {
             }
         }
         let bytes = resp.bytes()?;
-        let blob = ManifestBlob::read_from(&mut bytes.as_ref())?;
-        if blob.headers_encrypted {
-            bail!(
-                "registry served an encrypted image — pass the password via your local pull flow"
-            );
+        ManifestBlob::read_from(&mut bytes.as_ref())
+    }
+
+    /// Fetch and parse the manifest for an image. Returns the parsed
+    /// headers + the cluster region start offset so callers can pass it
+    /// straight to `stream_write`.
+    ///
+    /// Encrypted images need the image `password`: the registry can only
+    /// serve a partial manifest for them, which is completed here by
+    /// decrypting the directory and fetching the remaining sections by
+    /// range.
+    pub fn fetch_manifest(
+        &self,
+        name: &str,
+        tag: &str,
+        password: Option<String>,
+    ) -> Result<(PrimaryHeader, ProtectedHeader, Directory, DigestTable, u64)> {
+        let mut blob = self.fetch_manifest_blob(name, tag)?;
+        if blob.headers_encrypted && password.is_none() {
+            bail!("image '{name}:{tag}' is encrypted; a password is required");
         }
-        parse_manifest(&blob, None)
+        if blob.is_partial() {
+            let (_, directory) = parse_manifest_directory(&blob, password.clone())
+                .context("failed to decrypt image directory (wrong password?)")?;
+            let protected_start = blob.primary_bytes.len() as u64;
+            blob.protected_bytes =
+                self.fetch_blob_range(name, tag, protected_start, directory.protected_size as u64)?;
+            blob.digest_table_bytes = self.fetch_blob_range(
+                name,
+                tag,
+                directory.digest_table_offset,
+                directory.digest_table_size as u64,
+            )?;
+        }
+        parse_manifest(&blob, password)
     }
 
     /// Open a streaming response over the cluster region. The returned
@@ -164,9 +211,22 @@ impl Client {
         tag: &str,
         range: Option<(u64, u64)>,
     ) -> Result<Response> {
-        let url = self
-            .base
-            .join(&format!("images/{name}/tags/{tag}/clusters"))?;
+        self.stream_ranged(&format!("images/{name}/tags/{tag}/clusters"), range)
+    }
+
+    /// Open a streaming response over the raw `.gb` file, optionally limited
+    /// to an inclusive byte range.
+    pub fn stream_blob(
+        &self,
+        name: &str,
+        tag: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<Response> {
+        self.stream_ranged(&format!("images/{name}/tags/{tag}/blob"), range)
+    }
+
+    fn stream_ranged(&self, path: &str, range: Option<(u64, u64)>) -> Result<Response> {
+        let url = self.base.join(path)?;
         let mut req = self
             .auth(self.http.get(url))
             .timeout(Duration::from_secs(60 * 30));
@@ -177,6 +237,23 @@ impl Client {
         Ok(resp.error_for_status()?)
     }
 
+    /// Read `len` bytes of the raw `.gb` file starting at `offset`.
+    fn fetch_blob_range(&self, name: &str, tag: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let bytes = self
+            .stream_blob(name, tag, Some((offset, offset + len - 1)))?
+            .bytes()?;
+        if bytes.len() as u64 != len {
+            bail!(
+                "registry returned {} bytes for a {len} byte range",
+                bytes.len()
+            );
+        }
+        Ok(bytes.to_vec())
+    }
+
     /// Upload a local `.gb` image. The caller provides an open file handle
     /// and its byte length; the request body is the file's contents
     /// verbatim.
@@ -188,10 +265,17 @@ impl Client {
             .header(reqwest::header::CONTENT_LENGTH, len)
             .body(body)
             .send()?;
-        resp.error_for_status()?;
+        check_status(resp)?;
         Ok(())
     }
 
+    /// Fetch the registry's storage usage and quota limits.
+    pub fn usage(&self) -> Result<UsageResponse> {
+        let url = self.base.join("usage")?;
+        let resp = self.auth(self.http.get(url)).send()?;
+        Ok(check_status(resp)?.json()?)
+    }
+
     /// Download an image and reconstruct a valid `.gb` file at `dest`.
     pub fn pull_to_file(&self, name: &str, tag: &str, dest: &std::path::Path) -> Result<()> {
         let manifest_url = self
@@ -205,6 +289,17 @@ impl Client {
         let blob = ManifestBlob::read_from(&mut manifest_bytes.as_slice())?;
 
         let mut out = File::create(dest)?;
+        if blob.is_partial() {
+            // The sections can't be located without the password, but the
+            // file is stored verbatim, so take it whole
+            let mut resp = self.stream_blob(name, tag, None)?;
+            std::io::copy(&mut resp, &mut out)?;
+            out.flush()?;
+            drop(out);
+
+            let _ = ImageHandle::open(dest)?;
+            return Ok(());
+        }
         out.write_all(&blob.primary_bytes)?;
         out.write_all(&blob.protected_bytes)?;
 
@@ -223,15 +318,32 @@ impl Client {
     /// Stream an image directly to a target device or file by consuming
     /// each cluster as it arrives. Used by the UKI mode where no local
     /// staging is allowed.
+    ///
+    /// `password` is required for encrypted images, whose cluster region is
+    /// read through the blob endpoint since the registry can't locate it.
     pub fn stream_write_to_dest<F: Fn(usize, Option<bool>)>(
         &self,
         name: &str,
         tag: &str,
+        password: Option<String>,
         dest: &std::path::Path,
         progress: F,
     ) -> Result<(PrimaryHeader, ProtectedHeader, DigestTable)> {
-        let (primary, protected, _dir, digest, cluster_start) = self.fetch_manifest(name, tag)?;
-        let response = self.stream_clusters(name, tag, None)?;
+        let (primary, protected, directory, digest, cluster_start) =
+            self.fetch_manifest(name, tag, password)?;
+        let response = match primary.encryption_type {
+            HeaderEncryptionType::None => self.stream_clusters(name, tag, None)?,
+            HeaderEncryptionType::Aes256 => {
+                if directory.digest_table_offset <= cluster_start {
+                    bail!("image has an empty cluster region");
+                }
+                self.stream_blob(
+                    name,
+                    tag,
+                    Some((cluster_start, directory.digest_table_offset - 1)),
+                )?
+            }
+        };
         ImageHandle::stream_write(
             &primary,
             &protected,
@@ -19,11 +19,121 @@ pub struct RegistryImageEntry {
     pub timestamp: u64,
     /// SHA256 of the underlying `.gb` file, hex-encoded.
     pub id: String,
+    /// OS type of every element in the image (e.g. `Debian`).
+    #[serde(default)]
+    pub os: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ImageListResponse {
     pub images: Vec<RegistryImageEntry>,
+    /// Number of images that matched the query before pagination was
+    /// applied.
+    #[serde(default)]
+    pub total: usize,
+}
+
+/// Query parameters accepted by `GET /v1/images`. Every filter is optional
+/// and an empty query lists everything.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageQuery {
+    /// Only images whose name starts with this prefix.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name_prefix: Option<String>,
+    /// Only images built for this architecture.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch: Option<ImageArch>,
+    /// Only images containing an element of this OS type (case-insensitive).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    /// Only images created at or after this time (Unix seconds).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+    /// Only images created at or before this time (Unix seconds).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<u64>,
+    /// Number of matching images to skip.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+    /// Maximum number of images to return.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+impl ImageQuery {
+    /// Whether `entry` passes every filter in this query. Pagination is not
+    /// considered.
+    pub fn matches(&self, entry: &RegistryImageEntry) -> bool {
+        if let Some(prefix) = &self.name_prefix {
+            if !entry.name.starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+        if let Some(arch) = self.arch {
+            if entry.arch != arch {
+                return false;
+            }
+        }
+        if let Some(os) = &self.os {
+            if !entry.os.iter().any(|o| o.eq_ignore_ascii_case(os)) {
+                return false;
+            }
+        }
+        if self.since.is_some_and(|since| entry.timestamp < since) {
+            return false;
+        }
+        if self.until.is_some_and(|until| entry.timestamp > until) {
+            return false;
+        }
+        true
+    }
+
+    /// Filter and paginate `entries` (already in listing order).
+    pub fn apply(
+        &self,
+        entries: impl IntoIterator<Item = RegistryImageEntry>,
+    ) -> ImageListResponse {
+        let matched: Vec<RegistryImageEntry> =
+            entries.into_iter().filter(|e| self.matches(e)).collect();
+        let total = matched.len();
+        let images = matched
+            .into_iter()
+            .skip(self.offset.unwrap_or(0))
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect();
+        ImageListResponse { images, total }
+    }
+}
+
+// ── Usage ───────────────────────────────────────────────────────────────────
+
+/// Storage limits a registry enforces on pushes. `None` means unlimited.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct QuotaLimits {
+    /// Maximum bytes stored across all images.
+    pub max_total_bytes: Option<u64>,
+    /// Maximum number of tags under a single image name.
+    pub max_tags_per_name: Option<u64>,
+    /// Maximum bytes stored across all tags of a single image name.
+    pub max_bytes_per_name: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NameUsage {
+    pub name: String,
+    /// Number of tags stored under this name.
+    pub tags: u64,
+    /// On-disk size of all tags, in bytes.
+    pub bytes: u64,
+}
+
+/// Body of `GET /v1/usage`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UsageResponse {
+    /// On-disk size of every stored image, in bytes.
+    pub total_bytes: u64,
+    pub limits: QuotaLimits,
+    pub names: Vec<NameUsage>,
 }
 
 // ── Errors ──────────────────────────────────────────────────────────────────
@@ -33,3 +143,57 @@ pub struct ImageListResponse {
 pub struct ErrorResponse {
     pub message: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, arch: ImageArch, os: &str, timestamp: u64) -> RegistryImageEntry {
+        RegistryImageEntry {
+            name: name.to_string(),
+            tag: "latest".to_string(),
+            size: 0,
+            file_size: 0,
+            arch,
+            timestamp,
+            id: String::new(),
+            os: vec![os.to_string()],
+        }
+    }
+
+    #[test]
+    fn query_filters_and_paginates() {
+        let entries = vec![
+            entry("debian-desktop", ImageArch::Amd64, "Debian", 100),
+            entry("debian-server", ImageArch::Arm64, "Debian", 200),
+            entry("arch", ImageArch::Amd64, "ArchLinux", 300),
+        ];
+
+        let query = ImageQuery {
+            name_prefix: Some("debian".into()),
+            ..Default::default()
+        };
+        assert_eq!(query.apply(entries.clone()).total, 2);
+
+        let query = ImageQuery {
+            arch: Some(ImageArch::Amd64),
+            os: Some("archlinux".into()),
+            ..Default::default()
+        };
+        let result = query.apply(entries.clone());
+        assert_eq!(result.images.len(), 1);
+        assert_eq!(result.images[0].name, "arch");
+
+        let query = ImageQuery {
+            since: Some(150),
+            until: Some(300),
+            offset: Some(1),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let result = query.apply(entries);
+        assert_eq!(result.total, 2);
+        assert_eq!(result.images.len(), 1);
+        assert_eq!(result.images[0].name, "arch");
+    }
+}